@@ -0,0 +1,48 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use tsein_dns::protocol::{Aaaa, Name, Packet, Question, RRClass, RRData, RRType, A, RR};
+
+/// a response of 40 records, like those of names behind CDNs
+fn response() -> Bytes {
+    let name = Name::try_from("www.example.com").unwrap();
+    let mut packet = Packet::new_plain_answer(0x1234);
+    packet.set_question(Question::build(name.clone(), RRType::A, RRClass::Internet));
+    let ttl = Duration::from_secs(300);
+    for i in 0..20 {
+        let a = RRData::A(A::new(Ipv4Addr::new(192, 0, 2, i)));
+        packet.add_answer(RR::new(name.clone(), ttl, RRClass::Internet, a));
+        let aaaa = RRData::Aaaa(Aaaa::new(Ipv6Addr::new(
+            0x2001, 0xdb8, 0, 0, 0, 0, 0, i as u16,
+        )));
+        packet.add_answer(RR::new(name.clone(), ttl, RRClass::Internet, aaaa));
+    }
+    packet.into_bytes()
+}
+
+fn bench_parse(c: &mut Criterion) {
+    let packet = response();
+    assert_eq!(
+        Packet::parse_packet(packet.clone(), 0)
+            .unwrap()
+            .answers
+            .len(),
+        40
+    );
+    c.bench_function("parse 40 records", |b| {
+        b.iter(|| Packet::parse_packet(black_box(packet.clone()), 0).unwrap())
+    });
+}
+
+criterion_group!(benches, bench_parse);
+criterion_main!(benches);
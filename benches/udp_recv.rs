@@ -0,0 +1,99 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::VecDeque,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use bytes::{Bytes, BytesMut};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use tsein_dns::{
+    comm::RecvBuffer,
+    protocol::{Name, Packet, Question, RRClass, RRType},
+};
+
+/// counts allocations, to tell them apart from the time they take
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// datagrams held by the tasks answering them, like queries in flight under load
+const IN_FLIGHT: usize = 64;
+const DATAGRAMS: usize = 10_000;
+
+fn query() -> Bytes {
+    let name = Name::try_from("www.example.com").unwrap();
+    let q = Question::build(name, RRType::A, RRClass::Internet);
+    Packet::new_query(0x1234, q).into_bytes()
+}
+
+/// the receive loop as it was, cloning the whole buffer for every datagram
+fn recv_cloned(query: &[u8], datagrams: usize) {
+    let mut packet = BytesMut::from(&[0_u8; 1024][..]);
+    let mut in_flight = VecDeque::with_capacity(IN_FLIGHT);
+    for _ in 0..datagrams {
+        packet[..query.len()].copy_from_slice(query);
+        let received: Bytes = packet.clone().into();
+        if in_flight.len() == IN_FLIGHT {
+            in_flight.pop_front();
+        }
+        in_flight.push_back(black_box(received));
+    }
+}
+
+/// the receive loop taking each datagram off `RecvBuffer`
+fn recv_split(query: &[u8], datagrams: usize) {
+    let mut buf = RecvBuffer::new(1024);
+    let mut in_flight = VecDeque::with_capacity(IN_FLIGHT);
+    for _ in 0..datagrams {
+        buf.space()[..query.len()].copy_from_slice(query);
+        let received = buf.take(query.len());
+        if in_flight.len() == IN_FLIGHT {
+            in_flight.pop_front();
+        }
+        in_flight.push_back(black_box(received));
+    }
+}
+
+fn allocations(recv: fn(&[u8], usize), query: &[u8]) -> f64 {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    recv(query, DATAGRAMS);
+    let after = ALLOCATIONS.load(Ordering::Relaxed);
+    (after - before) as f64 / DATAGRAMS as f64
+}
+
+fn bench_recv(c: &mut Criterion) {
+    let query = query();
+    println!(
+        "allocations per datagram: cloned {:.3}, split {:.3}",
+        allocations(recv_cloned, &query),
+        allocations(recv_split, &query)
+    );
+
+    let mut group = c.benchmark_group("udp recv");
+    group.bench_function("cloned", |b| b.iter(|| recv_cloned(&query, DATAGRAMS)));
+    group.bench_function("split", |b| b.iter(|| recv_split(&query, DATAGRAMS)));
+    group.finish();
+}
+
+criterion_group!(benches, bench_recv);
+criterion_main!(benches);
@@ -0,0 +1,278 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, Write},
+    path::Path,
+};
+
+use memmap2::Mmap;
+
+use super::Action;
+use crate::protocol::Name;
+
+const MAGIC: &[u8; 8] = b"TSDNSBL1";
+
+/// ends of the parent domain keys in `key`, from the top level domain down
+fn parent_ends(key: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    let mut end = 0;
+    std::iter::from_fn(move || {
+        let len = *key.get(end)? as usize;
+        end += 1 + len;
+        Some(end)
+    })
+}
+
+/// ## `DomainList`
+/// Blocks names in the list and all names under them, kept in memory.
+///
+/// Huge lists could be compiled into a file, and served by `CompiledList`.
+#[derive(Debug, Clone)]
+pub struct DomainList {
+    keys: HashSet<Vec<u8>>,
+    action: Action,
+}
+
+impl DomainList {
+    pub fn new(names: impl IntoIterator<Item = Name>, action: Action) -> Self {
+        let keys = names.into_iter().map(|name| name.reversed_key()).collect();
+        Self { keys, action }
+    }
+
+    /// read names from `path`, one per line, comments start with `#`
+    pub fn load<P: AsRef<Path>>(path: P, action: Action) -> io::Result<Self> {
+        Self::from_lines(&std::fs::read_to_string(path)?, action)
+    }
+
+    fn from_lines(text: &str, action: Action) -> io::Result<Self> {
+        let mut names = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let name = Name::try_from(line).map_err(|_| {
+                let reason = format!("invalid name at line {}: {}", i + 1, line);
+                io::Error::new(io::ErrorKind::InvalidData, reason)
+            })?;
+            names.push(name);
+        }
+        Ok(Self::new(names, action))
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// the action, if `name` or any of its parents is in the list
+    pub fn check(&self, name: &Name) -> Option<Action> {
+        let key = name.reversed_key();
+        let blocked = parent_ends(&key).any(|end| self.keys.contains(&key[..end]));
+        blocked.then_some(self.action)
+    }
+
+    /// write the list in the format read by `CompiledList`:
+    ///
+    /// ```text
+    /// magic `TSDNSBL1` | count: u32 | count + 1 offsets: u32 | keys
+    /// ```
+    ///
+    /// Integers are little endian. Keys are sorted, the offsets point to their
+    /// starts in the key section, and the last one to its end.
+    pub fn compile<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut keys: Vec<_> = self.keys.iter().collect();
+        keys.sort_unstable();
+        let too_large = || io::Error::new(io::ErrorKind::InvalidInput, "list too large");
+
+        writer.write_all(MAGIC)?;
+        let count = u32::try_from(keys.len()).map_err(|_| too_large())?;
+        writer.write_all(&count.to_le_bytes())?;
+        let mut offset = 0_u32;
+        for key in keys.iter() {
+            writer.write_all(&offset.to_le_bytes())?;
+            offset = u32::try_from(key.len())
+                .ok()
+                .and_then(|len| offset.checked_add(len))
+                .ok_or_else(too_large)?;
+        }
+        writer.write_all(&offset.to_le_bytes())?;
+        for key in keys {
+            writer.write_all(key)?;
+        }
+        writer.flush()
+    }
+}
+
+/// ## `CompiledList`
+/// Blocks names like `DomainList`, reading a list compiled by it from a memory-mapped file.
+///
+/// Starting up takes no time and little memory whatever size the list has,
+/// while each parent of a queried name costs a binary search.
+#[derive(Debug)]
+pub struct CompiledList {
+    map: Mmap,
+    count: usize,
+    action: Action,
+}
+
+impl CompiledList {
+    /// map the compiled list at `path`, which should not be modified while mapped
+    pub fn open<P: AsRef<Path>>(path: P, action: Action) -> io::Result<Self> {
+        let file = File::open(path)?;
+        // SAFETY: the file is only read, and lists are replaced rather than written in place
+        let map = unsafe { Mmap::map(&file)? };
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        if map.len() < MAGIC.len() + 4 || &map[..MAGIC.len()] != MAGIC {
+            return Err(invalid("not a compiled blocklist"));
+        }
+        let count = u32::from_le_bytes(map[8..12].try_into().unwrap()) as usize;
+        let list = Self { map, count, action };
+
+        let keys_begin = 12 + (count + 1) * 4;
+        if list.map.len() < keys_begin {
+            return Err(invalid("truncated offsets"));
+        }
+        let keys_len = list.map.len() - keys_begin;
+        let mut last = 0;
+        for i in 0..=count {
+            let offset = list.offset(i);
+            if offset < last || offset > keys_len {
+                return Err(invalid("invalid key offsets"));
+            }
+            last = offset;
+        }
+        Ok(list)
+    }
+
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    fn offset(&self, i: usize) -> usize {
+        let pos = 12 + i * 4;
+        u32::from_le_bytes(self.map[pos..pos + 4].try_into().unwrap()) as usize
+    }
+
+    fn key(&self, i: usize) -> &[u8] {
+        let keys = &self.map[12 + (self.count + 1) * 4..];
+        &keys[self.offset(i)..self.offset(i + 1)]
+    }
+
+    fn contains(&self, key: &[u8]) -> bool {
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.key(mid).cmp(key) {
+                std::cmp::Ordering::Less => low = mid + 1,
+                std::cmp::Ordering::Greater => high = mid,
+                std::cmp::Ordering::Equal => return true,
+            }
+        }
+        false
+    }
+
+    /// the action, if `name` or any of its parents is in the list
+    pub fn check(&self, name: &Name) -> Option<Action> {
+        let key = name.reversed_key();
+        let blocked = parent_ends(&key).any(|end| self.contains(&key[..end]));
+        blocked.then_some(self.action)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::{CompiledList, DomainList};
+    use crate::{blocklist::Action, protocol::Name};
+
+    fn name(name: &str) -> Name {
+        Name::try_from(name).unwrap()
+    }
+
+    #[test]
+    fn test_compiled_list() {
+        let blocked = [
+            "doubleclick.net",
+            "ads.example.com",
+            "Tracker.Example",
+            "a.b.c.d.example.org",
+            "com.example",
+        ];
+        let list = DomainList::new(blocked.iter().map(|n| name(n)), Action::NxDomain);
+        let path = std::env::temp_dir().join(format!("tsein-dns-{}.blocklist", std::process::id()));
+        list.compile(std::fs::File::create(&path).unwrap()).unwrap();
+        let compiled = CompiledList::open(&path, Action::NxDomain).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(compiled.len(), list.len());
+
+        let queries = [
+            ("doubleclick.net", true),
+            ("ad.DoubleClick.net", true),
+            ("x.ads.example.com", true),
+            ("tracker.example", true),
+            ("a.b.c.d.example.org", true),
+            ("www.com.example", true),
+            ("example.com", false),
+            ("ads.example.org", false),
+            ("b.c.d.example.org", false),
+            ("notdoubleclick.net", false),
+            ("net", false),
+            (".", false),
+        ];
+        for (query, is_blocked) in queries {
+            let expected = is_blocked.then_some(Action::NxDomain);
+            assert_eq!(list.check(&name(query)), expected, "{}", query);
+            assert_eq!(compiled.check(&name(query)), expected, "{}", query);
+        }
+    }
+
+    #[test]
+    fn test_from_lines() {
+        let text = "
+            # trackers
+            doubleclick.net
+            Ads.Example.com  # and everything under it
+
+        ";
+        let list = DomainList::from_lines(text, Action::Sinkhole).unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.check(&name("ads.example.com")), Some(Action::Sinkhole));
+        assert_eq!(
+            list.check(&name("x.doubleclick.net")),
+            Some(Action::Sinkhole)
+        );
+        assert_eq!(list.check(&name("www.example.com")), None);
+
+        // labels are at most 63 octets
+        let text = format!("ok.example\n{}.example", "a".repeat(64));
+        let err = DomainList::from_lines(&text, Action::NxDomain).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_invalid_file() {
+        let path = std::env::temp_dir().join(format!("tsein-dns-{}.invalid", std::process::id()));
+        let mut file = std::fs::File::create(&path).unwrap();
+        // one key whose end is beyond the file
+        file.write_all(b"TSDNSBL1\x01\x00\x00\x00\x00\x00\x00\x00\x08\x00\x00\x00\x03com")
+            .unwrap();
+        drop(file);
+        assert!(CompiledList::open(&path, Action::NxDomain).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
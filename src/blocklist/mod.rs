@@ -0,0 +1,158 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+    time::Duration,
+};
+
+use thiserror::Error;
+
+pub use self::{
+    compiled::{CompiledList, DomainList},
+    pattern::{PatternList, PatternListBuilder},
+    rebinding::{Network, ParseNetworkError, RebindFilter},
+    tunnel::TunnelDetector,
+};
+use crate::{
+    comm::Answer,
+    protocol::{PacketError, Question, RRData, RRType, RR},
+};
+
+mod compiled;
+mod pattern;
+mod rebinding;
+mod tunnel;
+
+/// TTL of records answering blocked queries
+const BLOCKED_TTL: Duration = Duration::from_secs(60);
+
+/// how to answer a blocked query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// the name does not exist
+    NxDomain,
+    /// the name exists, but has no records
+    NoData,
+    /// answer `A` and `AAAA` queries by unspecified addresses, others by NODATA
+    Sinkhole,
+    /// refuse to answer
+    Refused,
+    /// answer queries of the address family by the address, others by NODATA
+    Redirect(IpAddr),
+}
+
+impl Action {
+    /// answers for `query` blocked by the action
+    pub fn answer(&self, query: &Question) -> Vec<Answer> {
+        let rdata = match (self, query.get_type()) {
+            (Action::NxDomain, _) => {
+                return vec![Answer::Error(PacketError::NameError(query.get_name()))]
+            }
+            (Action::Refused, _) => {
+                return vec![Answer::Error(PacketError::Prohibited(query.get_name()))]
+            }
+            (Action::Sinkhole, RRType::A) => RRData::A(Ipv4Addr::UNSPECIFIED.into()),
+            (Action::Sinkhole, RRType::Aaaa) => RRData::Aaaa(Ipv6Addr::UNSPECIFIED.into()),
+            (Action::Redirect(IpAddr::V4(v4)), RRType::A) => RRData::A((*v4).into()),
+            (Action::Redirect(IpAddr::V6(v6)), RRType::Aaaa) => RRData::Aaaa((*v6).into()),
+            _ => return vec![],
+        };
+        let rr = RR::new(query.get_name(), BLOCKED_TTL, query.get_class(), rdata);
+        vec![Answer::Answer(rr)]
+    }
+}
+
+/// Error occurred in parsing actions from strings
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Invalid Action: {0}")]
+pub struct ParseActionError(String);
+
+/// parse actions in configs: `nxdomain`, `nodata`, `sinkhole`, `refused`,
+/// or `redirect` followed by an address, like `redirect 192.0.2.1`.
+impl FromStr for Action {
+    type Err = ParseActionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut words = s.split_whitespace();
+        let action = match (words.next(), words.next(), words.next()) {
+            (Some(action), None, None) => match action.to_ascii_lowercase().as_str() {
+                "nxdomain" => Action::NxDomain,
+                "nodata" => Action::NoData,
+                "sinkhole" => Action::Sinkhole,
+                "refused" => Action::Refused,
+                _ => return Err(ParseActionError(s.to_string())),
+            },
+            (Some(action), Some(addr), None) if action.eq_ignore_ascii_case("redirect") => addr
+                .parse()
+                .map(Action::Redirect)
+                .map_err(|_| ParseActionError(s.to_string()))?,
+            _ => return Err(ParseActionError(s.to_string())),
+        };
+        Ok(action)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::{Action, ParseActionError};
+    use crate::{
+        comm::Answer,
+        protocol::{Name, PacketError, Question, RRClass, RRData, RRType},
+    };
+
+    #[test]
+    fn test_parse_action() {
+        assert_eq!("NXDOMAIN".parse(), Ok(Action::NxDomain));
+        assert_eq!("nodata".parse(), Ok(Action::NoData));
+        assert_eq!("sinkhole".parse(), Ok(Action::Sinkhole));
+        assert_eq!("refused".parse(), Ok(Action::Refused));
+        assert_eq!(
+            "redirect 192.0.2.1".parse(),
+            Ok(Action::Redirect(Ipv4Addr::new(192, 0, 2, 1).into()))
+        );
+        for invalid in ["", "block", "redirect", "redirect example.com", "nodata 1"] {
+            assert_eq!(
+                invalid.parse::<Action>(),
+                Err(ParseActionError(invalid.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_answer() {
+        let question = |ty| {
+            let name = Name::try_from("portal.example").unwrap();
+            Question::build(name, ty, RRClass::Internet)
+        };
+        let portal = Action::Redirect(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+        match &portal.answer(&question(RRType::A))[..] {
+            [Answer::Answer(rr)] => match rr.clone().into_rdata() {
+                RRData::A(a) => assert_eq!(Ipv4Addr::from(a), Ipv4Addr::new(192, 0, 2, 1)),
+                rdata => panic!("unexpected redirection: {:?}", rdata),
+            },
+            answers => panic!("unexpected answers: {:?}", answers),
+        }
+        assert!(portal.answer(&question(RRType::Aaaa)).is_empty());
+
+        let portal = Action::Redirect(IpAddr::V6(Ipv6Addr::LOCALHOST));
+        match &portal.answer(&question(RRType::Aaaa))[..] {
+            [Answer::Answer(rr)] => match rr.clone().into_rdata() {
+                RRData::Aaaa(aaaa) => assert_eq!(Ipv6Addr::from(aaaa), Ipv6Addr::LOCALHOST),
+                rdata => panic!("unexpected redirection: {:?}", rdata),
+            },
+            answers => panic!("unexpected answers: {:?}", answers),
+        }
+
+        assert!(matches!(
+            Action::Refused.answer(&question(RRType::Txt))[..],
+            [Answer::Error(PacketError::Prohibited(_))]
+        ));
+    }
+}
@@ -0,0 +1,202 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use regex::{RegexSet, RegexSetBuilder};
+
+use super::Action;
+use crate::protocol::Name;
+
+/// ## `Glob`
+/// `*` matches any characters including dots, `?` matches a single character,
+/// e.g. `*.doubleclick.*` matches `ad.doubleclick.net`.
+/// Names are matched in lower case and without the trailing dot.
+#[derive(Debug, Clone)]
+struct Glob {
+    pattern: Vec<u8>,
+}
+
+impl Glob {
+    fn new(pattern: &str) -> Self {
+        let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+        Self {
+            pattern: pattern.into_bytes(),
+        }
+    }
+
+    fn is_match(&self, name: &[u8]) -> bool {
+        let pattern = &self.pattern[..];
+        let (mut p, mut n) = (0, 0);
+        // position of the last `*` in pattern, and where it started matching in name
+        let mut star = None;
+        while n < name.len() {
+            match pattern.get(p) {
+                Some(b'*') => {
+                    star = Some((p, n));
+                    p += 1;
+                }
+                Some(&c) if c == b'?' || c == name[n] => {
+                    p += 1;
+                    n += 1;
+                }
+                _ => match star {
+                    // let the last `*` eat one more character
+                    Some((sp, sn)) => {
+                        star = Some((sp, sn + 1));
+                        p = sp + 1;
+                        n = sn + 1;
+                    }
+                    None => return false,
+                },
+            }
+        }
+        pattern[p..].iter().all(|&c| c == b'*')
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PatternListBuilder {
+    globs: Vec<(Glob, Action)>,
+    regexes: Vec<(String, Action)>,
+}
+
+impl PatternListBuilder {
+    pub fn glob(mut self, pattern: &str, action: Action) -> Self {
+        self.globs.push((Glob::new(pattern), action));
+        self
+    }
+
+    pub fn regex(mut self, pattern: &str, action: Action) -> Self {
+        self.regexes.push((pattern.to_string(), action));
+        self
+    }
+
+    /// compile all regexes at once
+    pub fn build(self) -> Result<PatternList, regex::Error> {
+        let (patterns, regex_actions): (Vec<String>, Vec<Action>) =
+            self.regexes.into_iter().unzip();
+        let regexes = RegexSetBuilder::new(patterns)
+            .case_insensitive(true)
+            .build()?;
+        Ok(PatternList {
+            globs: self.globs,
+            regexes,
+            regex_actions,
+        })
+    }
+}
+
+/// ## `PatternList`
+/// Blocks names matching glob or regex patterns.
+///
+/// Globs are cheap and tried first, regexes are compiled into a single set
+/// so a name is scanned once for all of them.
+#[derive(Debug)]
+pub struct PatternList {
+    globs: Vec<(Glob, Action)>,
+    regexes: RegexSet,
+    regex_actions: Vec<Action>,
+}
+
+impl PatternList {
+    pub fn builder() -> PatternListBuilder {
+        PatternListBuilder::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.globs.is_empty() && self.regexes.is_empty()
+    }
+
+    /// action of the first pattern matching `name`
+    pub fn check(&self, name: &Name) -> Option<Action> {
+        if self.is_empty() {
+            return None;
+        }
+        let name = name.to_string().to_ascii_lowercase();
+        let name = name.trim_end_matches('.');
+        if let Some((_, action)) = self
+            .globs
+            .iter()
+            .find(|(glob, _)| glob.is_match(name.as_bytes()))
+        {
+            return Some(*action);
+        }
+        self.regexes
+            .matches(name)
+            .iter()
+            .next()
+            .map(|index| self.regex_actions[index])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Glob, PatternList};
+    use crate::{blocklist::Action, protocol::Name};
+
+    fn name(name: &str) -> Name {
+        Name::try_from(name).unwrap()
+    }
+
+    #[test]
+    fn test_glob() {
+        let glob = Glob::new("*.doubleclick.*");
+        assert!(glob.is_match(b"ad.doubleclick.net"));
+        assert!(glob.is_match(b"a.b.doubleclick.co.uk"));
+        assert!(!glob.is_match(b"doubleclick.net"));
+        assert!(!glob.is_match(b"ad.doubleclicks.net"));
+
+        let glob = Glob::new("ads?.example.com.");
+        assert!(glob.is_match(b"ads1.example.com"));
+        assert!(!glob.is_match(b"ads.example.com"));
+        assert!(Glob::new("*").is_match(b""));
+    }
+
+    #[test]
+    fn test_glob_action() {
+        let patterns = PatternList::builder()
+            .glob("*.doubleclick.*", Action::NxDomain)
+            .glob("*.tracker.example", Action::Sinkhole)
+            .build()
+            .unwrap();
+        assert_eq!(
+            patterns.check(&name("Ad.DoubleClick.net")),
+            Some(Action::NxDomain)
+        );
+        assert_eq!(
+            patterns.check(&name("a.tracker.example")),
+            Some(Action::Sinkhole)
+        );
+        assert_eq!(patterns.check(&name("example.com")), None);
+    }
+
+    #[test]
+    fn test_regex_action() {
+        let patterns = PatternList::builder()
+            .glob("*.doubleclick.*", Action::NxDomain)
+            .regex(r"^ads?\d*\.", Action::NoData)
+            .build()
+            .unwrap();
+        assert_eq!(
+            patterns.check(&name("ads42.example.com")),
+            Some(Action::NoData)
+        );
+        assert_eq!(
+            patterns.check(&name("ad.example.com")),
+            Some(Action::NoData)
+        );
+        // globs are tried first
+        assert_eq!(
+            patterns.check(&name("ad.doubleclick.net")),
+            Some(Action::NxDomain)
+        );
+        assert_eq!(patterns.check(&name("bad.example.com")), None);
+
+        assert!(PatternList::builder()
+            .regex("(", Action::NoData)
+            .build()
+            .is_err());
+    }
+}
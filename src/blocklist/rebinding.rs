@@ -0,0 +1,222 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use thiserror::Error;
+
+use super::Action;
+use crate::{
+    comm::Answer,
+    protocol::{Name, Question, RRData},
+};
+
+/// an IP network, like `10.0.0.0/8`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    /// `prefix` is clamped to the length of `addr`
+    pub fn new(addr: IpAddr, prefix: u8) -> Self {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        Self {
+            addr,
+            prefix: prefix.min(max),
+        }
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Error occurred in parsing networks from strings
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Invalid Network: {0}")]
+pub struct ParseNetworkError(String);
+
+/// parse networks in CIDR notation like `192.0.2.0/24`,
+/// a bare address is a network of itself alone
+impl FromStr for Network {
+    type Err = ParseNetworkError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseNetworkError(s.to_string());
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Self::new(addr, prefix))
+    }
+}
+
+/// ## `RebindFilter`
+/// Rejects forwarded answers pointing public names to private addresses,
+/// against DNS rebinding attacks.
+///
+/// By default, RFC 1918, loopback, link-local and unique local addresses are forbidden.
+/// Names under local zones may still resolve to them.
+#[derive(Debug, Clone)]
+pub struct RebindFilter {
+    forbidden: Vec<Network>,
+    local_zones: Vec<Name>,
+    action: Action,
+}
+
+impl Default for RebindFilter {
+    fn default() -> Self {
+        let v4 = |a, b, prefix| Network::new(Ipv4Addr::new(a, b, 0, 0).into(), prefix);
+        let v6 = |segment, prefix| {
+            Network::new(Ipv6Addr::new(segment, 0, 0, 0, 0, 0, 0, 0).into(), prefix)
+        };
+        let forbidden = vec![
+            v4(10, 0, 8),
+            v4(172, 16, 12),
+            v4(192, 168, 16),
+            v4(127, 0, 8),
+            v4(169, 254, 16),
+            Network::new(Ipv6Addr::LOCALHOST.into(), 128),
+            v6(0xfe80, 10),
+            v6(0xfc00, 7),
+        ];
+        Self {
+            forbidden,
+            local_zones: vec![],
+            action: Action::NoData,
+        }
+    }
+}
+
+impl RebindFilter {
+    /// forbid `networks` instead of the default ones
+    pub fn with_forbidden(mut self, networks: Vec<Network>) -> Self {
+        self.forbidden = networks;
+        self
+    }
+
+    /// allow names under `zones` to resolve to forbidden addresses
+    pub fn with_local_zones(mut self, zones: Vec<Name>) -> Self {
+        self.local_zones = zones;
+        self
+    }
+
+    /// answer filtered queries by `action`, NODATA by default
+    pub fn with_action(mut self, action: Action) -> Self {
+        self.action = action;
+        self
+    }
+
+    fn is_forbidden(&self, ip: IpAddr) -> bool {
+        // IPv4-mapped addresses reach the same hosts as IPv4 ones
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        self.forbidden.iter().any(|net| net.contains(ip))
+    }
+
+    /// replace `answers` of `query` by the configured action,
+    /// if any `A` or `AAAA` record in them points to a forbidden address
+    pub fn filter(&self, query: &Question, answers: Vec<Answer>) -> Vec<Answer> {
+        let name = query.get_name();
+        if self
+            .local_zones
+            .iter()
+            .any(|zone| name.is_subdomain_of(zone))
+        {
+            return answers;
+        }
+        let rebinding = answers.iter().any(|ans| match ans {
+            Answer::Answer(rr) => match rr.clone().into_rdata() {
+                RRData::A(a) => self.is_forbidden(Ipv4Addr::from(a).into()),
+                RRData::Aaaa(aaaa) => self.is_forbidden(Ipv6Addr::from(aaaa).into()),
+                _ => false,
+            },
+            _ => false,
+        });
+        if rebinding {
+            tracing::warn!("filtered private addresses in answers of {}", name);
+            return self.action.answer(query);
+        }
+        answers
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use super::{Network, RebindFilter};
+
+    #[test]
+    fn test_network() {
+        let net = Network::new("172.16.0.0".parse().unwrap(), 12);
+        assert!(net.contains("172.31.255.255".parse().unwrap()));
+        assert!(!net.contains("172.32.0.1".parse().unwrap()));
+        assert!(!net.contains("::ffff:172.16.0.1".parse().unwrap()));
+
+        let any = Network::new(IpAddr::from([0, 0, 0, 0]), 0);
+        assert!(any.contains("203.0.113.1".parse().unwrap()));
+
+        assert_eq!("172.16.0.0/12".parse(), Ok(net));
+        let host = Network::new("2001:db8::1".parse().unwrap(), 128);
+        assert_eq!("2001:db8::1".parse(), Ok(host));
+        for invalid in ["172.16.0.0/33", "172.16.0.0/", "example.com/8", "::/-1"] {
+            assert!(invalid.parse::<Network>().is_err(), "{} parsed", invalid);
+        }
+
+        let filter = RebindFilter::default();
+        for forbidden in [
+            "10.1.2.3",
+            "127.0.0.1",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:192.168.1.1",
+        ] {
+            assert!(
+                filter.is_forbidden(forbidden.parse().unwrap()),
+                "{}",
+                forbidden
+            );
+        }
+        for allowed in ["192.0.2.1", "2001:db8::1", "::"] {
+            assert!(
+                !filter.is_forbidden(allowed.parse().unwrap()),
+                "{}",
+                allowed
+            );
+        }
+    }
+}
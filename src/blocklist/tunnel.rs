@@ -0,0 +1,287 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use moka::sync::Cache;
+use tokio::time::Instant;
+
+use super::Action;
+use crate::protocol::{Name, Question};
+
+/// base domains tracked at most, the least used ones are dropped beyond it
+const MAX_TRACKED: u64 = 4096;
+/// seconds suspicious queries are counted in by default
+const DEFAULT_WINDOW: Duration = Duration::from_secs(10);
+/// public suffixes of more than one label, taken by the common registrations under them
+const PUBLIC_SUFFIXES: &[&str] = &[
+    "co.uk", "org.uk", "ac.uk", "gov.uk", "com.au", "net.au", "org.au", "co.nz", "co.jp", "ne.jp",
+    "or.jp", "co.kr", "co.in", "co.za", "com.br", "com.cn", "net.cn", "org.cn", "com.hk", "com.tw",
+    "com.mx", "com.tr",
+];
+
+struct Window {
+    start: Instant,
+    suspicious: u32,
+    /// the flood is only reported once in a window
+    reported: bool,
+}
+
+/// ## `TunnelDetector`
+/// Spots DNS tunneling and DGA subdomains, by floods of random looking names
+/// under the same base domain.
+///
+/// A query is suspicious if the labels under its base domain, e.g. `example.com`
+/// of `x7kq2m.example.com` by default, are long and of high entropy.
+/// Under public suffixes of more than one label, like `co.uk`, the base domain
+/// has a label more than the suffix, e.g. `example.co.uk`.
+/// Only the common ones are known, more can be given by `with_public_suffixes`.
+/// Once a base domain gets more suspicious queries than allowed in a window,
+/// they are logged, and refused if configured so.
+/// A single random name, like those of CDNs, never trips it.
+pub struct TunnelDetector {
+    /// Shannon entropy in bits per character, from which labels are suspicious
+    min_entropy: f64,
+    /// labels shorter than this in total are never suspicious
+    min_len: usize,
+    max_suspicious: u32,
+    window: Duration,
+    base_labels: usize,
+    public_suffixes: Vec<Name>,
+    refuse: bool,
+    windows: Cache<Name, Arc<Mutex<Window>>>,
+}
+
+impl Default for TunnelDetector {
+    fn default() -> Self {
+        Self {
+            min_entropy: 3.5,
+            min_len: 16,
+            max_suspicious: 20,
+            window: DEFAULT_WINDOW,
+            base_labels: 2,
+            public_suffixes: PUBLIC_SUFFIXES
+                .iter()
+                .map(|suffix| Name::try_from(*suffix).unwrap())
+                .collect(),
+            refuse: false,
+            windows: windows(DEFAULT_WINDOW),
+        }
+    }
+}
+
+impl TunnelDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// labels of at least `min_len` characters with at least `bits` of entropy per character
+    /// are suspicious
+    pub fn with_entropy(mut self, bits: f64, min_len: usize) -> Self {
+        self.min_entropy = bits;
+        self.min_len = min_len;
+        self
+    }
+
+    /// allow `count` suspicious queries for each base domain in `window`
+    pub fn with_rate(mut self, count: u32, window: Duration) -> Self {
+        self.max_suspicious = count;
+        self.window = window;
+        self.windows = windows(window);
+        self
+    }
+
+    /// count the last `labels` labels of names as their base domain, 2 by default
+    pub fn with_base_labels(mut self, labels: usize) -> Self {
+        self.base_labels = labels.max(1);
+        self
+    }
+
+    /// take names under `suffixes` as public suffixes besides the common ones,
+    /// whose base domains have a label more than the suffix
+    pub fn with_public_suffixes(mut self, suffixes: Vec<Name>) -> Self {
+        self.public_suffixes.extend(suffixes);
+        self
+    }
+
+    /// refuse suspicious queries beyond the rate, instead of only logging them
+    pub fn with_refuse(mut self, refuse: bool) -> Self {
+        self.refuse = refuse;
+        self
+    }
+
+    /// check `query`, none if it passes, or the action it is answered by
+    pub fn check(&self, query: &Question) -> Option<Action> {
+        let name = query.get_name();
+        let base_labels = self
+            .public_suffixes
+            .iter()
+            .filter(|suffix| name.is_subdomain_of(suffix))
+            .map(|suffix| suffix.label_count() + 1)
+            .fold(self.base_labels, usize::max);
+        if name.label_count() <= base_labels {
+            return None;
+        }
+        let sub: Vec<u8> = name
+            .labels()
+            .take(name.label_count() - base_labels)
+            .flatten()
+            .copied()
+            .collect();
+        if sub.len() < self.min_len || entropy(&sub) < self.min_entropy {
+            return None;
+        }
+
+        let base = name.suffix(base_labels);
+        let now = Instant::now();
+        let window = self.windows.get_with(base.clone(), || {
+            let window = Window {
+                start: now,
+                suspicious: 0,
+                reported: false,
+            };
+            Arc::new(Mutex::new(window))
+        });
+        let mut window = window.lock().unwrap();
+        if now - window.start >= self.window {
+            *window = Window {
+                start: now,
+                suspicious: 0,
+                reported: false,
+            };
+        }
+        window.suspicious += 1;
+        if window.suspicious <= self.max_suspicious {
+            tracing::debug!("suspicious query {} under {}", name, base);
+            return None;
+        }
+        if !window.reported {
+            window.reported = true;
+            tracing::warn!(
+                "possible DNS tunnel under {}: {} high entropy queries in {:?}, like {}",
+                base,
+                window.suspicious,
+                self.window,
+                name
+            );
+        }
+        self.refuse.then_some(Action::Refused)
+    }
+}
+
+/// windows of base domains, dropped once they are over
+fn windows(window: Duration) -> Cache<Name, Arc<Mutex<Window>>> {
+    Cache::builder()
+        .time_to_live(window)
+        .max_capacity(MAX_TRACKED)
+        .build()
+}
+
+/// Shannon entropy of `text` in bits per character, ignoring ASCII case
+fn entropy(text: &[u8]) -> f64 {
+    let mut counts = [0_usize; 256];
+    for b in text {
+        counts[b.to_ascii_lowercase() as usize] += 1;
+    }
+    let len = text.len() as f64;
+    counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{entropy, TunnelDetector};
+    use crate::{
+        blocklist::Action,
+        protocol::{Name, Question, RRClass, RRType},
+    };
+
+    fn query(name: &str) -> Question {
+        Question::build(
+            Name::try_from(name).unwrap(),
+            RRType::Txt,
+            RRClass::Internet,
+        )
+    }
+
+    /// random looking labels like those of tunnels, encoding data in base32
+    fn encoded(seed: u64, len: usize) -> String {
+        const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+        let mut x = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+        (0..len)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                ALPHABET[(x % 32) as usize] as char
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_entropy() {
+        assert_eq!(entropy(b"aaaa"), 0.0);
+        assert_eq!(entropy(b"abcd"), 2.0);
+        assert_eq!(entropy(b"AbBa"), 1.0);
+        assert!(entropy(encoded(1, 40).as_bytes()) > 4.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flood() {
+        let detector = TunnelDetector::new()
+            .with_rate(10, Duration::from_secs(10))
+            .with_refuse(true);
+
+        // everyday names never trip it, however many
+        for i in 0..100 {
+            let name = ["www", "mail", "accounts", "static-content"][i % 4];
+            let q = query(&format!("{}.example.com", name));
+            assert_eq!(detector.check(&q), None);
+        }
+        // nor a few random ones, like those of CDNs
+        for i in 0..10 {
+            let q = query(&format!("{}.cdn.example", encoded(i, 20)));
+            assert_eq!(detector.check(&q), None);
+        }
+
+        // a flood of encoded data is refused beyond the rate
+        let refused: Vec<_> = (0..30)
+            .map(|i| query(&format!("{}.tunnel.example", encoded(100 + i, 50))))
+            .map(|q| detector.check(&q))
+            .collect();
+        assert!(refused[..10].iter().all(Option::is_none));
+        assert!(refused[10..].iter().all(|a| *a == Some(Action::Refused)));
+        // other domains are not affected
+        let q = query(&format!("{}.other.example", encoded(200, 50)));
+        assert_eq!(detector.check(&q), None);
+
+        // registrations under public suffixes are counted apart
+        for base in ["tunnel.co.uk", "other.co.uk"] {
+            let refused: Vec<_> = (0..11)
+                .map(|i| query(&format!("{}.{}", encoded(400 + i, 50), base)))
+                .map(|q| detector.check(&q))
+                .collect();
+            assert!(refused[..10].iter().all(Option::is_none), "{}", base);
+            assert_eq!(refused[10], Some(Action::Refused), "{}", base);
+        }
+
+        // counted again in the next window
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let q = query(&format!("{}.tunnel.example", encoded(300, 50)));
+        assert_eq!(detector.check(&q), None);
+    }
+}
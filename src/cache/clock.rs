@@ -0,0 +1,26 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use tokio::time;
+
+/// `Clock` is the source of time used for computing TTLs and deadlines of cached records.
+///
+/// Replace it to control expiry of records, for example in tests.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> time::Instant;
+}
+
+/// `TokioClock` reads time from the tokio runtime,
+/// so it follows `tokio::time::pause` and `tokio::time::advance`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    #[inline]
+    fn now(&self) -> time::Instant {
+        time::Instant::now()
+    }
+}
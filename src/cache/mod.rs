@@ -4,23 +4,73 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicBool, AtomicU64, Ordering},
+    Arc,
+};
 
 use async_recursion::async_recursion;
-use moka::future::Cache;
+use moka::future::{Cache, ConcurrentCacheExt};
 use tokio::{sync::mpsc, time};
 
+pub use self::clock::{Clock, TokioClock};
 use crate::{
-    comm::{Answer, Task},
-    protocol::Question,
+    comm::{Answer, ClientSubnet, Task},
+    protocol::{min_ttl, Name, PacketError, Question, RRData, RRType, RR},
 };
+
+mod clock;
+
 pub type Data = Vec<Answer>;
-type RawCache = Cache<Question, (Data, time::Instant)>;
+/// answers of a question, apart for each network of clients if they are cached so
+type Key = (Question, Option<ClientSubnet>);
+type RawCache = Cache<Key, (Data, time::Instant)>;
+/// SOA records of parent zones, none if the upstream has no SOA for the zone
+type SoaCache = Cache<Name, (Option<RR>, time::Instant)>;
+
+/// time to cache answers without a TTL
+const DEFAULT_TTL: time::Duration = time::Duration::from_secs(600);
+/// TTLs are at most 2^31 - 1 seconds, see [RFC2181](https://datatracker.ietf.org/doc/html/rfc2181#section-8)
+const MAX_TTL: time::Duration = time::Duration::from_secs(i32::MAX as u64);
+/// TTL of stale answers, see [RFC8767](https://datatracker.ietf.org/doc/html/rfc8767#section-4)
+const STALE_TTL: time::Duration = time::Duration::from_secs(30);
+/// default time to cache a SERVFAIL from upstream
+const SERVFAIL_TTL: time::Duration = time::Duration::from_secs(5);
+/// caching resolution failures SHOULD NOT exceed 5 minutes, see [RFC9520](https://datatracker.ietf.org/doc/html/rfc9520#section-3.2)
+const MAX_SERVFAIL_TTL: time::Duration = time::Duration::from_secs(300);
+
+/// counts of lookups answered by the cache
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// lookups answered by cached records
+    pub hits: u64,
+    /// lookups forwarded to upstream
+    pub misses: u64,
+    /// hits on cached NXDOMAIN or NODATA answers, also counted in `hits`
+    pub negative: u64,
+    /// questions currently cached
+    pub entries: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    negative: AtomicU64,
+}
 
 #[derive(Clone)]
 pub struct DnsCache {
     cache: RawCache,
+    soas: SoaCache,
     rec: Arc<mpsc::UnboundedSender<Task>>,
+    servfail_ttl: time::Duration,
+    min_ttl: time::Duration,
+    max_ttl: time::Duration,
+    stale_window: time::Duration,
+    client_subnet: Option<(u8, u8)>,
+    clock: Arc<dyn Clock>,
+    counters: Arc<Counters>,
 }
 
 impl DnsCache {
@@ -29,92 +79,1079 @@ impl DnsCache {
             .max_capacity(capacity)
             .time_to_live(time::Duration::from_secs(600))
             .build();
+        let soas = SoaCache::builder()
+            .max_capacity(capacity / 16 + 1)
+            .time_to_live(time::Duration::from_secs(600))
+            .build();
         let rec = Arc::new(rec_sender);
-        Self { cache, rec }
+        Self {
+            cache,
+            soas,
+            rec,
+            servfail_ttl: SERVFAIL_TTL,
+            min_ttl: time::Duration::ZERO,
+            max_ttl: MAX_TTL,
+            stale_window: time::Duration::ZERO,
+            client_subnet: None,
+            clock: Arc::new(TokioClock),
+            counters: Arc::new(Counters::default()),
+        }
+    }
+
+    /// set how long a SERVFAIL from upstream is cached, at most 5 minutes.
+    pub fn with_servfail_ttl(mut self, ttl: time::Duration) -> Self {
+        self.servfail_ttl = ttl.min(MAX_SERVFAIL_TTL);
+        self
+    }
+
+    /// raise TTLs of records shorter than `ttl`, SERVFAIL is not affected.
+    pub fn with_min_ttl(mut self, ttl: time::Duration) -> Self {
+        self.min_ttl = ttl;
+        self
+    }
+
+    /// cap TTLs of records longer than `ttl`, it wins over the floor set by `with_min_ttl`.
+    pub fn with_max_ttl(mut self, ttl: time::Duration) -> Self {
+        self.max_ttl = ttl;
+        self
+    }
+
+    /// serve expired answers for up to `window` after they expire,
+    /// if refreshing them from upstream fails. Disabled by default.
+    ///
+    /// Records are still evicted by the underlying cache after 10 minutes of real time.
+    pub fn with_stale_window(mut self, window: time::Duration) -> Self {
+        self.stale_window = window;
+        self
+    }
+
+    /// cache answers apart for each network of clients, the first `v4_prefix` or `v6_prefix` bits
+    /// of their addresses, and forward queries along with it.
+    ///
+    /// Upstreams told client subnets may answer each network differently,
+    /// see `Edns::with_client_subnet`. All clients share the answers by default.
+    pub fn with_client_subnet(mut self, v4_prefix: u8, v6_prefix: u8) -> Self {
+        self.client_subnet = Some((v4_prefix, v6_prefix));
+        self
+    }
+
+    /// set the source of time for TTLs and deadlines of cached records.
+    ///
+    /// Records are still evicted by the underlying cache after 10 minutes of real time.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
     }
 
     // get will surely return a record, if it does exist
     // or it will return a None, then, just NXDOMAIN.
-    #[async_recursion]
     pub async fn get(&mut self, q: Question) -> Vec<Answer> {
+        self.get_for(q, None).await
+    }
+
+    /// answers of `q` asked from `client`, see `with_client_subnet`
+    #[async_recursion]
+    pub async fn get_for(&mut self, q: Question, client: Option<ClientSubnet>) -> Vec<Answer> {
+        let subnet = client
+            .zip(self.client_subnet)
+            .map(|(c, (v4, v6))| c.narrowed(v4, v6));
+        let key = (q.clone(), subnet);
+        let now = self.clock.now();
+        // kept in case the refresh fails and stale answers are served instead
+        let previous = if self.stale_window.is_zero() {
+            None
+        } else {
+            self.cache.get(&key)
+        };
+        let forwarding = forward(
+            self.rec.clone(),
+            self.soas.clone(),
+            q.clone(),
+            subnet,
+            self.servfail_ttl,
+            (self.min_ttl, self.max_ttl),
+            self.clock.clone(),
+        );
+        // only set if the cached records are missing or expired
+        let missed = AtomicBool::new(false);
+        let init = async {
+            missed.store(true, Ordering::Relaxed);
+            forwarding.await
+        };
         let (got, ddl) = self
             .cache
-            .get_with_if(
-                q.clone(),
-                forward(self.rec.clone(), q.clone()),
-                |(_, ddl)| ddl <= &time::Instant::now(),
-            )
+            .get_with_if(key.clone(), init, |(_, ddl)| ddl <= &now)
             .await;
-        let ttl = ddl - time::Instant::now();
-        got.into_iter()
-            .map(|rr| match rr {
-                Answer::Error(e) => Answer::Error(e),
-                Answer::Answer(mut a) => {
-                    a.set_ttl(ttl);
-                    Answer::Answer(a)
-                }
-                Answer::NameServer(mut ns) => {
-                    ns.set_ttl(ttl);
-                    Answer::NameServer(ns)
-                }
-                Answer::Additional(mut additional) => {
-                    additional.set_ttl(ttl);
-                    Answer::Additional(additional)
+        if missed.load(Ordering::Relaxed) {
+            self.counters.misses.fetch_add(1, Ordering::Relaxed);
+            self.cache_related(&q, subnet, &got).await;
+        } else {
+            self.counters.hits.fetch_add(1, Ordering::Relaxed);
+            if is_negative(&got) {
+                self.counters.negative.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        // the refresh failed, by a SERVFAIL from upstream or by timeout
+        if is_servfail(&got) {
+            if let Some((stale, expired)) = previous {
+                if !is_servfail(&stale) && expired + self.stale_window > now {
+                    tracing::debug!("refreshing {} failed, serving stale answers", q.get_name());
+                    // put the stale answers back, still expired, so the next query retries
+                    self.cache.insert(key, (stale.clone(), expired)).await;
+                    return with_ttl(stale, STALE_TTL);
                 }
+            }
+        }
+        with_ttl(got, ddl - self.clock.now())
+    }
+
+    /// cache RRsets sent along the forwarded answers of `q`, see `related`.
+    ///
+    /// Fresh entries are never replaced, records sent along rank below answers
+    /// to questions of their own.
+    async fn cache_related(&self, q: &Question, subnet: Option<ClientSubnet>, data: &Data) {
+        let now = self.clock.now();
+        for (question, data) in related(q, data) {
+            let key = (question, subnet);
+            if self.cache.get(&key).is_some_and(|(_, ddl)| ddl > now) {
+                continue;
+            }
+            let records = data.iter().filter_map(|ans| match ans {
+                Answer::Answer(rr) => Some(rr),
+                _ => None,
+            });
+            let ttl = min_ttl(records)
+                .map_or(DEFAULT_TTL, |ttl| ttl.min(DEFAULT_TTL))
+                .max(self.min_ttl)
+                .min(self.max_ttl);
+            tracing::debug!(
+                "cache {} {:?} along with answers of {}",
+                key.0.get_name(),
+                key.0.get_type(),
+                q.get_name()
+            );
+            self.cache.insert(key, (data, now + ttl)).await;
+        }
+    }
+
+    /// drop cached answers of `name`, of type `ty` or of all types, in every class.
+    ///
+    /// Cached questions are scanned for the name, rather than rebuilding keys for each
+    /// class and type, so questions of unusual types are not missed.
+    /// The scan takes time linear to the number of entries, fine for occasional purging.
+    pub async fn invalidate(&self, name: &Name, ty: Option<RRType>) {
+        let stale: Vec<Key> = self
+            .cache
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| {
+                let q = &key.0;
+                q.get_name() == *name && ty.is_none_or(|ty| q.get_type() == ty)
             })
-            .collect()
+            .map(|key| (*key).clone())
+            .collect();
+        for key in stale {
+            tracing::info!("invalidate cached answers of {}", key.0.get_name());
+            self.cache.invalidate(&key).await;
+        }
+    }
+
+    /// drop all cached answers
+    pub fn invalidate_all(&self) {
+        self.cache.invalidate_all();
+        self.soas.invalidate_all();
+    }
+
+    /// counts of hits and misses since the cache was created, shared by its clones
+    pub fn stats(&self) -> CacheStats {
+        // apply pending insertions and evictions, or the entry count lags behind
+        self.cache.sync();
+        CacheStats {
+            hits: self.counters.hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            negative: self.counters.negative.load(Ordering::Relaxed),
+            entries: self.cache.entry_count(),
+        }
+    }
+}
+
+/// NXDOMAIN, or NODATA without any error
+fn is_negative(data: &Data) -> bool {
+    match data.first() {
+        Some(Answer::Error(e)) => matches!(e, PacketError::NameError(_)),
+        _ => !data.iter().any(|ans| matches!(ans, Answer::Answer(_))),
+    }
+}
+
+fn is_servfail(data: &Data) -> bool {
+    matches!(data.first(), Some(Answer::Error(PacketError::ServFail)))
+}
+
+/// RRsets in the forwarded answers to `query` answering other questions as well:
+/// the rest of a CNAME chain for each of its targets, the NS records of a zone
+/// enclosing the name, and glue addresses of those name servers within the zone.
+fn related(query: &Question, data: &Data) -> Vec<(Question, Data)> {
+    if matches!(data.first(), Some(Answer::Error(_))) {
+        return vec![];
+    }
+    let class = query.get_class();
+    let mut related = vec![];
+
+    let answers: Vec<&RR> = data
+        .iter()
+        .filter_map(|ans| match ans {
+            Answer::Answer(rr) => Some(rr),
+            _ => None,
+        })
+        .collect();
+    let mut name = query.get_name();
+    let mut chain = vec![];
+    // looping chains end once every record is in the chain
+    while query.get_type() != RRType::Cname && chain.len() < answers.len() {
+        let target = answers
+            .iter()
+            .filter(|rr| rr.get_type() == RRType::Cname && rr.get_domain() == name)
+            .find_map(|rr| match (*rr).clone().into_rdata() {
+                RRData::Cname(target) => Some(Name::from(target)),
+                _ => None,
+            });
+        let Some(target) = target else { break };
+        chain.push(std::mem::replace(&mut name, target));
+        let rest: Data = answers
+            .iter()
+            .filter(|rr| !chain.contains(&rr.get_domain()))
+            .map(|&rr| Answer::Answer(rr.clone()))
+            .collect();
+        if !rest.is_empty() {
+            let q = Question::build(name.clone(), query.get_type(), class);
+            related.push((q, rest));
+        }
+    }
+
+    let servers: Vec<&RR> = data
+        .iter()
+        .filter_map(|ans| match ans {
+            Answer::NameServer(rr) if rr.get_type() == RRType::Ns => Some(rr),
+            _ => None,
+        })
+        .collect();
+    let zone = servers
+        .iter()
+        .map(|rr| rr.get_domain())
+        .find(|zone| name.is_subdomain_of(zone) || query.get_name().is_subdomain_of(zone));
+    let Some(zone) = zone else {
+        return related;
+    };
+    let servers: Vec<&RR> = servers
+        .into_iter()
+        .filter(|rr| rr.get_domain() == zone)
+        .collect();
+    let mut targets: Vec<Name> = vec![];
+    for rr in servers.iter() {
+        if let RRData::Ns(ns) = (*rr).clone().into_rdata() {
+            let target = Name::from(ns);
+            if !targets.contains(&target) {
+                targets.push(target);
+            }
+        }
+    }
+    let ns = servers
+        .iter()
+        .map(|&rr| Answer::Answer(rr.clone()))
+        .collect();
+    related.push((Question::build(zone.clone(), RRType::Ns, class), ns));
+
+    // addresses of servers out of the zone are not for the zone to tell
+    for server in targets
+        .iter()
+        .filter(|target| target.is_subdomain_of(&zone))
+    {
+        for ty in [RRType::A, RRType::Aaaa] {
+            let glue: Data = data
+                .iter()
+                .filter_map(|ans| match ans {
+                    Answer::Additional(rr) if rr.get_type() == ty && rr.get_domain() == *server => {
+                        Some(Answer::Answer(rr.clone()))
+                    }
+                    _ => None,
+                })
+                .collect();
+            if !glue.is_empty() {
+                related.push((Question::build(server.clone(), ty, class), glue));
+            }
+        }
     }
+    related
 }
 
-async fn forward(rec: Arc<mpsc::UnboundedSender<Task>>, query: Question) -> (Data, time::Instant) {
+/// rewrite TTLs of records to `ttl`
+fn with_ttl(data: Data, ttl: time::Duration) -> Vec<Answer> {
+    data.into_iter()
+        .map(|rr| match rr {
+            Answer::Error(e) => Answer::Error(e),
+            Answer::Authoritative => Answer::Authoritative,
+            Answer::Answer(mut a) => {
+                a.set_ttl(ttl);
+                Answer::Answer(a)
+            }
+            Answer::NameServer(mut ns) => {
+                ns.set_ttl(ttl);
+                Answer::NameServer(ns)
+            }
+            Answer::Additional(mut additional) => {
+                additional.set_ttl(ttl);
+                Answer::Additional(additional)
+            }
+        })
+        .collect()
+}
+
+async fn forward(
+    rec: Arc<mpsc::UnboundedSender<Task>>,
+    soas: SoaCache,
+    query: Question,
+    subnet: Option<ClientSubnet>,
+    servfail_ttl: time::Duration,
+    ttl_limits: (time::Duration, time::Duration),
+    clock: Arc<dyn Clock>,
+) -> (Data, time::Instant) {
     let name = query.get_name();
     tracing::debug!("start forwarding query: {}", name);
     let (ans_to, mut ans_from) = mpsc::unbounded_channel();
-    let task = Task::Query(query, ans_to);
+    let task = Task::Query(query.clone(), ans_to, subnet);
     let _ = rec.send(task);
 
-    let mut min_ttl = time::Duration::from_secs(600);
     let mut answers = vec![];
+    let mut error = None;
+    // negative answers are cached by the SOA of the zone
+    let mut negative_ttl = None;
     while let Some(ans) = ans_from.recv().await {
         match ans {
             Answer::Error(e) => {
                 tracing::warn!("get error from upstream: {:?}", e);
-                min_ttl = time::Duration::from_secs(600);
-                answers.clear();
-                answers.push(Answer::Error(e));
-                break;
-            }
-            Answer::Answer(a) => {
-                min_ttl = if min_ttl < a.get_ttl() {
-                    min_ttl
-                } else {
-                    a.get_ttl()
-                };
-                answers.push(Answer::Answer(a));
+                error.get_or_insert(e);
             }
+            // served from cache later, the answers are no longer authoritative
+            Answer::Authoritative => {}
             Answer::NameServer(ns) => {
-                min_ttl = if min_ttl < ns.get_ttl() {
-                    min_ttl
-                } else {
-                    ns.get_ttl()
-                };
+                if let Some(negative) = soa_negative_ttl(&ns) {
+                    negative_ttl = Some(negative_ttl.unwrap_or(negative).min(negative));
+                }
                 answers.push(Answer::NameServer(ns));
             }
-            Answer::Additional(additional) => {
-                min_ttl = if min_ttl < additional.get_ttl() {
-                    min_ttl
-                } else {
-                    additional.get_ttl()
-                };
-                answers.push(Answer::Additional(additional));
+            ans => answers.push(ans),
+        }
+    }
+    // some upstreams omit the SOA of NXDOMAIN, borrow it from the parent zone
+    if matches!(error, Some(PacketError::NameError(_))) && negative_ttl.is_none() {
+        if let Some(soa) = parent_soa(&rec, &soas, &query, clock.as_ref()).await {
+            negative_ttl = soa_negative_ttl(&soa);
+            answers.push(Answer::NameServer(soa));
+        }
+    }
+    let records = answers.iter().filter_map(|ans| match ans {
+        Answer::Answer(rr) | Answer::NameServer(rr) | Answer::Additional(rr) => Some(rr),
+        _ => None,
+    });
+    // answers are cached no longer than answers without TTLs
+    let mut ttl = min_ttl(records).map_or(DEFAULT_TTL, |ttl| ttl.min(DEFAULT_TTL));
+    let is_nodata = !answers.iter().any(|ans| matches!(ans, Answer::Answer(_)));
+    match error {
+        Some(e) => {
+            ttl = match e {
+                // failures are retried soon, avoiding upstream query storms
+                PacketError::ServFail => servfail_ttl,
+                PacketError::NameError(_) => negative_ttl.unwrap_or(DEFAULT_TTL),
+                _ => DEFAULT_TTL,
+            };
+            // only the authority section is meaningful in failed responses
+            answers.retain(|ans| matches!(ans, Answer::NameServer(_)));
+            answers.insert(0, Answer::Error(e));
+        }
+        None if is_nodata => {
+            if let Some(negative) = negative_ttl {
+                ttl = ttl.min(negative);
             }
         }
+        None => {}
+    }
+    if !matches!(answers.first(), Some(Answer::Error(PacketError::ServFail))) {
+        let (floor, ceiling) = ttl_limits;
+        ttl = ttl.max(floor).min(ceiling);
     }
     tracing::info!(
-        "Got {} RRs from upstream with minimum ttl: {}s",
+        "Got {} RRs from upstream with min_ttl: {}s",
         answers.len(),
-        min_ttl.as_secs()
+        ttl.as_secs()
     );
-    let ddl = time::Instant::now() + min_ttl;
+    let ddl = clock.now() + ttl;
     (answers, ddl)
 }
+
+/// SOA record of the zone enclosing `query`, asked from upstream once per TTL of the SOA
+async fn parent_soa(
+    rec: &mpsc::UnboundedSender<Task>,
+    soas: &SoaCache,
+    query: &Question,
+    clock: &dyn Clock,
+) -> Option<RR> {
+    let name = query.get_name();
+    if name.label_count() == 0 {
+        return None;
+    }
+    let parent = name.get_parent_domain();
+    let now = clock.now();
+    let asking = async {
+        tracing::debug!("ask upstream for SOA of {}", parent);
+        let (ans_to, mut ans_from) = mpsc::unbounded_channel();
+        let q = Question::build(parent.clone(), RRType::Soa, query.get_class());
+        let _ = rec.send(Task::Query(q, ans_to, None));
+        let mut soa = None;
+        while let Some(ans) = ans_from.recv().await {
+            // the SOA of the parent itself, or of the zone enclosing it
+            if let Answer::Answer(rr) | Answer::NameServer(rr) = ans {
+                if rr.get_type() == RRType::Soa {
+                    soa.get_or_insert(rr);
+                }
+            }
+        }
+        let ttl = soa.as_ref().map_or(DEFAULT_TTL, RR::get_ttl);
+        (soa, clock.now() + ttl)
+    };
+    let (soa, _) = soas
+        .get_with_if(parent.clone(), asking, |(_, ddl)| ddl <= &now)
+        .await;
+    soa
+}
+
+/// TTL of negative answers by an SOA record in the authority section,
+/// the lesser of the TTL of the SOA record and its MINIMUM field
+fn soa_negative_ttl(rr: &RR) -> Option<time::Duration> {
+    match rr.clone().into_rdata() {
+        RRData::Soa(soa) => {
+            let minimum = time::Duration::from_secs(soa.minimum() as u64);
+            Some(minimum.min(rr.get_ttl()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use bytes::{BufMut, BytesMut};
+    use tokio::{sync::mpsc, time};
+
+    use super::{CacheStats, Clock, DnsCache};
+    use crate::{
+        comm::{forward, Answer, ClientSubnet, Task},
+        protocol::{Name, Packet, PacketError, Question, RRClass, RRData, RRType, RR},
+    };
+
+    /// a manually advanced clock
+    struct MockClock(Mutex<time::Instant>);
+
+    impl MockClock {
+        fn new() -> Arc<Self> {
+            Arc::new(Self(Mutex::new(time::Instant::now())))
+        }
+
+        fn advance(&self, duration: time::Duration) {
+            *self.0.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for MockClock {
+        fn now(&self) -> time::Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    /// a mock upstream answering queries with `respond`, returns the count of forwarded queries
+    fn upstream<F>(mut rec: mpsc::UnboundedReceiver<Task>, respond: F) -> Arc<AtomicUsize>
+    where
+        F: Fn(&Question) -> Vec<Answer> + Send + 'static,
+    {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        tokio::spawn(async move {
+            while let Some(Task::Query(q, ans_to, _)) = rec.recv().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                for ans in respond(&q) {
+                    let _ = ans_to.send(ans);
+                }
+            }
+        });
+        count
+    }
+
+    /// a mock upstream failing every query
+    fn failing_upstream(rec: mpsc::UnboundedReceiver<Task>) -> Arc<AtomicUsize> {
+        upstream(rec, |_| vec![Answer::Error(PacketError::ServFail)])
+    }
+
+    fn a_record(q: &Question, ttl: u64) -> Answer {
+        let a = RRData::A(Ipv4Addr::new(192, 0, 2, 1).into());
+        let rr = RR::new(
+            q.get_name(),
+            time::Duration::from_secs(ttl),
+            q.get_class(),
+            a,
+        );
+        Answer::Answer(rr)
+    }
+
+    /// an SOA record for `example.com.`, parsed from wire
+    fn soa_record(ttl: u32, minimum: u32) -> RR {
+        let mname = Name::try_from("ns.example.com").unwrap();
+        let rname = Name::try_from("admin.example.com").unwrap();
+        let mut pkt = BytesMut::new();
+        // NXDOMAIN response with an authority record
+        pkt.put_slice(&[0, 0, 0x81, 0x83, 0, 0, 0, 0, 0, 1, 0, 0]);
+        pkt.put(
+            Name::try_from("example.com")
+                .unwrap()
+                .as_bytes_uncompressed(),
+        );
+        pkt.put_u16(RRType::Soa.into());
+        pkt.put_u16(RRClass::Internet.into());
+        pkt.put_u32(ttl);
+        pkt.put_u16((mname.len() + 1 + rname.len() + 1 + 4 * 5) as u16);
+        pkt.put(mname.as_bytes_uncompressed());
+        pkt.put(rname.as_bytes_uncompressed());
+        for field in [1, 3600, 600, 86400, minimum] {
+            pkt.put_u32(field);
+        }
+        Packet::parse_packet(pkt.freeze(), 0)
+            .unwrap()
+            .authorities
+            .remove(0)
+    }
+
+    fn question() -> Question {
+        let name = Name::try_from("example.com").unwrap();
+        Question::build(name, RRType::A, RRClass::Internet)
+    }
+
+    #[tokio::test]
+    async fn test_servfail_ttl() {
+        time::pause();
+        let (rec_sender, rec) = mpsc::unbounded_channel();
+        let forwarded = failing_upstream(rec);
+        let mut cache =
+            DnsCache::new(16, rec_sender).with_servfail_ttl(time::Duration::from_secs(3));
+
+        let answers = cache.get(question()).await;
+        assert!(matches!(
+            answers[..],
+            [Answer::Error(PacketError::ServFail)]
+        ));
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+
+        // served from cache within the SERVFAIL window
+        time::advance(time::Duration::from_secs(2)).await;
+        let answers = cache.get(question()).await;
+        assert!(matches!(
+            answers[..],
+            [Answer::Error(PacketError::ServFail)]
+        ));
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+
+        // forwarded again after the window
+        time::advance(time::Duration::from_secs(2)).await;
+        cache.get(question()).await;
+        assert_eq!(forwarded.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_servfail_ttl_limit() {
+        let (rec_sender, _) = mpsc::unbounded_channel();
+        let cache =
+            DnsCache::new(16, rec_sender).with_servfail_ttl(time::Duration::from_secs(3600));
+        assert_eq!(cache.servfail_ttl, time::Duration::from_secs(300));
+    }
+
+    #[tokio::test]
+    async fn test_mock_clock() {
+        let (rec_sender, rec) = mpsc::unbounded_channel();
+        let forwarded = upstream(rec, |q| vec![a_record(q, 60)]);
+        let clock = MockClock::new();
+        let mut cache = DnsCache::new(16, rec_sender).with_clock(clock.clone());
+
+        cache.get(question()).await;
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+
+        // TTL of cached records counts down with the clock
+        clock.advance(time::Duration::from_secs(45));
+        let answers = cache.get(question()).await;
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+        match &answers[..] {
+            [Answer::Answer(rr)] => assert_eq!(rr.get_ttl(), time::Duration::from_secs(15)),
+            _ => panic!("unexpected answers: {:?}", answers),
+        }
+
+        // expired without sleeping
+        clock.advance(time::Duration::from_secs(15));
+        cache.get(question()).await;
+        assert_eq!(forwarded.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cached_answer_not_authoritative() {
+        let (rec_sender, rec) = mpsc::unbounded_channel();
+        upstream(rec, |q| vec![Answer::Authoritative, a_record(q, 60)]);
+        let mut cache = DnsCache::new(16, rec_sender);
+
+        let answers = cache.get(question()).await;
+        assert!(matches!(answers[..], [Answer::Answer(_)]));
+    }
+
+    #[tokio::test]
+    async fn test_related_rrsets() {
+        fn record(owner: &str, data: RRData) -> RR {
+            let owner = Name::try_from(owner).unwrap();
+            RR::new(
+                owner,
+                time::Duration::from_secs(60),
+                RRClass::Internet,
+                data,
+            )
+        }
+        fn name(name: &str) -> Name {
+            Name::try_from(name).unwrap()
+        }
+        fn query(owner: &str, ty: RRType) -> Question {
+            Question::build(name(owner), ty, RRClass::Internet)
+        }
+
+        let (rec_sender, rec) = mpsc::unbounded_channel();
+        let forwarded = upstream(rec, |q| {
+            if q.get_name() != name("alias.example.com") {
+                return vec![a_record(q, 60)];
+            }
+            let ns1 = RRData::Ns(name("ns1.example.com").into());
+            let ns2 = RRData::Ns(name("ns.example.net").into());
+            let glue = RRData::A(Ipv4Addr::new(192, 0, 2, 53).into());
+            let out_of_zone = RRData::A(Ipv4Addr::new(192, 0, 2, 54).into());
+            let target = RRData::Cname(name("www.example.com").into());
+            vec![
+                Answer::Answer(record("alias.example.com", target)),
+                a_record(&query("www.example.com", RRType::A), 60),
+                Answer::NameServer(record("example.com", ns1)),
+                Answer::NameServer(record("example.com", ns2)),
+                Answer::Additional(record("ns1.example.com", glue)),
+                Answer::Additional(record("ns.example.net", out_of_zone)),
+            ]
+        });
+        let mut cache = DnsCache::new(16, rec_sender);
+
+        let answers = cache.get(query("alias.example.com", RRType::A)).await;
+        assert_eq!(answers.len(), 6);
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+
+        // the target of the CNAME, name servers and glue are served from cache
+        let answers = cache.get(query("www.example.com", RRType::A)).await;
+        assert!(matches!(&answers[..], [Answer::Answer(rr)] if rr.get_type() == RRType::A));
+        let answers = cache.get(query("example.com", RRType::Ns)).await;
+        assert_eq!(answers.len(), 2);
+        assert!(answers
+            .iter()
+            .all(|ans| matches!(ans, Answer::Answer(rr) if rr.get_type() == RRType::Ns)));
+        let answers = cache.get(query("ns1.example.com", RRType::A)).await;
+        let glue: Vec<Ipv4Addr> = answers
+            .iter()
+            .filter_map(|ans| match ans {
+                Answer::Answer(rr) => match rr.clone().into_rdata() {
+                    RRData::A(a) => Some(a.into()),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect();
+        assert_eq!(glue, vec![Ipv4Addr::new(192, 0, 2, 53)]);
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+
+        // glue out of the zone is not cached
+        cache.get(query("ns.example.net", RRType::A)).await;
+        assert_eq!(forwarded.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_mixed_case_hit() {
+        let (rec_sender, rec) = mpsc::unbounded_channel();
+        let forwarded = upstream(rec, |q| vec![a_record(q, 60)]);
+        let mut cache = DnsCache::new(16, rec_sender);
+
+        cache.get(question()).await;
+        let name = Name::try_from("ExAmPlE.CoM").unwrap();
+        let answers = cache
+            .get(Question::build(name, RRType::A, RRClass::Internet))
+            .await;
+        assert!(matches!(answers[..], [Answer::Answer(_)]));
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_negative_cache() {
+        let (rec_sender, rec) = mpsc::unbounded_channel();
+        let forwarded = upstream(rec, |q| {
+            vec![
+                Answer::Error(PacketError::NameError(q.get_name())),
+                Answer::NameServer(soa_record(3600, 30)),
+            ]
+        });
+        let clock = MockClock::new();
+        let mut cache = DnsCache::new(16, rec_sender).with_clock(clock.clone());
+
+        let answers = cache.get(question()).await;
+        assert!(matches!(
+            answers[..],
+            [
+                Answer::Error(PacketError::NameError(_)),
+                Answer::NameServer(_)
+            ]
+        ));
+
+        // cached by the SOA MINIMUM, lesser than the TTL of the SOA
+        clock.advance(time::Duration::from_secs(29));
+        let answers = cache.get(question()).await;
+        assert!(matches!(
+            answers[..],
+            [
+                Answer::Error(PacketError::NameError(_)),
+                Answer::NameServer(_)
+            ]
+        ));
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+
+        clock.advance(time::Duration::from_secs(2));
+        cache.get(question()).await;
+        assert_eq!(forwarded.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_negative_cache_parent_soa() {
+        let (rec_sender, rec) = mpsc::unbounded_channel();
+        let soa_queries = Arc::new(AtomicUsize::new(0));
+        let counter = soa_queries.clone();
+        let forwarded = upstream(rec, move |q| match q.get_type() {
+            RRType::Soa => {
+                counter.fetch_add(1, Ordering::SeqCst);
+                assert_eq!(q.get_name(), Name::try_from("example.com").unwrap());
+                vec![Answer::Answer(soa_record(3600, 30))]
+            }
+            // bare NXDOMAIN without the SOA
+            _ => vec![Answer::Error(PacketError::NameError(q.get_name()))],
+        });
+        let clock = MockClock::new();
+        let mut cache = DnsCache::new(16, rec_sender).with_clock(clock.clone());
+        let nxdomain = |name: &str| {
+            let name = Name::try_from(name).unwrap();
+            Question::build(name, RRType::A, RRClass::Internet)
+        };
+
+        let answers = cache.get(nxdomain("www.example.com")).await;
+        match &answers[..] {
+            [Answer::Error(PacketError::NameError(_)), Answer::NameServer(soa)] => {
+                assert_eq!(soa.get_type(), RRType::Soa);
+                assert_eq!(soa.get_domain(), Name::try_from("example.com").unwrap());
+            }
+            _ => panic!("unexpected answers: {:?}", answers),
+        }
+
+        // the SOA of the parent is cached for other names under it
+        let answers = cache.get(nxdomain("ftp.example.com")).await;
+        assert!(matches!(
+            answers[..],
+            [
+                Answer::Error(PacketError::NameError(_)),
+                Answer::NameServer(_)
+            ]
+        ));
+        assert_eq!(soa_queries.load(Ordering::SeqCst), 1);
+        assert_eq!(forwarded.load(Ordering::SeqCst), 3);
+
+        // cached by the SOA MINIMUM of the parent
+        clock.advance(time::Duration::from_secs(29));
+        cache.get(nxdomain("www.example.com")).await;
+        assert_eq!(forwarded.load(Ordering::SeqCst), 3);
+        clock.advance(time::Duration::from_secs(2));
+        cache.get(nxdomain("www.example.com")).await;
+        assert_eq!(forwarded.load(Ordering::SeqCst), 4);
+        assert_eq!(soa_queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_nodata_cache() {
+        let (rec_sender, rec) = mpsc::unbounded_channel();
+        // cached by the SOA MINIMUM rather than the TTL of the SOA
+        let forwarded = upstream(rec, |_| vec![Answer::NameServer(soa_record(300, 10))]);
+        let clock = MockClock::new();
+        let mut cache = DnsCache::new(16, rec_sender).with_clock(clock.clone());
+
+        cache.get(question()).await;
+        clock.advance(time::Duration::from_secs(9));
+        cache.get(question()).await;
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+
+        clock.advance(time::Duration::from_secs(2));
+        cache.get(question()).await;
+        assert_eq!(forwarded.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_ttl_floor() {
+        let (rec_sender, rec) = mpsc::unbounded_channel();
+        let forwarded = upstream(rec, |q| vec![a_record(q, 1)]);
+        let clock = MockClock::new();
+        let mut cache = DnsCache::new(16, rec_sender)
+            .with_clock(clock.clone())
+            .with_min_ttl(time::Duration::from_secs(30));
+
+        let answers = cache.get(question()).await;
+        match &answers[..] {
+            [Answer::Answer(a)] => assert_eq!(a.get_ttl(), time::Duration::from_secs(30)),
+            _ => panic!("unexpected answers: {:?}", answers),
+        }
+
+        clock.advance(time::Duration::from_secs(20));
+        let answers = cache.get(question()).await;
+        match &answers[..] {
+            [Answer::Answer(a)] => assert_eq!(a.get_ttl(), time::Duration::from_secs(10)),
+            _ => panic!("unexpected answers: {:?}", answers),
+        }
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_ttl_ceiling() {
+        let (rec_sender, rec) = mpsc::unbounded_channel();
+        let forwarded = upstream(rec, |q| vec![a_record(q, 7 * 24 * 3600)]);
+        let clock = MockClock::new();
+        let mut cache = DnsCache::new(16, rec_sender)
+            .with_clock(clock.clone())
+            .with_max_ttl(time::Duration::from_secs(60));
+
+        let answers = cache.get(question()).await;
+        match &answers[..] {
+            [Answer::Answer(a)] => assert_eq!(a.get_ttl(), time::Duration::from_secs(60)),
+            _ => panic!("unexpected answers: {:?}", answers),
+        }
+
+        clock.advance(time::Duration::from_secs(61));
+        cache.get(question()).await;
+        assert_eq!(forwarded.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_long_ttl() {
+        let (rec_sender, rec) = mpsc::unbounded_channel();
+        let forwarded = upstream(rec, |q| vec![a_record(q, 3600)]);
+        let clock = MockClock::new();
+        let mut cache = DnsCache::new(16, rec_sender).with_clock(clock.clone());
+
+        cache.get(question()).await;
+        // cached no longer than answers without TTLs
+        clock.advance(super::DEFAULT_TTL - time::Duration::from_secs(1));
+        let answers = cache.get(question()).await;
+        match &answers[..] {
+            [Answer::Answer(a)] => assert_eq!(a.get_ttl(), time::Duration::from_secs(1)),
+            _ => panic!("unexpected answers: {:?}", answers),
+        }
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+        clock.advance(time::Duration::from_secs(1));
+        cache.get(question()).await;
+        assert_eq!(forwarded.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_serve_stale() {
+        let (rec_sender, rec) = mpsc::unbounded_channel();
+        let failing = Arc::new(AtomicBool::new(false));
+        let fail = failing.clone();
+        let forwarded = upstream(rec, move |q| {
+            if fail.load(Ordering::SeqCst) {
+                vec![Answer::Error(PacketError::ServFail)]
+            } else {
+                vec![a_record(q, 10)]
+            }
+        });
+        let clock = MockClock::new();
+        let mut cache = DnsCache::new(16, rec_sender)
+            .with_clock(clock.clone())
+            .with_stale_window(time::Duration::from_secs(60));
+
+        cache.get(question()).await;
+        failing.store(true, Ordering::SeqCst);
+
+        // expired for 10s, the refresh fails
+        clock.advance(time::Duration::from_secs(20));
+        let answers = cache.get(question()).await;
+        match &answers[..] {
+            [Answer::Answer(a)] => assert_eq!(a.get_ttl(), time::Duration::from_secs(30)),
+            _ => panic!("unexpected answers: {:?}", answers),
+        }
+        assert_eq!(forwarded.load(Ordering::SeqCst), 2);
+
+        // out of the stale window
+        clock.advance(time::Duration::from_secs(60));
+        let answers = cache.get(question()).await;
+        assert!(matches!(
+            answers[..],
+            [Answer::Error(PacketError::ServFail)]
+        ));
+        assert_eq!(forwarded.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let (rec_sender, rec) = mpsc::unbounded_channel();
+        upstream(rec, |q| {
+            if q.get_name() == Name::try_from("example.com").unwrap() {
+                vec![a_record(q, 60)]
+            } else {
+                vec![Answer::Error(PacketError::NameError(q.get_name()))]
+            }
+        });
+        let mut cache = DnsCache::new(16, rec_sender);
+        let missing = || {
+            let name = Name::try_from("missing.example.com").unwrap();
+            Question::build(name, RRType::A, RRClass::Internet)
+        };
+
+        cache.get(question()).await;
+        cache.get(question()).await;
+        cache.clone().get(question()).await;
+        cache.get(missing()).await;
+        cache.get(missing()).await;
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 3,
+                misses: 2,
+                negative: 1,
+                entries: 2,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_invalidate() {
+        let (rec_sender, rec) = mpsc::unbounded_channel();
+        let forwarded = upstream(rec, |q| vec![a_record(q, 60)]);
+        let mut cache = DnsCache::new(16, rec_sender);
+        let query =
+            |name: &str, ty| Question::build(Name::try_from(name).unwrap(), ty, RRClass::Internet);
+
+        for q in [
+            query("example.com", RRType::A),
+            query("example.com", RRType::Aaaa),
+            query("example.com", RRType::Mx),
+            query("example.net", RRType::A),
+        ] {
+            cache.get(q).await;
+        }
+        assert_eq!(forwarded.load(Ordering::SeqCst), 4);
+
+        // only `example.com. A` is dropped
+        let name = Name::try_from("EXAMPLE.com").unwrap();
+        cache.invalidate(&name, Some(RRType::A)).await;
+        assert_eq!(cache.stats().entries, 3);
+        cache.get(query("example.com", RRType::Aaaa)).await;
+        assert_eq!(forwarded.load(Ordering::SeqCst), 4);
+        cache.get(query("example.com", RRType::A)).await;
+        assert_eq!(forwarded.load(Ordering::SeqCst), 5);
+
+        // all types of `example.com.`
+        cache.invalidate(&name, None).await;
+        assert_eq!(cache.stats().entries, 1);
+        cache.get(query("example.net", RRType::A)).await;
+        assert_eq!(forwarded.load(Ordering::SeqCst), 5);
+        cache.get(query("example.com", RRType::Mx)).await;
+        assert_eq!(forwarded.load(Ordering::SeqCst), 6);
+
+        cache.invalidate_all();
+        assert_eq!(cache.stats().entries, 0);
+        cache.get(query("example.net", RRType::A)).await;
+        assert_eq!(forwarded.load(Ordering::SeqCst), 7);
+    }
+
+    #[tokio::test]
+    async fn test_serve_stale_on_servfail_response() {
+        let (rec_sender, rec) = mpsc::unbounded_channel();
+        let failing = Arc::new(AtomicBool::new(false));
+        let fail = failing.clone();
+        let forwarded = upstream(rec, move |q| {
+            if fail.load(Ordering::SeqCst) {
+                // an upstream response with RCODE SERVFAIL, rather than a timeout
+                let mut failure = Packet::new_failure(0, PacketError::ServFail);
+                failure.set_authorities(vec![soa_record(300, 60)]);
+                forward::into_answers(failure)
+            } else {
+                vec![a_record(q, 10)]
+            }
+        });
+        let clock = MockClock::new();
+        let mut cache = DnsCache::new(16, rec_sender)
+            .with_clock(clock.clone())
+            .with_stale_window(time::Duration::from_secs(60));
+
+        cache.get(question()).await;
+        failing.store(true, Ordering::SeqCst);
+        clock.advance(time::Duration::from_secs(20));
+
+        // the SERVFAIL is not cached, each refresh is retried and falls back to stale data
+        for forwards in [2, 3] {
+            let answers = cache.get(question()).await;
+            match &answers[..] {
+                [Answer::Answer(a)] => {
+                    assert_eq!(a.get_domain(), question().get_name());
+                    assert_eq!(a.get_ttl(), time::Duration::from_secs(30));
+                }
+                _ => panic!("unexpected answers: {:?}", answers),
+            }
+            assert_eq!(forwarded.load(Ordering::SeqCst), forwards);
+        }
+    }
+    #[tokio::test]
+    async fn test_client_subnet() {
+        let (rec_sender, mut rec) = mpsc::unbounded_channel();
+        let (subnets_to, mut subnets) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(Task::Query(q, ans_to, subnet)) = rec.recv().await {
+                let _ = subnets_to.send(subnet);
+                let _ = ans_to.send(a_record(&q, 60));
+            }
+        });
+        let client = |ip: &str| Some(ClientSubnet::from(ip.parse::<IpAddr>().unwrap()));
+
+        // clients of the same network share answers, other networks are forwarded apart
+        let mut cache = DnsCache::new(16, rec_sender.clone()).with_client_subnet(24, 56);
+        cache.get_for(question(), client("192.0.2.1")).await;
+        cache.get_for(question(), client("192.0.2.200")).await;
+        cache.get_for(question(), client("198.51.100.1")).await;
+        let network = |subnet: Option<ClientSubnet>| {
+            subnet.map(|subnet| (subnet.address().to_string(), subnet.prefix()))
+        };
+        let forwarded = network(subnets.recv().await.unwrap());
+        assert_eq!(forwarded, Some((String::from("192.0.2.0"), 24)));
+        let forwarded = network(subnets.recv().await.unwrap());
+        assert_eq!(forwarded, Some((String::from("198.51.100.0"), 24)));
+        assert!(subnets.try_recv().is_err());
+
+        // all clients share answers by default, nothing is told about them
+        let mut cache = DnsCache::new(16, rec_sender);
+        cache.get_for(question(), client("192.0.2.1")).await;
+        cache.get_for(question(), client("198.51.100.1")).await;
+        assert_eq!(subnets.recv().await.unwrap(), None);
+        assert!(subnets.try_recv().is_err());
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use quinn::Endpoint;
+use tokio::sync::mpsc;
+use tokio_rustls::rustls::ClientConfig;
+
+use crate::{
+    comm::{
+        client::{DohForwarder, LoadBalance, QuicForwarder, TcpForwarder, TlsForwarder},
+        Answer, Task,
+    },
+    protocol::{Name, Question, RRClass, RRType, RR},
+};
+
+/// ## `Upstream`
+/// The server a `Client` asks, and the protocol it is asked by.
+pub enum Upstream {
+    /// plain DNS over TCP
+    Tcp(SocketAddr),
+    /// DNS over TLS, the server is verified by `domain`
+    Tls {
+        addr: SocketAddr,
+        domain: String,
+        config: Arc<ClientConfig>,
+    },
+    /// DNS over QUIC, by the default client config of `endpoint`
+    Quic {
+        addr: SocketAddr,
+        domain: String,
+        endpoint: Endpoint,
+    },
+    /// DNS over HTTPS to `url`, connecting to `addr` instead of resolving the host of `url`
+    Https {
+        url: String,
+        addr: SocketAddr,
+        config: Arc<ClientConfig>,
+    },
+}
+
+/// ## `Client`
+/// A stub resolver for applications, asking an upstream without running a server.
+///
+/// ```no_run
+/// use tsein_dns::{
+///     client::{Client, Upstream},
+///     protocol::RRType,
+/// };
+///
+/// # async fn resolve() -> anyhow::Result<()> {
+/// let client = Client::new(Upstream::Tcp("192.0.2.53:53".parse()?)).await?;
+/// for rr in client.query("example.com", RRType::A).await? {
+///     println!("{:?}", rr.into_rdata());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Client {
+    tasks: mpsc::UnboundedSender<Task>,
+}
+
+impl Client {
+    /// connect to `upstream`, failing if it is unreachable.
+    ///
+    /// Queries are forwarded in the background, until the client and its clones are dropped.
+    pub async fn new(upstream: Upstream) -> Result<Self> {
+        let (tasks, rec) = mpsc::unbounded_channel();
+        match upstream {
+            Upstream::Tcp(addr) => {
+                let forwarder = TcpForwarder::try_new(rec, addr).await?;
+                tokio::spawn(forwarder.run());
+            }
+            Upstream::Tls {
+                addr,
+                domain,
+                config,
+            } => {
+                let forwarder = TlsForwarder::try_new(rec, config, &domain, addr).await?;
+                tokio::spawn(forwarder.run());
+            }
+            Upstream::Quic {
+                addr,
+                domain,
+                endpoint,
+            } => {
+                let upstreams = vec![(domain, addr)];
+                let forwarder =
+                    QuicForwarder::try_new(rec, endpoint, upstreams, LoadBalance::FirstHealthy)
+                        .await?;
+                tokio::spawn(forwarder.run());
+            }
+            Upstream::Https { url, addr, config } => {
+                let forwarder = DohForwarder::try_new(rec, config, &url, addr).await?;
+                tokio::spawn(forwarder.run());
+            }
+        }
+        Ok(Self { tasks })
+    }
+
+    /// records of type `ty` of `name` in class IN, in the order of the answer section.
+    ///
+    /// A CNAME chain leading to the records is included.
+    /// Failures like NXDOMAIN and SERVFAIL are errors of `PacketError`.
+    pub async fn query(&self, name: &str, ty: RRType) -> Result<Vec<RR>> {
+        let query = Question::build(Name::try_from(name)?, ty, RRClass::Internet);
+        let (ans_to, mut ans_from) = mpsc::unbounded_channel();
+        self.tasks
+            .send(Task::Query(query, ans_to, None))
+            .map_err(|_| anyhow!("forwarder of the client stopped"))?;
+
+        let mut records = vec![];
+        while let Some(ans) = ans_from.recv().await {
+            match ans {
+                Answer::Error(e) => return Err(e.into()),
+                Answer::Answer(rr) => records.push(rr),
+                _ => {}
+            }
+        }
+        Ok(records)
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::net::IpAddr;
+
+use super::unmapped;
+use crate::{blocklist::Network, protocol::PacketError};
+
+/// ## `Acl`
+/// Decides which clients may query at all, by the networks they are in.
+///
+/// Allowing by default, clients in the denied networks are refused,
+/// unless they are in the allowed networks as well.
+/// Denying by default, only clients in the allowed networks are served,
+/// unless they are in the denied networks as well.
+///
+/// Refused clients of TCP, TLS, QUIC and DoH are answered by REFUSED,
+/// those of UDP are ignored unless `with_udp_refused` is set.
+#[derive(Debug, Clone)]
+pub struct Acl {
+    allow_by_default: bool,
+    allowed: Vec<Network>,
+    denied: Vec<Network>,
+    udp_refused: bool,
+}
+
+impl Default for Acl {
+    fn default() -> Self {
+        Self::allow_by_default()
+    }
+}
+
+impl Acl {
+    /// serve all clients, but those denied by `with_denied`
+    pub fn allow_by_default() -> Self {
+        Self {
+            allow_by_default: true,
+            allowed: vec![],
+            denied: vec![],
+            udp_refused: false,
+        }
+    }
+
+    /// serve no client, but those allowed by `with_allowed`
+    pub fn deny_by_default() -> Self {
+        Self {
+            allow_by_default: false,
+            ..Self::allow_by_default()
+        }
+    }
+
+    pub fn with_allowed(mut self, network: Network) -> Self {
+        self.allowed.push(network);
+        self
+    }
+
+    pub fn with_denied(mut self, network: Network) -> Self {
+        self.denied.push(network);
+        self
+    }
+
+    /// answer refused UDP clients by REFUSED instead of ignoring them.
+    ///
+    /// Sources of datagrams are easily spoofed, the answers would go to someone else.
+    pub fn with_udp_refused(mut self, udp_refused: bool) -> Self {
+        self.udp_refused = udp_refused;
+        self
+    }
+
+    pub fn is_allowed(&self, client: IpAddr) -> bool {
+        let client = unmapped(client);
+        let allowed = self.allowed.iter().any(|net| net.contains(client));
+        let denied = self.denied.iter().any(|net| net.contains(client));
+        if self.allow_by_default {
+            allowed || !denied
+        } else {
+            allowed && !denied
+        }
+    }
+
+    /// check whether `client` may query, the error is `PacketError::Refused` otherwise
+    pub(crate) fn check(&self, client: IpAddr) -> Result<(), PacketError> {
+        if self.is_allowed(client) {
+            return Ok(());
+        }
+        tracing::debug!("refused client {} by ACL", client);
+        Err(PacketError::Refused(client))
+    }
+
+    pub(crate) fn udp_refused(&self) -> bool {
+        self.udp_refused
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use super::Acl;
+    use crate::blocklist::Network;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_allow_by_default() {
+        let acl = Acl::allow_by_default()
+            .with_denied(Network::new(ip("198.51.100.0"), 24))
+            .with_allowed(Network::new(ip("198.51.100.53"), 32));
+        assert!(acl.is_allowed(ip("192.0.2.1")));
+        assert!(acl.is_allowed(ip("2001:db8::1")));
+        assert!(!acl.is_allowed(ip("198.51.100.1")));
+        assert!(!acl.is_allowed(ip("::ffff:198.51.100.1")));
+        // allowed networks are carved out of the denied ones
+        assert!(acl.is_allowed(ip("198.51.100.53")));
+        assert!(acl.check(ip("198.51.100.1")).is_err());
+    }
+
+    #[test]
+    fn test_deny_by_default() {
+        let acl = Acl::deny_by_default()
+            .with_allowed(Network::new(ip("192.0.2.0"), 24))
+            .with_allowed(Network::new(ip("2001:db8::"), 32))
+            .with_denied(Network::new(ip("192.0.2.128"), 25));
+        assert!(acl.is_allowed(ip("192.0.2.1")));
+        assert!(acl.is_allowed(ip("::ffff:192.0.2.1")));
+        assert!(acl.is_allowed(ip("2001:db8::1")));
+        assert!(!acl.is_allowed(ip("198.51.100.1")));
+        assert!(!acl.is_allowed(ip("2001:db9::1")));
+        // denied networks are carved out of the allowed ones
+        assert!(!acl.is_allowed(ip("192.0.2.200")));
+        assert!(acl.check(ip("192.0.2.1")).is_ok());
+    }
+}
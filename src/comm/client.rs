@@ -4,98 +4,759 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::net::SocketAddr;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
 use bytes::Bytes;
+use hyper::{
+    client::conn::{Builder, SendRequest},
+    header::{ACCEPT, CONTENT_TYPE},
+    Body, Request, StatusCode, Uri,
+};
 use quinn::{Connection, Endpoint, NewConnection, RecvStream, SendStream};
-use tokio::sync::mpsc;
+use rand::prelude::random;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, WriteHalf},
+    net::TcpStream,
+    sync::{mpsc, oneshot, Mutex, Semaphore},
+    task::JoinHandle,
+    time::Instant,
+};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{ClientConfig, ServerName},
+    TlsConnector,
+};
 
 use crate::{
-    comm::{Answer, Task},
-    protocol::{Packet, PacketError, TransactionError},
+    comm::{
+        check_answers,
+        coalesce::Coalescer,
+        forward::{self, Edns},
+        ratelimit::RateLimiter,
+        stream::{doh::DNS_MESSAGE, write_packet},
+        Answer, Task, TaskMap,
+    },
+    metrics::Metrics,
+    protocol::{Name, Packet, PacketError, TransactionError},
 };
 
+/// protocol used for forwarding queries to upstream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardProtocol {
+    /// DNS over QUIC, [RFC9250](https://datatracker.ietf.org/doc/html/rfc9250)
+    Quic,
+    /// DNS over TLS, [RFC7858](https://datatracker.ietf.org/doc/html/rfc7858)
+    Tls,
+    /// plain DNS over TCP, only for trusted networks
+    Tcp,
+    /// DNS over HTTPS, [RFC8484](https://datatracker.ietf.org/doc/html/rfc8484)
+    Https,
+}
+
+/// Error occurred in parsing forward protocols from strings
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("Invalid Forward Protocol: {0}")]
+pub struct ParseProtocolError(String);
+
+/// parse protocols by their names: `quic`, `tls`, `tcp` or `https`
+impl FromStr for ForwardProtocol {
+    type Err = ParseProtocolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "quic" => Ok(ForwardProtocol::Quic),
+            "tls" => Ok(ForwardProtocol::Tls),
+            "tcp" => Ok(ForwardProtocol::Tcp),
+            "https" => Ok(ForwardProtocol::Https),
+            _ => Err(ParseProtocolError(s.to_string())),
+        }
+    }
+}
+
+/// how long an unreachable upstream is skipped before being tried again
+const UPSTREAM_COOLDOWN: Duration = Duration::from_secs(30);
+/// rounds of connection attempts to the QUIC upstreams by default, before a query fails
+const CONNECT_ATTEMPTS: u32 = 3;
+/// delay after the first failed round of connection attempts, doubled after each further one
+const RETRY_BASE: Duration = Duration::from_millis(100);
+/// delays between rounds of connection attempts are capped by this
+const RETRY_MAX: Duration = Duration::from_secs(2);
+/// how long a query waits for its turn when throttled, before failing with SERVFAIL
+const THROTTLE_WAIT: Duration = Duration::from_millis(500);
+/// how long a query waits for the response of a DoH upstream by default
+const DOH_TIMEOUT: Duration = Duration::from_secs(5);
+/// idle DoH connections are pinged at this interval, so they are not closed
+const DOH_KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// idle QUIC connections are looked for at least this far apart, however short `max_idle` is
+const REAP_PERIOD_MIN: Duration = Duration::from_millis(1);
+/// QUIC streams in flight at most by default,
+/// the concurrent bidirectional streams peers allow by default
+const MAX_QUIC_STREAMS: usize = 100;
+
+/// how often connections idle for `max_idle` are looked for,
+/// `tokio::time::interval` panics on a zero period
+fn reap_period(max_idle: Duration) -> Duration {
+    (max_idle / 2).max(REAP_PERIOD_MIN)
+}
+
 pub struct QuicForwarder {
     rec: mpsc::UnboundedReceiver<Task>,
     connection: QuicManager,
+    limiter: Option<Arc<RateLimiter<SocketAddr>>>,
+    edns: Arc<Edns>,
+    max_idle: Option<Duration>,
+    metrics: Arc<Metrics>,
+    streams: Arc<Semaphore>,
 }
 
 impl QuicForwarder {
+    /// forward queries to `upstreams`, spreading them by `policy`.
+    /// fails if none of the upstreams is reachable.
     pub async fn try_new(
         rec: mpsc::UnboundedReceiver<Task>,
         endpoint: Endpoint,
-        domain: &'static str,
+        upstreams: Vec<(String, SocketAddr)>,
+        policy: LoadBalance,
+    ) -> Result<Self> {
+        for (domain, addr) in upstreams.iter() {
+            tracing::info!(
+                "upstream quic://{}, statically configured as {}",
+                domain,
+                addr
+            );
+        }
+        let connection = QuicManager::try_build(endpoint, upstreams, policy).await?;
+
+        Ok(Self {
+            rec,
+            connection,
+            limiter: None,
+            edns: Arc::new(Edns::default()),
+            max_idle: None,
+            metrics: Arc::new(Metrics::default()),
+            streams: Arc::new(Semaphore::new(MAX_QUIC_STREAMS)),
+        })
+    }
+
+    /// keep at most `max` streams in flight, the others wait for their turn.
+    ///
+    /// Keep it within the concurrent streams the upstreams allow,
+    /// streams beyond that would wait for the upstream instead.
+    pub fn with_max_streams(mut self, max: usize) -> Self {
+        self.streams = Arc::new(Semaphore::new(max));
+        self
+    }
+
+    /// record how long upstreams take to answer in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// try all upstreams up to `attempts` times before a query fails with SERVFAIL.
+    ///
+    /// Queries wait between the rounds, twice as long after each failed round, with jitter.
+    pub fn with_connect_attempts(mut self, attempts: u32) -> Self {
+        self.connection.backoff.attempts = attempts.max(1);
+        self
+    }
+
+    /// send at most `rate` queries per second to each upstream, with bursts up to `burst`
+    pub fn with_rate_limit(mut self, rate: u32, burst: u32) -> Self {
+        let limiter = RateLimiter::new(rate, burst, THROTTLE_WAIT);
+        self.limiter = Some(Arc::new(limiter));
+        self
+    }
+
+    /// forward queries with the EDNS options of `edns`
+    pub fn with_edns(mut self, edns: Edns) -> Self {
+        self.edns = Arc::new(edns);
+        self
+    }
+
+    /// drop connections without queries for `max_idle`, the next query connects afresh.
+    ///
+    /// Set it below the idle timeout of the upstreams, which may drop connections silently,
+    /// or keep connections alive by `quinn::TransportConfig::keep_alive_interval` instead.
+    pub fn with_max_idle(mut self, max_idle: Duration) -> Self {
+        self.max_idle = Some(max_idle);
+        self
+    }
+
+    /// the upstream queries are currently forwarded to
+    pub fn active_upstream(&self) -> (String, SocketAddr) {
+        self.connection.active_upstream()
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        tracing::info!("forward task is running");
+        let connection = Arc::new(self.connection);
+        let reaper = self.max_idle.map(|max_idle| {
+            let connection = connection.clone();
+            tokio::spawn(async move {
+                let mut ticks = tokio::time::interval(reap_period(max_idle));
+                loop {
+                    ticks.tick().await;
+                    connection.reap(max_idle);
+                }
+            })
+        });
+        let checkers = futures::stream::FuturesUnordered::new();
+        while let Some(task) = self.rec.recv().await {
+            let Task::Query(q, ans_to, client) = task;
+            tracing::info!("forwarding new task from transaction layer.");
+            let connection = connection.clone();
+            let limiter = self.limiter.clone();
+            let metrics = self.metrics.clone();
+            let streams = self.streams.clone();
+            let query = self.edns.query(0, q, client);
+            checkers.push(tokio::spawn(async move {
+                // the semaphore is never closed
+                let _permit = streams.acquire_owned().await.unwrap();
+                let start = Instant::now();
+                connection.forward(query, ans_to, limiter.as_deref()).await;
+                metrics.observe_upstream(start.elapsed());
+            }));
+        }
+        for checker in checkers {
+            let _ = tokio::join!(checker);
+        }
+        if let Some(reaper) = reaper {
+            reaper.abort();
+        }
+        Ok(())
+    }
+}
+
+/// how queries are spread over upstreams
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoadBalance {
+    /// stick to one upstream, move on only when it fails
+    FirstHealthy,
+    /// take turns in the configured order
+    RoundRobin,
+    /// pick an upstream at random for each query
+    Random,
+    /// send queries for the same name to the same upstream, keeping its cache warm.
+    ///
+    /// Upstreams are ranked by rendezvous hashing, so only the names of a dead
+    /// upstream move, and they move evenly to the others.
+    ConsistentHash,
+}
+
+struct Upstream {
+    domain: String,
+    addr: SocketAddr,
+    /// the upstream is skipped until then
+    dead_until: Option<Instant>,
+}
+
+/// `Upstreams` keeps track of which upstream is in use and which are down
+struct Upstreams {
+    upstreams: Vec<Upstream>,
+    policy: LoadBalance,
+    active: usize,
+    /// next upstream to take for round robin
+    next: usize,
+    cooldown: Duration,
+}
+
+impl Upstreams {
+    fn new(upstreams: Vec<(String, SocketAddr)>, policy: LoadBalance, cooldown: Duration) -> Self {
+        let upstreams = upstreams
+            .into_iter()
+            .map(|(domain, addr)| Upstream {
+                domain,
+                addr,
+                dead_until: None,
+            })
+            .collect();
+        Self {
+            upstreams,
+            policy,
+            active: 0,
+            next: 0,
+            cooldown,
+        }
+    }
+
+    fn active(&self) -> &Upstream {
+        &self.upstreams[self.active]
+    }
+
+    fn mark_dead(&mut self, index: usize, now: Instant) {
+        self.upstreams[index].dead_until = Some(now + self.cooldown);
+    }
+
+    /// upstreams to try for the next query for `name`, the preferred one first
+    fn pick(&mut self, name: &Name, now: Instant) -> Vec<usize> {
+        let len = self.upstreams.len();
+        let from = match self.policy {
+            LoadBalance::FirstHealthy => self.active,
+            LoadBalance::RoundRobin => {
+                let from = self.next;
+                self.next = (from + 1) % len;
+                from
+            }
+            LoadBalance::Random => random::<usize>() % len,
+            LoadBalance::ConsistentHash => return self.healthy(self.rank(name), now),
+        };
+        self.candidates(from, now)
+    }
+
+    /// indices of upstreams worth trying, in configured order starting from `from`.
+    fn candidates(&self, from: usize, now: Instant) -> Vec<usize> {
+        let len = self.upstreams.len();
+        self.healthy((0..len).map(|i| (from + i) % len).collect(), now)
+    }
+
+    /// upstreams of `order` not cooling down, in the same order.
+    /// when every upstream is cooling down, all of them are returned
+    /// rather than giving up.
+    fn healthy(&self, order: Vec<usize>, now: Instant) -> Vec<usize> {
+        let healthy: Vec<usize> = order
+            .iter()
+            .copied()
+            .filter(|&i| self.upstreams[i].dead_until.is_none_or(|t| t <= now))
+            .collect();
+        if healthy.is_empty() {
+            order
+        } else {
+            healthy
+        }
+    }
+
+    /// upstreams by their weights for `name`, the heaviest first
+    fn rank(&self, name: &Name) -> Vec<usize> {
+        let weight = |upstream: &Upstream| {
+            let mut hasher = DefaultHasher::new();
+            name.hash(&mut hasher);
+            upstream.domain.hash(&mut hasher);
+            upstream.addr.hash(&mut hasher);
+            hasher.finish()
+        };
+        let mut order: Vec<usize> = (0..self.upstreams.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(weight(&self.upstreams[i])));
+        order
+    }
+}
+
+/// `Backoff` spaces out rounds of connection attempts
+#[derive(Debug, Clone, Copy)]
+struct Backoff {
+    /// rounds at most, the first one included
+    attempts: u32,
+    base: Duration,
+    max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            attempts: CONNECT_ATTEMPTS,
+            base: RETRY_BASE,
+            max: RETRY_MAX,
+        }
+    }
+}
+
+impl Backoff {
+    /// delay after `failed` rounds, between half of and the whole of the doubled delay,
+    /// so queries waiting for the same upstream do not retry in lockstep
+    fn delay(&self, failed: u32) -> Duration {
+        let doubled = self
+            .base
+            .saturating_mul(1 << failed.saturating_sub(1).min(16))
+            .min(self.max);
+        doubled / 2 + doubled.mul_f64(random::<f64>() / 2.0)
+    }
+}
+
+/// connections are shared by upstream address, SNI and protocol
+type ConnectionKey = (SocketAddr, String, &'static str);
+
+/// `QuicManager` keeps one connection per upstream warm,
+/// connections are only (re)established when missing or lost.
+struct QuicManager {
+    endpoint: Endpoint,
+    upstreams: std::sync::Mutex<Upstreams>,
+    connections: Coalescer<ConnectionKey, Connection>,
+    /// when a stream was last opened on each connection
+    last_used: std::sync::Mutex<HashMap<ConnectionKey, Instant>>,
+    backoff: Backoff,
+}
+
+impl QuicManager {
+    pub async fn try_build(
+        endpoint: Endpoint,
+        upstreams: Vec<(String, SocketAddr)>,
+        policy: LoadBalance,
+    ) -> Result<Self> {
+        if upstreams.is_empty() {
+            return Err(anyhow!("no upstream configured"));
+        }
+        let upstreams = Upstreams::new(upstreams, policy, UPSTREAM_COOLDOWN);
+        let candidates = upstreams.candidates(0, Instant::now());
+        let manager = Self {
+            endpoint,
+            upstreams: std::sync::Mutex::new(upstreams),
+            connections: Coalescer::new(),
+            last_used: std::sync::Mutex::new(HashMap::new()),
+            backoff: Backoff::default(),
+        };
+
+        // fail early if no upstream is reachable at all
+        let mut last_err = anyhow!("no upstream configured");
+        for index in candidates {
+            match manager.connection(index).await {
+                Ok(_) => {
+                    manager.upstreams.lock().unwrap().active = index;
+                    return Ok(manager);
+                }
+                Err(e) => last_err = e,
+            }
+        }
+        Err(last_err)
+    }
+
+    fn key(&self, index: usize) -> ConnectionKey {
+        let upstreams = self.upstreams.lock().unwrap();
+        let upstream = &upstreams.upstreams[index];
+        (upstream.addr, upstream.domain.clone(), "quic")
+    }
+
+    /// get the connection to the `index`th upstream, marking it dead on failure
+    async fn connection(&self, index: usize) -> Result<Connection> {
+        let key = self.key(index);
+        let (addr, domain, _) = &key;
+        let connect = || async {
+            let connecting = self.endpoint.connect(*addr, domain.as_str())?;
+            let NewConnection { connection, .. } = connecting.await?;
+            tracing::info!("connected to upstream quic://{} at {}", domain, addr);
+            Ok::<_, anyhow::Error>(connection)
+        };
+        match self.connections.get_or_connect(&key, connect).await {
+            Ok(connection) => {
+                self.last_used.lock().unwrap().insert(key, Instant::now());
+                Ok(connection)
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "failed connecting to upstream quic://{} at {}: {}",
+                    domain,
+                    addr,
+                    e
+                );
+                let mut upstreams = self.upstreams.lock().unwrap();
+                upstreams.mark_dead(index, Instant::now());
+                Err(e)
+            }
+        }
+    }
+
+    /// open a stream on the `index`th upstream, reconnecting once if the shared
+    /// connection is lost
+    async fn open_on(&self, index: usize) -> Result<(SendStream, RecvStream)> {
+        let key = self.key(index);
+        let connection = self.connection(index).await?;
+        match connection.open_bi().await {
+            Ok(streams) => Ok(streams),
+            Err(_) => {
+                tracing::debug!("QUIC connection lost, reconnecting...");
+                let lost = connection.stable_id();
+                self.connections.invalidate(&key, |c| c.stable_id() == lost);
+                let connection = self.connection(index).await?;
+                Ok(connection.open_bi().await?)
+            }
+        }
+    }
+
+    /// drop connections no stream is opened on for `max_idle`,
+    /// they are closed once the streams in flight finish
+    fn reap(&self, max_idle: Duration) {
+        let now = Instant::now();
+        let mut last_used = self.last_used.lock().unwrap();
+        last_used.retain(|key, used| {
+            if now - *used < max_idle {
+                return true;
+            }
+            tracing::debug!("dropping idle connection to quic://{} at {}", key.1, key.0);
+            self.connections.invalidate(key, |_| true);
+            false
+        });
+    }
+
+    pub fn active_upstream(&self) -> (String, SocketAddr) {
+        let upstreams = self.upstreams.lock().unwrap();
+        let upstream = upstreams.active();
+        (upstream.domain.clone(), upstream.addr)
+    }
+
+    /// open a stream for a query for `name` on an upstream chosen by the load balancing
+    /// policy, falling back to the others on failure.
+    ///
+    /// A token of the upstream is taken from `limiter` before the stream is opened,
+    /// so throttled queries hold no stream. When all of them fail,
+    /// they are tried again after a while, as `backoff` allows.
+    pub async fn open_bi(
+        &self,
+        name: &Name,
+        limiter: Option<&RateLimiter<SocketAddr>>,
+    ) -> Result<(SocketAddr, SendStream, RecvStream)> {
+        let mut failed = 0;
+        loop {
+            let candidates = self.upstreams.lock().unwrap().pick(name, Instant::now());
+            let mut last_err = anyhow!("no upstream configured");
+            for index in candidates {
+                let (addr, ..) = self.key(index);
+                if let Some(limiter) = limiter {
+                    if !limiter.acquire(&addr).await {
+                        return Err(anyhow!("too many queries to quic://{}, throttled", addr));
+                    }
+                }
+                match self.open_on(index).await {
+                    Ok((send, recv)) => {
+                        let mut upstreams = self.upstreams.lock().unwrap();
+                        upstreams.active = index;
+                        return Ok((upstreams.active().addr, send, recv));
+                    }
+                    Err(e) => last_err = e,
+                }
+            }
+            failed += 1;
+            if failed >= self.backoff.attempts {
+                return Err(last_err);
+            }
+            let delay = self.backoff.delay(failed);
+            tracing::debug!(
+                "no upstream reachable, trying again in {:?}: {}",
+                delay,
+                last_err
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// forward `packet` to upstream, and pass the answers back by `ans_to`
+    async fn forward(
+        &self,
+        packet: Packet,
+        ans_to: mpsc::UnboundedSender<Answer>,
+        limiter: Option<&RateLimiter<SocketAddr>>,
+    ) {
+        let name = match packet.question() {
+            Some(q) => q.get_name(),
+            None => Name::try_from(".").unwrap(),
+        };
+        let (remote, mut quic_send, quic_recv) = match self.open_bi(&name, limiter).await {
+            Ok(streams) => streams,
+            Err(e) => {
+                tracing::warn!("query for {} not forwarded: {}", name, e);
+                let _ = ans_to.send(Answer::Error(PacketError::ServFail));
+                return;
+            }
+        };
+        tracing::debug!("sending packet {:?} to quic://{}", packet, remote);
+
+        let packet_bytes = packet.into_bytes();
+        if (quic_send.write_all(&packet_bytes[..]).await).is_err() {
+            tracing::warn!("QUIC forward to quic://{} failed with write error!", remote);
+            let _ = ans_to.send(Answer::Error(PacketError::ServFail));
+            return;
+        }
+        let _ = quic_send.finish().await;
+        tracing::debug!("packet sent to upstream");
+
+        let stream_id = quic_recv.id();
+        let v = match quic_recv.read_to_end(u16::MAX as usize).await {
+            Ok(v) => v,
+            Err(e) => {
+                tracing::debug!("failed reading from {}: {}", remote, e);
+                let _ = ans_to.send(Answer::Error(PacketError::ServFail));
+                return;
+            }
+        };
+        let buf = Bytes::from(v);
+        let r = Packet::parse_packet(buf, 0);
+        tracing::debug!("received response {:?} on quic stream", r);
+        let packet = match r {
+            Ok(packet) => packet,
+            Err(TransactionError { id: _, error }) => {
+                if let PacketError::ServFail = error {
+                    tracing::debug!(
+                        "connection closed on stream {} against {}",
+                        stream_id,
+                        remote
+                    );
+                }
+                // an empty answer would be cached as NODATA, report the failure instead
+                let _ = ans_to.send(Answer::Error(error));
+                return;
+            }
+        };
+        tracing::debug!("get answer from upstream: {:?}", packet);
+        for ans in forward::stream_answers(packet) {
+            let _ = ans_to.send(ans);
+        }
+    }
+}
+
+/// `Connector` opens length-prefixed DNS streams to upstream
+#[async_trait]
+pub trait Connector {
+    type S: AsyncReadExt + AsyncWriteExt + Unpin + Send + 'static;
+    // name of the protocol
+    fn name(&self) -> &'static str;
+
+    // open a new stream to upstream
+    async fn connect(&self, addr: SocketAddr) -> std::io::Result<Self::S>;
+}
+
+pub struct TcpConnector;
+
+#[async_trait]
+impl Connector for TcpConnector {
+    type S = TcpStream;
+
+    fn name(&self) -> &'static str {
+        "tcp"
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> std::io::Result<Self::S> {
+        TcpStream::connect(addr).await
+    }
+}
+
+pub struct TlsUpstream {
+    connector: TlsConnector,
+    domain: ServerName,
+}
+
+#[async_trait]
+impl Connector for TlsUpstream {
+    type S = TlsStream<TcpStream>;
+
+    fn name(&self) -> &'static str {
+        "tls"
+    }
+
+    async fn connect(&self, addr: SocketAddr) -> std::io::Result<Self::S> {
+        let tcp = TcpStream::connect(addr).await?;
+        self.connector.connect(self.domain.clone(), tcp).await
+    }
+}
+
+/// `StreamForwarder` forwards queries over a single TCP or TLS connection.
+///
+/// Queries are pipelined, responses are matched back by their transaction IDs.
+pub struct StreamForwarder<C: Connector> {
+    rec: mpsc::UnboundedReceiver<Task>,
+    connection: StreamManager<C>,
+    limiter: Option<RateLimiter<SocketAddr>>,
+    edns: Edns,
+}
+
+pub type TlsForwarder = StreamForwarder<TlsUpstream>;
+pub type TcpForwarder = StreamForwarder<TcpConnector>;
+
+impl TlsForwarder {
+    pub async fn try_new(
+        rec: mpsc::UnboundedReceiver<Task>,
+        config: Arc<ClientConfig>,
+        domain: &str,
         addr: SocketAddr,
     ) -> Result<Self> {
         tracing::info!(
-            "establishing quic connection to quic://{}, statically configured as {}",
+            "establishing tls connection to tls://{}, statically configured as {}",
             domain,
             addr
         );
-        let connection = QuicManager::try_build(endpoint, domain, addr).await?;
+        let connector = TlsUpstream {
+            connector: TlsConnector::from(config),
+            domain: ServerName::try_from(domain)?,
+        };
+        let connection = StreamManager::try_build(connector, addr).await?;
+
+        Ok(Self {
+            rec,
+            connection,
+            limiter: None,
+            edns: Edns::default(),
+        })
+    }
+}
 
-        Ok(Self { rec, connection })
+impl TcpForwarder {
+    pub async fn try_new(rec: mpsc::UnboundedReceiver<Task>, addr: SocketAddr) -> Result<Self> {
+        tracing::info!("establishing tcp connection to tcp://{}", addr);
+        let connection = StreamManager::try_build(TcpConnector, addr).await?;
+
+        Ok(Self {
+            rec,
+            connection,
+            limiter: None,
+            edns: Edns::default(),
+        })
+    }
+}
+
+impl<C: Connector> StreamForwarder<C> {
+    /// send at most `rate` queries per second to upstream, with bursts up to `burst`.
+    ///
+    /// Throttled queries are queued in order.
+    pub fn with_rate_limit(mut self, rate: u32, burst: u32) -> Self {
+        self.limiter = Some(RateLimiter::new(rate, burst, THROTTLE_WAIT));
+        self
+    }
+
+    /// forward queries with the EDNS options of `edns`
+    pub fn with_edns(mut self, edns: Edns) -> Self {
+        self.edns = edns;
+        self
     }
 
     pub async fn run(mut self) -> Result<()> {
         tracing::info!("forward task is running");
         let checkers = futures::stream::FuturesUnordered::new();
+        let protocol = self.connection.connector.name();
         let remote = self.connection.remote_address();
         while let Some(task) = self.rec.recv().await {
-            let Task::Query(q, ans_to) = task;
+            let Task::Query(q, ans_to, client) = task;
             tracing::info!("forwarding new task from transaction layer.");
-            let (mut quic_send, quic_recv) = self.connection.open_bi().await;
-            let id = 0;
-
-            let packet = Packet::new_query(id, q);
-            tracing::debug!("sending packet {:?} to quic://{}", packet, remote);
+            if let Some(limiter) = &self.limiter {
+                if !limiter.acquire(&remote).await {
+                    tracing::warn!("too many queries to {}://{}, throttled", protocol, remote);
+                    let _ = ans_to.send(Answer::Error(PacketError::ServFail));
+                    continue;
+                }
+            }
 
-            let packet_bytes = packet.into_bytes();
-            if (quic_send.write_all(&packet_bytes[..]).await).is_err() {
-                tracing::warn!("QUIC forward to quic://{} failed with write error!", remote);
+            // register before sending packet, to avoid data racing
+            let (checker_sender, checker_receiver) = oneshot::channel();
+            let Some(id) = self.connection.register(checker_sender) else {
+                tracing::warn!("no free id for queries to {}://{}", protocol, remote);
+                let _ = ans_to.send(Answer::Error(PacketError::ServFail));
                 continue;
+            };
+
+            let packet = self.edns.query(id, q, client);
+            tracing::debug!("sending packet {:?} to {}://{}", packet, protocol, remote);
+            if let Err(e) = self.connection.send(packet).await {
+                // the checker will report a failure after timeout
+                tracing::warn!("forward to {}://{} failed: {}", protocol, remote, e);
             }
 
-            let checker = tokio::spawn(async move {
-                let stream_id = quic_recv.id();
-                let v = quic_recv
-                    .read_to_end(u16::MAX as usize)
-                    .await
-                    .expect("failed read to end");
-                let buf = Bytes::from(v);
-                let r = Packet::parse_packet(buf, 0);
-                tracing::debug!("received response {:?} on quic stream", r);
-                if let Err(..) = r {
-                    let TransactionError { id: _, error } = r.unwrap_err();
-                    match error {
-                        PacketError::ServFail => {
-                            tracing::debug!(
-                                "connection closed on stream {} against {}",
-                                stream_id,
-                                remote
-                            );
-                        }
-                        e => {
-                            let _ = ans_to.send(Answer::Error(e));
-                        }
-                    }
-                    return;
-                }
-                let packet = r.unwrap();
-                tracing::debug!("get answer from upstream: {:?}", packet);
-                for ans in packet.answers {
-                    let _ = ans_to.send(Answer::Answer(ans));
-                }
-                for ns in packet.authorities {
-                    let _ = ans_to.send(Answer::NameServer(ns));
-                }
-                for addi in packet.additions {
-                    let _ = ans_to.send(Answer::Additional(addi));
-                }
-            });
-            let _ = quic_send.finish().await;
-            tracing::debug!("packet sent to upstream");
+            let checker = tokio::spawn(check_answers(checker_receiver, ans_to));
             checkers.push(checker);
         }
         for checker in checkers {
@@ -105,55 +766,1093 @@ impl QuicForwarder {
     }
 }
 
-struct QuicManager {
-    endpoint: Endpoint,
+struct StreamManager<C: Connector> {
+    connector: C,
     addr: SocketAddr,
-    domain: String,
-    connection: Connection,
+    writer: WriteHalf<C::S>,
+    listening: JoinHandle<()>,
+    tasks: TaskMap,
 }
 
-impl QuicManager {
-    pub async fn try_build(
-        endpoint: Endpoint,
-        remote_domain: &'static str,
-        remote_addr: SocketAddr,
-    ) -> Result<Self> {
-        let conn = endpoint
-            .connect(remote_addr, remote_domain)
-            .expect("cannot initiate QUIC connection")
-            .await?;
-        let NewConnection { connection, .. } = conn;
+impl<C: Connector> StreamManager<C> {
+    pub async fn try_build(connector: C, remote_addr: SocketAddr) -> Result<Self> {
+        let tasks = TaskMap::new();
+        let (writer, listening) = Self::connect(&connector, remote_addr, tasks.clone()).await?;
         Ok(Self {
-            endpoint,
+            connector,
             addr: remote_addr,
-            domain: String::from(remote_domain),
-            connection,
+            writer,
+            listening,
+            tasks,
         })
     }
 
+    async fn connect(
+        connector: &C,
+        addr: SocketAddr,
+        tasks: TaskMap,
+    ) -> Result<(WriteHalf<C::S>, JoinHandle<()>)> {
+        let stream = connector.connect(addr).await?;
+        let (reader, writer) = tokio::io::split(stream);
+        let listening = tokio::spawn(forward::listening_stream(reader, tasks));
+        Ok((writer, listening))
+    }
+
     async fn reconnect(&mut self) -> Result<()> {
-        let conn = self
-            .endpoint
-            .connect(self.addr, self.domain.as_str())
-            .expect("cannot initiate QUIC connection")
-            .await?;
-        let NewConnection { connection, .. } = conn;
-        self.connection = connection;
+        let (writer, listening) =
+            Self::connect(&self.connector, self.addr, self.tasks.clone()).await?;
+        self.writer = writer;
+        self.listening = listening;
         Ok(())
     }
 
     pub fn remote_address(&self) -> SocketAddr {
-        self.connection.remote_address()
+        self.addr
     }
 
-    pub async fn open_bi(&mut self) -> (SendStream, RecvStream) {
-        let r = self.connection.open_bi().await;
-        if r.is_err() {
-            tracing::debug!("QUIC connection lost, reconnecting...");
-            self.reconnect().await.unwrap();
-            self.connection.open_bi().await.unwrap()
-        } else {
-            r.unwrap()
+    /// find a free transaction id for the query, and register its answer sender
+    pub fn register(&self, sender: oneshot::Sender<Vec<Answer>>) -> Option<u16> {
+        self.tasks.register(sender)
+    }
+
+    pub async fn send(&mut self, packet: Packet) -> Result<()> {
+        // the upstream may close idle connections
+        if self.listening.is_finished() {
+            tracing::debug!("connection closed by upstream, reconnecting...");
+            self.reconnect().await?;
+        }
+        if write_packet(&mut self.writer, packet.clone())
+            .await
+            .is_err()
+        {
+            tracing::debug!("connection lost, reconnecting...");
+            self.reconnect().await?;
+            write_packet(&mut self.writer, packet).await?;
         }
+        Ok(())
+    }
+}
+
+/// `DohForwarder` forwards queries by POSTing them to a DNS over HTTPS upstream.
+///
+/// Queries share a single HTTP/2 connection, which is reopened once closed.
+pub struct DohForwarder {
+    rec: mpsc::UnboundedReceiver<Task>,
+    connection: Arc<DohManager>,
+    timeout: Duration,
+    edns: Edns,
+}
+
+impl DohForwarder {
+    /// forward queries to `url`, connecting to `addr` instead of resolving the host of `url`.
+    /// fails if `url` is not HTTPS or the upstream is unreachable.
+    pub async fn try_new(
+        rec: mpsc::UnboundedReceiver<Task>,
+        config: Arc<ClientConfig>,
+        url: &str,
+        addr: SocketAddr,
+    ) -> Result<Self> {
+        let uri: Uri = url.parse()?;
+        if uri.scheme_str() != Some("https") {
+            return Err(anyhow!("not an HTTPS URL: {}", url));
+        }
+        let host = uri.host().ok_or_else(|| anyhow!("no host in {}", url))?;
+        tracing::info!(
+            "establishing https connection to {}, statically configured as {}",
+            url,
+            addr
+        );
+        let domain = ServerName::try_from(host)?;
+        let mut config = (*config).clone();
+        config.alpn_protocols = vec![Vec::from(&b"h2"[..])];
+        let connection = DohManager {
+            uri,
+            domain,
+            connector: TlsConnector::from(Arc::new(config)),
+            addr,
+            sender: Mutex::new(None),
+        };
+        connection.ready().await?;
+
+        Ok(Self {
+            rec,
+            connection: Arc::new(connection),
+            timeout: DOH_TIMEOUT,
+            edns: Edns::default(),
+        })
+    }
+
+    /// fail queries with SERVFAIL if the upstream does not respond within `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// forward queries with the EDNS options of `edns`
+    pub fn with_edns(mut self, edns: Edns) -> Self {
+        self.edns = edns;
+        self
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        tracing::info!("forward task is running");
+        let checkers = futures::stream::FuturesUnordered::new();
+        while let Some(task) = self.rec.recv().await {
+            let Task::Query(q, ans_to, client) = task;
+            tracing::info!("forwarding new task from transaction layer.");
+            let connection = self.connection.clone();
+            let timeout = self.timeout;
+            // ID 0 keeps the responses cacheable by HTTP caches
+            let query = self.edns.query(0, q, client);
+            checkers.push(tokio::spawn(async move {
+                let answers = match tokio::time::timeout(timeout, connection.query(query)).await {
+                    Ok(Ok(packet)) => forward::stream_answers(packet),
+                    Ok(Err(e)) => {
+                        tracing::warn!("forward to {} failed: {}", connection.uri, e);
+                        vec![Answer::Error(PacketError::ServFail)]
+                    }
+                    Err(_) => {
+                        tracing::warn!("forward to {} timed out", connection.uri);
+                        vec![Answer::Error(PacketError::ServFail)]
+                    }
+                };
+                for ans in answers {
+                    let _ = ans_to.send(ans);
+                }
+            }));
+        }
+        for checker in checkers {
+            let _ = tokio::join!(checker);
+        }
+        Ok(())
+    }
+}
+
+struct DohManager {
+    uri: Uri,
+    domain: ServerName,
+    connector: TlsConnector,
+    addr: SocketAddr,
+    sender: Mutex<Option<SendRequest<Body>>>,
+}
+
+impl DohManager {
+    /// make sure the connection is open, reconnecting if the upstream has closed it
+    async fn ready(&self) -> Result<()> {
+        let mut sender = self.sender.lock().await;
+        if let Some(open) = sender.as_mut() {
+            if futures::future::poll_fn(|cx| open.poll_ready(cx))
+                .await
+                .is_ok()
+            {
+                return Ok(());
+            }
+            tracing::debug!("connection to {} closed, reconnecting...", self.uri);
+        }
+        let tcp = TcpStream::connect(self.addr).await?;
+        let tls = self.connector.connect(self.domain.clone(), tcp).await?;
+        let (send_request, connection) = Builder::new()
+            .http2_only(true)
+            .http2_keep_alive_interval(DOH_KEEP_ALIVE)
+            .http2_keep_alive_while_idle(true)
+            .handshake(tls)
+            .await?;
+        let uri = self.uri.clone();
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                tracing::debug!("connection to {} closed: {}", uri, e);
+            }
+        });
+        *sender = Some(send_request);
+        Ok(())
+    }
+
+    async fn query(&self, query: Packet) -> Result<Packet> {
+        let req = Request::post(self.uri.clone())
+            .header(CONTENT_TYPE, DNS_MESSAGE)
+            .header(ACCEPT, DNS_MESSAGE)
+            .body(Body::from(query.into_bytes()))?;
+        self.ready().await?;
+        // requests are multiplexed, only sending them takes the lock
+        let responding = match self.sender.lock().await.as_mut() {
+            Some(sender) => sender.send_request(req),
+            None => return Err(anyhow!("no connection to {}", self.uri)),
+        };
+        let resp = responding.await?;
+        if resp.status() != StatusCode::OK {
+            return Err(anyhow!("upstream responded with {}", resp.status()));
+        }
+        let body = hyper::body::to_bytes(resp.into_body()).await?;
+        Ok(Packet::parse_packet(body, 0)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use futures::StreamExt;
+    use quinn::{Endpoint, NewConnection};
+    use tokio::{
+        net::{TcpListener, UdpSocket},
+        sync::mpsc,
+        time::Instant,
+    };
+
+    use super::{
+        reap_period, Backoff, DohForwarder, LoadBalance, QuicForwarder, TcpForwarder, Upstreams,
+        REAP_PERIOD_MIN, RETRY_BASE, RETRY_MAX,
+    };
+    use crate::{
+        comm::{stream::write_packet, Answer, DohService, Task},
+        protocol::{Name, Packet, PacketError, Question, RRClass, RRData, RRType, RR},
+    };
+
+    fn question(name: &str) -> Question {
+        Question::build(Name::try_from(name).unwrap(), RRType::A, RRClass::Internet)
+    }
+
+    /// answer the query with an A record, the last octet of the address is `tag`
+    fn response(query: Packet, tag: u8) -> Packet {
+        let q = query.questions[0].clone();
+        let a = RRData::A(Ipv4Addr::new(192, 0, 2, tag).into());
+        let mut resp = Packet::new_plain_answer(query.header.get_id());
+        resp.add_answer(RR::new(
+            q.get_name(),
+            Duration::from_secs(60),
+            q.get_class(),
+            a,
+        ));
+        resp.set_question(q);
+        resp
+    }
+
+    async fn query(tasks: &mpsc::UnboundedSender<Task>, name: &str) -> Vec<Answer> {
+        let (ans_to, mut ans_from) = mpsc::unbounded_channel();
+        tasks
+            .send(Task::Query(question(name), ans_to, None))
+            .unwrap();
+        let mut answers = vec![];
+        while let Some(ans) = ans_from.recv().await {
+            answers.push(ans);
+        }
+        answers
+    }
+
+    fn tag_of(answers: &[Answer]) -> u8 {
+        match answers {
+            [Answer::Answer(rr)] => match rr.clone().into_rdata() {
+                RRData::A(a) => Ipv4Addr::from(a).octets()[3],
+                _ => unreachable!(),
+            },
+            _ => panic!("unexpected answers: {:?}", answers),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tcp_forwarder() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            // the first connection answers two pipelined queries in reverse order
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let first = Packet::parse_stream(&mut stream).await.unwrap();
+            let second = Packet::parse_stream(&mut stream).await.unwrap();
+            write_packet(&mut stream, response(second, 2))
+                .await
+                .unwrap();
+            write_packet(&mut stream, response(first, 1)).await.unwrap();
+            drop(stream);
+
+            // the forwarder reconnects after the connection is closed
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            let third = Packet::parse_stream(&mut stream).await.unwrap();
+            write_packet(&mut stream, response(third, 3)).await.unwrap();
+        });
+
+        let (tasks, rec) = mpsc::unbounded_channel();
+        let forwarder = TcpForwarder::try_new(rec, addr).await.unwrap();
+        let forwarding = tokio::spawn(forwarder.run());
+
+        let (first, second) = tokio::join!(query(&tasks, "first.example"), async {
+            // keep the order of queries deterministic
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            query(&tasks, "second.example").await
+        });
+        assert_eq!(tag_of(&first), 1);
+        assert_eq!(tag_of(&second), 2);
+
+        // wait for the forwarder to notice the closed connection
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let third = query(&tasks, "third.example").await;
+        assert_eq!(tag_of(&third), 3);
+
+        server.await.unwrap();
+        drop(tasks);
+        forwarding.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tcp_rate_limit() {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = received.clone();
+        tokio::spawn(async move {
+            let (mut stream, _) = upstream.accept().await.unwrap();
+            while let Ok(q) = Packet::parse_stream(&mut stream).await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                write_packet(&mut stream, response(q, 1)).await.unwrap();
+            }
+        });
+
+        let (tasks, rec) = mpsc::unbounded_channel();
+        let forwarder = TcpForwarder::try_new(rec, addr)
+            .await
+            .unwrap()
+            .with_rate_limit(1, 2);
+        tokio::spawn(forwarder.run());
+
+        // a burst of 2 is let through, the 3rd query would wait for a second
+        let answers = tokio::join!(
+            query(&tasks, "a.example"),
+            query(&tasks, "b.example"),
+            query(&tasks, "c.example"),
+        );
+        let answers = [answers.0, answers.1, answers.2];
+        let throttled = answers
+            .iter()
+            .filter(|ans| matches!(ans[..], [Answer::Error(PacketError::ServFail)]))
+            .count();
+        assert_eq!(throttled, 1);
+        assert_eq!(received.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_upstream_rotation() {
+        let addr = "127.0.0.1:853".parse().unwrap();
+        let mut upstreams = Upstreams::new(
+            vec![
+                ("a.example".to_string(), addr),
+                ("b.example".to_string(), addr),
+                ("c.example".to_string(), addr),
+            ],
+            LoadBalance::FirstHealthy,
+            Duration::from_secs(30),
+        );
+        let now = Instant::now();
+        assert_eq!(upstreams.candidates(0, now), vec![0, 1, 2]);
+        assert_eq!(upstreams.candidates(2, now), vec![2, 0, 1]);
+
+        upstreams.mark_dead(1, now);
+        assert_eq!(upstreams.candidates(1, now), vec![2, 0]);
+        // back in rotation after cooling down
+        let later = now + Duration::from_secs(30);
+        assert_eq!(upstreams.candidates(1, later), vec![1, 2, 0]);
+
+        // with everyone down, still try all of them
+        upstreams.mark_dead(0, now);
+        upstreams.mark_dead(2, now);
+        assert_eq!(upstreams.candidates(0, now), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let addr = "127.0.0.1:853".parse().unwrap();
+        let mut upstreams = Upstreams::new(
+            vec![
+                ("a.example".to_string(), addr),
+                ("b.example".to_string(), addr),
+                ("c.example".to_string(), addr),
+            ],
+            LoadBalance::RoundRobin,
+            Duration::from_secs(30),
+        );
+        let now = Instant::now();
+        let name = Name::try_from("example.com").unwrap();
+        let firsts: Vec<usize> = (0..4).map(|_| upstreams.pick(&name, now)[0]).collect();
+        assert_eq!(firsts, vec![0, 1, 2, 0]);
+
+        // dead upstreams are skipped in turn
+        upstreams.mark_dead(2, now);
+        let firsts: Vec<usize> = (0..3).map(|_| upstreams.pick(&name, now)[0]).collect();
+        assert_eq!(firsts, vec![1, 0, 0]);
+    }
+
+    #[test]
+    fn test_consistent_hash() {
+        let pool: Vec<_> = (0..4)
+            .map(|i| (format!("{}.example", i), "127.0.0.1:853".parse().unwrap()))
+            .collect();
+        let mut upstreams = Upstreams::new(
+            pool.clone(),
+            LoadBalance::ConsistentHash,
+            Duration::from_secs(30),
+        );
+        let now = Instant::now();
+        let names: Vec<Name> = (0..64)
+            .map(|i| Name::try_from(format!("host{}.example.com", i).as_str()).unwrap())
+            .collect();
+        let firsts: Vec<usize> = names
+            .iter()
+            .map(|name| upstreams.pick(name, now)[0])
+            .collect();
+
+        // the same name always goes to the same upstream, in any case
+        for (name, first) in names.iter().zip(firsts.iter()) {
+            assert_eq!(upstreams.pick(name, now)[0], *first);
+        }
+        let upper = Name::try_from("HOST0.Example.COM").unwrap();
+        assert_eq!(upstreams.pick(&upper, now)[0], firsts[0]);
+        // and different names spread over the pool
+        for index in 0..pool.len() {
+            assert!(firsts.contains(&index), "upstream {} never picked", index);
+        }
+
+        // only the names of a dead upstream move
+        upstreams.mark_dead(firsts[0], now);
+        for (name, first) in names.iter().zip(firsts.iter()) {
+            let picked = upstreams.pick(name, now);
+            assert_eq!(picked.len(), pool.len() - 1);
+            if *first != firsts[0] {
+                assert_eq!(picked[0], *first);
+            } else {
+                assert_ne!(picked[0], *first);
+            }
+        }
+
+        // the ranking does not depend on the configured order
+        let mut reversed = Upstreams::new(
+            pool.into_iter().rev().collect(),
+            LoadBalance::ConsistentHash,
+            Duration::from_secs(30),
+        );
+        for (name, first) in names.iter().zip(firsts.iter()) {
+            let index = reversed.pick(name, now)[0];
+            assert_eq!(
+                reversed.upstreams[index].domain,
+                upstreams.upstreams[*first].domain
+            );
+        }
+    }
+
+    /// QUIC configs trusting each other by a self-signed certificate for `localhost`
+    fn quic_configs() -> (quinn::ServerConfig, quinn::ClientConfig) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = rustls::Certificate(cert.serialize_der().unwrap());
+        let key = rustls::PrivateKey(cert.serialize_private_key_der());
+        let server_config =
+            quinn::ServerConfig::with_single_cert(vec![cert_der.clone()], key).unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&cert_der).unwrap();
+        let crypto = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let mut client_config = quinn::ClientConfig::new(Arc::new(crypto));
+        let mut transport = quinn::TransportConfig::default();
+        // give up dead upstreams quickly
+        transport.max_idle_timeout(Some(Duration::from_millis(300).try_into().unwrap()));
+        client_config.transport = Arc::new(transport);
+
+        (server_config, client_config)
+    }
+
+    fn quic_client(config: quinn::ClientConfig) -> Endpoint {
+        let mut client = Endpoint::client("[::1]:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(config);
+        client
+    }
+
+    /// spawn a QUIC upstream answering queries by `respond`, returns its address and
+    /// the number of connections it has accepted
+    fn quic_upstream<F>(config: quinn::ServerConfig, respond: F) -> (SocketAddr, Arc<AtomicUsize>)
+    where
+        F: Fn(Packet) -> Packet + Copy + Send + 'static,
+    {
+        let (server, connections) = quic_upstream_at(config, "[::1]:0".parse().unwrap(), respond);
+        (server.local_addr().unwrap(), connections)
+    }
+
+    /// like `quic_upstream`, but bound to `addr` and returning the endpoint to close it by
+    fn quic_upstream_at<F>(
+        config: quinn::ServerConfig,
+        addr: SocketAddr,
+        respond: F,
+    ) -> (Endpoint, Arc<AtomicUsize>)
+    where
+        F: Fn(Packet) -> Packet + Copy + Send + 'static,
+    {
+        try_quic_upstream_at(config, addr, respond).unwrap()
+    }
+
+    /// like `quic_upstream_at`, once `addr` is released by the upstream closed before
+    async fn quic_upstream_restart<F>(
+        config: quinn::ServerConfig,
+        addr: SocketAddr,
+        respond: F,
+    ) -> (Endpoint, Arc<AtomicUsize>)
+    where
+        F: Fn(Packet) -> Packet + Copy + Send + 'static,
+    {
+        loop {
+            match try_quic_upstream_at(config.clone(), addr, respond) {
+                Ok(upstream) => return upstream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    }
+
+    fn try_quic_upstream_at<F>(
+        config: quinn::ServerConfig,
+        addr: SocketAddr,
+        respond: F,
+    ) -> std::io::Result<(Endpoint, Arc<AtomicUsize>)>
+    where
+        F: Fn(Packet) -> Packet + Copy + Send + 'static,
+    {
+        let (server, mut incoming) = Endpoint::server(config, addr)?;
+        let connections = Arc::new(AtomicUsize::new(0));
+        let accepted = connections.clone();
+        let endpoint = server.clone();
+        tokio::spawn(async move {
+            let _server = server;
+            while let Some(connecting) = incoming.next().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let Ok(NewConnection { mut bi_streams, .. }) = connecting.await else {
+                    continue;
+                };
+                tokio::spawn(async move {
+                    while let Some(Ok((mut send, recv))) = bi_streams.next().await {
+                        let Ok(buf) = recv.read_to_end(u16::MAX as usize).await else {
+                            continue;
+                        };
+                        let query = Packet::parse_packet(buf.into(), 0).unwrap();
+                        let _ = send.write_all(&respond(query).into_bytes()).await;
+                        let _ = send.finish().await;
+                    }
+                });
+            }
+        });
+        Ok((endpoint, connections))
+    }
+
+    #[tokio::test]
+    async fn test_quic_failover() {
+        let (server_config, client_config) = quic_configs();
+        let (alive, _) = quic_upstream(server_config, |q| response(q, 1));
+        // nobody answers on this socket
+        let silent = UdpSocket::bind("[::1]:0").await.unwrap();
+        let dead = silent.local_addr().unwrap();
+
+        let (tasks, rec) = mpsc::unbounded_channel();
+        let upstreams = vec![
+            ("localhost".to_string(), dead),
+            ("localhost".to_string(), alive),
+        ];
+        let forwarder = QuicForwarder::try_new(
+            rec,
+            quic_client(client_config),
+            upstreams,
+            LoadBalance::FirstHealthy,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            forwarder.active_upstream(),
+            ("localhost".to_string(), alive)
+        );
+        let forwarding = tokio::spawn(forwarder.run());
+
+        let answers = query(&tasks, "example.com").await;
+        assert_eq!(tag_of(&answers), 1);
+
+        drop(tasks);
+        forwarding.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_connection_lost() {
+        let (server_config, client_config) = quic_configs();
+        let (server, _) =
+            quic_upstream_at(server_config.clone(), "[::1]:0".parse().unwrap(), |q| {
+                response(q, 1)
+            });
+        let addr = server.local_addr().unwrap();
+
+        let (tasks, rec) = mpsc::unbounded_channel();
+        let upstreams = vec![("localhost".to_string(), addr)];
+        let forwarder = QuicForwarder::try_new(
+            rec,
+            quic_client(client_config),
+            upstreams,
+            LoadBalance::FirstHealthy,
+        )
+        .await
+        .unwrap()
+        .with_connect_attempts(1);
+        let forwarding = tokio::spawn(forwarder.run());
+        assert_eq!(tag_of(&query(&tasks, "example.com").await), 1);
+
+        // the upstream goes away, reconnecting fails
+        server.close(0_u32.into(), b"restarting");
+        // the address is free to bind again once the connections are drained
+        server.wait_idle().await;
+        drop(server);
+        let answers = query(&tasks, "example.com").await;
+        assert!(matches!(
+            answers[..],
+            [Answer::Error(PacketError::ServFail)]
+        ));
+
+        // and comes back, the forwarder is still there to reconnect
+        let (_server, _) = quic_upstream_at(server_config, addr, |q| response(q, 2));
+        assert_eq!(tag_of(&query(&tasks, "example.com").await), 2);
+
+        drop(tasks);
+        forwarding.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_reconnect_backoff() {
+        let (server_config, client_config) = quic_configs();
+        let (server, _) =
+            quic_upstream_at(server_config.clone(), "[::1]:0".parse().unwrap(), |q| {
+                response(q, 1)
+            });
+        let addr = server.local_addr().unwrap();
+
+        let (tasks, rec) = mpsc::unbounded_channel();
+        let upstreams = vec![("localhost".to_string(), addr)];
+        let forwarder = QuicForwarder::try_new(
+            rec,
+            quic_client(client_config),
+            upstreams,
+            LoadBalance::FirstHealthy,
+        )
+        .await
+        .unwrap()
+        .with_connect_attempts(10);
+        let forwarding = tokio::spawn(forwarder.run());
+        assert_eq!(tag_of(&query(&tasks, "example.com").await), 1);
+
+        server.close(0_u32.into(), b"restarting");
+        drop(server);
+        // the upstream fails the first attempts by a certificate nobody trusts
+        let (untrusted_config, _) = quic_configs();
+        let (untrusted, attempts) =
+            quic_upstream_restart(untrusted_config, addr, |q| response(q, 0)).await;
+        let failing = attempts.clone();
+        let restarting = tokio::spawn(async move {
+            while failing.load(Ordering::SeqCst) < 2 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            untrusted.close(0_u32.into(), b"restarting");
+            drop(untrusted);
+            quic_upstream_restart(server_config, addr, |q| response(q, 2)).await
+        });
+
+        let answers = query(&tasks, "example.com").await;
+        assert_eq!(tag_of(&answers), 2);
+        assert!(attempts.load(Ordering::SeqCst) >= 2);
+
+        drop(tasks);
+        forwarding.await.unwrap().unwrap();
+        drop(restarting.await.unwrap());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff::default();
+        for failed in 1..10 {
+            let doubled = (RETRY_BASE * 2_u32.pow(failed - 1)).min(RETRY_MAX);
+            let delay = backoff.delay(failed);
+            assert!(delay >= doubled / 2 && delay <= doubled, "{:?}", delay);
+        }
+        assert!(backoff.delay(u32::MAX) <= RETRY_MAX);
+    }
+
+    #[tokio::test]
+    async fn test_quic_max_streams() {
+        let (server_config, client_config) = quic_configs();
+        // an upstream taking queries but never answering them
+        let (server, mut incoming) =
+            Endpoint::server(server_config, "[::1]:0".parse().unwrap()).unwrap();
+        let upstream = server.local_addr().unwrap();
+        let streams = Arc::new(AtomicUsize::new(0));
+        let opened = streams.clone();
+        tokio::spawn(async move {
+            let _server = server;
+            let NewConnection { mut bi_streams, .. } =
+                incoming.next().await.unwrap().await.unwrap();
+            let mut held = vec![];
+            while let Some(Ok(stream)) = bi_streams.next().await {
+                opened.fetch_add(1, Ordering::SeqCst);
+                held.push(stream);
+            }
+        });
+
+        let (tasks, rec) = mpsc::unbounded_channel();
+        let upstreams = vec![("localhost".to_string(), upstream)];
+        let forwarder = QuicForwarder::try_new(
+            rec,
+            quic_client(client_config),
+            upstreams,
+            LoadBalance::FirstHealthy,
+        )
+        .await
+        .unwrap()
+        .with_max_streams(1);
+        let forwarding = tokio::spawn(forwarder.run());
+
+        let mut pending = vec![];
+        for i in 0..3 {
+            let (ans_to, ans_from) = mpsc::unbounded_channel();
+            let q = question(&format!("{}.example.com", i));
+            tasks.send(Task::Query(q, ans_to, None)).unwrap();
+            pending.push(ans_from);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // the others wait for the first to be answered
+        assert_eq!(streams.load(Ordering::SeqCst), 1);
+
+        forwarding.abort();
+    }
+
+    #[tokio::test]
+    async fn test_quic_rate_limit() {
+        let (server_config, client_config) = quic_configs();
+        let (server, mut incoming) =
+            Endpoint::server(server_config, "[::1]:0".parse().unwrap()).unwrap();
+        let upstream = server.local_addr().unwrap();
+        let streams = Arc::new(AtomicUsize::new(0));
+        let opened = streams.clone();
+        tokio::spawn(async move {
+            let _server = server;
+            let NewConnection { mut bi_streams, .. } =
+                incoming.next().await.unwrap().await.unwrap();
+            while let Some(Ok((mut send, recv))) = bi_streams.next().await {
+                opened.fetch_add(1, Ordering::SeqCst);
+                let Ok(buf) = recv.read_to_end(u16::MAX as usize).await else {
+                    continue;
+                };
+                let Ok(q) = Packet::parse_packet(buf.into(), 0) else {
+                    continue;
+                };
+                let _ = send.write_all(&response(q, 1).into_bytes()).await;
+                let _ = send.finish().await;
+            }
+        });
+
+        let (tasks, rec) = mpsc::unbounded_channel();
+        let upstreams = vec![("localhost".to_string(), upstream)];
+        let forwarder = QuicForwarder::try_new(
+            rec,
+            quic_client(client_config),
+            upstreams,
+            LoadBalance::FirstHealthy,
+        )
+        .await
+        .unwrap()
+        .with_rate_limit(1, 2);
+        let forwarding = tokio::spawn(forwarder.run());
+
+        // a burst of 2 is let through, the 3rd query is throttled before opening a stream
+        let answers = tokio::join!(
+            query(&tasks, "a.example"),
+            query(&tasks, "b.example"),
+            query(&tasks, "c.example"),
+        );
+        let answers = [answers.0, answers.1, answers.2];
+        let throttled = answers
+            .iter()
+            .filter(|ans| matches!(ans[..], [Answer::Error(PacketError::ServFail)]))
+            .count();
+        assert_eq!(throttled, 1);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(streams.load(Ordering::SeqCst), 2);
+
+        forwarding.abort();
+    }
+
+    #[tokio::test]
+    async fn test_quic_round_robin() {
+        let (server_config, client_config) = quic_configs();
+        let (first, first_conns) = quic_upstream(server_config.clone(), |q| response(q, 1));
+        let (second, second_conns) = quic_upstream(server_config, |q| response(q, 2));
+
+        let (tasks, rec) = mpsc::unbounded_channel();
+        let upstreams = vec![
+            ("localhost".to_string(), first),
+            ("localhost".to_string(), second),
+        ];
+        let forwarder = QuicForwarder::try_new(
+            rec,
+            quic_client(client_config),
+            upstreams,
+            LoadBalance::RoundRobin,
+        )
+        .await
+        .unwrap();
+        let forwarding = tokio::spawn(forwarder.run());
+
+        let mut tags = vec![];
+        for _ in 0..4 {
+            tags.push(tag_of(&query(&tasks, "example.com").await));
+        }
+        assert_eq!(tags, vec![1, 2, 1, 2]);
+        // connections are reused rather than re-established on rotation
+        assert_eq!(first_conns.load(Ordering::SeqCst), 1);
+        assert_eq!(second_conns.load(Ordering::SeqCst), 1);
+
+        drop(tasks);
+        forwarding.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_truncated() {
+        let (server_config, client_config) = quic_configs();
+        let (upstream, _) = quic_upstream(server_config, |q| {
+            let mut resp = response(q, 1);
+            resp.header.set_truncated(true);
+            resp
+        });
+
+        let (tasks, rec) = mpsc::unbounded_channel();
+        let upstreams = vec![("localhost".to_string(), upstream)];
+        let forwarder = QuicForwarder::try_new(
+            rec,
+            quic_client(client_config),
+            upstreams,
+            LoadBalance::FirstHealthy,
+        )
+        .await
+        .unwrap();
+        let forwarding = tokio::spawn(forwarder.run());
+
+        let answers = query(&tasks, "example.com").await;
+        assert!(matches!(
+            answers[..],
+            [Answer::Error(PacketError::ServFail)]
+        ));
+
+        drop(tasks);
+        forwarding.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_coalescing() {
+        let (server_config, client_config) = quic_configs();
+        let (upstream, connections) = quic_upstream(server_config, |q| response(q, 1));
+
+        let (tasks, rec) = mpsc::unbounded_channel();
+        // the same upstream configured twice
+        let upstreams = vec![
+            ("localhost".to_string(), upstream),
+            ("localhost".to_string(), upstream),
+        ];
+        let forwarder = QuicForwarder::try_new(
+            rec,
+            quic_client(client_config),
+            upstreams,
+            LoadBalance::RoundRobin,
+        )
+        .await
+        .unwrap();
+        let forwarding = tokio::spawn(forwarder.run());
+
+        let queries = (0..8).map(|_| query(&tasks, "example.com"));
+        for answers in futures::future::join_all(queries).await {
+            assert_eq!(tag_of(&answers), 1);
+        }
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        drop(tasks);
+        forwarding.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_keep_alive() {
+        let (server_config, mut client_config) = quic_configs();
+        let mut transport = quinn::TransportConfig::default();
+        transport.max_idle_timeout(Some(Duration::from_millis(300).try_into().unwrap()));
+        transport.keep_alive_interval(Some(Duration::from_millis(100)));
+        client_config.transport = Arc::new(transport);
+        let (upstream, connections) = quic_upstream(server_config, |q| response(q, 1));
+
+        let (tasks, rec) = mpsc::unbounded_channel();
+        let upstreams = vec![("localhost".to_string(), upstream)];
+        let forwarder = QuicForwarder::try_new(
+            rec,
+            quic_client(client_config),
+            upstreams,
+            LoadBalance::FirstHealthy,
+        )
+        .await
+        .unwrap();
+        let forwarding = tokio::spawn(forwarder.run());
+
+        assert_eq!(tag_of(&query(&tasks, "example.com").await), 1);
+        // idle for twice the idle timeout
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert_eq!(tag_of(&query(&tasks, "example.com").await), 1);
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        drop(tasks);
+        forwarding.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_reap_idle() {
+        let (server_config, client_config) = quic_configs();
+        let (upstream, connections) = quic_upstream(server_config, |q| response(q, 1));
+
+        let (tasks, rec) = mpsc::unbounded_channel();
+        let upstreams = vec![("localhost".to_string(), upstream)];
+        let forwarder = QuicForwarder::try_new(
+            rec,
+            quic_client(client_config),
+            upstreams,
+            LoadBalance::FirstHealthy,
+        )
+        .await
+        .unwrap()
+        .with_max_idle(Duration::from_millis(100));
+        let forwarding = tokio::spawn(forwarder.run());
+
+        assert_eq!(tag_of(&query(&tasks, "example.com").await), 1);
+        // still within the idle timeout of 300 ms, but dropped anyway
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert_eq!(tag_of(&query(&tasks, "example.com").await), 1);
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+        drop(tasks);
+        forwarding.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quic_reap_zero_idle() {
+        assert_eq!(reap_period(Duration::ZERO), REAP_PERIOD_MIN);
+        assert_eq!(reap_period(Duration::from_nanos(1)), REAP_PERIOD_MIN);
+        assert_eq!(reap_period(Duration::from_secs(2)), Duration::from_secs(1));
+
+        let (server_config, client_config) = quic_configs();
+        let (upstream, _) = quic_upstream(server_config, |q| response(q, 1));
+        let (tasks, rec) = mpsc::unbounded_channel();
+        let upstreams = vec![("localhost".to_string(), upstream)];
+        let forwarder = QuicForwarder::try_new(
+            rec,
+            quic_client(client_config),
+            upstreams,
+            LoadBalance::FirstHealthy,
+        )
+        .await
+        .unwrap()
+        .with_max_idle(Duration::ZERO);
+        let forwarding = tokio::spawn(forwarder.run());
+
+        // connections are dropped at once, queries still go through
+        assert_eq!(tag_of(&query(&tasks, "example.com").await), 1);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(tag_of(&query(&tasks, "example.com").await), 1);
+
+        drop(tasks);
+        forwarding.await.unwrap().unwrap();
+    }
+
+    /// spawn a DoH upstream for `localhost` on HTTP/2, answering queries with tag 1
+    /// unless it is `silent`, returns its address and a client config trusting it
+    async fn doh_upstream(silent: bool) -> (SocketAddr, Arc<rustls::ClientConfig>) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = rustls::Certificate(cert.serialize_der().unwrap());
+        let key = rustls::PrivateKey(cert.serialize_private_key_der());
+        let mut server_config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der.clone()], key)
+            .unwrap();
+        server_config.alpn_protocols = vec![Vec::from(&b"h2"[..])];
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (task_sender, mut tasks) = mpsc::unbounded_channel();
+        let server = DohService::new(listener, task_sender).with_tls(Arc::new(server_config));
+        tokio::spawn(server.run());
+        tokio::spawn(async move {
+            let mut pending = vec![];
+            while let Some(Task::Query(q, ans_to, _)) = tasks.recv().await {
+                if silent {
+                    pending.push(ans_to);
+                    continue;
+                }
+                let query = Packet::new_query(0, q);
+                for rr in response(query, 1).answers {
+                    let _ = ans_to.send(Answer::Answer(rr));
+                }
+            }
+        });
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&cert_der).unwrap();
+        let client_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        (addr, Arc::new(client_config))
+    }
+
+    #[tokio::test]
+    async fn test_doh_forwarder() {
+        let (upstream, config) = doh_upstream(false).await;
+        let (tasks, rec) = mpsc::unbounded_channel();
+        let url = "https://localhost/dns-query";
+        let forwarder = DohForwarder::try_new(rec, config, url, upstream)
+            .await
+            .unwrap();
+        let forwarding = tokio::spawn(forwarder.run());
+
+        let queries = (0..4).map(|_| query(&tasks, "example.com"));
+        for answers in futures::future::join_all(queries).await {
+            assert_eq!(tag_of(&answers), 1);
+        }
+
+        drop(tasks);
+        forwarding.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_doh_timeout() {
+        let (upstream, config) = doh_upstream(true).await;
+        let (tasks, rec) = mpsc::unbounded_channel();
+        let url = "https://localhost/dns-query";
+        let forwarder = DohForwarder::try_new(rec, config.clone(), url, upstream)
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(200));
+        let forwarding = tokio::spawn(forwarder.run());
+
+        let answers = query(&tasks, "example.com").await;
+        assert!(matches!(
+            answers[..],
+            [Answer::Error(PacketError::ServFail)]
+        ));
+
+        // only HTTPS upstreams are accepted
+        let (_, rec) = mpsc::unbounded_channel();
+        let url = "http://localhost/dns-query";
+        assert!(DohForwarder::try_new(rec, config, url, upstream)
+            .await
+            .is_err());
+
+        drop(tasks);
+        forwarding.await.unwrap().unwrap();
     }
 }
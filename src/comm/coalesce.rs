@@ -0,0 +1,125 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    hash::Hash,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::OnceCell;
+
+/// `Coalescer` shares connections by key, e.g. `(addr, sni, proto)`.
+///
+/// Concurrent attempts to reach the same key wait for a single connection attempt,
+/// instead of each opening its own. A failed attempt is retried by the next caller.
+pub(crate) struct Coalescer<K, C> {
+    slots: Mutex<HashMap<K, Arc<OnceCell<C>>>>,
+}
+
+impl<K, C> Coalescer<K, C>
+where
+    K: Hash + Eq + Clone,
+    C: Clone,
+{
+    pub fn new() -> Self {
+        Self {
+            slots: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// get the connection to `key`, or establish it by `connect`
+    pub async fn get_or_connect<F, Fut, E>(&self, key: &K, connect: F) -> Result<C, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<C, E>>,
+    {
+        let slot = {
+            let mut slots = self.slots.lock().unwrap();
+            slots.entry(key.clone()).or_default().clone()
+        };
+        slot.get_or_try_init(connect).await.cloned()
+    }
+
+    /// forget the connection to `key` if it is `is_stale`,
+    /// connections established by others in the meantime are kept
+    pub fn invalidate<F>(&self, key: &K, is_stale: F)
+    where
+        F: FnOnce(&C) -> bool,
+    {
+        let mut slots = self.slots.lock().unwrap();
+        let stale = match slots.get(key).and_then(|slot| slot.get()) {
+            Some(connection) => is_stale(connection),
+            None => false,
+        };
+        if stale {
+            slots.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::Coalescer;
+
+    #[tokio::test]
+    async fn test_coalesce() {
+        let coalescer = Arc::new(Coalescer::new());
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let connect = |attempts: Arc<AtomicUsize>| async move {
+            let n = attempts.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok::<usize, ()>(n)
+        };
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let coalescer = coalescer.clone();
+                let attempts = attempts.clone();
+                tokio::spawn(async move {
+                    coalescer
+                        .get_or_connect(&"upstream", || connect(attempts))
+                        .await
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert_eq!(handle.await.unwrap(), Ok(0));
+        }
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        // only the stale connection is dropped
+        coalescer.invalidate(&"upstream", |c| *c == 1);
+        let got = coalescer
+            .get_or_connect(&"upstream", || connect(attempts.clone()))
+            .await;
+        assert_eq!(got, Ok(0));
+        coalescer.invalidate(&"upstream", |c| *c == 0);
+        let got = coalescer
+            .get_or_connect(&"upstream", || connect(attempts.clone()))
+            .await;
+        assert_eq!(got, Ok(1));
+
+        // failures are not kept
+        let failed = coalescer
+            .get_or_connect(&"other", || async { Err::<usize, ()>(()) })
+            .await;
+        assert_eq!(failed, Err(()));
+        let got = coalescer
+            .get_or_connect(&"other", || connect(attempts.clone()))
+            .await;
+        assert_eq!(got, Ok(2));
+    }
+}
@@ -0,0 +1,206 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash, Hasher},
+    net::IpAddr,
+};
+
+use crate::protocol::{EdnsOption, OptBuilder, Packet, PacketError, RRData, RRType, RR};
+
+/// code of the cookie option
+pub(crate) const COOKIE: u16 = 10;
+/// length of client cookies
+const CLIENT_COOKIE: usize = 8;
+
+/// ## `ClientCookie`
+/// The cookie option of a query, see [RFC7873](https://datatracker.ietf.org/doc/html/rfc7873#section-5.2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ClientCookie {
+    client: [u8; CLIENT_COOKIE],
+    // the server cookie that came along was made by us for this client
+    valid: bool,
+}
+
+impl ClientCookie {
+    /// the query came with a server cookie of ours
+    pub fn is_valid(&self) -> bool {
+        self.valid
+    }
+}
+
+/// ## `Cookies`
+/// Server cookies of DNS Cookies, see [RFC7873](https://datatracker.ietf.org/doc/html/rfc7873#section-4.2).
+///
+/// A server cookie is a keyed hash of the client cookie and the client address,
+/// so it only validates along with the cookie and from the address it was given to.
+/// The key is made up once per `Cookies`, server cookies of others do not validate.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Cookies {
+    key: RandomState,
+}
+
+impl Cookies {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn server_cookie(&self, client: &[u8; CLIENT_COOKIE], ip: IpAddr) -> [u8; 8] {
+        let mut hasher = self.key.build_hasher();
+        client.hash(&mut hasher);
+        ip.hash(&mut hasher);
+        hasher.finish().to_be_bytes()
+    }
+
+    /// read the cookie option of `query` sent from `ip`, if it has one.
+    ///
+    /// Options other than a client cookie, optionally followed by a server cookie of 8 to 32 bytes,
+    /// are malformed.
+    pub fn check(&self, query: &Packet, ip: IpAddr) -> Result<Option<ClientCookie>, PacketError> {
+        let Some(RRData::Opt(opt)) = query
+            .additions
+            .iter()
+            .find(|rr| rr.get_type() == RRType::Opt)
+            .cloned()
+            .map(RR::into_rdata)
+        else {
+            return Ok(None);
+        };
+        let Some(data) = opt.get_option(COOKIE) else {
+            return Ok(None);
+        };
+        let (client, server) = match data.len() {
+            CLIENT_COOKIE | 16..=40 => data.split_at(CLIENT_COOKIE),
+            _ => return Err(PacketError::FormatError),
+        };
+        let client = client.try_into().unwrap();
+        let valid = server == self.server_cookie(&client, ip);
+        Ok(Some(ClientCookie { client, valid }))
+    }
+
+    /// echo `cookie` in the `OPT` record of `resp` to `ip`, along with its server cookie.
+    ///
+    /// Responses without an `OPT` record get one.
+    pub fn answer(&self, resp: &mut Packet, cookie: &ClientCookie, ip: IpAddr) {
+        let data = [cookie.client, self.server_cookie(&cookie.client, ip)].concat();
+        let Some(i) = resp
+            .additions
+            .iter()
+            .position(|rr| rr.get_type() == RRType::Opt)
+        else {
+            let opt = OptBuilder::new().option(EdnsOption::Cookie(data)).build();
+            let _ = resp.add_addition(opt);
+            return;
+        };
+        let opt = resp.additions.remove(i);
+        let (udp_size, ext_rcode) = (opt.udp_size().unwrap(), opt.ext_rcode().unwrap());
+        let (version, dnssec_ok) = (opt.edns_version().unwrap(), opt.dnssec_ok().unwrap());
+        if let RRData::Opt(options) = opt.into_rdata() {
+            let options = options.with_option(COOKIE, data);
+            let opt = RR::new_opt(udp_size, ext_rcode, version, dnssec_ok, options);
+            resp.additions.insert(i, opt);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use super::{ClientCookie, Cookies, COOKIE};
+    use crate::protocol::{
+        EdnsOption, Name, OptBuilder, Packet, PacketError, Question, RRClass, RRData, RRType,
+    };
+
+    fn query(cookie: Option<Vec<u8>>) -> Packet {
+        let name = Name::try_from("example.com").unwrap();
+        let q = Question::build(name, RRType::A, RRClass::Internet);
+        let mut pkt = Packet::new_query(1, q);
+        if let Some(cookie) = cookie {
+            let opt = OptBuilder::new().option(EdnsOption::Cookie(cookie)).build();
+            pkt.add_addition(opt).unwrap();
+        }
+        // as received
+        Packet::parse_packet(pkt.into_bytes(), 0).unwrap()
+    }
+
+    /// the cookie option `resp` carries
+    fn echoed(resp: Packet) -> Vec<u8> {
+        let resp = Packet::parse_packet(resp.into_bytes(), 0).unwrap();
+        let opt = resp.additions.into_iter().next().unwrap().into_rdata();
+        let RRData::Opt(opt) = opt else {
+            panic!("not an OPT record: {:?}", opt);
+        };
+        opt.get_option(COOKIE).unwrap().to_vec()
+    }
+
+    #[test]
+    fn test_cookie_round_trip() {
+        let cookies = Cookies::new();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let client_cookie = vec![1, 2, 3, 4, 5, 6, 7, 8];
+
+        // the first query only has a client cookie
+        let first = cookies.check(&query(Some(client_cookie.clone())), ip);
+        let first = first.unwrap().unwrap();
+        assert!(!first.is_valid());
+        let mut resp = Packet::new_plain_answer(1);
+        cookies.answer(&mut resp, &first, ip);
+        let cookie = echoed(resp);
+        assert_eq!(cookie.len(), 16);
+        assert_eq!(cookie[..8], client_cookie[..]);
+
+        // the server cookie returned validates on the next query
+        let next = cookies.check(&query(Some(cookie.clone())), ip);
+        assert!(next.unwrap().unwrap().is_valid());
+
+        // but not from another address, with another client cookie, or on another server
+        let other: IpAddr = "192.0.2.2".parse().unwrap();
+        let moved = cookies.check(&query(Some(cookie.clone())), other);
+        assert!(!moved.unwrap().unwrap().is_valid());
+        let mut forged = cookie.clone();
+        forged[0] ^= 1;
+        let forged = cookies.check(&query(Some(forged)), ip);
+        assert!(!forged.unwrap().unwrap().is_valid());
+        let elsewhere = Cookies::new().check(&query(Some(cookie)), ip);
+        assert!(!elsewhere.unwrap().unwrap().is_valid());
+    }
+
+    #[test]
+    fn test_cookie_malformed() {
+        let cookies = Cookies::new();
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        assert!(matches!(cookies.check(&query(None), ip), Ok(None)));
+        for len in [0, 7, 9, 15, 41] {
+            let checked = cookies.check(&query(Some(vec![0; len])), ip);
+            let malformed = matches!(checked, Err(PacketError::FormatError));
+            assert!(malformed, "{} bytes", len);
+        }
+        // server cookies of other servers may be up to 32 bytes
+        let checked = cookies.check(&query(Some(vec![0; 40])), ip);
+        assert!(!checked.unwrap().unwrap().is_valid());
+    }
+
+    #[test]
+    fn test_cookie_into_opt() {
+        let cookies = Cookies::new();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let cookie = ClientCookie {
+            client: [8; 8],
+            valid: false,
+        };
+        // failures carrying an OPT record keep it, the cookie is added to its options
+        let mut resp = Packet::new_edns_failure(1, PacketError::TimedOut);
+        cookies.answer(&mut resp, &cookie, ip);
+        assert_eq!(resp.additions.len(), 1);
+        let RRData::Opt(opt) = resp.additions[0].clone().into_rdata() else {
+            unreachable!();
+        };
+        assert_eq!(opt.get_options().len(), 2);
+        assert_eq!(echoed(resp)[..8], [8; 8]);
+    }
+}
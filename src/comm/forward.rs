@@ -4,58 +4,362 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::sync::Arc;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
 
-use bytes::BytesMut;
-use tokio::net::UdpSocket;
+use rand::prelude::random;
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpStream, UdpSocket},
+};
 use tracing;
 
 use crate::{
-    comm::{Answer, TaskMap},
-    protocol::{Packet, TransactionError},
+    comm::{cookie::COOKIE, stream::write_packet, unmapped, Answer, RecvBuffer, TaskMap},
+    protocol::{
+        EdnsOption, Name, OptBuilder, Packet, PacketError, Question, RRData, RRType, Rcode,
+        TransactionError, RR,
+    },
 };
 
+/// responses received over UDP are this large at most, the largest datagram
+const MAX_RESPONSE_SIZE: usize = 65535;
+/// code of the client subnet option
+const CLIENT_SUBNET: u16 = 8;
+
+/// ## `ClientSubnet`
+/// The network a query is asked from, see [RFC7871](https://datatracker.ietf.org/doc/html/rfc7871#section-6).
+///
+/// Queries come with the subnet of their ECS option, or the address of their client alone,
+/// it is narrowed to a network before answers are cached by it or it is told to upstreams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ClientSubnet {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl From<IpAddr> for ClientSubnet {
+    fn from(address: IpAddr) -> Self {
+        let address = unmapped(address);
+        let prefix = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        Self { address, prefix }
+    }
+}
+
+impl ClientSubnet {
+    /// the subnet told by the ECS option of `query`,
+    /// or the host `ip` it is sent from if the option is absent or malformed
+    pub(crate) fn of_query(query: &Packet, ip: IpAddr) -> Self {
+        let opt = query
+            .additions
+            .iter()
+            .find(|rr| rr.get_type() == RRType::Opt)
+            .cloned()
+            .map(RR::into_rdata);
+        let Some(RRData::Opt(opt)) = opt else {
+            return ip.into();
+        };
+        opt.get_option(CLIENT_SUBNET)
+            .and_then(Self::parse)
+            .unwrap_or_else(|| ip.into())
+    }
+
+    /// read the data of an ECS option, the address is as long as the source prefix needs
+    fn parse(data: &[u8]) -> Option<Self> {
+        let [f0, f1, prefix, _scope, address @ ..] = data else {
+            return None;
+        };
+        if address.len() != (*prefix as usize).div_ceil(8) {
+            return None;
+        }
+        let address = match (u16::from_be_bytes([*f0, *f1]), *prefix) {
+            (1, 0..=32) => {
+                let mut octets = [0; 4];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::from(octets)
+            }
+            (2, 0..=128) => {
+                let mut octets = [0; 16];
+                octets[..address.len()].copy_from_slice(address);
+                IpAddr::from(octets)
+            }
+            _ => return None,
+        };
+        // bits beyond the prefix are cleared
+        Some(Self::from(address).narrowed(*prefix, *prefix))
+    }
+
+    /// the network of the first `v4_prefix` or `v6_prefix` bits by the family, if it is narrower
+    pub fn narrowed(self, v4_prefix: u8, v6_prefix: u8) -> Self {
+        match self.address {
+            IpAddr::V4(v4) => {
+                let prefix = self.prefix.min(v4_prefix).min(32);
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                let address = Ipv4Addr::from(u32::from(v4) & mask).into();
+                Self { address, prefix }
+            }
+            IpAddr::V6(v6) => {
+                let prefix = self.prefix.min(v6_prefix).min(128);
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                let address = Ipv6Addr::from(u128::from(v6) & mask).into();
+                Self { address, prefix }
+            }
+        }
+    }
+
+    pub fn address(&self) -> IpAddr {
+        self.address
+    }
+
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+}
+
+/// ## `Edns`
+/// The EDNS(0) options forwarded queries are sent with.
+///
+/// EDNS is hop by hop, so the OPT of clients is never passed on.
+/// Queries carry our buffer size and cookie instead, the cookie is made up once per `Edns`.
+#[derive(Debug, Clone)]
+pub struct Edns {
+    dnssec_ok: bool,
+    cookie: [u8; 8],
+    client_subnet: Option<(u8, u8)>,
+}
+
+impl Default for Edns {
+    fn default() -> Self {
+        Self {
+            dnssec_ok: false,
+            cookie: random(),
+            client_subnet: None,
+        }
+    }
+}
+
+impl Edns {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// ask upstreams for DNSSEC records by the DO bit
+    pub fn with_dnssec_ok(mut self, dnssec_ok: bool) -> Self {
+        self.dnssec_ok = dnssec_ok;
+        self
+    }
+
+    /// tell upstreams the network of the client of each query,
+    /// the first `v4_prefix` or `v6_prefix` bits of its address.
+    ///
+    /// RFC7871 recommends 24 and 56 bits, nothing is told by default.
+    /// Answers should be cached apart for each network then, see `DnsCache::with_client_subnet`.
+    pub fn with_client_subnet(mut self, v4_prefix: u8, v6_prefix: u8) -> Self {
+        self.client_subnet = Some((v4_prefix, v6_prefix));
+        self
+    }
+
+    /// a query for `q` asked from `client`, carrying our OPT record
+    pub(crate) fn query(&self, id: u16, q: Question, client: Option<ClientSubnet>) -> Packet {
+        let mut opt = OptBuilder::new()
+            .dnssec_ok(self.dnssec_ok)
+            .option(EdnsOption::Cookie(self.cookie.to_vec()));
+        if let (Some((v4_prefix, v6_prefix)), Some(client)) = (self.client_subnet, client) {
+            let subnet = client.narrowed(v4_prefix, v6_prefix);
+            opt = opt.option(EdnsOption::ClientSubnet {
+                address: subnet.address,
+                source_prefix: subnet.prefix,
+                scope_prefix: 0,
+            });
+        }
+        let mut packet = Packet::new_query(id, q);
+        // a new query has no OPT yet
+        let _ = packet.add_addition(opt.build());
+        packet
+    }
+}
+
+/// split records in a response packet into answers.
+///
+/// A failed response leads with an `Answer::Error`, the records follow,
+/// so the SOA of negative responses is kept for caching.
+pub(crate) fn into_answers(pkt: Packet) -> Vec<Answer> {
+    let error = match pkt.get_rcode() {
+        Rcode::NoError => None,
+        Rcode::NameError => {
+            let name = match pkt.question() {
+                Some(q) => q.get_name(),
+                None => Name::try_from(".").unwrap(),
+            };
+            Some(PacketError::NameError(name))
+        }
+        rcode => {
+            tracing::debug!("upstream failed with {:?}", rcode);
+            Some(PacketError::ServFail)
+        }
+    };
+    error
+        .into_iter()
+        .map(Answer::Error)
+        .chain(pkt.answers.into_iter().map(Answer::Answer))
+        .chain(pkt.authorities.into_iter().map(Answer::NameServer))
+        .chain(
+            pkt.additions
+                .into_iter()
+                // the OPT of upstream is for us, not for clients
+                .filter(|rr| rr.get_type() != RRType::Opt)
+                .map(Answer::Additional),
+        )
+        .collect()
+}
+
+/// split records in a response received over a stream transport into answers.
+///
+/// Streams are not limited in size, so a truncated response means the upstream
+/// misbehaves, it is answered with `ServFail` instead.
+pub(crate) fn stream_answers(pkt: Packet) -> Vec<Answer> {
+    if pkt.is_trunc() {
+        tracing::warn!(
+            "upstream sent truncated response {} over a stream",
+            pkt.get_id()
+        );
+        return vec![Answer::Error(PacketError::ServFail)];
+    }
+    into_answers(pkt)
+}
+
+/// pass responses read from a TCP or TLS stream back to the forwarded queries
+pub async fn listening_stream<S>(mut stream: S, map: TaskMap)
+where
+    S: AsyncReadExt + Unpin,
+{
+    loop {
+        let (id, answers) = match Packet::parse_stream(&mut stream).await {
+            Ok(pkt) => (pkt.get_id(), stream_answers(pkt)),
+            Err(TransactionError {
+                id: Some(id),
+                error,
+            }) => (id, vec![Answer::Error(error)]),
+            Err(TransactionError {
+                id: None,
+                error: PacketError::ServFail,
+            }) => {
+                // read to end of file in stream
+                tracing::debug!("upstream stream reaches its end");
+                break;
+            }
+            Err(e) => {
+                tracing::debug!("received failure from upstream: {}", e);
+                break;
+            }
+        };
+        map.answer(id, answers);
+    }
+}
+
+/// forward `query` over a new TCP connection, and pass the response back by `map`.
+///
+/// Queries are dropped on failures, their tasks time out with `ServFail`.
+pub async fn forward_tcp(upstream: SocketAddr, query: Packet, map: TaskMap) {
+    let id = query.get_id();
+    let exchange = async {
+        let mut stream = TcpStream::connect(upstream).await?;
+        write_packet(&mut stream, query).await?;
+        Packet::parse_stream(&mut stream)
+            .await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))
+    };
+    let answers = match exchange.await {
+        Ok(pkt) if pkt.get_id() == id => stream_answers(pkt),
+        Ok(pkt) => {
+            tracing::debug!(
+                "tcp://{} answered {} to query {}",
+                upstream,
+                pkt.get_id(),
+                id
+            );
+            return;
+        }
+        Err(e) => {
+            tracing::debug!("cannot forward query {} to tcp://{}: {}", id, upstream, e);
+            return;
+        }
+    };
+    map.answer(id, answers);
+}
+
+/// the query answered by the truncated response `pkt`, to be asked again over TCP.
+///
+/// Upstreams echo the cookie and the client subnet of queries, they are asked with again.
+fn retry_query(pkt: &Packet) -> Option<Packet> {
+    let mut query = Packet::new_query(pkt.get_id(), pkt.question()?.clone());
+    let Some(opt_rr) = pkt.additions.iter().find(|rr| rr.get_type() == RRType::Opt) else {
+        return Some(query);
+    };
+    let mut opt = OptBuilder::new().dnssec_ok(opt_rr.dnssec_ok().unwrap_or_default());
+    if let RRData::Opt(options) = opt_rr.clone().into_rdata() {
+        if let Some(cookie) = options.get_option(COOKIE) {
+            opt = opt.option(EdnsOption::Cookie(cookie.to_vec()));
+        }
+        if let Some(subnet) = options
+            .get_option(CLIENT_SUBNET)
+            .and_then(ClientSubnet::parse)
+        {
+            opt = opt.option(EdnsOption::ClientSubnet {
+                address: subnet.address,
+                source_prefix: subnet.prefix,
+                scope_prefix: 0,
+            });
+        }
+    }
+    let _ = query.add_addition(opt.build());
+    Some(query)
+}
+
+/// pass responses received from the upstream of `forward` back to the forwarded queries.
+///
+/// Responses may be as large as any datagram, the payload we advertise is only a hint to upstreams.
+/// Truncated ones are asked for again over TCP, see `forward_tcp`.
 pub async fn listening(forward: Arc<UdpSocket>, map: TaskMap) {
-    let mut buf = BytesMut::from(&[0_u8; 1024][..]);
-    while let Ok(sz) = forward.recv(&mut buf).await {
+    let mut buf = RecvBuffer::new(MAX_RESPONSE_SIZE);
+    while let Ok(sz) = forward.recv(buf.space()).await {
+        let received = buf.take(sz);
         if sz < 20 {
             // malformed packet
             tracing::debug!(
                 "received malformed packet from upstream, length {}, data: {:?}",
                 sz,
-                buf
+                received
             );
             continue;
         }
-        let rs = Packet::parse_packet(buf.clone().into(), 0);
+        let rs = Packet::parse_packet(received, 0);
         match rs {
-            Ok(pkt) => {
+            // the response did not fit in a datagram, the whole of it is asked for over TCP
+            Ok(pkt) if pkt.is_trunc() => {
                 let id = pkt.get_id();
-                let rrs = pkt
-                    .answers
-                    .into_iter()
-                    .map(Answer::Answer)
-                    .chain(pkt.authorities.into_iter().map(Answer::NameServer))
-                    .chain(pkt.additions.into_iter().map(Answer::Additional))
-                    .collect();
-                {
-                    let mut guard = map.lock().await;
-                    if let Some(sender) = guard.remove(&id) {
-                        sender.send(rrs).unwrap();
+                match (forward.peer_addr(), retry_query(&pkt)) {
+                    (Ok(upstream), Some(query)) => {
+                        tracing::debug!("response {} truncated, retrying over TCP", id);
+                        tokio::spawn(forward_tcp(upstream, query, map.clone()));
                     }
+                    _ => map.answer(id, vec![Answer::Error(PacketError::ServFail)]),
                 }
             }
+            Ok(pkt) => {
+                let id = pkt.get_id();
+                map.answer(id, into_answers(pkt));
+            }
             Err(TransactionError {
                 id: Some(id),
                 error,
             }) => {
-                let err = vec![Answer::Error(error)];
-                {
-                    let mut guard = map.lock().await;
-                    if let Some(sender) = guard.remove(&id) {
-                        sender.send(err).unwrap();
-                    }
-                }
+                map.answer(id, vec![Answer::Error(error)]);
             }
             Err(e) => {
                 tracing::debug!("received failure from upstream: {}", e);
@@ -67,3 +371,169 @@ pub async fn listening(forward: Arc<UdpSocket>, map: TaskMap) {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use bytes::{BufMut, BytesMut};
+    use tokio::{net::UdpSocket, sync::oneshot};
+
+    use super::{into_answers, listening, listening_stream, ClientSubnet};
+    use crate::{
+        comm::{Answer, TaskMap},
+        protocol::{
+            EdnsOption, Name, OptBuilder, Packet, PacketError, Question, RRClass, RRData, RRType,
+            RR,
+        },
+    };
+
+    #[tokio::test]
+    async fn test_listening_stream() {
+        let answer = RR::new(
+            Name::try_from("example.com").unwrap(),
+            Duration::from_secs(114),
+            RRClass::Internet,
+            RRData::A(Ipv4Addr::new(19, 19, 81, 0).into()),
+        );
+        let mut resp = Packet::new_plain_answer(114);
+        resp.add_answer(answer);
+        let resp = resp.into_bytes();
+
+        let mut stream = BytesMut::new();
+        stream.put_u16(resp.len() as u16);
+        stream.put(&resp[..]);
+
+        let map = TaskMap::new();
+        let (sender, receiver) = oneshot::channel();
+        let (other, mut other_receiver) = oneshot::channel();
+        assert!(map.try_insert(114, sender).is_ok());
+        assert!(map.try_insert(514, other).is_ok());
+
+        listening_stream(&stream[..], map.clone()).await;
+        let answers = receiver.await.unwrap();
+        assert_eq!(answers.len(), 1);
+        assert!(matches!(answers[0], Answer::Answer(_)));
+        // response of other queries are still pending
+        assert!(other_receiver.try_recv().is_err());
+        assert!(map.contains(514));
+    }
+
+    #[tokio::test]
+    async fn test_listening_large() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let forward = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        forward
+            .connect(upstream.local_addr().unwrap())
+            .await
+            .unwrap();
+        let forward_addr = forward.local_addr().unwrap();
+        let map = TaskMap::new();
+        let (sender, receiver) = oneshot::channel();
+        assert!(map.try_insert(114, sender).is_ok());
+        tokio::spawn(listening(Arc::new(forward), map));
+
+        // larger than the payload advertised, as upstreams may answer
+        let name = Name::try_from("example.com").unwrap();
+        let mut resp = Packet::new_plain_answer(114);
+        for i in 0..=255 {
+            let a = RRData::A(Ipv4Addr::new(192, 0, 2, i).into());
+            resp.add_answer(RR::new(
+                name.clone(),
+                Duration::from_secs(60),
+                RRClass::Internet,
+                a,
+            ));
+        }
+        let resp = resp.into_bytes();
+        assert!(resp.len() > 1232);
+        upstream.send_to(&resp, forward_addr).await.unwrap();
+
+        let answers = receiver.await.unwrap();
+        assert_eq!(answers.len(), 256);
+    }
+
+    #[test]
+    fn test_client_subnet_narrowed() {
+        let subnet = |ip: &str| ClientSubnet::from(ip.parse::<IpAddr>().unwrap());
+        let network = |subnet: ClientSubnet| (subnet.address().to_string(), subnet.prefix());
+        // IPv4 clients of dual-stack sockets are told as IPv4
+        let v4 = subnet("::ffff:192.0.2.77");
+        assert_eq!(network(v4), (String::from("192.0.2.77"), 32));
+        assert_eq!(
+            network(v4.narrowed(24, 56)),
+            (String::from("192.0.2.0"), 24)
+        );
+        assert_eq!(network(v4.narrowed(0, 0)), (String::from("0.0.0.0"), 0));
+        // networks are never widened
+        let v4 = v4.narrowed(20, 56);
+        assert_eq!(
+            network(v4.narrowed(24, 56)),
+            (String::from("192.0.0.0"), 20)
+        );
+
+        let v6 = subnet("2001:db8:1:2:3:4:5:6").narrowed(24, 56);
+        assert_eq!(network(v6), (String::from("2001:db8:1::"), 56));
+    }
+
+    #[test]
+    fn test_client_subnet_of_query() {
+        let client = IpAddr::from([192, 0, 2, 77]);
+        let q = Question::build(
+            Name::try_from("www.example.com").unwrap(),
+            RRType::A,
+            RRClass::Internet,
+        );
+        let with_option = |option: EdnsOption| {
+            let mut query = Packet::new_query(1, q.clone());
+            query
+                .add_addition(OptBuilder::new().option(option).build())
+                .unwrap();
+            query
+        };
+        let of_query = |query: &Packet| {
+            let subnet = ClientSubnet::of_query(query, client);
+            (subnet.address().to_string(), subnet.prefix())
+        };
+
+        let query = Packet::new_query(1, q.clone());
+        assert_eq!(of_query(&query), (String::from("192.0.2.77"), 32));
+
+        let query = with_option(EdnsOption::ClientSubnet {
+            address: IpAddr::from([198, 51, 100, 99]),
+            source_prefix: 20,
+            scope_prefix: 0,
+        });
+        assert_eq!(of_query(&query), (String::from("198.51.96.0"), 20));
+
+        // the address outgrowing the source prefix
+        let query = with_option(EdnsOption::Other(8, vec![0, 1, 24, 0, 198, 51, 100, 0]));
+        assert_eq!(of_query(&query), (String::from("192.0.2.77"), 32));
+        // unknown family
+        let query = with_option(EdnsOption::Other(8, vec![0, 3, 8, 0, 198]));
+        assert_eq!(of_query(&query), (String::from("192.0.2.77"), 32));
+    }
+
+    #[test]
+    fn test_into_answers_rcode() {
+        let name = Name::try_from("nonexistent.example.com").unwrap();
+        let mut resp = Packet::new_failure(514, PacketError::NameError(name.clone()));
+        resp.set_question(Question::build(name.clone(), RRType::A, RRClass::Internet));
+        let resp = Packet::parse_packet(resp.into_bytes(), 0).unwrap();
+        match &into_answers(resp)[..] {
+            [Answer::Error(PacketError::NameError(n))] => assert_eq!(*n, name),
+            answers => panic!("unexpected answers: {:?}", answers),
+        }
+
+        let resp = Packet::new_failure(514, PacketError::FormatError);
+        let resp = Packet::parse_packet(resp.into_bytes(), 0).unwrap();
+        assert!(matches!(
+            into_answers(resp)[..],
+            [Answer::Error(PacketError::ServFail)]
+        ));
+    }
+}
@@ -4,42 +4,104 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::{collections::BTreeMap, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
 
-use bytes::{Bytes, BytesMut};
-use rand::prelude::random;
-pub use stream::{QuicService, TcpService, TlsListener, TlsService};
+pub use acl::Acl;
+use bytes::Bytes;
+use cookie::Cookies;
+pub use forward::{ClientSubnet, Edns};
+pub use policy::TypePolicy;
+pub use recv::RecvBuffer;
+pub use rrl::Rrl;
+use rrl::Verdict;
+pub use stream::{DohService, QuicService, TcpService, TlsListener, TlsService};
+pub(crate) use tasks::TaskMap;
 use tokio::{
     net::UdpSocket,
-    sync::{mpsc, oneshot, Mutex, OnceCell},
-    time::timeout,
+    sync::{mpsc, oneshot, OnceCell, Semaphore},
+    time::{timeout, Instant},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{
+    field::{display, Empty},
+    Instrument, Span,
 };
-use tracing;
 
-use crate::protocol::{Packet, PacketError, Question, TransactionError, RR};
+use crate::{
+    metrics::{Metrics, Transport},
+    protocol::{Op, Packet, PacketError, Question, Rcode, TransactionError, MAX_UDP_SIZE, RR},
+    querylog::{QueryLogger, QueryRecord},
+};
 
+pub(crate) mod acl;
 pub mod client;
+pub(crate) mod coalesce;
+pub(crate) mod cookie;
 pub(crate) mod forward;
+pub(crate) mod policy;
+pub(crate) mod ratelimit;
+pub(crate) mod recv;
+pub(crate) mod rrl;
 pub(crate) mod stream;
+pub(crate) mod tasks;
 
-pub(crate) type TaskMap = Arc<Mutex<BTreeMap<u16, oneshot::Sender<Vec<Answer>>>>>;
+/// UDP transactions in flight at most by default
+const MAX_UDP_IN_FLIGHT: usize = 1024;
+/// queries over UDP larger than this are cut short
+const UDP_RECV_SIZE: usize = 1024;
+/// responses over UDP are this large at most by default, whatever clients take,
+/// the EDNS buffer size recommended by [DNS flag day 2020](https://www.dnsflagday.net/2020/)
+const MAX_UDP_RESPONSE: usize = 1232;
+/// forwarded queries larger than this go over TCP by default,
+/// the EDNS buffer size recommended by [DNS flag day 2020](https://www.dnsflagday.net/2020/)
+const TCP_THRESHOLD: usize = 1232;
+/// how long queries in flight are waited for on shutdown
+pub(crate) const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+/// how long forwarded queries are waited for by default
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(5);
 
 static TIME_OUT: OnceCell<Duration> = OnceCell::const_new();
 
+/// wait `timeout` for upstreams to answer forwarded queries, `ServFail` is answered beyond it.
+///
+/// The timeout is fixed by the first call or the first query forwarded, whichever comes first,
+/// later calls are no-ops. Returns whether `timeout` is applied.
+pub fn set_forward_timeout(timeout: Duration) -> bool {
+    TIME_OUT.set(timeout).is_ok()
+}
+
 async fn get_time_out() -> Duration {
-    *TIME_OUT
-        .get_or_init(|| async { Duration::from_secs(5) })
-        .await
+    *TIME_OUT.get_or_init(|| async { FORWARD_TIMEOUT }).await
+}
+
+/// IPv4 clients of dual-stack sockets come as IPv4-mapped addresses, take them as IPv4
+pub(crate) fn unmapped(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        ip => ip,
+    }
 }
 
 #[derive(Debug)]
 pub enum Task {
-    Query(Question, mpsc::UnboundedSender<Answer>),
+    /// the question, where its answers go, and the client it is asked for,
+    /// none for the queries of our own
+    Query(
+        Question,
+        mpsc::UnboundedSender<Answer>,
+        Option<ClientSubnet>,
+    ),
 }
 
 #[derive(Debug, Clone)]
 pub enum Answer {
     Error(PacketError),
+    /// the records that follow come from a zone this server is authoritative for
+    Authoritative,
     Answer(RR),
     NameServer(RR),
     Additional(RR),
@@ -51,6 +113,31 @@ pub struct UdpService {
     udp: Arc<UdpSocket>,
     // recursive lookup socket, to upstream
     forward: Arc<UdpSocket>,
+    // permits of transactions in flight, queries coming without one are dropped
+    in_flight: Arc<Semaphore>,
+    max_in_flight: usize,
+    // restrictions on query types by client
+    policy: Arc<TypePolicy>,
+    // clients allowed to query
+    acl: Arc<Acl>,
+    // responses are cut to the payload size of clients, and to this
+    max_response: usize,
+    // forwarded queries larger than this are sent over TCP
+    tcp_threshold: usize,
+    // EDNS options sent to upstream
+    edns: Edns,
+    // server cookies given to clients
+    cookies: Cookies,
+    // queries without a valid server cookie are answered by TC
+    cookies_required: bool,
+    // response rate limiting, against reflection attacks
+    rrl: Option<Arc<Rrl>>,
+    // where the queries answered are logged
+    query_log: Option<Arc<dyn QueryLogger>>,
+    // counts the queries answered
+    metrics: Arc<Metrics>,
+    // stops serving once cancelled
+    shutdown: CancellationToken,
 }
 
 impl UdpService {
@@ -58,15 +145,105 @@ impl UdpService {
         UdpService {
             udp: Arc::new(udp),
             forward: Arc::new(forward),
+            in_flight: Arc::new(Semaphore::new(MAX_UDP_IN_FLIGHT)),
+            max_in_flight: MAX_UDP_IN_FLIGHT,
+            policy: Arc::new(TypePolicy::default()),
+            acl: Arc::new(Acl::default()),
+            max_response: MAX_UDP_RESPONSE,
+            tcp_threshold: TCP_THRESHOLD,
+            edns: Edns::default(),
+            cookies: Cookies::new(),
+            cookies_required: false,
+            rrl: None,
+            query_log: None,
+            metrics: Arc::new(Metrics::default()),
+            shutdown: CancellationToken::new(),
         }
     }
 
+    /// answer at most `max` queries at once, dropping the others.
+    ///
+    /// Replies to spoofed sources in a flood would pile up otherwise.
+    pub fn with_max_in_flight(mut self, max: usize) -> Self {
+        self.in_flight = Arc::new(Semaphore::new(max));
+        self.max_in_flight = max;
+        self
+    }
+
+    /// refuse or rate limit queries of some types by `policy`
+    pub fn with_policy(mut self, policy: Arc<TypePolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// only serve clients allowed by `acl`
+    pub fn with_acl(mut self, acl: Arc<Acl>) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    /// send responses of `size` bytes at most, even to clients taking more by EDNS.
+    ///
+    /// Clients without EDNS get 512 bytes at most anyway.
+    pub fn with_max_response(mut self, size: usize) -> Self {
+        self.max_response = size.max(MAX_UDP_SIZE);
+        self
+    }
+
+    /// forward queries longer than `size` bytes over TCP,
+    /// large datagrams are likely fragmented or dropped on the way.
+    pub fn with_tcp_threshold(mut self, size: usize) -> Self {
+        self.tcp_threshold = size;
+        self
+    }
+
+    /// forward queries with the EDNS options of `edns`
+    pub fn with_edns(mut self, edns: Edns) -> Self {
+        self.edns = edns;
+        self
+    }
+
+    /// answer queries without a valid server cookie by an empty response with TC set,
+    /// so that clients retry over TCP until they have one.
+    ///
+    /// Spoofed sources get no more than they sent, rather than the full answer.
+    pub fn with_cookies_required(mut self, required: bool) -> Self {
+        self.cookies_required = required;
+        self
+    }
+
+    /// limit identical responses to a network by `rrl`,
+    /// clients with a valid server cookie are not limited
+    pub fn with_rrl(mut self, rrl: Arc<Rrl>) -> Self {
+        self.rrl = Some(rrl);
+        self
+    }
+
+    /// log the queries answered to `logger`
+    pub fn with_query_log(mut self, logger: Arc<dyn QueryLogger>) -> Self {
+        self.query_log = Some(logger);
+        self
+    }
+
+    /// count the queries answered in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// stop taking queries once `shutdown` is cancelled,
+    /// `run_udp` returns after answering the queries in flight
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
     #[warn(deprecated_in_future)]
     pub async fn run_forward(
         self: Arc<Self>,
         mut recur_receiver: mpsc::UnboundedReceiver<Task>,
     ) -> Result<(), std::io::Error> {
-        let mp: TaskMap = Arc::new(Mutex::new(BTreeMap::new()));
+        let mp = TaskMap::new();
 
         let (buf_sender, mut buf_receiver) = mpsc::channel::<Bytes>(4);
 
@@ -87,47 +264,37 @@ impl UdpService {
         let mut checkers = vec![];
 
         while let Some(task) = recur_receiver.recv().await {
-            // Get a task from main, try generate a unique id for it
-            let id: u16 = random();
-            let Task::Query(query, answer_sender) = task;
+            let Task::Query(query, answer_sender, client) = task;
 
             // sending answer between `listening` handle and `checker`
             let (checker_sender, checker_receiver) = oneshot::channel();
-            let mp = mp.clone();
-            {
-                // insert into map before sending packet, to avoid data racing
-                let mut guard = mp.lock().await;
-                guard.insert(id, checker_sender);
-            }
+            // insert into map before sending packet, to avoid data racing,
+            // under an id unique among the queries in flight
+            let Some(id) = mp.register(checker_sender) else {
+                tracing::warn!("no free id for forwarded queries");
+                let _ = answer_sender.send(Answer::Error(PacketError::ServFail));
+                continue;
+            };
 
             let packet_sender = buf_sender.clone();
             // recursive look up
-            let pkt = Packet::new_query(id, query);
-            let buf = pkt.into_bytes();
-            packet_sender.send(buf).await.unwrap();
-            // check after the packet is sent
-            let checker = tokio::spawn(async move {
-                let answers = timeout(get_time_out().await, checker_receiver).await;
-                if answers.is_err() {
-                    // timeout
-                    answer_sender
-                        .send(Answer::Error(PacketError::ServFail))
-                        .unwrap();
-                    return;
-                }
-                let answers = answers.unwrap();
-                if answers.is_err() {
-                    // sender closed unexpectedly
-                    answer_sender
-                        .send(Answer::Error(PacketError::ServFail))
-                        .unwrap();
-                    return;
-                }
-                let answers = answers.unwrap();
-                for answer in answers.into_iter() {
-                    answer_sender.send(answer).unwrap();
+            let pkt = self.edns.query(id, query, client);
+            if pkt.size() > self.tcp_threshold {
+                // a failure fails the query alone, the others may still go over UDP
+                match self.forward.peer_addr() {
+                    Ok(upstream) => {
+                        tokio::spawn(forward::forward_tcp(upstream, pkt, mp.clone()));
+                    }
+                    Err(e) => {
+                        tracing::warn!("cannot forward query over TCP: {}", e);
+                        mp.answer(id, vec![Answer::Error(PacketError::ServFail)]);
+                    }
                 }
-            });
+            } else {
+                packet_sender.send(pkt.into_bytes()).await.unwrap();
+            }
+            // check after the packet is sent
+            let checker = tokio::spawn(check_answers(checker_receiver, answer_sender));
             checkers.push(checker);
         }
         let (l, f) = tokio::join!(listening, forwarding);
@@ -138,11 +305,28 @@ impl UdpService {
     }
 
     async fn udp_fail(&self, err: TransactionError, client: SocketAddr) {
-        let udp = self.udp.clone();
         let TransactionError { id, error } = err;
         let id = id.unwrap_or(0);
+        self.metrics.count_query(Transport::Udp, error.full_rcode());
         let packet = Packet::new_failure(id, error);
-        udp.send_to(&packet.into_bytes(), client).await.unwrap();
+        self.udp_send(packet, client).await;
+    }
+
+    /// send `resp` to `client`, unless response rate limiting drops or truncates it
+    async fn udp_send(&self, resp: Packet, client: SocketAddr) {
+        let verdict = match &self.rrl {
+            Some(rrl) => rrl.check(client.ip(), &resp),
+            None => Verdict::Send,
+        };
+        let resp = match verdict {
+            Verdict::Send => resp,
+            Verdict::Slip => rrl::slipped(&resp),
+            Verdict::Drop => {
+                tracing::debug!("rate limited response to {}", client);
+                return;
+            }
+        };
+        self.udp.send_to(&resp.into_bytes(), client).await.unwrap();
     }
 
     pub async fn run_udp(
@@ -150,10 +334,15 @@ impl UdpService {
         task_sender: mpsc::UnboundedSender<Task>,
     ) -> Result<(), std::io::Error> {
         let s = self.clone();
-        let mut packet = BytesMut::from(&[0_u8; 1024][..]);
+        let mut buf = RecvBuffer::new(UDP_RECV_SIZE);
         loop {
             // receive packet
-            let (n, client) = s.udp.recv_from(&mut packet).await?;
+            let (n, client) = tokio::select! {
+                _ = s.shutdown.cancelled() => break,
+                received = s.udp.recv_from(buf.space()) => received?,
+            };
+            // owned by the task answering it, the buffer moves on to the next packet
+            let packet = buf.take(n);
 
             // validate packet
             if n < 12 {
@@ -163,11 +352,31 @@ impl UdpService {
                 continue;
             }
 
-            let pkt = match Packet::parse_packet(packet.clone().into(), 0) {
+            if let Err(error) = s.acl.check(client.ip()) {
+                if s.acl.udp_refused() {
+                    let id = Some(u16::from_be_bytes([packet[0], packet[1]]));
+                    let s = s.clone();
+                    tokio::spawn(async move {
+                        s.udp_fail(TransactionError { id, error }, client).await
+                    });
+                }
+                continue;
+            }
+
+            let permit = match s.in_flight.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    tracing::debug!("too many queries in flight, dropped query from {}", client);
+                    continue;
+                }
+            };
+
+            let pkt = match Packet::parse_packet(packet, 0) {
                 Ok(pkt) => pkt,
                 Err(err) => {
                     let s = s.clone();
                     tokio::spawn(async move {
+                        let _permit = permit;
                         tracing::debug!(
                             "received malformed packet from {} with failure {}",
                             client,
@@ -181,43 +390,263 @@ impl UdpService {
             tracing::debug!("received packet from client: {}", client);
 
             let task_sender = task_sender.clone();
-            let query = pkt.question.clone().unwrap();
 
             // spawn a new task to proceed the packet
             let s = s.clone();
-            tokio::spawn(async move {
+            let query_span = QuerySpan::new(Transport::Udp, client, &pkt, s.query_log.clone());
+            let span = query_span.span();
+            let answering = async move {
+                let _permit = permit;
                 let id = pkt.get_id();
-                let rs = transaction(pkt, task_sender).await;
-                if rs.is_err() {
-                    s.udp_fail(rs.unwrap_err(), client).await;
+                let cookie = match s.cookies.check(&pkt, client.ip()) {
+                    Ok(cookie) => cookie,
+                    Err(error) => {
+                        query_span.finish(error.full_rcode(), 0);
+                        s.udp_fail(
+                            TransactionError {
+                                id: Some(id),
+                                error,
+                            },
+                            client,
+                        )
+                        .await;
+                        return;
+                    }
+                };
+                if s.cookies_required && !cookie.is_some_and(|c| c.is_valid()) {
+                    let mut resp = Packet::new_plain_answer(id);
+                    resp.header.set_truncated(true);
+                    resp.header.set_rec_avl(true);
+                    if let Some(q) = pkt.question() {
+                        resp.add_question(q.clone());
+                    }
+                    if let Some(cookie) = cookie {
+                        s.cookies.answer(&mut resp, &cookie, client.ip());
+                    }
+                    s.metrics
+                        .count_query(Transport::Udp, resp.header.full_rcode());
+                    query_span.finish(resp.header.full_rcode(), 0);
+                    s.udp_send(resp, client).await;
                     return;
                 }
-                let answers = rs.unwrap();
-                let mut resp = Packet::new_plain_answer(id);
-                for ans in answers {
-                    match ans {
-                        Answer::Error(rcode) => {
-                            resp = Packet::new_failure(id, rcode);
-                            break;
+                let edns = pkt.has_edns();
+                let max_size = pkt.udp_payload_size().min(s.max_response);
+                let (query, answers) =
+                    match transaction(pkt, client.ip(), &s.policy, task_sender).await {
+                        Ok(answered) => answered,
+                        Err(err) => {
+                            query_span.finish(err.error.full_rcode(), 0);
+                            s.udp_fail(err, client).await;
+                            return;
                         }
-                        Answer::Answer(ans) => resp.add_answer(ans),
-                        Answer::NameServer(ns) => resp.add_authority(ns),
-                        Answer::Additional(ad) => resp.add_addition(ad),
-                    }
+                    };
+                let mut resp = build_response(id, query, answers, edns);
+                if let Some(cookie) = cookie {
+                    s.cookies.answer(&mut resp, &cookie, client.ip());
                 }
-                resp.set_question(query);
-                let packet = resp.into_bytes();
-                let udp = s.udp.clone();
-                udp.send_to(&packet, client).await.unwrap();
+                // oversized answers are trimmed, clients will retry over TCP
+                resp.truncate(max_size);
+                let rcode = resp.header.full_rcode();
+                s.metrics.count_query(Transport::Udp, rcode);
+                query_span.finish(rcode, resp.answer_count());
+                // clients proven by their cookie are not spoofed, nothing to limit
+                if cookie.is_some_and(|c| c.is_valid()) {
+                    s.udp.send_to(&resp.into_bytes(), client).await.unwrap();
+                } else {
+                    s.udp_send(resp, client).await;
+                }
+            };
+            tokio::spawn(answering.instrument(span));
+        }
+        // every permit is back once the queries in flight are answered
+        let max = s.max_in_flight as u32;
+        if timeout(DRAIN_TIMEOUT, s.in_flight.acquire_many(max))
+            .await
+            .is_err()
+        {
+            tracing::warn!("udp server quit with queries in flight");
+        }
+        Ok(())
+    }
+}
+
+/// assemble the response to `query` from the answers collected for it,
+/// failures carry an `OPT` record only if the query did, as `edns` tells.
+///
+/// AA is only set for answers marked with `Answer::Authoritative`,
+/// forwarded and cached answers leave it clear.
+/// RA is always set, failures like a cached NXDOMAIN included.
+/// The first failure decides the RCODE, records given along with it are kept,
+/// like the CNAME chain to a missing name and the SOA of its zone.
+pub(crate) fn build_response(id: u16, query: Question, answers: Vec<Answer>, edns: bool) -> Packet {
+    let mut resp = Packet::new_plain_answer(id);
+    let mut error = None;
+    for ans in answers {
+        match ans {
+            Answer::Error(rcode) => {
+                error.get_or_insert(rcode);
+            }
+            Answer::Authoritative => resp.header.set_authoritative(true),
+            Answer::Answer(ans) => resp.add_answer(ans),
+            Answer::NameServer(ns) => resp.add_authority(ns),
+            Answer::Additional(ad) => {
+                // a second OPT record is dropped, it would make the response malformed
+                let _ = resp.add_addition(ad);
+            }
+        }
+    }
+    if let Some(rcode) = error {
+        let mut failure = match edns {
+            true => Packet::new_edns_failure(id, rcode),
+            false => Packet::new_failure(id, rcode),
+        };
+        failure.header.set_authoritative(resp.is_auth());
+        failure.header.set_rec_avl(true);
+        failure.set_answers(resp.answers);
+        failure.set_authorities(resp.authorities);
+        for ad in resp.additions {
+            let _ = failure.add_addition(ad);
+        }
+        resp = failure;
+    }
+    resp.set_question(query);
+    resp
+}
+
+/// ## `QuerySpan`
+/// The span a query is answered in, from its arrival to its response.
+///
+/// It carries the transport, id, client, name and type of the query,
+/// and records how long the query took and its final RCODE once answered,
+/// so they are logged as the span closes.
+/// With a query log, the query goes into it as well once answered.
+pub(crate) struct QuerySpan {
+    span: Span,
+    start: Instant,
+    log: Option<QueryLog>,
+}
+
+/// what the query log is told about a query, besides its response
+struct QueryLog {
+    logger: Arc<dyn QueryLogger>,
+    transport: Transport,
+    client: IpAddr,
+    question: Option<Question>,
+}
+
+impl QuerySpan {
+    pub(crate) fn new(
+        transport: Transport,
+        client: SocketAddr,
+        packet: &Packet,
+        logger: Option<Arc<dyn QueryLogger>>,
+    ) -> Self {
+        let span = tracing::info_span!(
+            "query",
+            transport = transport.name(),
+            id = packet.get_id(),
+            %client,
+            name = Empty,
+            qtype = Empty,
+            rcode = Empty,
+            elapsed = Empty,
+        );
+        if let Some(q) = packet.question() {
+            span.record("name", &display(q.get_name()));
+            span.record("qtype", &display(q.get_type()));
+        }
+        let log = logger.map(|logger| QueryLog {
+            logger,
+            transport,
+            client: unmapped(client.ip()),
+            question: packet.question().cloned(),
+        });
+        Self {
+            span,
+            start: Instant::now(),
+            log,
+        }
+    }
+
+    pub(crate) fn span(&self) -> Span {
+        self.span.clone()
+    }
+
+    /// record the 12-bit `rcode` the query is answered with, the count of `answers`,
+    /// and the time it took
+    pub(crate) fn finish(&self, rcode: u16, answers: u16) {
+        let elapsed = self.start.elapsed();
+        match u8::try_from(rcode) {
+            Ok(rcode) => self.span.record("rcode", &display(Rcode::from(rcode))),
+            Err(_) => self.span.record("rcode", &rcode),
+        };
+        self.span.record("elapsed", &tracing::field::debug(elapsed));
+        if let Some(log) = &self.log {
+            log.logger.log(QueryRecord {
+                time: SystemTime::now(),
+                client: log.client,
+                transport: log.transport,
+                question: log.question.clone(),
+                rcode,
+                answers,
+                elapsed,
             });
         }
     }
 }
 
+/// only standard queries are answered, other operations are not implemented
+pub(crate) fn check_op(pkt: &Packet) -> Result<(), TransactionError> {
+    match pkt.get_op() {
+        Op::Query => Ok(()),
+        op => Err(TransactionError {
+            id: Some(pkt.get_id()),
+            error: PacketError::NotImpl(op),
+        }),
+    }
+}
+
+/// take the question of a query, which should have exactly one.
+///
+/// RFC 1035 allows more, but no server answers them, so such queries are refused as malformed.
+pub(crate) fn take_question(pkt: &mut Packet) -> Result<Question, TransactionError> {
+    match pkt.questions.len() {
+        1 => Ok(pkt.questions.remove(0)),
+        _ => Err(TransactionError {
+            id: Some(pkt.get_id()),
+            error: PacketError::FormatError,
+        }),
+    }
+}
+
+/// wait for answers of a forwarded query, and pass them back to the task.
+///
+/// If the upstream does not respond in time, or the query is dropped, a `ServFail` is sent.
+pub(crate) async fn check_answers(
+    receiver: oneshot::Receiver<Vec<Answer>>,
+    answer_sender: mpsc::UnboundedSender<Answer>,
+) {
+    let answers = match timeout(get_time_out().await, receiver).await {
+        Ok(Ok(answers)) => answers,
+        // timeout, or sender closed unexpectedly
+        Ok(Err(_)) | Err(_) => {
+            let _ = answer_sender.send(Answer::Error(PacketError::ServFail));
+            return;
+        }
+    };
+    for answer in answers.into_iter() {
+        let _ = answer_sender.send(answer);
+    }
+}
+
+/// the question of `pkt` with its answers, a failure among them,
+/// failing on its own only if the query is not asked at all
 async fn transaction(
-    pkt: Packet,
+    mut pkt: Packet,
+    client: IpAddr,
+    policy: &TypePolicy,
     task_sender: mpsc::UnboundedSender<Task>,
-) -> Result<Vec<Answer>, TransactionError> {
+) -> Result<(Question, Vec<Answer>), TransactionError> {
     let id = Some(pkt.get_id());
     if !pkt.is_query() {
         let err = TransactionError {
@@ -226,22 +655,919 @@ async fn transaction(
         };
         return Err(err);
     }
+    check_op(&pkt)?;
+    policy.check(client, &pkt).await?;
 
-    let query = pkt.question.unwrap();
+    let subnet = ClientSubnet::of_query(&pkt, client);
+    let query = take_question(&mut pkt)?;
     let (a_sender, mut a_recv) = mpsc::unbounded_channel::<Answer>();
-    let task = Task::Query(query, a_sender);
+    let task = Task::Query(query.clone(), a_sender, Some(subnet));
     task_sender.send(task).unwrap();
 
+    // failures are answered by `build_response` too, along with the records given with them
     let mut answers = vec![];
     while let Some(answer) = a_recv.recv().await {
-        match answer {
-            Answer::Error(error) => {
-                let err = TransactionError { id, error };
-                return Err(err);
+        answers.push(answer);
+    }
+
+    Ok((query, answers))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        collections::{BTreeMap, HashSet},
+        fmt::Debug,
+        net::Ipv4Addr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use bytes::{Bytes, BytesMut};
+    use hyper::{Body, Request};
+    use tokio::{
+        io::{AsyncBufReadExt, BufReader},
+        net::{TcpListener, TcpStream, UdpSocket},
+        sync::mpsc,
+    };
+    use tokio_util::sync::CancellationToken;
+    use tracing::{
+        field::{Field, Visit},
+        span::{Attributes, Id, Record},
+        Subscriber,
+    };
+    use tracing_subscriber::{
+        layer::{Context, SubscriberExt},
+        registry::LookupSpan,
+        Layer,
+    };
+
+    use super::{
+        build_response, check_op, stream::write_packet, take_question, Acl, Answer, DohService,
+        Edns, Rrl, Task, TcpService, TypePolicy, UdpService,
+    };
+    use crate::{
+        blocklist::Network,
+        protocol::{
+            EdnsOption, Name, Op, OptBuilder, Packet, PacketError, Question, RRClass, RRData,
+            RRType, Rcode, RR,
+        },
+        querylog::{JsonLinesLogger, QueryLogger},
+    };
+
+    fn answers() -> (Question, Vec<Answer>) {
+        let name = Name::try_from("example.com").unwrap();
+        let q = Question::build(name.clone(), RRType::A, RRClass::Internet);
+        let a = RR::new(
+            name,
+            Duration::from_secs(300),
+            RRClass::Internet,
+            RRData::A(Ipv4Addr::new(192, 0, 2, 1).into()),
+        );
+        (q, vec![Answer::Answer(a)])
+    }
+
+    #[test]
+    fn test_zone_answer_authoritative() {
+        let (q, answers) = answers();
+        let answers = [vec![Answer::Authoritative], answers].concat();
+        let resp = build_response(1, q, answers, false);
+        let resp = Packet::parse_packet(resp.into_bytes(), 0).unwrap();
+        assert!(resp.is_auth());
+        assert!(resp.is_rec_avl());
+        assert_eq!(resp.answer_count(), 1);
+    }
+
+    #[test]
+    fn test_failure_recursion_available() {
+        let (q, _) = answers();
+        let nx = PacketError::NameError(q.get_name());
+        let resp = build_response(1, q, vec![Answer::Error(nx)], false);
+        let resp = Packet::parse_packet(resp.into_bytes(), 0).unwrap();
+        assert_eq!(resp.get_rcode(), Rcode::NameError);
+        assert!(!resp.is_auth());
+        assert!(resp.is_rec_avl());
+    }
+
+    #[test]
+    fn test_failure_keeps_records() {
+        let (q, answers) = answers();
+        let Answer::Answer(record) = answers[0].clone() else {
+            unreachable!();
+        };
+        // a CNAME chain to a missing name, with the authority of its zone
+        let answers = vec![
+            Answer::Answer(record.clone()),
+            Answer::Error(PacketError::NameError(q.get_name())),
+            Answer::NameServer(record),
+        ];
+        let resp = build_response(1, q, answers, false);
+        let resp = Packet::parse_packet(resp.into_bytes(), 0).unwrap();
+        assert_eq!(resp.get_rcode(), Rcode::NameError);
+        assert!(resp.is_rec_avl());
+        assert_eq!(resp.answer_count(), 1);
+        assert_eq!(resp.authority_count(), 1);
+        assert_eq!(resp.question_count(), 1);
+    }
+
+    #[test]
+    fn test_timed_out_edns() {
+        let (q, _) = answers();
+        let timed_out = || vec![Answer::Error(PacketError::TimedOut)];
+        // queries without EDNS get no OPT record, nor the extended error in it
+        let resp = build_response(1, q.clone(), timed_out(), false);
+        let resp = Packet::parse_packet(resp.into_bytes(), 0).unwrap();
+        assert_eq!(resp.get_rcode(), Rcode::ServFail);
+        assert!(!resp.has_edns());
+
+        let resp = build_response(1, q, timed_out(), true);
+        let resp = Packet::parse_packet(resp.into_bytes(), 0).unwrap();
+        assert_eq!(resp.get_rcode(), Rcode::ServFail);
+        let RRData::Opt(opt) = resp.additions[0].clone().into_rdata() else {
+            panic!("not an OPT record");
+        };
+        assert!(opt.get_option(15).is_some());
+    }
+
+    #[test]
+    fn test_forwarded_answer_not_authoritative() {
+        let (q, answers) = answers();
+        let resp = build_response(1, q, answers, false);
+        let resp = Packet::parse_packet(resp.into_bytes(), 0).unwrap();
+        assert!(!resp.is_auth());
+        assert!(resp.is_rec_avl());
+        assert_eq!(resp.answer_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_udp_in_flight() {
+        let serve = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = serve.local_addr().unwrap();
+        let forward = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let service = Arc::new(UdpService::new(serve, forward).with_max_in_flight(1));
+        let (task_sender, mut tasks) = mpsc::unbounded_channel();
+        tokio::spawn(service.run_udp(task_sender));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(addr).await.unwrap();
+        let (q, _) = answers();
+        let query = |id| Packet::new_query(id, q.clone()).into_bytes();
+        for id in 0..3 {
+            client.send(&query(id)).await.unwrap();
+        }
+
+        // the first query is in flight, the others are dropped
+        let Task::Query(_, ans_to, _) = tasks.recv().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(tasks.try_recv().is_err());
+
+        drop(ans_to);
+        let mut buf = [0; 512];
+        let n = client.recv(&mut buf).await.unwrap();
+        let resp = Packet::parse_packet(Bytes::copy_from_slice(&buf[..n]), 0).unwrap();
+        assert_eq!(resp.get_id(), 0);
+
+        // queries are taken again once the transaction is done
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        client.send(&query(3)).await.unwrap();
+        assert!(tasks.recv().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_udp_client_limit() {
+        let serve = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = serve.local_addr().unwrap();
+        let forward = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let policy = Arc::new(TypePolicy::new().with_client_limit(1, 2));
+        let service = Arc::new(UdpService::new(serve, forward).with_policy(policy));
+        let (task_sender, mut tasks) = mpsc::unbounded_channel();
+        tokio::spawn(service.run_udp(task_sender));
+        tokio::spawn(async move {
+            let (_, answers) = answers();
+            while let Some(Task::Query(_, ans_to, _)) = tasks.recv().await {
+                for ans in answers.iter() {
+                    let _ = ans_to.send(ans.clone());
+                }
             }
-            answer => answers.push(answer),
+        });
+
+        async fn ask(client: &UdpSocket, id: u16) -> Packet {
+            let (q, _) = answers();
+            client
+                .send(&Packet::new_query(id, q).into_bytes())
+                .await
+                .unwrap();
+            let mut buf = [0; 512];
+            let n = client.recv(&mut buf).await.unwrap();
+            Packet::parse_packet(Bytes::copy_from_slice(&buf[..n]), 0).unwrap()
+        }
+        let greedy = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        greedy.connect(addr).await.unwrap();
+        for id in 0..2 {
+            assert_eq!(ask(&greedy, id).await.answer_count(), 1);
+        }
+        let resp = ask(&greedy, 2).await;
+        assert_eq!(resp.get_id(), 2);
+        assert_eq!(resp.get_rcode(), Rcode::Refused);
+
+        // another address of the loopback network has its own quota,
+        // only Linux has the addresses besides 127.0.0.1 without configuration
+        if cfg!(target_os = "linux") {
+            let other = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+            other.connect(addr).await.unwrap();
+            assert_eq!(ask(&other, 3).await.answer_count(), 1);
         }
     }
 
-    Ok(answers)
+    #[tokio::test]
+    async fn test_udp_acl() {
+        let stranger = Network::new("127.0.0.1".parse().unwrap(), 32);
+        for refused in [false, true] {
+            let serve = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let addr = serve.local_addr().unwrap();
+            let forward = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let acl = Acl::allow_by_default()
+                .with_denied(stranger)
+                .with_udp_refused(refused);
+            let service = UdpService::new(serve, forward).with_acl(Arc::new(acl));
+            let (task_sender, mut tasks) = mpsc::unbounded_channel();
+            tokio::spawn(Arc::new(service).run_udp(task_sender));
+
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.connect(addr).await.unwrap();
+            let (q, _) = answers();
+            client
+                .send(&Packet::new_query(7, q).into_bytes())
+                .await
+                .unwrap();
+            let mut buf = [0; 512];
+            let received = tokio::time::timeout(Duration::from_millis(100), client.recv(&mut buf));
+            match received.await {
+                Ok(n) => {
+                    assert!(refused, "denied queries are dropped by default");
+                    let n = n.unwrap();
+                    let resp = Packet::parse_packet(Bytes::copy_from_slice(&buf[..n]), 0).unwrap();
+                    assert_eq!(resp.get_id(), 7);
+                    assert_eq!(resp.get_rcode(), Rcode::Refused);
+                }
+                Err(_) => assert!(!refused, "denied queries should be refused"),
+            }
+            assert!(tasks.try_recv().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_cookies_required() {
+        let serve = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = serve.local_addr().unwrap();
+        let forward = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let service = UdpService::new(serve, forward).with_cookies_required(true);
+        let (task_sender, mut tasks) = mpsc::unbounded_channel();
+        tokio::spawn(Arc::new(service).run_udp(task_sender));
+        tokio::spawn(async move {
+            let (_, answers) = answers();
+            while let Some(Task::Query(_, ans_to, _)) = tasks.recv().await {
+                for ans in answers.iter() {
+                    let _ = ans_to.send(ans.clone());
+                }
+            }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(addr).await.unwrap();
+        let ask = |cookie: Vec<u8>| {
+            let client = &client;
+            async move {
+                let (q, _) = answers();
+                let mut query = Packet::new_query(5, q);
+                let opt = OptBuilder::new().option(EdnsOption::Cookie(cookie)).build();
+                query.add_addition(opt).unwrap();
+                client.send(&query.into_bytes()).await.unwrap();
+                let mut buf = [0; 512];
+                let n = client.recv(&mut buf).await.unwrap();
+                let resp = Packet::parse_packet(Bytes::copy_from_slice(&buf[..n]), 0).unwrap();
+                let opt = resp.additions.last().cloned().unwrap().into_rdata();
+                let RRData::Opt(opt) = opt else {
+                    panic!("no OPT record in {:?}", resp);
+                };
+                (resp, opt.get_option(10).unwrap().to_vec())
+            }
+        };
+
+        // the client cookie alone gets a server cookie, and TC set
+        let client_cookie = vec![0x5a; 8];
+        let (resp, cookie) = ask(client_cookie.clone()).await;
+        assert!(resp.is_trunc());
+        assert_eq!(resp.answer_count(), 0);
+        assert_eq!(resp.question_count(), 1);
+        assert_eq!(cookie[..8], client_cookie[..]);
+
+        // the server cookie given validates, the query is answered
+        let (resp, echoed) = ask(cookie.clone()).await;
+        assert!(!resp.is_trunc());
+        assert_eq!(resp.answer_count(), 1);
+        assert_eq!(echoed, cookie);
+
+        // a forged one does not
+        let mut forged = cookie;
+        forged[15] ^= 1;
+        let (resp, _) = ask(forged).await;
+        assert!(resp.is_trunc());
+    }
+
+    #[tokio::test]
+    async fn test_udp_payload_size() {
+        let serve = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = serve.local_addr().unwrap();
+        let forward = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let service = UdpService::new(serve, forward).with_max_response(1024);
+        let (task_sender, mut tasks) = mpsc::unbounded_channel();
+        tokio::spawn(Arc::new(service).run_udp(task_sender));
+        // 100 answers, about 1.6 KB
+        tokio::spawn(async move {
+            let (_, answers) = answers();
+            while let Some(Task::Query(_, ans_to, _)) = tasks.recv().await {
+                for _ in 0..100 {
+                    let _ = ans_to.send(answers[0].clone());
+                }
+            }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(addr).await.unwrap();
+        // no EDNS, the payload size clients advertise, and beyond what we send
+        for (payload, max_size) in [(None, 512), (Some(800), 800), (Some(4096), 1024)] {
+            let (q, _) = answers();
+            let mut query = Packet::new_query(9, q);
+            if let Some(payload) = payload {
+                let opt = OptBuilder::new()
+                    .udp_size(payload)
+                    .option(EdnsOption::Cookie(vec![0xc0; 8]));
+                query.add_addition(opt.build()).unwrap();
+            }
+            client.send(&query.into_bytes()).await.unwrap();
+            let mut buf = [0; 4096];
+            let n = client.recv(&mut buf).await.unwrap();
+            assert!(n <= max_size, "{} bytes beyond {}", n, max_size);
+            assert!(
+                n > max_size - 16,
+                "{} bytes left room below {}",
+                n,
+                max_size
+            );
+            let resp = Packet::parse_packet(Bytes::copy_from_slice(&buf[..n]), 0).unwrap();
+            assert!(resp.is_trunc());
+            // EDNS clients get their OPT record back, with the cookie
+            assert_eq!(resp.has_edns(), payload.is_some());
+            if payload.is_some() {
+                let RRData::Opt(opt) = resp.additions[0].clone().into_rdata() else {
+                    panic!("not an OPT record");
+                };
+                assert_eq!(opt.get_option(10).unwrap()[..8], [0xc0; 8]);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_timed_out() {
+        let serve = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = serve.local_addr().unwrap();
+        let forward = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let service = UdpService::new(serve, forward);
+        let (task_sender, mut tasks) = mpsc::unbounded_channel();
+        tokio::spawn(Arc::new(service).run_udp(task_sender));
+        // the transaction layer runs out of the budget of every query
+        tokio::spawn(async move {
+            while let Some(Task::Query(_, ans_to, _)) = tasks.recv().await {
+                let _ = ans_to.send(Answer::Error(PacketError::TimedOut));
+            }
+        });
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(addr).await.unwrap();
+        for edns in [false, true] {
+            let (q, _) = answers();
+            let mut query = Packet::new_query(3, q);
+            if edns {
+                query
+                    .add_addition(OptBuilder::new().udp_size(1232).build())
+                    .unwrap();
+            }
+            client.send(&query.into_bytes()).await.unwrap();
+            let mut buf = [0; 512];
+            let n = client.recv(&mut buf).await.unwrap();
+            let resp = Packet::parse_packet(Bytes::copy_from_slice(&buf[..n]), 0).unwrap();
+            assert_eq!(resp.get_rcode(), Rcode::ServFail);
+            assert_eq!(resp.question_count(), 1);
+            // only EDNS queries get an OPT record, telling the extended error
+            assert_eq!(resp.has_edns(), edns);
+            if edns {
+                let RRData::Opt(opt) = resp.additions[0].clone().into_rdata() else {
+                    panic!("not an OPT record");
+                };
+                assert_eq!(opt.get_option(15), Some(&b"\x00\x16query timed out"[..]));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_rrl() {
+        let serve = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = serve.local_addr().unwrap();
+        let forward = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let rrl = Arc::new(Rrl::new(2).with_slip(2));
+        let service = UdpService::new(serve, forward).with_rrl(rrl);
+        let (task_sender, mut tasks) = mpsc::unbounded_channel();
+        tokio::spawn(Arc::new(service).run_udp(task_sender));
+        tokio::spawn(async move {
+            let (_, answers) = answers();
+            while let Some(Task::Query(_, ans_to, _)) = tasks.recv().await {
+                for ans in answers.iter() {
+                    let _ = ans_to.send(ans.clone());
+                }
+            }
+        });
+
+        // a flood of identical queries from a network, each from a port of its own
+        let mut received = vec![];
+        for id in 0..6 {
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.connect(addr).await.unwrap();
+            let (q, _) = answers();
+            let query = Packet::new_query(id, q).into_bytes();
+            client.send(&query).await.unwrap();
+            let mut buf = [0; 512];
+            let resp = tokio::time::timeout(Duration::from_millis(100), client.recv(&mut buf));
+            received.push(resp.await.ok().map(|n| {
+                let n = n.unwrap();
+                Packet::parse_packet(Bytes::copy_from_slice(&buf[..n]), 0).unwrap()
+            }));
+        }
+        // the rate is answered in full, then every other response is dropped or slips
+        for resp in &received[..2] {
+            let resp = resp.as_ref().unwrap();
+            assert!(!resp.is_trunc());
+            assert_eq!(resp.answer_count(), 1);
+        }
+        for (i, resp) in received[2..].iter().enumerate() {
+            match resp {
+                Some(resp) => {
+                    assert_eq!(i % 2, 1, "response {} should be dropped", i + 2);
+                    assert!(resp.is_trunc());
+                    assert_eq!(resp.answer_count(), 0);
+                    assert_eq!(resp.question_count(), 1);
+                }
+                None => assert_eq!(i % 2, 0, "response {} should slip", i + 2),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_shutdown() {
+        let serve = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let forward = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let shutdown = CancellationToken::new();
+        let service = UdpService::new(serve, forward).with_shutdown(shutdown.clone());
+        let (task_sender, _tasks) = mpsc::unbounded_channel();
+        let running = tokio::spawn(Arc::new(service).run_udp(task_sender));
+
+        shutdown.cancel();
+        let returned = tokio::time::timeout(Duration::from_secs(1), running)
+            .await
+            .expect("the service should return on shutdown");
+        assert!(returned.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_forward_large_over_tcp() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+        let tcp = TcpListener::bind(addr).await.unwrap();
+        let forward = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        forward.connect(addr).await.unwrap();
+
+        let (q, answers) = answers();
+        // the query for `example.com` just fits
+        let edns = Edns::new();
+        let threshold = edns.query(0, q.clone(), None).size();
+        let serve = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let service = UdpService::new(serve, forward)
+            .with_tcp_threshold(threshold)
+            .with_edns(edns);
+        let service = Arc::new(service);
+        let (tasks, rec) = mpsc::unbounded_channel();
+        tokio::spawn(service.run_forward(rec));
+
+        let (ans_to, _ans_from) = mpsc::unbounded_channel();
+        tasks.send(Task::Query(q, ans_to, None)).unwrap();
+        let mut buf = [0; 512];
+        let n = upstream.recv(&mut buf).await.unwrap();
+        assert_eq!(n, threshold);
+
+        let name = Name::try_from("www.example.com").unwrap();
+        let large = Question::build(name, RRType::A, RRClass::Internet);
+        let (ans_to, mut ans_from) = mpsc::unbounded_channel();
+        tasks
+            .send(Task::Query(large.clone(), ans_to, None))
+            .unwrap();
+        let (mut stream, _) = tcp.accept().await.unwrap();
+        let query = Packet::parse_stream(&mut stream).await.unwrap();
+        assert_eq!(query.questions[0].get_name(), large.get_name());
+        let resp = build_response(query.get_id(), large, answers, false);
+        write_packet(&mut stream, resp).await.unwrap();
+
+        assert!(matches!(ans_from.recv().await, Some(Answer::Answer(_))));
+        assert!(ans_from.recv().await.is_none());
+        // nothing else went over UDP
+        assert!(upstream.try_recv(&mut buf).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_truncated_retried_over_tcp() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = upstream.local_addr().unwrap();
+        let tcp = TcpListener::bind(addr).await.unwrap();
+        let forward = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        forward.connect(addr).await.unwrap();
+        let serve = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let service = Arc::new(UdpService::new(serve, forward));
+        let (tasks, rec) = mpsc::unbounded_channel();
+        tokio::spawn(service.run_forward(rec));
+
+        let (q, answers) = answers();
+        let (ans_to, mut ans_from) = mpsc::unbounded_channel();
+        tasks.send(Task::Query(q.clone(), ans_to, None)).unwrap();
+        let mut buf = [0; 512];
+        let (n, from) = upstream.recv_from(&mut buf).await.unwrap();
+        let query = Packet::parse_packet(Bytes::copy_from_slice(&buf[..n]), 0).unwrap();
+        let RRData::Opt(opt) = query.additions[0].clone().into_rdata() else {
+            panic!("not an OPT record");
+        };
+        let cookie = opt.get_option(10).unwrap().to_vec();
+
+        // too large for a datagram, the records are left out
+        let mut truncated = build_response(query.get_id(), q.clone(), vec![], false);
+        truncated.header.set_truncated(true);
+        let opt = OptBuilder::new().option(EdnsOption::Cookie(cookie.clone()));
+        truncated.add_addition(opt.build()).unwrap();
+        upstream
+            .send_to(&truncated.into_bytes(), from)
+            .await
+            .unwrap();
+
+        let (mut stream, _) = tcp.accept().await.unwrap();
+        let retried = Packet::parse_stream(&mut stream).await.unwrap();
+        assert_eq!(retried.get_id(), query.get_id());
+        assert_eq!(retried.questions[0].get_name(), q.get_name());
+        let RRData::Opt(opt) = retried.additions[0].clone().into_rdata() else {
+            panic!("not an OPT record");
+        };
+        assert_eq!(opt.get_option(10).unwrap(), &cookie[..]);
+        let resp = build_response(retried.get_id(), q, answers, false);
+        write_packet(&mut stream, resp).await.unwrap();
+
+        // the full answer, not the truncated one
+        assert!(matches!(ans_from.recv().await, Some(Answer::Answer(_))));
+        assert!(ans_from.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_forward_over_tcp_without_upstream() {
+        // the forward socket is not connected, so there is no upstream to connect to
+        let forward = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let serve = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let service = UdpService::new(serve, forward).with_tcp_threshold(0);
+        let (tasks, rec) = mpsc::unbounded_channel();
+        tokio::spawn(Arc::new(service).run_forward(rec));
+
+        // each query fails alone, the service goes on
+        for _ in 0..2 {
+            let (q, _) = answers();
+            let (ans_to, mut ans_from) = mpsc::unbounded_channel();
+            tasks.send(Task::Query(q, ans_to, None)).unwrap();
+            assert!(matches!(
+                ans_from.recv().await,
+                Some(Answer::Error(PacketError::ServFail))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_forwards() {
+        const QUERIES: u16 = 256;
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let forward = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        forward
+            .connect(upstream.local_addr().unwrap())
+            .await
+            .unwrap();
+        let serve = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let service = Arc::new(UdpService::new(serve, forward));
+        let (tasks, rec) = mpsc::unbounded_channel();
+        tokio::spawn(service.run_forward(rec));
+
+        // answer `{i}.example.com` by 10.0.0.0 + i, once all queries arrived, in reverse
+        tokio::spawn(async move {
+            let mut queries = vec![];
+            let mut buf = [0; 512];
+            while queries.len() < QUERIES as usize {
+                let (n, from) = upstream.recv_from(&mut buf).await.unwrap();
+                let query = Packet::parse_packet(Bytes::copy_from_slice(&buf[..n]), 0).unwrap();
+                queries.push((query, from));
+            }
+            let ids: HashSet<u16> = queries.iter().map(|(q, _)| q.get_id()).collect();
+            assert_eq!(ids.len(), QUERIES as usize);
+            for (query, from) in queries.into_iter().rev() {
+                let q = query.questions[0].clone();
+                let label = q.get_name().to_string();
+                let i: u32 = label.split('.').next().unwrap().parse().unwrap();
+                let a = RR::new(
+                    q.get_name(),
+                    Duration::from_secs(300),
+                    RRClass::Internet,
+                    RRData::A(Ipv4Addr::from(0x0a00_0000 + i).into()),
+                );
+                let resp = build_response(query.get_id(), q, vec![Answer::Answer(a)], false);
+                upstream.send_to(&resp.into_bytes(), from).await.unwrap();
+            }
+        });
+
+        let forwards = (0..QUERIES).map(|i| {
+            let name = Name::try_from(format!("{}.example.com", i).as_str()).unwrap();
+            let q = Question::build(name, RRType::A, RRClass::Internet);
+            let (ans_to, mut ans_from) = mpsc::unbounded_channel();
+            tasks.send(Task::Query(q, ans_to, None)).unwrap();
+            async move {
+                let Some(Answer::Answer(rr)) = ans_from.recv().await else {
+                    panic!("no answer to query {}", i);
+                };
+                let RRData::A(a) = rr.into_rdata() else {
+                    panic!("not an A record");
+                };
+                assert_eq!(a.addr(), Ipv4Addr::from(0x0a00_0000 + i as u32));
+                assert!(ans_from.recv().await.is_none());
+            }
+        });
+        futures::future::join_all(forwards).await;
+    }
+
+    #[tokio::test]
+    async fn test_forward_own_opt() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let forward = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        forward
+            .connect(upstream.local_addr().unwrap())
+            .await
+            .unwrap();
+        let serve = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = serve.local_addr().unwrap();
+        let service = Arc::new(UdpService::new(serve, forward));
+        // no transaction layer in between, queries are forwarded right away
+        let (tasks, rec) = mpsc::unbounded_channel();
+        tokio::spawn(service.clone().run_udp(tasks));
+        tokio::spawn(service.run_forward(rec));
+
+        let client_cookie = vec![0xc0; 8];
+        let (q, _) = answers();
+        let mut query = Packet::new_query(1, q);
+        let opt = OptBuilder::new()
+            .udp_size(4096)
+            .dnssec_ok(true)
+            .option(EdnsOption::Cookie(client_cookie.clone()))
+            .build();
+        query.add_addition(opt).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.send_to(&query.into_bytes(), addr).await.unwrap();
+
+        let mut buf = [0; 512];
+        let n = upstream.recv(&mut buf).await.unwrap();
+        let forwarded = Packet::parse_packet(Bytes::copy_from_slice(&buf[..n]), 0).unwrap();
+        assert_eq!(forwarded.additions.len(), 1);
+        let opt = forwarded.additions[0].clone();
+        assert_eq!(opt.udp_size(), Some(1232));
+        assert_eq!(opt.dnssec_ok(), Some(false));
+        let RRData::Opt(opt) = opt.into_rdata() else {
+            panic!("not an OPT record");
+        };
+        let cookie = opt.get_option(10).unwrap();
+        assert_eq!(cookie.len(), 8);
+        assert_ne!(cookie, &client_cookie[..]);
+    }
+
+    #[test]
+    fn test_check_op() {
+        let (q, _) = answers();
+        let query = Packet::new_query(1, q).into_bytes();
+        assert!(check_op(&Packet::parse_packet(query.clone(), 0).unwrap()).is_ok());
+
+        let mut update = BytesMut::from(&query[..]);
+        update[2] |= 5 << 3;
+        let update = Packet::parse_packet(update.freeze(), 0).unwrap();
+        let err = check_op(&update).unwrap_err();
+        assert!(matches!(err.error, PacketError::NotImpl(Op::Update)));
+        let resp = Packet::new_failure(1, err.error);
+        assert_eq!(resp.get_rcode(), Rcode::NotImpl);
+    }
+
+    #[test]
+    fn test_take_question() {
+        let (q, _) = answers();
+        let mut query = Packet::new_query(1, q.clone());
+        let taken = take_question(&mut query).unwrap();
+        assert_eq!(taken.get_name(), q.get_name());
+        assert!(query.questions.is_empty());
+
+        // parsed fine, but only single questions are answered
+        query.add_question(q.clone());
+        query.add_question(q);
+        let err = take_question(&mut query).unwrap_err();
+        assert!(matches!(err.error, PacketError::FormatError));
+        assert_eq!(err.id, Some(1));
+    }
+
+    /// fields of a span, by their names
+    #[derive(Default)]
+    struct Fields(BTreeMap<&'static str, String>);
+
+    impl Visit for Fields {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0.insert(field.name(), format!("{:?}", value));
+        }
+    }
+
+    /// collects the fields of spans as they close
+    #[derive(Clone, Default)]
+    struct Closed(Arc<Mutex<Vec<BTreeMap<&'static str, String>>>>);
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for Closed {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+            let mut fields = Fields::default();
+            attrs.record(&mut fields);
+            ctx.span(id).unwrap().extensions_mut().insert(fields);
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+            let span = ctx.span(id).unwrap();
+            let mut extensions = span.extensions_mut();
+            values.record(extensions.get_mut::<Fields>().unwrap());
+        }
+
+        fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+            let span = ctx.span(&id).unwrap();
+            let fields = span.extensions_mut().remove::<Fields>().unwrap();
+            self.0.lock().unwrap().push(fields.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_log() {
+        let (task_sender, mut tasks) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (_, answers) = answers();
+            while let Some(Task::Query(_, ans_to, _)) = tasks.recv().await {
+                for ans in answers.iter() {
+                    let _ = ans_to.send(ans.clone());
+                }
+            }
+        });
+        let (writer, reader) = tokio::io::duplex(4096);
+        let (logger, _) = JsonLinesLogger::spawn(writer);
+        let logger: Arc<dyn QueryLogger> = Arc::new(logger);
+        let mut lines = BufReader::new(reader).lines();
+        let (q, _) = answers();
+
+        let serve = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = serve.local_addr().unwrap();
+        let forward = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let service = UdpService::new(serve, forward).with_query_log(logger.clone());
+        tokio::spawn(Arc::new(service).run_udp(task_sender.clone()));
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        udp.connect(addr).await.unwrap();
+        udp.send(&Packet::new_query(11, q.clone()).into_bytes())
+            .await
+            .unwrap();
+        udp.recv(&mut [0; 512]).await.unwrap();
+        let udp_line = tokio::time::timeout(Duration::from_secs(1), lines.next_line());
+        let udp_line = udp_line.await.unwrap().unwrap().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = TcpService::new(listener, task_sender.clone(), 16);
+        tokio::spawn(service.with_query_log(logger.clone()).run());
+        let mut tcp = TcpStream::connect(addr).await.unwrap();
+        write_packet(&mut tcp, Packet::new_query(12, q.clone()))
+            .await
+            .unwrap();
+        Packet::parse_stream(&mut tcp).await.unwrap();
+        let tcp_line = tokio::time::timeout(Duration::from_secs(1), lines.next_line());
+        let tcp_line = tcp_line.await.unwrap().unwrap().unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let service = DohService::new(listener, task_sender).with_query_log(logger);
+        tokio::spawn(service.run());
+        let doh = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) = hyper::client::conn::handshake(doh).await.unwrap();
+        tokio::spawn(conn);
+        let req = Request::post("/dns-query")
+            .header("content-type", "application/dns-message")
+            .body(Body::from(Packet::new_query(13, q.clone()).into_bytes()))
+            .unwrap();
+        sender.send_request(req).await.unwrap();
+        let doh_line = tokio::time::timeout(Duration::from_secs(1), lines.next_line());
+        let doh_line = doh_line.await.unwrap().unwrap().unwrap();
+
+        // one line for each query
+        let more = tokio::time::timeout(Duration::from_millis(100), lines.next_line());
+        assert!(more.await.is_err());
+        let queried = [(udp_line, "udp"), (tcp_line, "tcp"), (doh_line, "doh")];
+        for (line, transport) in queried {
+            let json: serde_json::Value = serde_json::from_str(&line).unwrap();
+            assert!(json["time"].as_f64().unwrap() > 0.0);
+            assert_eq!(json["client"], "127.0.0.1");
+            assert_eq!(json["transport"], transport);
+            assert_eq!(json["name"], "example.com.");
+            assert_eq!(json["type"], "A");
+            assert_eq!(json["class"], "IN");
+            assert_eq!(json["rcode"], "NOERROR");
+            assert_eq!(json["answers"], 1);
+            assert!(json["latency_ms"].as_f64().unwrap() >= 0.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_span() {
+        let closed = Closed::default();
+        let subscriber = tracing_subscriber::registry().with(closed.clone());
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        let (task_sender, mut tasks) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (_, answers) = answers();
+            while let Some(Task::Query(_, ans_to, _)) = tasks.recv().await {
+                for ans in answers.iter() {
+                    let _ = ans_to.send(ans.clone());
+                }
+            }
+        });
+        let (q, _) = answers();
+
+        let serve = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = serve.local_addr().unwrap();
+        let forward = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let service = Arc::new(UdpService::new(serve, forward));
+        tokio::spawn(service.run_udp(task_sender.clone()));
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        udp.connect(addr).await.unwrap();
+        udp.send(&Packet::new_query(11, q.clone()).into_bytes())
+            .await
+            .unwrap();
+        udp.recv(&mut [0; 512]).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(TcpService::new(listener, task_sender.clone(), 16).run());
+        let mut tcp = TcpStream::connect(addr).await.unwrap();
+        write_packet(&mut tcp, Packet::new_query(12, q.clone()))
+            .await
+            .unwrap();
+        Packet::parse_stream(&mut tcp).await.unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(DohService::new(listener, task_sender).run());
+        let doh = TcpStream::connect(addr).await.unwrap();
+        let doh_client = doh.local_addr().unwrap();
+        let (mut sender, conn) = hyper::client::conn::handshake(doh).await.unwrap();
+        tokio::spawn(conn);
+        let req = Request::post("/dns-query")
+            .header("content-type", "application/dns-message")
+            .body(Body::from(Packet::new_query(13, q).into_bytes()))
+            .unwrap();
+        hyper::body::to_bytes(sender.send_request(req).await.unwrap().into_body())
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let closed = closed.0.lock().unwrap();
+        let spans: Vec<_> = closed.iter().filter(|s| s.contains_key("qtype")).collect();
+        assert_eq!(spans.len(), 3, "{:?}", closed);
+        for (span, transport, id, client) in [
+            (spans[0], "udp", "11", udp.local_addr().unwrap()),
+            (spans[1], "tcp", "12", tcp.local_addr().unwrap()),
+            (spans[2], "doh", "13", doh_client),
+        ] {
+            assert_eq!(span["transport"], transport);
+            assert_eq!(span["id"], id);
+            assert_eq!(span["client"], client.to_string());
+            assert_eq!(span["name"], "example.com.");
+            assert_eq!(span["qtype"], "A");
+            assert_eq!(span["rcode"], "NOERROR");
+            assert!(span.contains_key("elapsed"));
+        }
+    }
 }
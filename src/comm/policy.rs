@@ -0,0 +1,170 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{collections::HashMap, net::IpAddr, time::Duration};
+
+use super::{ratelimit::RateLimiter, unmapped};
+use crate::{
+    blocklist::Network,
+    protocol::{Packet, PacketError, RRType, TransactionError},
+};
+
+/// ## `TypePolicy`
+/// Restricts who may query expensive types, like `AXFR` and `ANY`.
+///
+/// A type may be open to some clients only, and may be rate limited per client.
+/// Queries of all types may be rate limited per client as well.
+/// Queries denied by the policy are answered by REFUSED.
+#[derive(Default)]
+pub struct TypePolicy {
+    allowed: HashMap<RRType, Vec<Network>>,
+    limits: HashMap<RRType, RateLimiter<IpAddr>>,
+    client_limit: Option<RateLimiter<IpAddr>>,
+}
+
+impl TypePolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// only clients in `networks` may query `ty`, e.g. zone transfers by secondaries
+    pub fn with_allowed(mut self, ty: RRType, networks: Vec<Network>) -> Self {
+        self.allowed.insert(ty, networks);
+        self
+    }
+
+    /// each client may query `ty` `rate` times per second, in bursts of `burst` at most
+    pub fn with_limit(mut self, ty: RRType, rate: u32, burst: u32) -> Self {
+        // over the limit, queries are refused at once instead of waiting
+        let limiter = RateLimiter::new(rate, burst, Duration::ZERO);
+        self.limits.insert(ty, limiter);
+        self
+    }
+
+    /// each client may query `rate` times per second whatever the type,
+    /// in bursts of `burst` at most
+    pub fn with_client_limit(mut self, rate: u32, burst: u32) -> Self {
+        self.client_limit = Some(RateLimiter::new(rate, burst, Duration::ZERO));
+        self
+    }
+
+    /// check whether `client` may send the query in `pkt`
+    pub(crate) async fn check(&self, client: IpAddr, pkt: &Packet) -> Result<(), TransactionError> {
+        let query = match pkt.question() {
+            Some(query) => query,
+            None => return Ok(()),
+        };
+        let ty = query.get_type();
+        let client = unmapped(client);
+
+        let allowed = self
+            .allowed
+            .get(&ty)
+            .is_none_or(|networks| networks.iter().any(|net| net.contains(client)));
+        let allowed = match &self.client_limit {
+            Some(limiter) if allowed => limiter.acquire(&client).await,
+            _ => allowed,
+        };
+        let allowed = match self.limits.get(&ty) {
+            Some(limiter) if allowed => limiter.acquire(&client).await,
+            _ => allowed,
+        };
+        if allowed {
+            return Ok(());
+        }
+        tracing::debug!(
+            "refused {} query of {} from {}",
+            ty,
+            query.get_name(),
+            client
+        );
+        Err(TransactionError {
+            id: Some(pkt.get_id()),
+            error: PacketError::Prohibited(query.get_name()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use tokio::time;
+
+    use super::TypePolicy;
+    use crate::{
+        blocklist::Network,
+        protocol::{Name, Packet, PacketError, Question, RRClass, RRType, Rcode},
+    };
+
+    fn query(ty: RRType) -> Packet {
+        let name = Name::try_from("example.com").unwrap();
+        let q = Question::build(name, ty, RRClass::Internet);
+        Packet::parse_packet(Packet::new_query(1, q).into_bytes(), 0).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_allowed() {
+        let secondaries = vec![Network::new("192.0.2.0".parse().unwrap(), 24)];
+        let policy = TypePolicy::new().with_allowed(RRType::Axfr, secondaries);
+        let axfr = query(RRType::Axfr);
+
+        let stranger: IpAddr = "198.51.100.1".parse().unwrap();
+        let err = policy.check(stranger, &axfr).await.unwrap_err();
+        assert!(matches!(err.error, PacketError::Prohibited(_)));
+        assert_eq!(
+            Packet::new_failure(1, err.error).get_rcode(),
+            Rcode::Refused
+        );
+
+        let secondary: IpAddr = "192.0.2.53".parse().unwrap();
+        assert!(policy.check(secondary, &axfr).await.is_ok());
+        let mapped: IpAddr = "::ffff:192.0.2.53".parse().unwrap();
+        assert!(policy.check(mapped, &axfr).await.is_ok());
+
+        // other types are not restricted
+        assert!(policy.check(stranger, &query(RRType::A)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_limit() {
+        time::pause();
+        let policy = TypePolicy::new().with_limit(RRType::Any, 1, 2);
+        let any = query(RRType::Any);
+        let client: IpAddr = "198.51.100.1".parse().unwrap();
+
+        assert!(policy.check(client, &any).await.is_ok());
+        assert!(policy.check(client, &any).await.is_ok());
+        assert!(policy.check(client, &any).await.is_err());
+        // other clients and types have their own quotas
+        let other: IpAddr = "198.51.100.2".parse().unwrap();
+        assert!(policy.check(other, &any).await.is_ok());
+        assert!(policy.check(client, &query(RRType::A)).await.is_ok());
+
+        time::advance(time::Duration::from_secs(1)).await;
+        assert!(policy.check(client, &any).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_client_limit() {
+        time::pause();
+        let policy = TypePolicy::new().with_client_limit(1, 2);
+        let client: IpAddr = "198.51.100.1".parse().unwrap();
+
+        // the quota is shared by all types
+        assert!(policy.check(client, &query(RRType::A)).await.is_ok());
+        assert!(policy.check(client, &query(RRType::Aaaa)).await.is_ok());
+        assert!(policy.check(client, &query(RRType::Mx)).await.is_err());
+        // also by the IPv4-mapped address of the client
+        let mapped: IpAddr = "::ffff:198.51.100.1".parse().unwrap();
+        assert!(policy.check(mapped, &query(RRType::A)).await.is_err());
+        let other: IpAddr = "198.51.100.2".parse().unwrap();
+        assert!(policy.check(other, &query(RRType::A)).await.is_ok());
+
+        time::advance(time::Duration::from_secs(1)).await;
+        assert!(policy.check(client, &query(RRType::A)).await.is_ok());
+    }
+}
@@ -0,0 +1,167 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use moka::sync::Cache;
+use tokio::time::Instant;
+
+/// beyond this many buckets, the least used ones are dropped
+const MAX_BUCKETS: u64 = 4096;
+
+struct Bucket {
+    /// negative when tokens are promised to waiting callers
+    tokens: f64,
+    last: Instant,
+}
+
+/// `RateLimiter` keeps a token bucket for each key, e.g. each upstream.
+///
+/// A bucket holds at most `burst` tokens and refills `rate` tokens per second.
+/// Callers wait for their token in the order they come.
+///
+/// Buckets left alone until they are full again are dropped, they are as good as new.
+pub(crate) struct RateLimiter<K> {
+    rate: f64,
+    burst: f64,
+    max_wait: Duration,
+    buckets: Cache<K, Arc<Mutex<Bucket>>>,
+}
+
+impl<K> RateLimiter<K>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+{
+    /// allow `rate` tokens per second and bursts of `burst` tokens, both at least 1.
+    /// Callers give up if their token would come after `max_wait`.
+    pub fn new(rate: u32, burst: u32, max_wait: Duration) -> Self {
+        let (rate, burst) = (rate.max(1) as f64, burst.max(1) as f64);
+        // the longest a bucket takes to refill, from the tokens promised to waiting callers
+        let refill = Duration::from_secs_f64(burst / rate) + max_wait;
+        let buckets = Cache::builder()
+            .time_to_idle(refill)
+            .max_capacity(MAX_BUCKETS)
+            .build();
+        Self {
+            rate,
+            burst,
+            max_wait,
+            buckets,
+        }
+    }
+
+    /// take a token of `key`, waiting for it if the bucket is empty.
+    ///
+    /// Returns false without taking any token, if it would wait longer than `max_wait`.
+    pub async fn acquire(&self, key: &K) -> bool {
+        let wait = {
+            let now = Instant::now();
+            let bucket = self.buckets.get_with(key.clone(), || {
+                let bucket = Bucket {
+                    tokens: self.burst,
+                    last: now,
+                };
+                Arc::new(Mutex::new(bucket))
+            });
+            let mut bucket = bucket.lock().unwrap();
+            let refilled = (now - bucket.last).as_secs_f64() * self.rate;
+            bucket.tokens = (bucket.tokens + refilled).min(self.burst);
+            bucket.last = now;
+
+            let wait = if bucket.tokens >= 1.0 {
+                Duration::ZERO
+            } else {
+                Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate)
+            };
+            if wait > self.max_wait {
+                return false;
+            }
+            bucket.tokens -= 1.0;
+            wait
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use moka::sync::ConcurrentCacheExt;
+    use tokio::time::{self, Instant};
+
+    use super::{RateLimiter, MAX_BUCKETS};
+
+    #[tokio::test]
+    async fn test_throttle() {
+        time::pause();
+        let limiter = Arc::new(RateLimiter::new(5, 2, Duration::from_secs(1)));
+        let start = Instant::now();
+        let handles: Vec<_> = (0..6)
+            .map(|_| {
+                let limiter = limiter.clone();
+                tokio::spawn(async move {
+                    assert!(limiter.acquire(&"upstream").await);
+                    Instant::now() - start
+                })
+            })
+            .collect();
+        let mut elapsed = vec![];
+        for handle in handles {
+            // in 100ms, the timer rounds deadlines up to the next millisecond
+            elapsed.push(handle.await.unwrap().as_millis() / 100);
+        }
+        elapsed.sort_unstable();
+        // the burst goes at once, the rest at 5 per second
+        assert_eq!(elapsed, vec![0, 0, 2, 4, 6, 8]);
+
+        // other keys have their own buckets
+        let now = Instant::now();
+        assert!(limiter.acquire(&"other").await);
+        assert_eq!(Instant::now(), now);
+    }
+
+    #[tokio::test]
+    async fn test_bounded() {
+        let limiter = RateLimiter::new(1000, 1, Duration::ZERO);
+        for key in 0..MAX_BUCKETS * 2 {
+            assert!(limiter.acquire(&key).await);
+        }
+        limiter.buckets.sync();
+        assert!(limiter.buckets.entry_count() <= MAX_BUCKETS);
+
+        // buckets refilled are dropped
+        let limiter = RateLimiter::new(1000, 1, Duration::ZERO);
+        assert!(limiter.acquire(&"upstream").await);
+        assert!(limiter.buckets.get(&"upstream").is_some());
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(limiter.buckets.get(&"upstream").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_fail_fast() {
+        time::pause();
+        let limiter = RateLimiter::new(5, 2, Duration::from_millis(300));
+        let key = "upstream";
+        let acquired = tokio::join!(
+            limiter.acquire(&key),
+            limiter.acquire(&key),
+            limiter.acquire(&key),
+            limiter.acquire(&key),
+        );
+        // the 3rd token comes 200ms later and is waited for, the 4th 400ms later is not
+        assert_eq!(acquired, (true, true, true, false));
+        // the failed caller did not take a token
+        assert!(limiter.acquire(&key).await);
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::{Bytes, BytesMut};
+
+/// bytes allocated at once for datagrams to be split off
+const CHUNK: usize = 64 * 1024;
+
+/// ## `RecvBuffer`
+/// Space datagrams are received into, one after another.
+///
+/// Each datagram taken is split off a larger chunk as `Bytes` of its own,
+/// so it may be handed to another task while the next one is received.
+/// Taken datagrams never share bytes with each other, nor with the space left.
+/// A new chunk is allocated only once the current one is used up,
+/// and the current one is reused if all datagrams taken from it are dropped by then.
+#[derive(Debug)]
+pub struct RecvBuffer {
+    buf: BytesMut,
+    size: usize,
+}
+
+impl RecvBuffer {
+    /// receive datagrams of `size` bytes at most
+    pub fn new(size: usize) -> Self {
+        Self {
+            buf: BytesMut::with_capacity(CHUNK.max(size)),
+            size,
+        }
+    }
+
+    /// space to receive the next datagram into, whatever was received but not taken is dropped
+    pub fn space(&mut self) -> &mut [u8] {
+        self.buf.clear();
+        self.buf.reserve(self.size);
+        self.buf.resize(self.size, 0);
+        &mut self.buf[..]
+    }
+
+    /// take the first `n` bytes received into `space`
+    pub fn take(&mut self, n: usize) -> Bytes {
+        self.buf.split_to(n.min(self.buf.len())).freeze()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RecvBuffer, CHUNK};
+
+    #[test]
+    fn test_recv_buffer() {
+        let mut buf = RecvBuffer::new(16);
+        let space = buf.space();
+        assert_eq!(space.len(), 16);
+        space[..3].copy_from_slice(b"one");
+        let one = buf.take(3);
+
+        // the next datagram does not overwrite the one taken
+        buf.space()[..3].copy_from_slice(b"two");
+        let two = buf.take(3);
+        assert_eq!(&one[..], b"one");
+        assert_eq!(&two[..], b"two");
+        // both come from the same chunk
+        assert_eq!(one.as_ptr().wrapping_add(3), two.as_ptr());
+
+        // a datagram dropped without taking it leaves nothing behind
+        buf.space()[..5].copy_from_slice(b"three");
+        assert_eq!(buf.space()[..5], [0; 5]);
+
+        // space runs out, taken datagrams are kept as they are
+        let taken: Vec<_> = (0..CHUNK / 16)
+            .map(|i| {
+                buf.space().fill(i as u8);
+                buf.take(16)
+            })
+            .collect();
+        for (i, datagram) in taken.iter().enumerate() {
+            assert!(datagram.iter().all(|&b| b == i as u8));
+        }
+        assert_eq!(&one[..], b"one");
+    }
+}
@@ -0,0 +1,381 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use moka::sync::Cache;
+use tokio::time::Instant;
+
+use super::unmapped;
+use crate::protocol::{Name, Packet, RRType, Rcode};
+
+/// beyond this many accounts, the least used ones are dropped
+const MAX_ACCOUNTS: u64 = 16384;
+/// seconds a flood is remembered by default
+const DEFAULT_WINDOW: Duration = Duration::from_secs(15);
+/// every 2nd limited response is sent truncated by default
+const DEFAULT_SLIP: u32 = 2;
+
+/// what a response is, responses of different kinds are counted apart
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Kind {
+    /// answers and empty answers, by the type asked for
+    Answer(Option<RRType>),
+    /// failures, by their RCODE
+    Error(u16),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    network: IpAddr,
+    name: Option<Name>,
+    kind: Kind,
+}
+
+#[derive(Debug)]
+struct Account {
+    /// responses that may still be sent, negative once limited
+    balance: f64,
+    last: Instant,
+    /// responses limited so far, to pick the ones slipped
+    limited: u32,
+}
+
+/// what to do with a response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Send,
+    /// send it truncated, so that the client could retry over TCP
+    Slip,
+    Drop,
+}
+
+/// ## `Rrl`
+/// Response Rate Limiting of UDP, see [the RRL design](https://kb.isc.org/docs/aa-01000).
+///
+/// Responses are accounted by the network of the client, a /24 or a /64,
+/// the name asked for, and the kind of the response, i.e. the type asked for or the failure.
+/// Failures are accounted by the zone of the name instead, so a flood of random names
+/// under a zone is limited as one.
+/// Each account is credited `rate` responses per second, up to `rate`,
+/// and is limited once the responses sent overdraw it.
+/// Limited responses are dropped, but every `slip`-th is sent truncated,
+/// so that a real client behind a spoofed flood still gets its answer over TCP.
+///
+/// The overdraft is no larger than `window` seconds of credit,
+/// so a flood is limited until it stops for about that long.
+/// Accounts left alone until they are back to full credit are dropped.
+#[derive(Debug)]
+pub struct Rrl {
+    rate: f64,
+    window: Duration,
+    slip: u32,
+    accounts: Cache<Key, Arc<Mutex<Account>>>,
+}
+
+impl Rrl {
+    /// send at most `rate` identical responses per second to a network, at least 1
+    pub fn new(rate: u32) -> Self {
+        Self {
+            rate: rate.max(1) as f64,
+            window: DEFAULT_WINDOW,
+            slip: DEFAULT_SLIP,
+            accounts: accounts(DEFAULT_WINDOW),
+        }
+    }
+
+    /// remember floods for `window`, at least a second
+    pub fn with_window(mut self, window: Duration) -> Self {
+        self.window = window.max(Duration::from_secs(1));
+        self.accounts = accounts(self.window);
+        self
+    }
+
+    /// send every `slip`-th limited response truncated, 0 drops them all, 1 truncates them all
+    pub fn with_slip(mut self, slip: u32) -> Self {
+        self.slip = slip;
+        self
+    }
+
+    /// account `resp` to `client`, and tell what to do with it
+    pub(crate) fn check(&self, client: IpAddr, resp: &Packet) -> Verdict {
+        let kind = match resp.get_rcode() {
+            Rcode::NoError => Kind::Answer(resp.question().map(|q| q.get_type())),
+            _ => Kind::Error(resp.header.full_rcode()),
+        };
+        let key = Key {
+            network: network(client),
+            name: accounted_name(resp),
+            kind,
+        };
+
+        let now = Instant::now();
+        let account = self.accounts.get_with(key, || {
+            let account = Account {
+                balance: self.rate,
+                last: now,
+                limited: 0,
+            };
+            Arc::new(Mutex::new(account))
+        });
+        let mut account = account.lock().unwrap();
+        let credit = (now - account.last).as_secs_f64() * self.rate;
+        let overdraft = -self.window.as_secs_f64() * self.rate;
+        account.balance = ((account.balance + credit).min(self.rate) - 1.0).max(overdraft);
+        account.last = now;
+
+        if account.balance >= 0.0 {
+            account.limited = 0;
+            return Verdict::Send;
+        }
+        account.limited = account.limited.wrapping_add(1);
+        match self.slip {
+            1.. if account.limited.is_multiple_of(self.slip) => Verdict::Slip,
+            _ => Verdict::Drop,
+        }
+    }
+}
+
+/// accounts of floods remembered for `window`,
+/// one is back to full credit a second after the longest overdraft is paid off
+fn accounts(window: Duration) -> Cache<Key, Arc<Mutex<Account>>> {
+    Cache::builder()
+        .time_to_idle(window + Duration::from_secs(1))
+        .max_capacity(MAX_ACCOUNTS)
+        .build()
+}
+
+/// the name `resp` is accounted by, failures by the owner of the SOA in authority,
+/// or by the parent of the name asked for without one
+fn accounted_name(resp: &Packet) -> Option<Name> {
+    let name = resp.question()?.get_name();
+    if resp.get_rcode() == Rcode::NoError {
+        return Some(name);
+    }
+    let zone = resp
+        .authorities
+        .iter()
+        .find(|rr| rr.get_type() == RRType::Soa)
+        .map(|soa| soa.get_domain());
+    Some(zone.unwrap_or_else(|| name.get_parent_domain()))
+}
+
+/// the network `client` is accounted by, clients of a network are often one
+fn network(client: IpAddr) -> IpAddr {
+    match unmapped(client) {
+        IpAddr::V4(v4) => Ipv4Addr::from(u32::from(v4) & !0xff).into(),
+        IpAddr::V6(v6) => Ipv6Addr::from(u128::from(v6) & !(u64::MAX as u128)).into(),
+    }
+}
+
+/// `resp` without its records and with TC set, the question is kept
+pub(crate) fn slipped(resp: &Packet) -> Packet {
+    let mut header = resp.header;
+    header.set_answers(0);
+    header.set_authorities(0);
+    header.set_additional(0);
+    header.set_truncated(true);
+    Packet {
+        header,
+        questions: resp.questions.clone(),
+        answers: vec![],
+        authorities: vec![],
+        additions: vec![],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::Duration,
+    };
+
+    use bytes::{BufMut, BytesMut};
+    use moka::sync::ConcurrentCacheExt;
+    use tokio::time;
+
+    use super::{network, slipped, Rrl, Verdict, MAX_ACCOUNTS};
+    use crate::{
+        comm::{build_response, Answer},
+        protocol::{Name, Packet, PacketError, Question, RRClass, RRData, RRType, RR},
+    };
+
+    fn response(name: &str, answers: Vec<Answer>) -> Packet {
+        let name = Name::try_from(name).unwrap();
+        let q = Question::build(name, RRType::A, RRClass::Internet);
+        build_response(1, q, answers, false)
+    }
+
+    #[test]
+    fn test_network() {
+        let net = |ip: &str| network(ip.parse().unwrap()).to_string();
+        assert_eq!(net("192.0.2.77"), "192.0.2.0");
+        assert_eq!(net("::ffff:192.0.2.77"), "192.0.2.0");
+        assert_eq!(net("2001:db8:1:2:3:4:5:6"), "2001:db8:1:2::");
+    }
+
+    #[tokio::test]
+    async fn test_flood_limited() {
+        time::pause();
+        let rrl = Rrl::new(5).with_window(Duration::from_secs(2));
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        let resp = response("example.com", vec![]);
+        let verdicts: Vec<_> = (0..10).map(|_| rrl.check(client, &resp)).collect();
+        // the rate goes out, then every other limited response slips
+        let (sent, limited) = verdicts.split_at(5);
+        assert!(sent.iter().all(|v| *v == Verdict::Send));
+        assert_eq!(
+            limited,
+            [
+                Verdict::Drop,
+                Verdict::Slip,
+                Verdict::Drop,
+                Verdict::Slip,
+                Verdict::Drop
+            ]
+        );
+
+        // the same network, but other names, kinds, and networks are accounted apart
+        let neighbour: IpAddr = "192.0.2.200".parse().unwrap();
+        assert_ne!(rrl.check(neighbour, &resp), Verdict::Send);
+        let other: IpAddr = "198.51.100.1".parse().unwrap();
+        assert_eq!(rrl.check(other, &resp), Verdict::Send);
+        let other_name = response("example.org", vec![]);
+        assert_eq!(rrl.check(client, &other_name), Verdict::Send);
+        let nx = Name::try_from("example.com").unwrap();
+        let failure = response(
+            "example.com",
+            vec![Answer::Error(PacketError::NameError(nx))],
+        );
+        assert_eq!(rrl.check(client, &failure), Verdict::Send);
+
+        // a long flood is remembered no longer than the window
+        for _ in 0..100 {
+            assert_ne!(rrl.check(client, &resp), Verdict::Send);
+        }
+        time::advance(Duration::from_secs(1)).await;
+        assert_ne!(rrl.check(client, &resp), Verdict::Send);
+        time::advance(Duration::from_secs(2)).await;
+        assert_eq!(rrl.check(client, &resp), Verdict::Send);
+    }
+
+    /// the SOA record of `zone`, parsed from wire
+    fn soa(zone: &str) -> RR {
+        let mname = Name::try_from("ns.example")
+            .unwrap()
+            .as_bytes_uncompressed();
+        let rname = Name::try_from("admin.example")
+            .unwrap()
+            .as_bytes_uncompressed();
+        let mut pkt = BytesMut::new();
+        pkt.put_slice(&[0, 0, 0x81, 0x83, 0, 0, 0, 0, 0, 1, 0, 0]);
+        pkt.put(Name::try_from(zone).unwrap().as_bytes_uncompressed());
+        pkt.put_u16(RRType::Soa.into());
+        pkt.put_u16(RRClass::Internet.into());
+        pkt.put_u32(300);
+        pkt.put_u16((mname.len() + rname.len() + 4 * 5) as u16);
+        pkt.put(mname);
+        pkt.put(rname);
+        for field in [1, 3600, 600, 86400, 60] {
+            pkt.put_u32(field);
+        }
+        let pkt = Packet::parse_packet(pkt.freeze(), 0).unwrap();
+        pkt.authorities[0].clone()
+    }
+
+    /// an NXDOMAIN response to `name`, with the SOA of `zone` if given
+    fn nxdomain(name: &str, zone: Option<&str>) -> Packet {
+        let nx = Answer::Error(PacketError::NameError(Name::try_from(name).unwrap()));
+        let soa = zone.map(|zone| Answer::NameServer(soa(zone)));
+        response(name, std::iter::once(nx).chain(soa).collect())
+    }
+
+    #[tokio::test]
+    async fn test_failures_by_zone() {
+        time::pause();
+        let rrl = Rrl::new(5).with_slip(0);
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        // random names under a zone are limited as one, whatever their depth
+        let verdicts: Vec<_> = (0..10)
+            .map(|i| {
+                let name = format!("r{}.x{}.example.com", i, i);
+                rrl.check(client, &nxdomain(&name, Some("example.com")))
+            })
+            .collect();
+        assert!(verdicts[..5].iter().all(|v| *v == Verdict::Send));
+        assert!(verdicts[5..].iter().all(|v| *v == Verdict::Drop));
+        // other zones are accounted apart
+        let other = nxdomain("r0.example.org", Some("example.org"));
+        assert_eq!(rrl.check(client, &other), Verdict::Send);
+
+        // without an SOA, by the parent of the name
+        for i in 0..5 {
+            let name = format!("r{}.example.net", i);
+            assert_eq!(rrl.check(client, &nxdomain(&name, None)), Verdict::Send);
+        }
+        let flooded = nxdomain("r5.example.net", None);
+        assert_eq!(rrl.check(client, &flooded), Verdict::Drop);
+        let servfail = |name: &str| response(name, vec![Answer::Error(PacketError::ServFail)]);
+        for i in 0..5 {
+            let name = format!("r{}.example.info", i);
+            assert_eq!(rrl.check(client, &servfail(&name)), Verdict::Send);
+        }
+        assert_eq!(
+            rrl.check(client, &servfail("r5.example.info")),
+            Verdict::Drop
+        );
+
+        // answers are still accounted by the name asked for
+        for i in 0..10 {
+            let name = format!("r{}.example.com", i);
+            assert_eq!(rrl.check(client, &response(&name, vec![])), Verdict::Send);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_slip_ratio() {
+        time::pause();
+        let client: IpAddr = "2001:db8::1".parse().unwrap();
+        let resp = response("example.com", vec![]);
+        for (slip, slipped) in [(0, 0), (1, 6), (3, 2)] {
+            let rrl = Rrl::new(1).with_slip(slip);
+            let verdicts: Vec<_> = (0..7).map(|_| rrl.check(client, &resp)).collect();
+            assert_eq!(verdicts[0], Verdict::Send);
+            let count = verdicts.iter().filter(|v| **v == Verdict::Slip).count();
+            assert_eq!(count, slipped, "slip {}", slip);
+        }
+    }
+
+    #[test]
+    fn test_bounded() {
+        let rrl = Rrl::new(1);
+        for i in 0..MAX_ACCOUNTS * 2 {
+            let client = IpAddr::from(Ipv4Addr::from((i as u32) << 8));
+            rrl.check(client, &response("example.com", vec![]));
+        }
+        rrl.accounts.sync();
+        assert!(rrl.accounts.entry_count() <= MAX_ACCOUNTS);
+    }
+
+    #[test]
+    fn test_slipped() {
+        let name = Name::try_from("example.com").unwrap();
+        let a = RRData::A(Ipv4Addr::new(192, 0, 2, 1).into());
+        let a = RR::new(name, Duration::from_secs(60), RRClass::Internet, a);
+        let resp = response("example.com", vec![Answer::Answer(a)]);
+        assert_eq!(resp.answer_count(), 1);
+        let resp = Packet::parse_packet(slipped(&resp).into_bytes(), 0).unwrap();
+        assert!(resp.is_trunc());
+        assert_eq!(resp.get_id(), 1);
+        assert_eq!(resp.question_count(), 1);
+        assert_eq!(resp.answer_count(), 0);
+    }
+}
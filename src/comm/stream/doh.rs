@@ -0,0 +1,473 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+use bytes::{Bytes, BytesMut};
+use hyper::{
+    body::HttpBody,
+    header::{CACHE_CONTROL, CONTENT_TYPE},
+    server::conn::{Connection, Http},
+    service::{service_fn, Service},
+    Body, Method, Request, Response, StatusCode,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::mpsc,
+};
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use crate::{
+    comm::{
+        build_response, check_op, take_question, Acl, ClientSubnet, QuerySpan, Task, TypePolicy,
+        DRAIN_TIMEOUT,
+    },
+    metrics::{Metrics, Transport},
+    protocol::{Packet, PacketError},
+    querylog::QueryLogger,
+};
+
+/// the only path queries are accepted on
+const DOH_PATH: &str = "/dns-query";
+/// media type of DNS messages, see [RFC8484](https://datatracker.ietf.org/doc/html/rfc8484#section-6)
+pub(crate) const DNS_MESSAGE: &str = "application/dns-message";
+/// DNS messages are at most 65535 bytes long
+const MAX_MESSAGE_SIZE: usize = 65535;
+
+/// ## `DohService`
+/// Serves DNS over HTTPS, see [RFC8484](https://datatracker.ietf.org/doc/html/rfc8484).
+///
+/// Queries are taken from the base64url `dns` parameter of `GET` requests,
+/// or from the body of `POST` requests, both on `/dns-query`.
+/// Connections are plain HTTP unless `with_tls` is called, like behind a reverse proxy.
+pub struct DohService {
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    task: mpsc::UnboundedSender<Task>,
+    policy: Arc<TypePolicy>,
+    acl: Arc<Acl>,
+    metrics: Arc<Metrics>,
+    query_log: Option<Arc<dyn QueryLogger>>,
+    shutdown: CancellationToken,
+}
+
+impl DohService {
+    pub fn new(listener: TcpListener, task: mpsc::UnboundedSender<Task>) -> Self {
+        Self {
+            listener,
+            tls: None,
+            task,
+            policy: Arc::new(TypePolicy::default()),
+            acl: Arc::new(Acl::default()),
+            metrics: Arc::new(Metrics::default()),
+            query_log: None,
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// serve HTTPS by `config`, which should offer `h2` or `http/1.1` by ALPN
+    pub fn with_tls(mut self, config: Arc<ServerConfig>) -> Self {
+        self.tls = Some(TlsAcceptor::from(config));
+        self
+    }
+
+    /// refuse or rate limit queries of some types by `policy`
+    pub fn with_policy(mut self, policy: Arc<TypePolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// only serve clients allowed by `acl`, queries of others are answered by REFUSED
+    pub fn with_acl(mut self, acl: Arc<Acl>) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    /// count the queries answered and the connections served in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// log the queries answered to `logger`
+    pub fn with_query_log(mut self, logger: Arc<dyn QueryLogger>) -> Self {
+        self.query_log = Some(logger);
+        self
+    }
+
+    /// stop accepting connections once `shutdown` is cancelled.
+    ///
+    /// Open connections are closed after answering the requests in flight, `run` waits for them.
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub async fn run(self) {
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        if let Ok(local) = self.listener.local_addr() {
+            tracing::info!("starting service on: {}://{}{}", scheme, local, DOH_PATH);
+        }
+        let http = Http::new();
+        // every connection holds a sender, all are dropped once the connections are closed
+        let (open, mut closed) = mpsc::channel::<()>(1);
+        loop {
+            let (stream, client) = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                accepted = self.listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(_) => break,
+                },
+            };
+            tracing::info!("incoming connection from {}://{}", scheme, client);
+            let task = self.task.clone();
+            let policy = self.policy.clone();
+            let acl = self.acl.clone();
+            let metrics = self.metrics.clone();
+            let query_log = self.query_log.clone();
+            let connection = self.metrics.connection(Transport::Doh);
+            let service = service_fn(move |req| {
+                handle(
+                    req,
+                    client,
+                    task.clone(),
+                    policy.clone(),
+                    acl.clone(),
+                    metrics.clone(),
+                    query_log.clone(),
+                )
+            });
+            let tls = self.tls.clone();
+            let http = http.clone();
+            let shutdown = self.shutdown.clone();
+            let open = open.clone();
+            tokio::spawn(async move {
+                let _open = open;
+                let _connection = connection;
+                let served = match tls {
+                    Some(tls) => match tls.accept(stream).await {
+                        Ok(stream) => serve(http.serve_connection(stream, service), shutdown).await,
+                        Err(e) => {
+                            tracing::warn!("TLS handshake with {} failed: {}", client, e);
+                            return;
+                        }
+                    },
+                    None => serve(http.serve_connection(stream, service), shutdown).await,
+                };
+                if let Err(e) = served {
+                    tracing::debug!("connection to {}://{} closed: {}", scheme, client, e);
+                }
+            });
+        }
+        drop(open);
+        if tokio::time::timeout(DRAIN_TIMEOUT, closed.recv())
+            .await
+            .is_err()
+        {
+            tracing::warn!("doh service quit with requests in flight");
+        }
+    }
+}
+
+/// serve `conn` until it is closed, or gracefully closed after `shutdown` is cancelled
+async fn serve<I, S>(conn: Connection<I, S>, shutdown: CancellationToken) -> hyper::Result<()>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    S::Future: Send + 'static,
+{
+    tokio::pin!(conn);
+    tokio::select! {
+        served = &mut conn => return served,
+        _ = shutdown.cancelled() => {}
+    }
+    conn.as_mut().graceful_shutdown();
+    conn.await
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = code;
+    resp
+}
+
+/// answer a DoH request, malformed requests are answered by HTTP errors
+/// and failed queries by DNS errors
+async fn handle(
+    req: Request<Body>,
+    client: SocketAddr,
+    task: mpsc::UnboundedSender<Task>,
+    policy: Arc<TypePolicy>,
+    acl: Arc<Acl>,
+    metrics: Arc<Metrics>,
+    query_log: Option<Arc<dyn QueryLogger>>,
+) -> Result<Response<Body>, Infallible> {
+    if req.uri().path() != DOH_PATH {
+        return Ok(status(StatusCode::NOT_FOUND));
+    }
+    let query = match *req.method() {
+        Method::GET => match dns_param(req.uri().query()) {
+            Some(query) => query,
+            None => return Ok(status(StatusCode::BAD_REQUEST)),
+        },
+        Method::POST => {
+            let is_dns_message = req
+                .headers()
+                .get(CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.split(';').next())
+                .is_some_and(|media| media.trim().eq_ignore_ascii_case(DNS_MESSAGE));
+            if !is_dns_message {
+                return Ok(status(StatusCode::UNSUPPORTED_MEDIA_TYPE));
+            }
+            match read_body(req.into_body()).await {
+                Ok(query) => query,
+                Err(code) => return Ok(status(code)),
+            }
+        }
+        _ => return Ok(status(StatusCode::METHOD_NOT_ALLOWED)),
+    };
+
+    let packet = match Packet::parse_packet(query, 0) {
+        Ok(packet) => packet,
+        Err(_) => return Ok(status(StatusCode::BAD_REQUEST)),
+    };
+    let query_span = QuerySpan::new(Transport::Doh, client, &packet, query_log);
+    let resp = answer(packet, client, &task, &policy, &acl)
+        .instrument(query_span.span())
+        .await;
+    let rcode = resp.header.full_rcode();
+    metrics.count_query(Transport::Doh, rcode);
+    query_span.finish(rcode, resp.answer_count());
+    // cached by HTTP caches no longer than the records
+    let max_age = resp.min_ttl().unwrap_or_default().as_secs();
+    let resp = Response::builder()
+        .header(CONTENT_TYPE, DNS_MESSAGE)
+        .header(CACHE_CONTROL, format!("max-age={}", max_age))
+        .body(Body::from(resp.into_bytes()))
+        .unwrap();
+    Ok(resp)
+}
+
+/// the query encoded in the `dns` parameter, padding is tolerated though not sent by clients
+fn dns_param(query: Option<&str>) -> Option<Bytes> {
+    let encoded = query?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("dns="))?
+        .trim_end_matches('=');
+    let decoded = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD).ok()?;
+    (decoded.len() <= MAX_MESSAGE_SIZE).then(|| Bytes::from(decoded))
+}
+
+async fn read_body(mut body: Body) -> Result<Bytes, StatusCode> {
+    if body.size_hint().lower() > MAX_MESSAGE_SIZE as u64 {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    let mut buf = BytesMut::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if buf.len() + chunk.len() > MAX_MESSAGE_SIZE {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf.freeze())
+}
+
+/// the response to the query `packet`
+async fn answer(
+    mut packet: Packet,
+    client: SocketAddr,
+    task: &mpsc::UnboundedSender<Task>,
+    policy: &TypePolicy,
+    acl: &Acl,
+) -> Packet {
+    let id = packet.get_id();
+    if !packet.is_query() {
+        return Packet::new_failure(id, PacketError::FormatError);
+    }
+    if let Err(error) = acl.check(client.ip()) {
+        return Packet::new_failure(id, error);
+    }
+    let checked = match check_op(&packet) {
+        Ok(()) => policy.check(client.ip(), &packet).await,
+        err => err,
+    };
+    let subnet = ClientSubnet::of_query(&packet, client.ip());
+    let edns = packet.has_edns();
+    let query = match checked.and_then(|()| take_question(&mut packet)) {
+        Ok(query) => query,
+        Err(e) => return Packet::new_failure(id, e.error),
+    };
+
+    let (ans_to, mut ans_from) = mpsc::unbounded_channel();
+    let _ = task.send(Task::Query(query.clone(), ans_to, Some(subnet)));
+    let mut answers = vec![];
+    while let Some(ans) = ans_from.recv().await {
+        answers.push(ans);
+    }
+    build_response(id, query, answers, edns)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{Ipv4Addr, SocketAddr},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use bytes::Bytes;
+    use hyper::{
+        header::{CACHE_CONTROL, CONTENT_TYPE},
+        Body, Request, Response, StatusCode,
+    };
+    use tokio::{
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+
+    use super::DohService;
+    use crate::{
+        comm::{Answer, Task},
+        metrics::Metrics,
+        protocol::{Name, Packet, Question, RRClass, RRData, RRType, RR},
+    };
+
+    /// a DoH server on plain HTTP, whose transaction layer answers `A` records with TTL 60
+    async fn server(metrics: Arc<Metrics>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (task_sender, mut tasks) = mpsc::unbounded_channel();
+        let service = DohService::new(listener, task_sender).with_metrics(metrics);
+        tokio::spawn(service.run());
+        tokio::spawn(async move {
+            while let Some(Task::Query(q, ans_to, _)) = tasks.recv().await {
+                let a = RRData::A(Ipv4Addr::new(192, 0, 2, 1).into());
+                let rr = RR::new(q.get_name(), Duration::from_secs(60), q.get_class(), a);
+                let _ = ans_to.send(Answer::Answer(rr));
+            }
+        });
+        addr
+    }
+
+    async fn request(addr: SocketAddr, req: Request<Body>) -> Response<Body> {
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(conn);
+        sender.send_request(req).await.unwrap()
+    }
+
+    fn query() -> Bytes {
+        let name = Name::try_from("example.com").unwrap();
+        Packet::new_query(0x1234, Question::build(name, RRType::A, RRClass::Internet)).into_bytes()
+    }
+
+    async fn check_answer(resp: Response<Body>) {
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_TYPE], "application/dns-message");
+        assert_eq!(resp.headers()[CACHE_CONTROL], "max-age=60");
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let packet = Packet::parse_packet(body, 0).unwrap();
+        assert_eq!(packet.get_id(), 0x1234);
+        assert_eq!(packet.answers.len(), 1);
+        assert_eq!(
+            packet.answers[0].get_domain(),
+            Name::try_from("example.com").unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get() {
+        let addr = server(Arc::default()).await;
+        let dns = base64::encode_config(query(), base64::URL_SAFE_NO_PAD);
+        let req = Request::get(format!("/dns-query?ct&dns={}", dns))
+            .body(Body::empty())
+            .unwrap();
+        check_answer(request(addr, req).await).await;
+    }
+
+    #[tokio::test]
+    async fn test_post() {
+        let addr = server(Arc::default()).await;
+        let req = Request::post("/dns-query")
+            .header(CONTENT_TYPE, "application/dns-message")
+            .body(Body::from(query()))
+            .unwrap();
+        check_answer(request(addr, req).await).await;
+    }
+
+    #[tokio::test]
+    async fn test_invalid_requests() {
+        let addr = server(Arc::default()).await;
+        let cases = [
+            (Request::get("/resolve?dns=AAAA"), StatusCode::NOT_FOUND),
+            (Request::get("/dns-query"), StatusCode::BAD_REQUEST),
+            (Request::get("/dns-query?dns=AAAA"), StatusCode::BAD_REQUEST),
+            (Request::put("/dns-query"), StatusCode::METHOD_NOT_ALLOWED),
+            (
+                Request::post("/dns-query").header(CONTENT_TYPE, "text/plain"),
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ),
+        ];
+        for (req, code) in cases {
+            let req = req.body(Body::from(query())).unwrap();
+            let uri = req.uri().clone();
+            assert_eq!(request(addr, req).await.status(), code, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let metrics = Arc::new(Metrics::new());
+        let addr = server(metrics.clone()).await;
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, conn) = hyper::client::conn::handshake(stream).await.unwrap();
+        tokio::spawn(conn);
+        let get = |query| {
+            let dns = base64::encode_config(query, base64::URL_SAFE_NO_PAD);
+            Request::get(format!("/dns-query?dns={}", dns))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let resp = sender.send_request(get(query())).await.unwrap();
+        check_answer(resp).await;
+        // malformed queries are answered by HTTP errors, not counted
+        let resp = sender.send_request(get(Bytes::from("bad"))).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let text = metrics.render();
+        for line in [
+            "tsein_dns_queries_total{transport=\"doh\",rcode=\"NOERROR\"} 1",
+            "tsein_dns_connections{transport=\"doh\"} 1",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{} missing in\n{}",
+                line,
+                text
+            );
+        }
+
+        // the connection is uncounted once closed
+        drop(sender);
+        let closed = async {
+            while !metrics
+                .render()
+                .contains("tsein_dns_connections{transport=\"doh\"} 0")
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), closed)
+            .await
+            .expect("the connection should be uncounted");
+    }
+}
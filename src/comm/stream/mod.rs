@@ -4,6 +4,9 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::time::Duration;
+
+pub use doh::DohService;
 pub use quic::QuicService;
 pub use service::Service;
 pub use tcp::TcpService;
@@ -12,29 +15,30 @@ use tokio::io::AsyncWriteExt;
 
 use crate::protocol::{Packet, PacketError, TransactionError};
 
+pub mod doh;
 pub mod quic;
 pub mod service;
 pub mod tcp;
 pub mod tls;
 pub(crate) mod worker;
 
+/// connections and QUIC streams waiting longer than this for a query are closed by default
+pub(crate) const IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// use write_packet to write packet into TCP, TLS and IETF-QUIC streams
 pub async fn write_packet<S>(stream: &mut S, packet: Packet) -> Result<(), std::io::Error>
 where
     S: AsyncWriteExt + Unpin,
 {
     let id = packet.get_id();
-    let buf = packet.into_bytes();
-    if buf.len() > u16::MAX as usize {
-        let fail = PacketError::ServFail;
-        let resp = Packet::new_failure(id, fail).into_bytes();
-        let len = resp.len() as u16;
-        stream.write_u16(len).await?;
-        return stream.write_all(&resp).await;
+    match packet.write_to(stream).await {
+        // too long to be framed, nothing was written
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+            let fail = PacketError::ServFail;
+            Packet::new_failure(id, fail).write_to(stream).await
+        }
+        r => r,
     }
-    let len = buf.len() as u16;
-    stream.write_u16(len).await?;
-    stream.write_all(&buf).await
 }
 
 pub(crate) async fn stream_fail<S>(
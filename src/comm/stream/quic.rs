@@ -4,66 +4,171 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use bytes::Bytes;
-use futures::StreamExt;
+use futures::{stream::FuturesUnordered, StreamExt};
 use quinn::{Incoming, RecvStream, SendStream};
-use tokio::{io::AsyncReadExt, sync::mpsc};
+use tokio::{io::AsyncReadExt, sync::mpsc, task::JoinHandle};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
-    comm::{stream::stream_fail, Answer, Task},
-    protocol::{Packet, PacketError, TransactionError},
+    comm::{
+        build_response, check_op,
+        stream::{stream_fail, IDLE_TIMEOUT},
+        take_question, Acl, ClientSubnet, QuerySpan, Task, TypePolicy, DRAIN_TIMEOUT,
+    },
+    metrics::{Metrics, Transport},
+    protocol::{Packet, PacketError, Question, TransactionError},
+    querylog::QueryLogger,
 };
 
 pub struct QuicService {
     listener: Incoming,
     task: mpsc::UnboundedSender<Task>,
+    policy: Arc<TypePolicy>,
+    acl: Arc<Acl>,
+    metrics: Arc<Metrics>,
+    query_log: Option<Arc<dyn QueryLogger>>,
+    shutdown: CancellationToken,
+    idle: Duration,
 }
 
 impl QuicService {
     pub fn new(listener: Incoming, task: mpsc::UnboundedSender<Task>) -> Self {
-        Self { listener, task }
+        Self {
+            listener,
+            task,
+            policy: Arc::new(TypePolicy::default()),
+            acl: Arc::new(Acl::default()),
+            metrics: Arc::new(Metrics::default()),
+            query_log: None,
+            shutdown: CancellationToken::new(),
+            idle: IDLE_TIMEOUT,
+        }
+    }
+
+    /// refuse or rate limit queries of some types by `policy`
+    pub fn with_policy(mut self, policy: Arc<TypePolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// only serve clients allowed by `acl`, queries of others are answered by REFUSED
+    pub fn with_acl(mut self, acl: Arc<Acl>) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    /// count the queries answered and the connections served in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// log the queries answered to `logger`
+    pub fn with_query_log(mut self, logger: Arc<dyn QueryLogger>) -> Self {
+        self.query_log = Some(logger);
+        self
+    }
+
+    /// stop accepting connections and streams once `shutdown` is cancelled,
+    /// `run` returns after answering the queries in flight
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// close streams waiting longer than `idle` for their query, 10 seconds by default
+    pub fn with_idle_timeout(mut self, idle: Duration) -> Self {
+        self.idle = idle;
+        self
     }
 
     pub async fn run(mut self) {
         let mut futs = futures::stream::FuturesUnordered::new();
-        while let Some(conn) = self.listener.next().await {
+        loop {
+            let conn = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                conn = self.listener.next() => match conn {
+                    Some(conn) => conn,
+                    None => break,
+                },
+            };
             let client = conn.remote_address();
             tracing::info!("connection from quic://{}", client);
             let task_sender = self.task.clone();
-            let fut = tokio::spawn(async move { client_handler(conn, task_sender).await });
+            let policy = self.policy.clone();
+            let acl = self.acl.clone();
+            let answered = Answered {
+                metrics: self.metrics.clone(),
+                query_log: self.query_log.clone(),
+            };
+            let shutdown = self.shutdown.clone();
+            let idle = self.idle;
+            let fut = tokio::spawn(async move {
+                client_handler(conn, task_sender, policy, acl, answered, shutdown, idle).await
+            });
             futs.push(fut);
         }
         // join all
-        while !futs.is_empty() {
-            let _ = futs.next().await;
+        let joining = async {
+            while !futs.is_empty() {
+                let _ = futs.next().await;
+            }
+        };
+        if !self.shutdown.is_cancelled() {
+            joining.await;
+        } else if tokio::time::timeout(DRAIN_TIMEOUT, joining).await.is_err() {
+            tracing::warn!("quic service quit with queries in flight");
         }
     }
 }
 
+/// where the queries answered on QUIC streams are counted and logged
+#[derive(Clone)]
+struct Answered {
+    metrics: Arc<Metrics>,
+    query_log: Option<Arc<dyn QueryLogger>>,
+}
+
 /// `worker` is a handler for a QUIC `stream`
 /// like a tiny `super::worker::Worker` implementation
+#[allow(clippy::too_many_arguments)]
 async fn worker(
     mut recv: RecvStream,
     mut send: SendStream,
     task_sender: mpsc::UnboundedSender<Task>,
+    policy: Arc<TypePolicy>,
+    acl: Arc<Acl>,
+    answered: Answered,
     client: SocketAddr,
+    idle: Duration,
 ) {
     let stream_id = send.id().index();
     tracing::debug!("serving stream {} from quic://{}", stream_id, client);
 
     let mut v = vec![];
-    let r = recv.read_buf(&mut v).await;
+    let r = tokio::time::timeout(idle, recv.read_buf(&mut v)).await;
     let len = match r {
-        Ok(l) => l,
+        Ok(Ok(l)) => l,
         Err(_) => {
+            tracing::debug!(
+                "stream {} from quic://{} idled for {:?}",
+                stream_id,
+                client,
+                idle
+            );
+            let _ = send.finish().await;
+            return;
+        }
+        Ok(Err(_)) => {
             tracing::warn!("failed to read on stream {}", recv.id());
             return;
         }
     };
     tracing::debug!("read {} bytes on stream {}", len, recv.id());
-    let pkt = match Packet::parse_packet(Bytes::from(v), 0) {
+    let mut pkt = match Packet::parse_packet(Bytes::from(v), 0) {
         Err(TransactionError {
             id: _,
             error: PacketError::ServFail,
@@ -87,56 +192,37 @@ async fn worker(
             let _ = stream_fail(&mut send, e).await.is_err();
             return;
         }
-        Ok(pkt) => {
-            if !pkt.is_query() {
-                let id = pkt.get_id();
-                let error = PacketError::FormatError;
-                let fail = TransactionError {
-                    id: Some(id),
-                    error,
-                };
-                let _ = stream_fail(&mut send, fail).await.is_err();
-                return;
-            }
-            pkt
-        }
+        Ok(pkt) => pkt,
     };
 
+    let Answered { metrics, query_log } = answered;
+    let query_span = QuerySpan::new(Transport::Quic, client, &pkt, query_log);
+    // the RCODE and answers the query is answered with
+    let finish = |rcode: u16, answers: u16| {
+        metrics.count_query(Transport::Quic, rcode);
+        query_span.finish(rcode, answers);
+    };
     let id = pkt.get_id();
-    let query = pkt.question.unwrap();
+    let subnet = ClientSubnet::of_query(&pkt, client.ip());
+    let edns = pkt.has_edns();
+    let query = match admit(&mut pkt, client, &policy, &acl).await {
+        Ok(query) => query,
+        Err(e) => {
+            finish(e.error.full_rcode(), 0);
+            let _ = stream_fail(&mut send, e).await.is_err();
+            return;
+        }
+    };
     let (ans_send, mut ans_recv) = mpsc::unbounded_channel();
-    let task = Task::Query(query.clone(), ans_send);
+    let task = Task::Query(query.clone(), ans_send, Some(subnet));
     let _ = task_sender.send(task);
 
     let mut answers = vec![];
-    let mut auths = vec![];
-    let mut additionals = vec![];
     while let Some(ans) = ans_recv.recv().await {
-        match ans {
-            Answer::Error(error) => {
-                let err = TransactionError {
-                    id: Some(id),
-                    error,
-                };
-                let _ = stream_fail(&mut send, err).await.is_err();
-                break;
-            }
-            Answer::Answer(a) => {
-                answers.push(a);
-            }
-            Answer::NameServer(a) => {
-                auths.push(a);
-            }
-            Answer::Additional(a) => {
-                additionals.push(a);
-            }
-        }
+        answers.push(ans);
     }
-    let mut packet = Packet::new_plain_answer(id);
-    packet.set_question(query);
-    packet.set_answers(answers);
-    packet.set_authorities(auths);
-    packet.set_addtionals(additionals);
+    let packet = build_response(id, query, answers, edns);
+    finish(packet.header.full_rcode(), packet.answer_count());
 
     if send.write_all(&packet.into_bytes()[..]).await.is_err() {
         tracing::warn!(
@@ -150,10 +236,43 @@ async fn worker(
     tracing::debug!("stream {} to quic://{} closed", stream_id, client);
 }
 
+/// check the query `pkt` of `client` may be answered, and take its question
+async fn admit(
+    pkt: &mut Packet,
+    client: SocketAddr,
+    policy: &TypePolicy,
+    acl: &Acl,
+) -> Result<Question, TransactionError> {
+    let id = Some(pkt.get_id());
+    if !pkt.is_query() {
+        let error = PacketError::FormatError;
+        return Err(TransactionError { id, error });
+    }
+    acl.check(client.ip())
+        .map_err(|error| TransactionError { id, error })?;
+    check_op(pkt)?;
+    policy.check(client.ip(), pkt).await?;
+    take_question(pkt)
+}
+
+/// abort `workers` on a closed connection, their answers could never be sent.
+///
+/// Dropping their answer receivers tells the transaction layer to stop the lookups.
+fn cancel(workers: &FuturesUnordered<JoinHandle<()>>) {
+    for worker in workers.iter() {
+        worker.abort();
+    }
+}
+
 /// client_handler could be used for handling streams from a specific client.
 async fn client_handler(
     conn: quinn::Connecting,
     task_sender: mpsc::UnboundedSender<Task>,
+    policy: Arc<TypePolicy>,
+    acl: Arc<Acl>,
+    answered: Answered,
+    shutdown: CancellationToken,
+    idle: Duration,
 ) -> Result<(), quinn::ConnectionError> {
     let quinn::NewConnection {
         connection,
@@ -165,8 +284,16 @@ async fn client_handler(
         connection.remote_address()
     );
     let client = connection.remote_address();
+    let _connection = answered.metrics.connection(Transport::Quic);
     let mut futs = futures::stream::FuturesUnordered::new();
-    while let Some(stream) = bi_streams.next().await {
+    loop {
+        let stream = tokio::select! {
+            _ = shutdown.cancelled() => break,
+            stream = bi_streams.next() => match stream {
+                Some(stream) => stream,
+                None => break,
+            },
+        };
         let (send, recv) = match stream {
             Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
                 tracing::warn!(
@@ -174,20 +301,27 @@ async fn client_handler(
                     connection.remote_address()
                 );
                 // connection is closed, keeping proceeding futures is meaningless
-                // quit directly, but normally.
+                // cancel them and quit directly, but normally.
+                cancel(&futs);
                 return Ok(());
             }
             Err(e) => {
                 tracing::warn!("connection to quic://{} closed due to {:?}", client, e);
                 // connection is closed, keeping proceeding futures is meaningless
-                // quit directly, and return an error.
+                // cancel them and quit directly, and return an error.
+                cancel(&futs);
                 return Err(e);
             }
             Ok(s) => s,
         };
 
         let task_sender = task_sender.clone();
-        let worker = tokio::spawn(async move { worker(recv, send, task_sender, client).await });
+        let policy = policy.clone();
+        let acl = acl.clone();
+        let answered = answered.clone();
+        let worker = tokio::spawn(async move {
+            worker(recv, send, task_sender, policy, acl, answered, client, idle).await
+        });
         futs.push(worker);
     }
     // join all
@@ -196,3 +330,90 @@ async fn client_handler(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use quinn::Endpoint;
+    use tokio::sync::mpsc;
+    use tokio_util::sync::CancellationToken;
+
+    use super::QuicService;
+    use crate::{
+        comm::Task,
+        metrics::Metrics,
+        protocol::{Name, Packet, Question, RRClass, RRType},
+    };
+
+    #[tokio::test]
+    async fn test_cancel_on_close() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = rustls::Certificate(cert.serialize_der().unwrap());
+        let key = rustls::PrivateKey(cert.serialize_private_key_der());
+        let server_config =
+            quinn::ServerConfig::with_single_cert(vec![cert_der.clone()], key).unwrap();
+        let (server, incoming) =
+            Endpoint::server(server_config, "[::1]:0".parse().unwrap()).unwrap();
+        let addr = server.local_addr().unwrap();
+        let (task_sender, mut tasks) = mpsc::unbounded_channel();
+        let metrics = Arc::new(Metrics::new());
+        let service = QuicService::new(incoming, task_sender).with_metrics(metrics.clone());
+        tokio::spawn(service.run());
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&cert_der).unwrap();
+        let crypto = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let mut client = Endpoint::client("[::1]:0".parse().unwrap()).unwrap();
+        client.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+        let conn = client.connect(addr, "localhost").unwrap().await.unwrap();
+
+        let name = Name::try_from("example.com").unwrap();
+        let query = Packet::new_query(0, Question::build(name, RRType::A, RRClass::Internet));
+        let (mut send, _recv) = conn.connection.open_bi().await.unwrap();
+        send.write_all(&query.into_bytes()).await.unwrap();
+        send.finish().await.unwrap();
+
+        // the query is in flight, until the connection is closed
+        let Task::Query(_, ans_sender, _) = tasks.recv().await.unwrap();
+        assert!(!ans_sender.is_closed());
+        let connections = "tsein_dns_connections{transport=\"quic\"}";
+        assert!(metrics.render().contains(&format!("{} 1", connections)));
+        conn.connection.close(0_u32.into(), b"bye");
+        tokio::time::timeout(Duration::from_secs(1), ans_sender.closed())
+            .await
+            .expect("the task should be cancelled");
+        // the connection is uncounted once its handler returns
+        let uncounted = async {
+            while !metrics.render().contains(&format!("{} 0", connections)) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), uncounted)
+            .await
+            .expect("the connection should be uncounted");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = rustls::Certificate(cert.serialize_der().unwrap());
+        let key = rustls::PrivateKey(cert.serialize_private_key_der());
+        let server_config = quinn::ServerConfig::with_single_cert(vec![cert_der], key).unwrap();
+        let (_server, incoming) =
+            Endpoint::server(server_config, "[::1]:0".parse().unwrap()).unwrap();
+        let (task_sender, _tasks) = mpsc::unbounded_channel();
+        let shutdown = CancellationToken::new();
+        let service = QuicService::new(incoming, task_sender).with_shutdown(shutdown.clone());
+        let running = tokio::spawn(service.run());
+
+        shutdown.cancel();
+        tokio::time::timeout(Duration::from_secs(1), running)
+            .await
+            .expect("the service should return on shutdown")
+            .unwrap();
+    }
+}
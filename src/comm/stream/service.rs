@@ -4,7 +4,14 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::{net::SocketAddr, sync::Arc, time};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{self, Duration},
+};
 
 use async_trait::async_trait;
 use moka::future::Cache;
@@ -12,10 +19,20 @@ use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     sync::{mpsc, oneshot},
 };
+use tokio_util::sync::CancellationToken;
 
-use crate::comm::{
-    stream::worker::{Message, Worker},
-    Task,
+use crate::{
+    comm::{
+        stream::{
+            stream_fail,
+            worker::{Message, Worker},
+            IDLE_TIMEOUT,
+        },
+        Acl, Task, TypePolicy, DRAIN_TIMEOUT,
+    },
+    metrics::{Metrics, Transport},
+    protocol::{Packet, PacketError, TransactionError},
+    querylog::QueryLogger,
 };
 
 #[async_trait]
@@ -24,6 +41,8 @@ pub trait Listener {
     type W: AsyncWriteExt + Unpin + Send;
     // name of the protocol
     fn name(&self) -> &'static str;
+    // transport queries are counted by
+    fn transport(&self) -> Transport;
     // get serving address and port
     fn local_addr(&self) -> std::io::Result<SocketAddr>;
 
@@ -40,9 +59,20 @@ where
     message: mpsc::UnboundedReceiver<Message>,
     bell: mpsc::UnboundedSender<Message>,
     pool: Cache<SocketAddr, Arc<oneshot::Sender<()>>>,
+    /// connections served at most at the same time, more are closed once accepted
+    limit: usize,
+    /// workers not shut down yet
+    live: Arc<AtomicUsize>,
+    policy: Arc<TypePolicy>,
+    acl: Arc<Acl>,
+    metrics: Arc<Metrics>,
+    query_log: Option<Arc<dyn QueryLogger>>,
+    shutdown: CancellationToken,
+    idle: Duration,
 }
 
 impl<L: 'static + Listener + Send + Sync> Service<L> {
+    /// serve queries from `listener`, on `limit` connections at most at the same time
     pub fn new(listener: L, task: mpsc::UnboundedSender<Task>, limit: u64) -> Self {
         let (bell, message) = mpsc::unbounded_channel::<Message>();
         let timeout = time::Duration::from_secs(4);
@@ -56,55 +86,142 @@ impl<L: 'static + Listener + Send + Sync> Service<L> {
             message,
             bell,
             pool,
+            limit: limit as usize,
+            live: Arc::new(AtomicUsize::new(0)),
+            policy: Arc::new(TypePolicy::default()),
+            acl: Arc::new(Acl::default()),
+            metrics: Arc::new(Metrics::default()),
+            query_log: None,
+            shutdown: CancellationToken::new(),
+            idle: IDLE_TIMEOUT,
         }
     }
 
+    /// refuse or rate limit queries of some types by `policy`
+    pub fn with_policy(mut self, policy: Arc<TypePolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// only serve clients allowed by `acl`, the first query of others is answered by REFUSED
+    pub fn with_acl(mut self, acl: Arc<Acl>) -> Self {
+        self.acl = acl;
+        self
+    }
+
+    /// count the queries answered and the connections served in `metrics`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// log the queries answered to `logger`
+    pub fn with_query_log(mut self, logger: Arc<dyn QueryLogger>) -> Self {
+        self.query_log = Some(logger);
+        self
+    }
+
+    /// stop accepting connections once `shutdown` is cancelled.
+    ///
+    /// Idle connections are closed, `run` returns after answering the queries in flight.
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// close connections waiting longer than `idle` for a query, 10 seconds by default
+    pub fn with_idle_timeout(mut self, idle: Duration) -> Self {
+        self.idle = idle;
+        self
+    }
+
     pub async fn update(&mut self) -> Option<Message> {
         self.message.recv().await
     }
 
-    pub async fn serve<R: 'static, W: 'static>(
-        &mut self,
-        client: SocketAddr,
-        read_stream: R,
-        write_stream: W,
-    ) where
-        R: AsyncReadExt + Unpin + Send,
-        W: AsyncWriteExt + Unpin + Send,
+    pub async fn serve<R, W>(&mut self, client: SocketAddr, read_stream: R, write_stream: W)
+    where
+        R: 'static + AsyncReadExt + Unpin + Send,
+        W: 'static + AsyncWriteExt + Unpin + Send,
     {
         let stream = (read_stream, write_stream);
         let task_sender = self.task.clone();
         let (tx, rx) = oneshot::channel();
         let bell = self.bell.clone();
         self.pool.insert(client, Arc::new(tx)).await;
-        let worker = Worker::new(client, stream, task_sender, bell, rx);
+        self.live.fetch_add(1, Ordering::AcqRel);
+        let policy = self.policy.clone();
+        let shutdown = self.shutdown.clone();
+        let worker = Worker::new(client, stream, task_sender, policy, bell, rx, shutdown)
+            .with_idle_timeout(self.idle)
+            .with_metrics(self.metrics.clone(), self.listener.transport())
+            .with_query_log(self.query_log.clone());
         tokio::spawn(async move { worker.run().await });
     }
 
     pub async fn run(self) {
-        let mut listener = self.listener;
-        let task = self.task.clone();
-        let msg_sender = self.bell.clone();
-        let pool = self.pool.clone();
+        let Self {
+            mut listener,
+            task,
+            message: mut msg,
+            bell: msg_sender,
+            pool,
+            limit,
+            live,
+            policy,
+            acl,
+            metrics,
+            query_log,
+            shutdown,
+            idle,
+        } = self;
 
         let protocol = listener.name();
+        let transport = listener.transport();
         let server_addr = format!("{}://{}", protocol, listener.local_addr().unwrap());
 
         tracing::info!("starting service on: {}", server_addr);
+        let workers = pool.clone();
+        let serving = live.clone();
+        let stopping = shutdown.clone();
+        // the bell is dropped with the loop, so workers are managed until the last one quits
         let listening = tokio::spawn(async move {
-            while let Ok((stream, client)) = listener.acquire().await {
+            loop {
+                let (stream, client) = tokio::select! {
+                    _ = stopping.cancelled() => break,
+                    accepted = listener.acquire() => match accepted {
+                        Ok(accepted) => accepted,
+                        Err(_) => break,
+                    },
+                };
                 let client_uri = format!("{}://{}", listener.name(), client);
+                if let Err(error) = acl.check(client.ip()) {
+                    tokio::spawn(refuse(stream, error, idle));
+                    continue;
+                }
+                if serving.load(Ordering::Acquire) >= limit {
+                    // dropping the stream closes it
+                    tracing::warn!("refused {}, {} connections are served", client_uri, limit);
+                    continue;
+                }
+                serving.fetch_add(1, Ordering::AcqRel);
                 tracing::info!("incoming connection from {}", client_uri);
 
                 let task = task.clone();
                 let msg_sender = msg_sender.clone();
-                let handler = Worker::serve(stream, client, task, msg_sender);
-                pool.insert(client, Arc::new(handler)).await;
+                let policy = policy.clone();
+                let stopping = stopping.clone();
+                let (handler, receiver) = oneshot::channel();
+                let worker =
+                    Worker::new(client, stream, task, policy, msg_sender, receiver, stopping)
+                        .with_idle_timeout(idle)
+                        .with_metrics(metrics.clone(), transport)
+                        .with_query_log(query_log.clone());
+                tokio::spawn(worker.run());
+                workers.insert(client, Arc::new(handler)).await;
                 tracing::debug!("worker for {} started", client_uri);
             }
         });
-        let mut msg = self.message;
-        let pool = self.pool.clone();
 
         tracing::info!("starting manage workers in {} service", protocol);
         let updating = tokio::spawn(async move {
@@ -116,11 +233,265 @@ impl<L: 'static + Listener + Send + Sync> Service<L> {
                     }
                     Message::ShutDown(client) => {
                         pool.invalidate(&client).await;
+                        live.fetch_sub(1, Ordering::AcqRel);
                         tracing::info!("worker for {}://{} shutdown", protocol, client);
                     }
                 }
             }
         });
-        let _ = tokio::join!(listening, updating);
+        let _ = listening.await;
+        if !shutdown.is_cancelled() {
+            let _ = updating.await;
+            return;
+        }
+        tracing::info!("{} service stopped accepting, draining workers", protocol);
+        if tokio::time::timeout(DRAIN_TIMEOUT, updating).await.is_err() {
+            tracing::warn!("{} service quit with queries in flight", protocol);
+        }
+    }
+}
+
+/// answer the first query of a refused client by `error`, then close the connection
+async fn refuse<R, W>((mut rd, mut wr): (R, W), error: PacketError, idle: Duration)
+where
+    R: AsyncReadExt + Unpin,
+    W: AsyncWriteExt + Unpin,
+{
+    if let Ok(Ok(query)) = tokio::time::timeout(idle, Packet::parse_stream(&mut rd)).await {
+        let id = Some(query.get_id());
+        let _ = stream_fail(&mut wr, TransactionError { id, error }).await;
+    }
+    let _ = wr.shutdown().await;
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+    };
+    use tokio_util::sync::CancellationToken;
+
+    use crate::{
+        blocklist::Network,
+        comm::{stream::write_packet, Acl, Answer, Task, TcpService},
+        metrics::Metrics,
+        protocol::{Name, Packet, PacketError, Question, RRClass, RRData, RRType, Rcode, RR},
+    };
+
+    fn query(id: u16) -> Packet {
+        let name = Name::try_from("example.com").unwrap();
+        let q = Question::build(name, RRType::A, RRClass::Internet);
+        Packet::new_query(id, q)
+    }
+
+    #[tokio::test]
+    async fn test_limit() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (task_sender, mut tasks) = mpsc::unbounded_channel();
+        tokio::spawn(TcpService::new(listener, task_sender, 2).run());
+
+        // both connections are accepted once their queries arrive
+        let mut served = vec![];
+        for id in 0..2 {
+            let mut conn = TcpStream::connect(addr).await.unwrap();
+            write_packet(&mut conn, query(id)).await.unwrap();
+            assert!(tasks.recv().await.is_some());
+            served.push(conn);
+        }
+
+        // the excess one is closed
+        let mut excess = TcpStream::connect(addr).await.unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(1), excess.read(&mut [0; 2]))
+            .await
+            .expect("the excess connection should be closed");
+        assert_eq!(read.unwrap(), 0);
+
+        // a slot frees once a connection is closed
+        drop(served.pop());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        write_packet(&mut conn, query(2)).await.unwrap();
+        let task = tokio::time::timeout(Duration::from_secs(1), tasks.recv()).await;
+        assert!(task.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_pipelining() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (task_sender, mut tasks) = mpsc::unbounded_channel();
+        tokio::spawn(TcpService::new(listener, task_sender, 16).run());
+
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        for id in 1..=3 {
+            let name = Name::try_from(format!("{}.example.com", id).as_str()).unwrap();
+            let q = Question::build(name, RRType::A, RRClass::Internet);
+            write_packet(&mut conn, Packet::new_query(id, q))
+                .await
+                .unwrap();
+        }
+        // all three are in flight at once
+        let mut pending = vec![];
+        for _ in 0..3 {
+            let task = tokio::time::timeout(Duration::from_secs(1), tasks.recv()).await;
+            let Task::Query(q, ans_to, _) = task.expect("queries should be read ahead").unwrap();
+            pending.push((q.get_name().to_string(), ans_to));
+        }
+        pending.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        // and answered as they are done, the last one first
+        for id in (1..=3).rev() {
+            let (name, ans_to) = pending.pop().unwrap();
+            assert_eq!(name, format!("{}.example.com.", id));
+            drop(ans_to);
+            let resp = Packet::parse_stream(&mut conn).await.unwrap();
+            assert_eq!(resp.get_id(), id);
+            assert_eq!(resp.get_rcode(), Rcode::NoError);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_acl() {
+        let serve = |allowed: &str| {
+            let allowed = Network::new(allowed.parse().unwrap(), 32);
+            let acl = Arc::new(Acl::deny_by_default().with_allowed(allowed));
+            async move {
+                let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+                let (task_sender, tasks) = mpsc::unbounded_channel();
+                tokio::spawn(
+                    TcpService::new(listener, task_sender, 16)
+                        .with_acl(acl)
+                        .run(),
+                );
+                (addr, tasks)
+            }
+        };
+
+        let (addr, mut tasks) = serve("192.0.2.1").await;
+        let mut stranger = TcpStream::connect(addr).await.unwrap();
+        write_packet(&mut stranger, query(1)).await.unwrap();
+        let resp = Packet::parse_stream(&mut stranger).await.unwrap();
+        assert_eq!(resp.get_id(), 1);
+        assert_eq!(resp.get_rcode(), Rcode::Refused);
+        assert_eq!(stranger.read(&mut [0; 2]).await.unwrap(), 0);
+        assert!(tasks.try_recv().is_err());
+
+        let (addr, mut tasks) = serve("127.0.0.1").await;
+        let mut allowed = TcpStream::connect(addr).await.unwrap();
+        write_packet(&mut allowed, query(2)).await.unwrap();
+        assert!(tasks.recv().await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (task_sender, mut tasks) = mpsc::unbounded_channel();
+        let service = TcpService::new(listener, task_sender, 16)
+            .with_idle_timeout(Duration::from_millis(100));
+        tokio::spawn(service.run());
+
+        // queries are answered as usual
+        let mut busy = TcpStream::connect(addr).await.unwrap();
+        write_packet(&mut busy, query(0)).await.unwrap();
+        let Task::Query(_, ans_to, _) = tasks.recv().await.unwrap();
+        drop(ans_to);
+        assert!(Packet::parse_stream(&mut busy).await.is_ok());
+
+        // an idle one is closed
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        let read = tokio::time::timeout(Duration::from_secs(1), idle.read(&mut [0; 2]))
+            .await
+            .expect("the idle connection should be closed");
+        assert_eq!(read.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (task_sender, mut tasks) = mpsc::unbounded_channel();
+        let shutdown = CancellationToken::new();
+        let service = TcpService::new(listener, task_sender, 16).with_shutdown(shutdown.clone());
+        let mut running = tokio::spawn(service.run());
+
+        let mut idle = TcpStream::connect(addr).await.unwrap();
+        let mut busy = TcpStream::connect(addr).await.unwrap();
+        write_packet(&mut busy, query(7)).await.unwrap();
+        let Task::Query(q, ans_to, _) = tasks.recv().await.unwrap();
+
+        // the query in flight holds the service
+        shutdown.cancel();
+        let waiting = tokio::time::timeout(Duration::from_millis(100), &mut running).await;
+        assert!(waiting.is_err());
+
+        let a = RRData::A(Ipv4Addr::new(192, 0, 2, 1).into());
+        let rr = RR::new(q.get_name(), Duration::from_secs(60), q.get_class(), a);
+        ans_to.send(Answer::Answer(rr)).unwrap();
+        drop(ans_to);
+        let resp = Packet::parse_stream(&mut busy).await.unwrap();
+        assert_eq!(resp.get_id(), 7);
+        assert_eq!(resp.answers.len(), 1);
+
+        tokio::time::timeout(Duration::from_secs(1), running)
+            .await
+            .expect("the service should return once drained")
+            .unwrap();
+        // idle connections are closed, and no more are accepted
+        assert_eq!(idle.read(&mut [0; 2]).await.unwrap(), 0);
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (task_sender, mut tasks) = mpsc::unbounded_channel();
+        let metrics = Arc::new(Metrics::new());
+        let service = TcpService::new(listener, task_sender, 16).with_metrics(metrics.clone());
+        tokio::spawn(service.run());
+
+        let mut conn = TcpStream::connect(addr).await.unwrap();
+        for (id, answer) in [
+            (1, None),
+            (2, None),
+            (3, Some(Answer::Error(PacketError::ServFail))),
+        ] {
+            write_packet(&mut conn, query(id)).await.unwrap();
+            let Task::Query(_, ans_to, _) = tasks.recv().await.unwrap();
+            if let Some(answer) = answer {
+                ans_to.send(answer).unwrap();
+            }
+            drop(ans_to);
+            assert_eq!(Packet::parse_stream(&mut conn).await.unwrap().get_id(), id);
+        }
+
+        let text = metrics.render();
+        for line in [
+            "tsein_dns_queries_total{transport=\"tcp\",rcode=\"NOERROR\"} 2",
+            "tsein_dns_queries_total{transport=\"tcp\",rcode=\"SERVFAIL\"} 1",
+            "tsein_dns_queries_total{transport=\"udp\",rcode=\"NOERROR\"} 0",
+            "tsein_dns_connections{transport=\"tcp\"} 1",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{} missing in\n{}",
+                line,
+                text
+            );
+        }
+
+        // the connection is no longer counted once closed
+        drop(conn);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(metrics
+            .render()
+            .contains("tsein_dns_connections{transport=\"tcp\"} 0"));
     }
 }
@@ -13,6 +13,7 @@ use tokio::{
 };
 
 use super::{service::Listener, Service};
+use crate::metrics::Transport;
 
 pub type TcpService = Service<TcpListener>;
 
@@ -25,6 +26,10 @@ impl Listener for TcpListener {
         "tcp"
     }
 
+    fn transport(&self) -> Transport {
+        Transport::Tcp
+    }
+
     fn local_addr(&self) -> std::io::Result<SocketAddr> {
         self.local_addr()
     }
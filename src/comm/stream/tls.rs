@@ -14,6 +14,7 @@ use tokio::{
 use tokio_rustls::{rustls::ServerConfig, server::TlsStream, TlsAcceptor};
 
 use super::{service::Listener, Service};
+use crate::metrics::Transport;
 
 pub type TlsService = Service<TlsListener>;
 
@@ -38,6 +39,10 @@ impl Listener for TlsListener {
         "tls"
     }
 
+    fn transport(&self) -> Transport {
+        Transport::Tls
+    }
+
     fn local_addr(&self) -> std::io::Result<SocketAddr> {
         self.listener.local_addr()
     }
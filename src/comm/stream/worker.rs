@@ -4,19 +4,26 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
-    sync::{mpsc, oneshot, oneshot::error::TryRecvError},
+    sync::{mpsc, oneshot, oneshot::error::TryRecvError, Semaphore},
 };
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
 
-use super::{stream_fail, write_packet};
+use super::{write_packet, IDLE_TIMEOUT};
 use crate::{
-    comm::{Answer, Task},
+    comm::{build_response, check_op, take_question, ClientSubnet, QuerySpan, Task, TypePolicy},
+    metrics::{Metrics, Transport},
     protocol::{Packet, PacketError, TransactionError},
+    querylog::QueryLogger,
 };
 
+/// queries answered at once on a stream at most, the next is read once one is answered
+const MAX_PIPELINED: usize = 32;
+
 pub enum Message {
     Update(SocketAddr),
     ShutDown(SocketAddr),
@@ -30,11 +37,25 @@ where
     client: SocketAddr,
     stream: (ReadHalf, WriteHalf),
     task_sender: mpsc::UnboundedSender<Task>,
+    policy: Arc<TypePolicy>,
     m_sender: mpsc::UnboundedSender<Message>,
 
     // it does not matter what to send
     // but the state of the receiver matters
     m_receiver: oneshot::Receiver<()>,
+
+    // no more queries are read once cancelled
+    shutdown: CancellationToken,
+
+    // the stream is closed if no query comes in time
+    idle: Duration,
+
+    // counts the queries answered, by the transport of the stream
+    metrics: Arc<Metrics>,
+    transport: Transport,
+
+    // where the queries answered are logged
+    query_log: Option<Arc<dyn QueryLogger>>,
 }
 
 impl<R, W> Worker<R, W>
@@ -46,165 +67,225 @@ where
         client: SocketAddr,
         stream: (R, W),
         task_sender: mpsc::UnboundedSender<Task>,
+        policy: Arc<TypePolicy>,
         m_sender: mpsc::UnboundedSender<Message>,
         m_receiver: oneshot::Receiver<()>,
+        shutdown: CancellationToken,
     ) -> Self {
         Self {
             client,
             stream,
             task_sender,
+            policy,
             m_sender,
             m_receiver,
+            shutdown,
+            idle: IDLE_TIMEOUT,
+            metrics: Arc::new(Metrics::default()),
+            transport: Transport::Tcp,
+            query_log: None,
         }
     }
-    // TODO: parallelize the reading and sending tasks, there is space for optimization
+
+    /// close the stream once no query comes in `idle`
+    pub fn with_idle_timeout(mut self, idle: Duration) -> Self {
+        self.idle = idle;
+        self
+    }
+
+    /// count the stream and the queries answered on it as of `transport`
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>, transport: Transport) -> Self {
+        self.metrics = metrics;
+        self.transport = transport;
+        self
+    }
+
+    /// log the queries answered on the stream to `logger`, if any
+    pub fn with_query_log(mut self, logger: Option<Arc<dyn QueryLogger>>) -> Self {
+        self.query_log = logger;
+        self
+    }
+
+    /// answer the queries on the stream until it is closed, idle or shut down.
+    ///
+    /// Queries are read while earlier ones are answered, so clients may pipeline them,
+    /// and their responses are written as they are answered, in whatever order.
     pub async fn run(self) {
-        let client = self.client;
+        let Self {
+            client,
+            stream: (mut rd, wr),
+            task_sender,
+            policy,
+            m_sender: updater,
+            m_receiver: mut checker,
+            shutdown,
+            idle,
+            metrics,
+            transport,
+            query_log,
+        } = self;
         tracing::debug!("Actor against {} starting...", client);
 
-        let (mut rd, mut wr) = self.stream;
-
-        // if the packet from a client failed too many times
-        // take caution
-        let mut is_suspected = false;
-
-        let updater = self.m_sender;
-        let mut checker = self.m_receiver;
-
-        // while still not shut down
-        while let Err(TryRecvError::Empty) = checker.try_recv() {
-            // this worker is still online
-            // update
-            let msg = Message::Update(self.client);
-            let _ = updater.send(msg);
-
-            let read = Packet::parse_stream(&mut rd).await;
-            if read.is_err() {
-                let err = read.unwrap_err();
-
-                if let TransactionError {
-                    id: _,
-                    error: PacketError::ServFail,
-                } = err
-                {
-                    // read to end of file in stream
-                    // quit normally
-                    tracing::trace!("connection from {} reaches its end", client);
-                    break;
-                }
+        let _connection = metrics.connection(transport);
+        // responses are framed by the writer alone
+        let (responses, pending) = mpsc::unbounded_channel();
+        // cancelled once the stream cannot be written any more
+        let broken = CancellationToken::new();
+        let writing = write_responses(wr, pending, broken.clone(), client);
 
-                tracing::warn!("received malformed data {} from client {}", err, client);
-
-                if stream_fail(&mut wr, err).await.is_err() || is_suspected {
-                    // stream is closed by peer or the suspected client send corrupted message again
-                    // quit directly
-                    tracing::warn!(
-                        "actor against {} quit due to corrupted data or connection problems",
-                        client
-                    );
-                    let msg = Message::ShutDown(self.client);
-                    let _ = updater.send(msg);
-                    return;
-                }
-                if !is_suspected {
-                    is_suspected = true
-                }
-                continue;
-            }
+        let reading = async {
+            // if the packet from a client failed too many times
+            // take caution
+            let mut is_suspected = false;
+            let in_flight = Arc::new(Semaphore::new(MAX_PIPELINED));
+            // fail a query at once, the RCODE is returned
+            let fail = |err: TransactionError| {
+                let rcode = err.error.full_rcode();
+                metrics.count_query(transport, rcode);
+                let TransactionError { id, error } = err;
+                let _ = responses.send(Packet::new_failure(id.unwrap_or(0), error));
+                rcode
+            };
 
-            let packet = read.unwrap();
-            if !packet.is_query() {
-                let id = packet.get_id();
-                let error = PacketError::FormatError;
-                let failure = Packet::new_failure(id, error);
-                if write_packet(&mut wr, failure).await.is_err() || is_suspected {
-                    // stream is closed by peer or the suspected client send malformed data again
-                    // quit directly
-                    tracing::warn!(
-                        "actor against {} quit due to malformed data or connection problems",
-                        client
-                    );
-                    let msg = Message::ShutDown(self.client);
-                    let _ = updater.send(msg);
-                    return;
-                }
-                continue;
-            }
+            // while still not shut down
+            while let Err(TryRecvError::Empty) = checker.try_recv() {
+                // this worker is still online
+                // update
+                let _ = updater.send(Message::Update(client));
 
-            // forgive the client
-            is_suspected = false;
-
-            let query = packet.question.clone().unwrap();
-            let (ask, mut answer) = mpsc::unbounded_channel();
-            let task = Task::Query(query.clone(), ask);
-            let _ = self.task_sender.send(task);
-
-            let mut answers = vec![];
-            let mut auths = vec![];
-            let mut additionals = vec![];
-            while let Some(ans) = answer.recv().await {
-                match ans {
-                    Answer::Error(error) => {
-                        let id = Some(packet.get_id());
-                        let err = TransactionError { id, error };
-                        if stream_fail(&mut wr, err).await.is_err() {
-                            // stream is closed by peer
-                            // quit directly
-                            tracing::warn!(
-                                "actor against {} quit due to connection problems",
-                                client
-                            );
-                            let msg = Message::ShutDown(client);
-                            let _ = updater.send(msg);
-                            return;
+                // the next query is only read once there is room for it
+                let permit = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = broken.cancelled() => break,
+                    permit = in_flight.clone().acquire_owned() => permit.unwrap(),
+                };
+                // queries in flight are still answered, but no new one is waited for
+                let read = tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = broken.cancelled() => break,
+                    read = tokio::time::timeout(idle, Packet::parse_stream(&mut rd)) => match read {
+                        Ok(read) => read,
+                        Err(_) => {
+                            tracing::debug!("connection from {} idled for {:?}", client, idle);
+                            break;
                         }
+                    },
+                };
+                let packet = match read {
+                    Ok(packet) => packet,
+                    Err(TransactionError {
+                        id: _,
+                        error: PacketError::ServFail,
+                    }) => {
+                        // read to end of file in stream
+                        // quit normally
+                        tracing::trace!("connection from {} reaches its end", client);
                         break;
                     }
-                    Answer::Answer(a) => {
-                        answers.push(a);
-                    }
-                    Answer::NameServer(n) => {
-                        auths.push(n);
+                    Err(err) => {
+                        tracing::warn!("received malformed data {} from client {}", err, client);
+                        fail(err);
+                        if is_suspected {
+                            // the suspected client send corrupted message again
+                            tracing::warn!("actor against {} quit due to corrupted data", client);
+                            break;
+                        }
+                        is_suspected = true;
+                        continue;
                     }
-                    Answer::Additional(a) => {
-                        additionals.push(a);
+                };
+
+                let query_span = QuerySpan::new(transport, client, &packet, query_log.clone());
+                if !packet.is_query() {
+                    let id = Some(packet.get_id());
+                    let rcode = fail(TransactionError {
+                        id,
+                        error: PacketError::FormatError,
+                    });
+                    query_span.finish(rcode, 0);
+                    if is_suspected {
+                        // the suspected client send malformed data again
+                        tracing::warn!("actor against {} quit due to malformed data", client);
+                        break;
                     }
+                    continue;
                 }
+                // forgive the client
+                is_suspected = false;
+
+                let span = query_span.span();
+                let policy = policy.clone();
+                let task_sender = task_sender.clone();
+                let metrics = metrics.clone();
+                let responses = responses.clone();
+                let answering = async move {
+                    let _permit = permit;
+                    let resp = answer(packet, client, &policy, &task_sender).await;
+                    let rcode = resp.header.full_rcode();
+                    metrics.count_query(transport, rcode);
+                    query_span.finish(rcode, resp.answer_count());
+                    let _ = responses.send(resp);
+                };
+                tokio::spawn(answering.instrument(span));
             }
-            let mut packet = Packet::new_plain_answer(packet.get_id());
-            packet.set_question(query);
-            packet.set_answers(answers);
-            packet.set_authorities(auths);
-            packet.set_addtionals(additionals);
-            if write_packet(&mut wr, packet).await.is_err() {
-                // stream is closed by peer,
-                // quit directly
-                tracing::warn!("actor against {} quit due to connection problems", client);
-                let msg = Message::ShutDown(client);
-                let _ = updater.send(msg);
-                return;
-            }
-        }
-        let msg = Message::ShutDown(client);
-        let _ = updater.send(msg);
+            // the writer is done once the queries in flight are answered
+            drop(responses);
+        };
+        tokio::join!(reading, writing);
+
+        let _ = updater.send(Message::ShutDown(client));
         tracing::debug!("actor against {} shutdown", client);
     }
 }
 
-impl<R: 'static, W: 'static> Worker<R, W>
-where
-    R: AsyncReadExt + Unpin + Send,
-    W: AsyncWriteExt + Unpin + Send,
+/// write `responses` to the stream until they are all written,
+/// then close it, or cancel `broken` if the stream fails
+async fn write_responses<W>(
+    mut wr: W,
+    mut responses: mpsc::UnboundedReceiver<Packet>,
+    broken: CancellationToken,
+    client: SocketAddr,
+) where
+    W: AsyncWriteExt + Unpin,
 {
-    pub fn serve(
-        stream: (R, W),
-        client: SocketAddr,
-        task_sender: mpsc::UnboundedSender<Task>,
-        msg_sender: mpsc::UnboundedSender<Message>,
-    ) -> oneshot::Sender<()> {
-        let (sender, receiver) = oneshot::channel();
-        let worker = Self::new(client, stream, task_sender, msg_sender, receiver);
-        tokio::spawn(async move { worker.run().await });
-        sender
+    while let Some(packet) = responses.recv().await {
+        if write_packet(&mut wr, packet).await.is_err() {
+            // stream is closed by peer,
+            // quit directly
+            tracing::warn!("actor against {} quit due to connection problems", client);
+            broken.cancel();
+            return;
+        }
+    }
+    let _ = wr.shutdown().await;
+}
+
+/// answer the query `packet` of `client`, or fail it if it is not allowed or malformed
+async fn answer(
+    mut packet: Packet,
+    client: SocketAddr,
+    policy: &TypePolicy,
+    task_sender: &mpsc::UnboundedSender<Task>,
+) -> Packet {
+    let id = packet.get_id();
+    let checked = match check_op(&packet) {
+        Ok(()) => policy.check(client.ip(), &packet).await,
+        err => err,
+    };
+    let subnet = ClientSubnet::of_query(&packet, client.ip());
+    let edns = packet.has_edns();
+    let query = match checked.and_then(|()| take_question(&mut packet)) {
+        Ok(query) => query,
+        Err(TransactionError { id: _, error }) => return Packet::new_failure(id, error),
+    };
+
+    let (ask, mut answer) = mpsc::unbounded_channel();
+    let task = Task::Query(query.clone(), ask, Some(subnet));
+    let _ = task_sender.send(task);
+
+    let mut answers = vec![];
+    while let Some(ans) = answer.recv().await {
+        answers.push(ans);
     }
+    build_response(id, query, answers, edns)
 }
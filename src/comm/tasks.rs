@@ -0,0 +1,158 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use rand::prelude::random;
+use tokio::sync::oneshot;
+
+use super::Answer;
+
+/// shards of `TaskMap`, a power of 2 so ids spread evenly
+const SHARDS: usize = 16;
+/// random ids tried by `TaskMap::register`, before it looks through all ids in turn
+const RANDOM_ATTEMPTS: usize = 16;
+
+type Shard = Mutex<HashMap<u16, oneshot::Sender<Vec<Answer>>>>;
+
+/// ## `TaskMap`
+/// Senders of answers to forwarded queries, by transaction id.
+///
+/// Ids are spread over shards locked on their own,
+/// so queries and responses of different ids do not wait for each other.
+/// Clones share the same map.
+#[derive(Clone)]
+pub(crate) struct TaskMap {
+    shards: Arc<[Shard]>,
+}
+
+impl Default for TaskMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TaskMap {
+    pub fn new() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+        }
+    }
+
+    fn shard(&self, id: u16) -> &Shard {
+        &self.shards[id as usize % SHARDS]
+    }
+
+    /// register `sender` under a random id that is not taken yet,
+    /// `None` if all ids are taken by queries in flight
+    pub fn register(&self, mut sender: oneshot::Sender<Vec<Answer>>) -> Option<u16> {
+        for _ in 0..RANDOM_ATTEMPTS {
+            match self.try_insert(random(), sender) {
+                Ok(id) => return Some(id),
+                Err(taken) => sender = taken,
+            }
+        }
+        // most ids are taken, look for the free ones in turn from a random start
+        let start: u16 = random();
+        for offset in 0..=u16::MAX {
+            match self.try_insert(start.wrapping_add(offset), sender) {
+                Ok(id) => return Some(id),
+                Err(taken) => sender = taken,
+            }
+        }
+        None
+    }
+
+    /// register `sender` under `id`, giving it back if `id` is taken.
+    ///
+    /// Ids of queries that gave up waiting, e.g. timed out, are free again.
+    pub fn try_insert(
+        &self,
+        id: u16,
+        sender: oneshot::Sender<Vec<Answer>>,
+    ) -> Result<u16, oneshot::Sender<Vec<Answer>>> {
+        let mut shard = self.shard(id).lock().unwrap();
+        if shard.get(&id).is_some_and(|waiting| !waiting.is_closed()) {
+            return Err(sender);
+        }
+        shard.insert(id, sender);
+        Ok(id)
+    }
+
+    pub fn remove(&self, id: u16) -> Option<oneshot::Sender<Vec<Answer>>> {
+        self.shard(id).lock().unwrap().remove(&id)
+    }
+
+    #[cfg(test)]
+    pub fn contains(&self, id: u16) -> bool {
+        self.shard(id).lock().unwrap().contains_key(&id)
+    }
+
+    /// pass `answers` to the query of `id`, if it is still waiting
+    pub fn answer(&self, id: u16, answers: Vec<Answer>) {
+        if let Some(sender) = self.remove(id) {
+            let _ = sender.send(answers);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::sync::oneshot;
+
+    use super::{TaskMap, SHARDS};
+    use crate::{comm::Answer, protocol::PacketError};
+
+    #[tokio::test]
+    async fn test_task_map() {
+        let map = TaskMap::new();
+        let (sender, receiver) = oneshot::channel();
+        let id = map.register(sender).unwrap();
+        assert!(map.contains(id));
+        // the id is taken, also by clones
+        let (other, _) = oneshot::channel();
+        assert!(map.clone().try_insert(id, other).is_err());
+        // ids of the same shard are kept apart
+        let (neighbour, neighbour_receiver) = oneshot::channel();
+        let neighbour_id = id.wrapping_add(SHARDS as u16);
+        assert!(map.try_insert(neighbour_id, neighbour).is_ok());
+
+        map.answer(id, vec![Answer::Error(PacketError::ServFail)]);
+        assert!(!map.contains(id));
+        assert_eq!(receiver.await.unwrap().len(), 1);
+        assert!(map.contains(neighbour_id));
+
+        // ids of queries that gave up are taken over
+        drop(neighbour_receiver);
+        let (late, mut late_receiver) = oneshot::channel();
+        assert!(map.try_insert(neighbour_id, late).is_ok());
+        map.answer(neighbour_id, vec![]);
+        assert!(late_receiver.try_recv().unwrap().is_empty());
+        assert!(!map.contains(neighbour_id));
+    }
+
+    #[test]
+    fn test_register_full() {
+        let map = TaskMap::new();
+        let mut receivers = vec![];
+        for id in 0..=u16::MAX {
+            let (sender, receiver) = oneshot::channel();
+            assert!(map.try_insert(id, sender).is_ok());
+            receivers.push(receiver);
+        }
+        // all ids are in flight, the query fails rather than waiting for one
+        let (sender, _receiver) = oneshot::channel();
+        assert_eq!(map.register(sender), None);
+
+        // the only free id is found
+        drop(receivers.remove(4321));
+        let (sender, _receiver) = oneshot::channel();
+        assert_eq!(map.register(sender), Some(4321));
+    }
+}
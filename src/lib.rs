@@ -4,11 +4,32 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+/// blocking queries by their names
+pub mod blocklist;
+
+/// stub resolver for applications, asking an upstream directly
+pub mod client;
+
 /// DNS Resource Records caching
 pub mod cache;
 
 /// network communication manager
 pub mod comm;
 
+/// counters of the server, exported for Prometheus
+pub mod metrics;
+
 /// DNS protocol utilities
 pub mod protocol;
+
+/// log of the queries answered, for auditing
+pub mod querylog;
+
+/// iterative resolution from the root servers
+pub mod resolver;
+
+/// answering queries from serving services
+pub mod transaction;
+
+/// local records answered before forwarding
+pub mod zone;
@@ -4,85 +4,380 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-// TODO: refract into a clap application
 use std::{
     fs::File,
     io::BufReader,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    process::ExitCode,
     sync::Arc,
+    time::Duration,
 };
 
+use clap::Parser;
 use rustls_pemfile::{certs, pkcs8_private_keys};
 use tokio::{
     net::{TcpListener, UdpSocket},
     sync::mpsc,
+    task::JoinHandle,
 };
 use tokio_rustls::rustls::{Certificate, PrivateKey};
+use tokio_util::sync::CancellationToken;
 use tracing::instrument;
-use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{
+    fmt::{self, format::FmtSpan},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+};
 use tsein_dns::{
+    blocklist::{
+        Action, CompiledList, DomainList, Network, PatternList, RebindFilter, TunnelDetector,
+    },
     cache::DnsCache,
     comm::{
-        client::QuicForwarder, QuicService, Task, TcpService, TlsListener, TlsService, UdpService,
+        self,
+        client::{
+            DohForwarder, ForwardProtocol, LoadBalance, QuicForwarder, TcpForwarder, TlsForwarder,
+        },
+        Acl, DohService, Edns, QuicService, Rrl, Task, TcpService, TlsListener, TlsService,
+        TypePolicy, UdpService,
     },
+    metrics::{Metrics, MetricsService},
+    protocol::{Name, RRType},
+    querylog::{JsonLinesLogger, QueryLogger},
+    resolver::Resolver,
+    transaction::Transaction,
+    zone::Zone,
 };
 
-const CACHE_SIZE: u64 = 9192;
+/// zones allowed to resolve to private addresses
+static LOCAL_ZONES: &[&str] = &["localhost"];
+/// `ANY` queries allowed per second from each client, and in a burst
+const ANY_LIMIT: (u32, u32) = (5, 10);
+/// bits of IPv4 and IPv6 client addresses told to upstreams by `--client-subnet`, as RFC7871 recommends
+const CLIENT_SUBNET: (u8, u8) = (24, 56);
+
+/// serving addresses of the listeners, `None` disables the listener
+struct Listeners {
+    udp: Option<SocketAddr>,
+    tcp: Option<SocketAddr>,
+    tls: Option<SocketAddr>,
+    quic: Option<SocketAddr>,
+    doh: Option<SocketAddr>,
+}
+
+impl Listeners {
+    fn any(&self) -> bool {
+        self.udp.is_some() || self.tcp.is_some() || self.needs_tls()
+    }
+
+    fn needs_tls(&self) -> bool {
+        self.tls.is_some() || self.quic.is_some() || self.doh.is_some()
+    }
+}
 
-static KEY_PATH: &str = "secret/localhost+2-key.pem";
-static CERT_PATH: &str = "secret/localhost+2.pem";
+/// A DNS server supporting UDP, TCP, TLS, QUIC and HTTPS
+#[derive(Parser, Debug)]
+#[command(version, author)]
+struct Args {
+    /// address all listeners are bound to
+    #[arg(long, default_value_t = IpAddr::V4(Ipv4Addr::UNSPECIFIED))]
+    listen: IpAddr,
+    /// port of plain DNS over UDP
+    #[arg(long, default_value_t = 1053)]
+    udp_port: u16,
+    /// port of plain DNS over TCP
+    #[arg(long, default_value_t = 1053)]
+    tcp_port: u16,
+    /// port of DNS over TLS
+    #[arg(long, default_value_t = 1853)]
+    tls_port: u16,
+    /// port of DNS over QUIC
+    #[arg(long, default_value_t = 1853)]
+    quic_port: u16,
+    /// port of DNS over HTTPS
+    #[arg(long, default_value_t = 1443)]
+    doh_port: u16,
+    /// do not serve plain DNS over UDP
+    #[arg(long)]
+    no_udp: bool,
+    /// do not serve plain DNS over TCP
+    #[arg(long)]
+    no_tcp: bool,
+    /// do not serve DNS over TLS
+    #[arg(long)]
+    no_tls: bool,
+    /// do not serve DNS over QUIC
+    #[arg(long)]
+    no_quic: bool,
+    /// do not serve DNS over HTTPS
+    #[arg(long)]
+    no_doh: bool,
+    /// certificate chain of TLS, QUIC and DoH listeners, in PEM
+    #[arg(long, default_value = "secret/localhost+2.pem")]
+    cert: PathBuf,
+    /// private key of the certificate, in PKCS#8 PEM
+    #[arg(long, default_value = "secret/localhost+2-key.pem")]
+    key: PathBuf,
+    /// name the upstream is verified by
+    #[arg(long, default_value = "dns-unfiltered.adguard.com")]
+    upstream_domain: String,
+    /// address of the upstream, the port is ignored for `https`, which always uses 443
+    #[arg(long, default_value = "[2a10:50c0::1:ff]:853")]
+    upstream_addr: SocketAddr,
+    /// number of records cached at most
+    #[arg(long, default_value_t = 9192)]
+    cache_size: u64,
+    /// TCP and TLS connections served at most at the same time, more are closed once accepted
+    #[arg(long, default_value_t = 1024)]
+    max_connections: u64,
+    /// seconds a query may take in total, answered by SERVFAIL beyond it
+    #[arg(long, default_value_t = 10)]
+    query_budget: u64,
+    /// seconds a forwarded query is waited for, answered by SERVFAIL beyond it
+    #[arg(long, default_value_t = 5)]
+    forward_timeout: u64,
+    /// resolve from the root servers by itself, instead of forwarding to upstream
+    #[arg(long)]
+    iterative: bool,
+    /// protocol queries are forwarded by: `quic`, `tls`, `tcp` or `https`
+    #[arg(long, default_value = "quic")]
+    forward_protocol: ForwardProtocol,
+    /// seconds between keepalives on idle QUIC upstream connections, 0 disables them
+    #[arg(long, default_value_t = 15)]
+    quic_keep_alive: u64,
+    /// seconds after which idle QUIC upstream connections are dropped, 0 keeps them
+    #[arg(long, default_value_t = 0)]
+    quic_max_idle: u64,
+    /// times QUIC upstreams are tried, with growing pauses, before a query fails
+    #[arg(long, default_value_t = 3)]
+    quic_connect_attempts: u32,
+    /// network allowed to query in CIDR notation, may be repeated
+    #[arg(long)]
+    allow: Vec<Network>,
+    /// network refused in CIDR notation, may be repeated
+    #[arg(long)]
+    deny: Vec<Network>,
+    /// only serve clients in `--allow`, rather than all clients not in `--deny`
+    #[arg(long)]
+    deny_by_default: bool,
+    /// answer refused UDP clients by REFUSED, rather than ignoring them
+    #[arg(long)]
+    refuse_udp: bool,
+    /// answer UDP queries without a valid server cookie by TC, moving their clients to TCP
+    #[arg(long)]
+    require_cookies: bool,
+    /// identical UDP responses per second to a /24 or /64 network, 0 for no limit
+    #[arg(long, default_value_t = 0)]
+    rrl_rate: u32,
+    /// seconds a flood of UDP responses is remembered by rate limiting
+    #[arg(long, default_value_t = 15)]
+    rrl_window: u64,
+    /// every `rrl_slip`-th rate limited response is sent truncated rather than dropped, 0 drops all
+    #[arg(long, default_value_t = 2)]
+    rrl_slip: u32,
+    /// queries each client may send per second, 0 for no limit
+    #[arg(long, default_value_t = 0)]
+    client_rate: u32,
+    /// queries each client may send at once, beyond its rate
+    #[arg(long, default_value_t = 100)]
+    client_burst: u32,
+    /// refuse floods of high entropy names under a domain, which are only logged otherwise
+    #[arg(long)]
+    refuse_tunnels: bool,
+    /// tell upstreams the /24 or /56 network of each client, answers are cached apart for each
+    #[arg(long)]
+    client_subnet: bool,
+    /// add the address hints of SVCB and HTTPS answers to the additional section
+    #[arg(long)]
+    svcb_hints: bool,
+    /// domain appended to single-label names that do not exist, may be repeated
+    #[arg(long = "search")]
+    search_list: Vec<Name>,
+    /// names matching the glob are answered by the action instead of upstream, may be repeated,
+    /// e.g. `*.doubleclick.*=nxdomain` or `portal.example=redirect 192.0.2.1`
+    #[arg(long = "rule", value_name = "GLOB=ACTION", value_parser = parse_rule)]
+    rules: Vec<(String, Action)>,
+    /// local records answered authoritatively, lines like `nas.home.arpa A 300 192.168.1.10`
+    #[arg(long)]
+    zone_file: Option<PathBuf>,
+    /// blocked names, one per line, answered by unspecified addresses along with the names under them
+    #[arg(long)]
+    blocklist: Option<PathBuf>,
+    /// network of the secondaries allowed to transfer zones in CIDR notation, may be repeated
+    #[arg(long = "secondary")]
+    secondaries: Vec<Network>,
+    /// port metrics are exported on for Prometheus, at `/metrics`, disabled unless given
+    #[arg(long)]
+    metrics_port: Option<u16>,
+    /// file the queries answered are appended to as JSON lines, disabled unless given
+    #[arg(long)]
+    query_log: Option<PathBuf>,
+    /// blocklist compiled by `--compile-blocklist`, names under its entries are answered by NXDOMAIN
+    #[arg(long)]
+    compiled_blocklist: Option<PathBuf>,
+    /// compile the blocklist at `<IN>`, one name per line, into `<OUT>` and exit, without serving
+    #[arg(long, num_args = 2, value_names = ["IN", "OUT"])]
+    compile_blocklist: Option<Vec<PathBuf>>,
+}
 
-fn load_certs(path: &str) -> std::io::Result<Vec<Certificate>> {
+impl Args {
+    /// response rate limiting of UDP, if `--rrl-rate` is set
+    fn rrl(&self) -> Option<Arc<Rrl>> {
+        let rrl = match self.rrl_rate {
+            0 => return None,
+            rate => Rrl::new(rate),
+        };
+        let rrl = rrl
+            .with_window(Duration::from_secs(self.rrl_window))
+            .with_slip(self.rrl_slip);
+        Some(Arc::new(rrl))
+    }
+
+    /// the EDNS options of forwarded queries
+    fn edns(&self) -> Edns {
+        let (v4_prefix, v6_prefix) = CLIENT_SUBNET;
+        match self.client_subnet {
+            true => Edns::new().with_client_subnet(v4_prefix, v6_prefix),
+            false => Edns::new(),
+        }
+    }
+
+    fn acl(&self) -> Acl {
+        let acl = match self.deny_by_default {
+            true => Acl::deny_by_default(),
+            false => Acl::allow_by_default(),
+        };
+        let acl = self
+            .allow
+            .iter()
+            .fold(acl, |acl, net| acl.with_allowed(*net));
+        let acl = self.deny.iter().fold(acl, |acl, net| acl.with_denied(*net));
+        acl.with_udp_refused(self.refuse_udp)
+    }
+
+    /// the blocklist mapped from `--compiled-blocklist`, if given
+    fn compiled_blocklist(&self) -> std::io::Result<Option<CompiledList>> {
+        let Some(path) = &self.compiled_blocklist else {
+            return Ok(None);
+        };
+        let list = CompiledList::open(path, Action::NxDomain)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        tracing::info!(
+            "loaded {} names from blocklist {}",
+            list.len(),
+            path.display()
+        );
+        Ok(Some(list))
+    }
+
+    /// the glob patterns of `--rule`
+    fn rules(&self) -> PatternList {
+        self.rules
+            .iter()
+            .fold(PatternList::builder(), |rules, (pattern, action)| {
+                rules.glob(pattern, *action)
+            })
+            .build()
+            .expect("globs are not compiled")
+    }
+
+    fn listeners(&self) -> Listeners {
+        let on = |port, disabled: bool| (!disabled).then(|| SocketAddr::new(self.listen, port));
+        Listeners {
+            udp: on(self.udp_port, self.no_udp),
+            tcp: on(self.tcp_port, self.no_tcp),
+            tls: on(self.tls_port, self.no_tls),
+            quic: on(self.quic_port, self.no_quic),
+            doh: on(self.doh_port, self.no_doh),
+        }
+    }
+}
+
+/// parse a `--rule` like `*.doubleclick.*=nxdomain`
+fn parse_rule(rule: &str) -> Result<(String, Action), String> {
+    let (pattern, action) = rule
+        .split_once('=')
+        .ok_or_else(|| format!("no action in rule {}", rule))?;
+    let action = action.parse::<Action>().map_err(|e| e.to_string())?;
+    Ok((pattern.to_string(), action))
+}
+
+fn load_certs(path: &Path) -> std::io::Result<Vec<Certificate>> {
     certs(&mut BufReader::new(File::open(path)?))
         .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid cert"))
         .map(|mut certs| certs.drain(..).map(Certificate).collect())
 }
 
-fn load_keys(path: &str) -> std::io::Result<Vec<PrivateKey>> {
+fn load_keys(path: &Path) -> std::io::Result<Vec<PrivateKey>> {
     pkcs8_private_keys(&mut BufReader::new(File::open(path)?))
         .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "invalid key"))
         .map(|mut keys| keys.drain(..).map(PrivateKey).collect())
 }
 
-async fn transaction(mut tasks: mpsc::UnboundedReceiver<Task>, cache: DnsCache) {
-    tracing::info!("initiated transaction layer");
-    let lookups = futures::stream::FuturesUnordered::new();
-    while let Some(task) = tasks.recv().await {
-        tracing::debug!("received task");
-
-        match task {
-            Task::Query(query, ans_sender) => {
-                tracing::debug!("looking up local cache for query: {}", query.get_name());
-                let mut c = cache.clone();
-                let lookup = tokio::spawn(async move {
-                    let name = query.get_name();
-                    let answers = c.get(query).await;
-                    for ans in answers.into_iter() {
-                        let _ = ans_sender.send(ans);
-                    }
-                    tracing::debug!("transaction on query {} successful!", name);
-                });
-                lookups.push(lookup);
-            }
-        };
-    }
-    for lookup in lookups {
-        let _ = tokio::join!(lookup);
+/// compile the blocklist at `input` into `output`, returning the number of names in it
+///
+/// The list is written beside `output` and renamed over it, so servers mapping the old one are not broken.
+fn compile_blocklist(input: &Path, output: &Path) -> std::io::Result<usize> {
+    let list = DomainList::load(input, Action::NxDomain)?;
+    let mut temp = output.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    list.compile(std::io::BufWriter::new(File::create(&temp)?))?;
+    std::fs::rename(&temp, output)?;
+    Ok(list.len())
+}
+
+/// server config of TLS, QUIC and DoH listeners, by the key and certificate on disk
+fn load_server_config(cert: &Path, key: &Path) -> std::io::Result<rustls::ServerConfig> {
+    let invalid = |e: String| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
+    let mut keys = load_keys(key)
+        .map_err(|e| invalid(format!("cannot load keys from {}: {}", key.display(), e)))?;
+    if keys.is_empty() {
+        return Err(invalid(format!("no key in {}", key.display())));
     }
+    let certs = load_certs(cert)
+        .map_err(|e| invalid(format!("cannot load certs from {}: {}", cert.display(), e)))?;
+    let mut serv_config = rustls::ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, keys.remove(0))
+        .map_err(|e| invalid(e.to_string()))?;
+    serv_config.alpn_protocols = vec![
+        Vec::from(&b"dot"[..]),
+        Vec::from(&b"doq"[..]),
+        Vec::from(&b"doq-i11"[..]),
+    ];
+    Ok(serv_config)
 }
 
-fn main() {
-    // init logger
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    // init logger, queries are logged with their RCODE and timing as their spans close
+    let layer = fmt::layer().with_span_events(FmtSpan::CLOSE);
     if let Ok(local_timer) = fmt::time::OffsetTime::local_rfc_3339() {
         tracing_subscriber::registry()
-            .with(fmt::layer().with_timer(local_timer))
+            .with(layer.with_timer(local_timer))
             .init();
     } else {
         let sys_timer = fmt::time::SystemTime;
         tracing_subscriber::registry()
-            .with(fmt::layer().with_timer(sys_timer))
+            .with(layer.with_timer(sys_timer))
             .init();
     }
+    if let Some([input, output]) = args.compile_blocklist.as_deref() {
+        return match compile_blocklist(input, output) {
+            Ok(len) => {
+                tracing::info!("compiled {} names into {}", len, output.display());
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                tracing::error!("cannot compile blocklist {}: {}", input.display(), e);
+                ExitCode::FAILURE
+            }
+        };
+    }
     tracing::info!(
         "Starting {}, version {}, author {}",
         env!("CARGO_PKG_NAME"),
@@ -91,33 +386,16 @@ fn main() {
     );
     tracing::info!("initializing tokio runtime");
 
-    let upstream_domain: &str = "dns-unfiltered.adguard.com";
-    let upstream_addr: SocketAddr = SocketAddr::new(
-        IpAddr::from(Ipv6Addr::new(0x2a10, 0x50c0, 0, 0, 0, 0, 0x1, 0xff)),
-        853,
-    );
-
-    run(upstream_domain, upstream_addr);
+    run(args)
 }
 
 #[instrument]
 #[tokio::main]
-async fn run(upstream_domain: &'static str, upstream_addr: SocketAddr) {
-    // load ssl keys and certs
-    let mut keys = match load_keys(KEY_PATH) {
-        Ok(keys) => keys,
-        Err(e) => {
-            tracing::error!("cannot load keys from {}: {}", KEY_PATH, e);
-            return;
-        }
-    };
-    let certs = match load_certs(CERT_PATH) {
-        Ok(certs) => certs,
-        Err(e) => {
-            tracing::error!("cannot load certs from {}: {}", CERT_PATH, e);
-            return;
-        }
-    };
+async fn run(args: Args) -> ExitCode {
+    let listeners = args.listeners();
+    comm::set_forward_timeout(Duration::from_secs(args.forward_timeout));
+    let upstream_domain = args.upstream_domain.as_str();
+    let upstream_addr = args.upstream_addr;
 
     let mut roots = rustls::RootCertStore::empty();
     for cert in
@@ -126,31 +404,30 @@ async fn run(upstream_domain: &'static str, upstream_addr: SocketAddr) {
         roots.add(&Certificate(cert.0)).unwrap();
     }
 
-    let mut serv_config = match rustls::ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(certs, keys.remove(0))
-    {
-        Ok(cfg) => cfg,
-        Err(e) => {
-            tracing::error!("cannot generate server config: {}", e);
-            return;
+    // only TLS, QUIC and DoH listeners need the certificate
+    let serv_config = if listeners.needs_tls() {
+        match load_server_config(&args.cert, &args.key) {
+            Ok(cfg) => Some(Arc::new(cfg)),
+            Err(e) => {
+                tracing::error!("cannot generate server config: {}", e);
+                return ExitCode::FAILURE;
+            }
         }
+    } else {
+        None
     };
 
-    serv_config.alpn_protocols = vec![
-        Vec::from(&b"dot"[..]),
-        Vec::from(&b"doq"[..]),
-        Vec::from(&b"doq-i11"[..]),
-    ];
-    let serv_config = Arc::new(serv_config);
-
-    // init UDP serving ports
-    tracing::info!("binding port 1053 as udp serving port");
-    let udp_serve = UdpSocket::bind("0.0.0.0:1053").await.unwrap();
-    let forward = UdpSocket::bind("0.0.0.0:1054").await.unwrap();
-
-    let udp_server = Arc::new(UdpService::new(udp_serve, forward));
+    let secondaries = args.secondaries.clone();
+    let (any_rate, any_burst) = ANY_LIMIT;
+    let policy = TypePolicy::new()
+        .with_allowed(RRType::Axfr, secondaries.clone())
+        .with_allowed(RRType::Ixfr, secondaries)
+        .with_limit(RRType::Any, any_rate, any_burst);
+    let policy = match args.client_rate {
+        0 => policy,
+        rate => policy.with_client_limit(rate, args.client_burst),
+    };
+    let policy = Arc::new(policy);
 
     // tasks received from downstream
     let (task_sender, task_recv) = mpsc::unbounded_channel();
@@ -159,8 +436,13 @@ async fn run(upstream_domain: &'static str, upstream_addr: SocketAddr) {
     let (rec_sender, rec_recv) = mpsc::unbounded_channel();
 
     // init cache
-    tracing::info!("initialize cache with size: {}", CACHE_SIZE);
-    let cache = DnsCache::new(CACHE_SIZE, rec_sender);
+    tracing::info!("initialize cache with size: {}", args.cache_size);
+    let mut cache = DnsCache::new(args.cache_size, rec_sender);
+    if args.client_subnet {
+        let (v4_prefix, v6_prefix) = CLIENT_SUBNET;
+        cache = cache.with_client_subnet(v4_prefix, v6_prefix);
+    }
+    let metrics = Arc::new(Metrics::new().with_cache(cache.clone()));
 
     // deprecated udp forward service
     // tracing::info!("init UDP forwarding...");
@@ -169,77 +451,631 @@ async fn run(upstream_domain: &'static str, upstream_addr: SocketAddr) {
     // forwarder.run_forward(rec_recv).await
     // });
 
-    tracing::info!("init UDP serving...");
-    let udp_task_sender = task_sender.clone();
-    let udp_serving = tokio::spawn(async move {
-        tracing::info!("initiated udp server");
-        udp_server.clone().run_udp(udp_task_sender).await
-    });
-
-    tracing::info!("binding port 1053 as tcp serving port");
-    let tcp_serve = TcpListener::bind("0.0.0.0:1053").await.unwrap();
-    let tcp_server = TcpService::new(tcp_serve, task_sender.clone(), CACHE_SIZE);
-    tracing::info!("init TCP serving...");
-    let tcp_serving = tokio::spawn(async move {
-        tracing::info!("initiated tcp server");
-        tcp_server.run().await
-    });
-
-    tracing::info!("binding port 1853 as tls serving port");
-    let tls_underlay = TcpListener::bind("0.0.0.0:1853").await.unwrap();
-    let tls_serve = TlsListener::new(tls_underlay, serv_config.clone());
-    let tls_server = TlsService::new(tls_serve, task_sender.clone(), CACHE_SIZE);
-    let tls_serving = tokio::spawn(async move {
-        tracing::info!("initiated tls server");
-        tls_server.run().await
-    });
-
-    tracing::info!("binding port 1853 as quic serving port");
-    let quic_serv = SocketAddr::new(IpAddr::from(Ipv4Addr::UNSPECIFIED), 1853);
-    let quic_config = quinn::ServerConfig::with_crypto(serv_config);
-    let (endpoint, incoming) = quinn::Endpoint::server(quic_config.clone(), quic_serv).unwrap();
-    let quic_server = QuicService::new(incoming, task_sender);
-    let quic_serving = tokio::spawn(async move {
-        tracing::info!(
-            "starting service on: quic://{}",
-            endpoint.local_addr().unwrap()
-        );
-        quic_server.run().await
-    });
+    let (query_log, query_logging) = match &args.query_log {
+        Some(path) => match JsonLinesLogger::open(path).await {
+            Ok((logger, writing)) => {
+                tracing::info!("logging queries to {}", path.display());
+                let logger: Arc<dyn QueryLogger> = Arc::new(logger);
+                (Some(logger), Some(writing))
+            }
+            Err(e) => {
+                tracing::error!("cannot open query log {}: {}", path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => (None, None),
+    };
+
+    // listeners stop on SIGINT or SIGTERM, answering the queries in flight first
+    let shutdown = CancellationToken::new();
+    tokio::spawn(stop_on_signal(shutdown.clone()));
+
+    let serving = match serve(
+        &listeners,
+        task_sender,
+        policy,
+        Arc::new(args.acl()),
+        serv_config,
+        args.max_connections,
+        args.require_cookies,
+        args.rrl(),
+        query_log,
+        metrics.clone(),
+        shutdown.clone(),
+    )
+    .await
+    {
+        Ok(serving) => serving,
+        Err(e) => {
+            tracing::error!("cannot start listeners: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    if let Some(port) = args.metrics_port {
+        let listener = match TcpListener::bind((args.listen, port)).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                tracing::error!("cannot export metrics on port {}: {}", port, e);
+                return ExitCode::FAILURE;
+            }
+        };
+        let exporter = MetricsService::new(listener, metrics.clone()).with_shutdown(shutdown);
+        tokio::spawn(exporter.run());
+    }
 
-    tracing::info!("binding port 1854 as quic forwarding port");
-    let forward = SocketAddr::new(IpAddr::from(Ipv6Addr::UNSPECIFIED), 1854);
-    let quic_config = rustls::ClientConfig::builder()
+    let client_config = rustls::ClientConfig::builder()
         .with_safe_defaults()
         .with_root_certificates(roots)
         .with_no_client_auth();
 
-    let mut endpoint = quinn::Endpoint::client(forward).unwrap();
-    endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(quic_config)));
-    let forwarder = QuicForwarder::try_new(rec_recv, endpoint, upstream_domain, upstream_addr)
-        .await
-        .unwrap();
-    tracing::info!("init forward");
-    let forwarding = tokio::spawn(forwarder.run());
+    let forwarding = if args.iterative {
+        tracing::info!("init iterative resolver");
+        tokio::spawn(Resolver::new(rec_recv).run())
+    } else {
+        match args.forward_protocol {
+            ForwardProtocol::Quic => {
+                tracing::info!("binding port 1854 as quic forwarding port");
+                let forward = SocketAddr::new(IpAddr::from(Ipv6Addr::UNSPECIFIED), 1854);
+                let mut endpoint = quinn::Endpoint::client(forward).unwrap();
+                let mut config = quinn::ClientConfig::new(Arc::new(client_config));
+                if args.quic_keep_alive > 0 {
+                    let mut transport = quinn::TransportConfig::default();
+                    transport.keep_alive_interval(Some(Duration::from_secs(args.quic_keep_alive)));
+                    config.transport = Arc::new(transport);
+                }
+                endpoint.set_default_client_config(config);
+                let upstreams = vec![(upstream_domain.to_string(), upstream_addr)];
+                let mut forwarder = QuicForwarder::try_new(
+                    rec_recv,
+                    endpoint,
+                    upstreams,
+                    LoadBalance::FirstHealthy,
+                )
+                .await
+                .unwrap()
+                .with_connect_attempts(args.quic_connect_attempts);
+                if args.quic_max_idle > 0 {
+                    forwarder = forwarder.with_max_idle(Duration::from_secs(args.quic_max_idle));
+                }
+                forwarder = forwarder.with_metrics(metrics).with_edns(args.edns());
+                tracing::info!("init forward");
+                tokio::spawn(forwarder.run())
+            }
+            ForwardProtocol::Tls => {
+                let forwarder = TlsForwarder::try_new(
+                    rec_recv,
+                    Arc::new(client_config),
+                    upstream_domain,
+                    upstream_addr,
+                )
+                .await
+                .unwrap()
+                .with_edns(args.edns());
+                tracing::info!("init forward");
+                tokio::spawn(forwarder.run())
+            }
+            ForwardProtocol::Tcp => {
+                let forwarder = TcpForwarder::try_new(rec_recv, upstream_addr)
+                    .await
+                    .unwrap()
+                    .with_edns(args.edns());
+                tracing::info!("init forward");
+                tokio::spawn(forwarder.run())
+            }
+            ForwardProtocol::Https => {
+                let url = format!("https://{}/dns-query", upstream_domain);
+                let upstream_addr = SocketAddr::new(upstream_addr.ip(), 443);
+                let forwarder =
+                    DohForwarder::try_new(rec_recv, Arc::new(client_config), &url, upstream_addr)
+                        .await
+                        .unwrap()
+                        .with_edns(args.edns());
+                tracing::info!("init forward");
+                tokio::spawn(forwarder.run())
+            }
+        }
+    };
 
     tracing::info!("init transaction");
-    let transaction = tokio::spawn(async move {
-        transaction(task_recv, cache).await;
-    });
-
-    let (f, s, do_tcp, do_tls, do_quic, t) = tokio::join!(
-        forwarding,
-        udp_serving,
-        tcp_serving,
-        tls_serving,
-        quic_serving,
-        transaction
-    );
-    f.unwrap().unwrap();
-    s.unwrap().unwrap();
-    do_tcp.unwrap();
-    do_quic.unwrap();
-    do_tls.unwrap();
-    t.unwrap();
+    let local_zones = LOCAL_ZONES
+        .iter()
+        .map(|zone| Name::try_from(*zone).unwrap())
+        .collect();
+    let mut transaction = Transaction::new(cache)
+        .with_search_list(args.search_list.clone())
+        .with_patterns(args.rules())
+        .with_rebind_filter(RebindFilter::default().with_local_zones(local_zones))
+        .with_tunnel_detector(TunnelDetector::new().with_refuse(args.refuse_tunnels))
+        .with_svcb_hints(args.svcb_hints)
+        .with_budget(Duration::from_secs(args.query_budget));
+    if let Some(path) = &args.zone_file {
+        match Zone::load(path) {
+            Ok(zone) => {
+                tracing::info!(
+                    "loaded {} local records from {}",
+                    zone.len(),
+                    path.display()
+                );
+                transaction = transaction.with_zone(zone);
+            }
+            Err(e) => {
+                tracing::error!("cannot load zone {}: {}", path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    if let Some(path) = &args.blocklist {
+        match DomainList::load(path, Action::Sinkhole) {
+            Ok(list) => {
+                tracing::info!(
+                    "loaded {} names from blocklist {}",
+                    list.len(),
+                    path.display()
+                );
+                transaction = transaction.with_domain_list(list);
+            }
+            Err(e) => {
+                tracing::error!("cannot load blocklist {}: {}", path.display(), e);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    match args.compiled_blocklist() {
+        Ok(Some(list)) => transaction = transaction.with_compiled_list(list),
+        Ok(None) => {}
+        Err(e) => {
+            tracing::error!("cannot load blocklist {}", e);
+            return ExitCode::FAILURE;
+        }
+    }
+    let transaction = tokio::spawn(transaction.run(task_recv));
+
+    let serving = serving.into_iter().map(|(_, _, handle)| handle);
+    for s in futures::future::join_all(serving).await {
+        s.unwrap();
+    }
+    // the listeners are drained, nothing is left to answer
+    forwarding.abort();
+    transaction.abort();
+    // the log is written out once the last query logged is dropped
+    if let Some(writing) = query_logging {
+        if tokio::time::timeout(Duration::from_secs(1), writing)
+            .await
+            .is_err()
+        {
+            tracing::warn!("query log may miss the last queries");
+        }
+    }
     tracing::info!("quit service");
+    ExitCode::SUCCESS
+}
+
+/// cancel `shutdown` on SIGINT or SIGTERM
+async fn stop_on_signal(shutdown: CancellationToken) {
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                term.recv().await;
+            }
+            Err(e) => {
+                tracing::warn!("cannot listen to SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+    tracing::info!("shutting down, waiting for queries in flight");
+    shutdown.cancel();
+}
+
+/// bind and spawn the enabled listeners,
+/// returns their protocols, local addresses and serving tasks.
+///
+/// `tls` must be given if TLS, QUIC or DoH listeners are enabled.
+/// The listeners return once `shutdown` is cancelled and their queries are answered.
+#[allow(clippy::too_many_arguments)]
+async fn serve(
+    listeners: &Listeners,
+    tasks: mpsc::UnboundedSender<Task>,
+    policy: Arc<TypePolicy>,
+    acl: Arc<Acl>,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    max_connections: u64,
+    require_cookies: bool,
+    rrl: Option<Arc<Rrl>>,
+    query_log: Option<Arc<dyn QueryLogger>>,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+) -> std::io::Result<Vec<(&'static str, SocketAddr, JoinHandle<()>)>> {
+    let invalid = |msg| std::io::Error::new(std::io::ErrorKind::InvalidInput, msg);
+    if !listeners.any() {
+        return Err(invalid("all listeners are disabled"));
+    }
+    if listeners.needs_tls() && tls.is_none() {
+        return Err(invalid("TLS, QUIC and DoH listeners need a server config"));
+    }
+
+    let mut serving = vec![];
+    if let Some(addr) = listeners.udp {
+        tracing::info!("binding {} as udp serving port", addr);
+        let udp_serve = UdpSocket::bind(addr).await?;
+        // the socket of the deprecated udp forwarder, any port does
+        let forward = UdpSocket::bind(SocketAddr::new(addr.ip(), 0)).await?;
+        let local = udp_serve.local_addr()?;
+        let udp_server = UdpService::new(udp_serve, forward)
+            .with_policy(policy.clone())
+            .with_acl(acl.clone())
+            .with_cookies_required(require_cookies)
+            .with_metrics(metrics.clone())
+            .with_shutdown(shutdown.clone());
+        let udp_server = match rrl {
+            Some(rrl) => udp_server.with_rrl(rrl),
+            None => udp_server,
+        };
+        let udp_server = match query_log.clone() {
+            Some(logger) => udp_server.with_query_log(logger),
+            None => udp_server,
+        };
+        let udp_server = Arc::new(udp_server);
+        let tasks = tasks.clone();
+        let udp_serving = tokio::spawn(async move {
+            tracing::info!("initiated udp server");
+            if let Err(e) = udp_server.run_udp(tasks).await {
+                tracing::error!("udp server quit due to {}", e);
+            }
+        });
+        serving.push(("udp", local, udp_serving));
+    }
+
+    if let Some(addr) = listeners.tcp {
+        tracing::info!("binding {} as tcp serving port", addr);
+        let tcp_serve = TcpListener::bind(addr).await?;
+        let local = tcp_serve.local_addr()?;
+        let tcp_server = TcpService::new(tcp_serve, tasks.clone(), max_connections)
+            .with_policy(policy.clone())
+            .with_acl(acl.clone())
+            .with_metrics(metrics.clone())
+            .with_shutdown(shutdown.clone());
+        let tcp_server = match query_log.clone() {
+            Some(logger) => tcp_server.with_query_log(logger),
+            None => tcp_server,
+        };
+        let tcp_serving = tokio::spawn(async move {
+            tracing::info!("initiated tcp server");
+            tcp_server.run().await
+        });
+        serving.push(("tcp", local, tcp_serving));
+    }
+
+    if let (Some(addr), Some(config)) = (listeners.tls, tls.clone()) {
+        tracing::info!("binding {} as tls serving port", addr);
+        let tls_underlay = TcpListener::bind(addr).await?;
+        let local = tls_underlay.local_addr()?;
+        let tls_serve = TlsListener::new(tls_underlay, config);
+        let tls_server = TlsService::new(tls_serve, tasks.clone(), max_connections)
+            .with_policy(policy.clone())
+            .with_acl(acl.clone())
+            .with_metrics(metrics.clone())
+            .with_shutdown(shutdown.clone());
+        let tls_server = match query_log.clone() {
+            Some(logger) => tls_server.with_query_log(logger),
+            None => tls_server,
+        };
+        let tls_serving = tokio::spawn(async move {
+            tracing::info!("initiated tls server");
+            tls_server.run().await
+        });
+        serving.push(("tls", local, tls_serving));
+    }
+
+    if let (Some(addr), Some(config)) = (listeners.doh, tls.clone()) {
+        tracing::info!("binding {} as doh serving port", addr);
+        let doh_serve = TcpListener::bind(addr).await?;
+        let local = doh_serve.local_addr()?;
+        // browsers negotiate HTTP by ALPN, not the protocols of DoT and DoQ
+        let mut config = (*config).clone();
+        config.alpn_protocols = vec![Vec::from(&b"h2"[..]), Vec::from(&b"http/1.1"[..])];
+        let doh_server = DohService::new(doh_serve, tasks.clone())
+            .with_tls(Arc::new(config))
+            .with_policy(policy.clone())
+            .with_acl(acl.clone())
+            .with_metrics(metrics.clone())
+            .with_shutdown(shutdown.clone());
+        let doh_server = match query_log.clone() {
+            Some(logger) => doh_server.with_query_log(logger),
+            None => doh_server,
+        };
+        let doh_serving = tokio::spawn(doh_server.run());
+        serving.push(("doh", local, doh_serving));
+    }
+
+    if let (Some(addr), Some(config)) = (listeners.quic, tls) {
+        tracing::info!("binding {} as quic serving port", addr);
+        let quic_config = quinn::ServerConfig::with_crypto(config);
+        let (endpoint, incoming) = quinn::Endpoint::server(quic_config, addr)?;
+        let local = endpoint.local_addr()?;
+        let quic_server = QuicService::new(incoming, tasks)
+            .with_policy(policy)
+            .with_acl(acl)
+            .with_metrics(metrics)
+            .with_shutdown(shutdown);
+        let quic_server = match query_log {
+            Some(logger) => quic_server.with_query_log(logger),
+            None => quic_server,
+        };
+        let quic_serving = tokio::spawn(async move {
+            tracing::info!("starting service on: quic://{}", local);
+            // the endpoint stops serving once dropped
+            let _endpoint = endpoint;
+            quic_server.run().await
+        });
+        serving.push(("quic", local, quic_serving));
+    }
+    Ok(serving)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{path::PathBuf, sync::Arc};
+
+    use clap::Parser;
+    use tokio::sync::mpsc;
+    use tokio_util::sync::CancellationToken;
+    use tsein_dns::{blocklist::Action, comm::client::ForwardProtocol, protocol::Name};
+
+    use super::{compile_blocklist, serve, Args, Listeners};
+
+    #[test]
+    fn test_args() {
+        // the defaults are what used to be hardcoded
+        let args = Args::try_parse_from(["tsein-dns"]).unwrap();
+        let listeners = args.listeners();
+        assert_eq!(listeners.udp, Some("0.0.0.0:1053".parse().unwrap()));
+        assert_eq!(listeners.tcp, Some("0.0.0.0:1053".parse().unwrap()));
+        assert_eq!(listeners.tls, Some("0.0.0.0:1853".parse().unwrap()));
+        assert_eq!(listeners.quic, Some("0.0.0.0:1853".parse().unwrap()));
+        assert_eq!(listeners.doh, Some("0.0.0.0:1443".parse().unwrap()));
+        assert_eq!(args.forward_protocol, ForwardProtocol::Quic);
+        assert_eq!(args.cache_size, 9192);
+        assert_eq!(args.max_connections, 1024);
+        assert_eq!(args.query_budget, 10);
+        assert_eq!(args.forward_timeout, 5);
+        assert!(!args.refuse_tunnels);
+        assert!(!args.client_subnet);
+        assert!(!args.svcb_hints);
+        assert!(!args.require_cookies);
+        assert!(args.rrl().is_none());
+        assert_eq!(args.quic_keep_alive, 15);
+        assert_eq!(args.quic_max_idle, 0);
+        assert_eq!(args.quic_connect_attempts, 3);
+        assert_eq!(args.client_rate, 0);
+        assert_eq!(args.metrics_port, None);
+        assert_eq!(args.query_log, None);
+        assert_eq!(args.compile_blocklist, None);
+        assert!(args.compiled_blocklist().unwrap().is_none());
+        assert!(!args.iterative);
+        assert!(args.search_list.is_empty());
+        assert!(args.rules().is_empty());
+        assert_eq!(args.zone_file, None);
+        assert_eq!(args.blocklist, None);
+        assert!(args.secondaries.is_empty());
+
+        let args = Args::try_parse_from([
+            "tsein-dns",
+            "--listen",
+            "::1",
+            "--tcp-port",
+            "53",
+            "--upstream-addr",
+            "192.0.2.53:853",
+            "--forward-protocol",
+            "TLS",
+            "--max-connections",
+            "16",
+        ])
+        .unwrap();
+        let listeners = args.listeners();
+        assert_eq!(listeners.tcp, Some("[::1]:53".parse().unwrap()));
+        assert_eq!(listeners.udp, Some("[::1]:1053".parse().unwrap()));
+        assert_eq!(args.upstream_addr, "192.0.2.53:853".parse().unwrap());
+        assert_eq!(args.forward_protocol, ForwardProtocol::Tls);
+        // connections are limited apart from the cache
+        assert_eq!(args.max_connections, 16);
+        assert_eq!(args.cache_size, 9192);
+
+        // listeners are disabled one by one
+        let args = Args::try_parse_from(["tsein-dns", "--no-tls", "--no-quic", "--no-doh"]);
+        let listeners = args.unwrap().listeners();
+        assert_eq!(listeners.udp, Some("0.0.0.0:1053".parse().unwrap()));
+        assert_eq!(listeners.tcp, Some("0.0.0.0:1053".parse().unwrap()));
+        assert!(!listeners.needs_tls());
+        let args = Args::try_parse_from(["tsein-dns", "--no-udp", "--no-tcp", "--no-doh"]);
+        let listeners = args.unwrap().listeners();
+        assert_eq!(
+            (listeners.udp, listeners.tcp, listeners.doh),
+            (None, None, None)
+        );
+        assert_eq!(listeners.tls, Some("0.0.0.0:1853".parse().unwrap()));
+
+        let args = Args::try_parse_from([
+            "tsein-dns",
+            "--iterative",
+            "--search",
+            "home.arpa",
+            "--search",
+            "example.com",
+            "--rule",
+            "*.doubleclick.*=nxdomain",
+            "--rule",
+            "portal.example=redirect 192.0.2.1",
+            "--zone-file",
+            "home.zone",
+            "--blocklist",
+            "hosts.txt",
+            "--secondary",
+            "192.0.2.0/24",
+        ])
+        .unwrap();
+        assert!(args.iterative);
+        let search = ["home.arpa", "example.com"].map(|name| Name::try_from(name).unwrap());
+        assert_eq!(args.search_list, search);
+        let rules = args.rules();
+        let check = |name| rules.check(&Name::try_from(name).unwrap());
+        assert_eq!(check("ad.doubleclick.net"), Some(Action::NxDomain));
+        assert_eq!(
+            check("portal.example"),
+            Some(Action::Redirect("192.0.2.1".parse().unwrap()))
+        );
+        assert_eq!(check("example.com"), None);
+        assert_eq!(args.zone_file, Some(PathBuf::from("home.zone")));
+        assert_eq!(args.blocklist, Some(PathBuf::from("hosts.txt")));
+        assert_eq!(args.secondaries, ["192.0.2.0/24".parse().unwrap()]);
+        assert!(Args::try_parse_from(["tsein-dns", "--rule", "*.doubleclick.*"]).is_err());
+        assert!(Args::try_parse_from(["tsein-dns", "--rule", "ads.*=block"]).is_err());
+
+        let args = Args::try_parse_from(["tsein-dns", "--rrl-rate", "5", "--rrl-slip", "0"]);
+        assert!(args.unwrap().rrl().is_some());
+
+        assert!(Args::try_parse_from(["tsein-dns", "--forward-protocol", "udp"]).is_err());
+
+        let args = Args::try_parse_from([
+            "tsein-dns",
+            "--deny-by-default",
+            "--allow",
+            "192.0.2.0/24",
+            "--allow",
+            "2001:db8::/32",
+            "--deny",
+            "192.0.2.1",
+        ])
+        .unwrap();
+        let acl = args.acl();
+        assert!(acl.is_allowed("192.0.2.2".parse().unwrap()));
+        assert!(acl.is_allowed("2001:db8::1".parse().unwrap()));
+        assert!(!acl.is_allowed("192.0.2.1".parse().unwrap()));
+        assert!(!acl.is_allowed("198.51.100.1".parse().unwrap()));
+        assert!(Args::try_parse_from(["tsein-dns", "--allow", "192.0.2.0/33"]).is_err());
+
+        let args =
+            Args::try_parse_from(["tsein-dns", "--compile-blocklist", "hosts.txt", "hosts.bl"]);
+        let paths = args.unwrap().compile_blocklist.unwrap();
+        assert_eq!(
+            paths,
+            [PathBuf::from("hosts.txt"), PathBuf::from("hosts.bl")]
+        );
+        assert!(Args::try_parse_from(["tsein-dns", "--compile-blocklist", "hosts.txt"]).is_err());
+    }
+
+    #[test]
+    fn test_compile_blocklist() {
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("tsein-dns-{}.hosts", std::process::id()));
+        let output = dir.join(format!("tsein-dns-{}.compiled", std::process::id()));
+        std::fs::write(&input, "# ads\ndoubleclick.net\nads.example.com\n").unwrap();
+        assert_eq!(compile_blocklist(&input, &output).unwrap(), 2);
+        std::fs::remove_file(&input).unwrap();
+
+        // served from the list it compiled
+        let served = [
+            "tsein-dns",
+            "--compiled-blocklist",
+            output.to_str().unwrap(),
+        ];
+        let list = Args::try_parse_from(served)
+            .unwrap()
+            .compiled_blocklist()
+            .unwrap()
+            .unwrap();
+        std::fs::remove_file(&output).unwrap();
+        let check = |name| list.check(&Name::try_from(name).unwrap());
+        assert_eq!(check("x.doubleclick.net"), Some(Action::NxDomain));
+        assert_eq!(check("ads.example.com"), Some(Action::NxDomain));
+        assert_eq!(check("example.com"), None);
+
+        // a missing list is an error, and nothing is written
+        assert!(compile_blocklist(&input, &output).is_err());
+        assert!(!output.exists());
+    }
+
+    #[tokio::test]
+    async fn test_serve_enabled_only() {
+        let (tasks, _) = mpsc::unbounded_channel();
+        let listeners = Listeners {
+            udp: Some("127.0.0.1:0".parse().unwrap()),
+            tcp: None,
+            tls: None,
+            quic: None,
+            doh: None,
+        };
+        // no certificate is needed without TLS, QUIC and DoH listeners
+        let serving = serve(
+            &listeners,
+            tasks.clone(),
+            Arc::default(),
+            Arc::default(),
+            None,
+            64,
+            false,
+            None,
+            None,
+            Arc::default(),
+            CancellationToken::new(),
+        )
+        .await
+        .unwrap();
+        let protocols: Vec<_> = serving.iter().map(|(protocol, ..)| *protocol).collect();
+        assert_eq!(protocols, vec!["udp"]);
+
+        let disabled = Listeners {
+            udp: None,
+            tcp: None,
+            tls: None,
+            quic: None,
+            doh: None,
+        };
+        assert!(serve(
+            &disabled,
+            tasks.clone(),
+            Arc::default(),
+            Arc::default(),
+            None,
+            64,
+            false,
+            None,
+            None,
+            Arc::default(),
+            CancellationToken::new()
+        )
+        .await
+        .is_err());
+
+        let tls = Listeners {
+            tls: Some("127.0.0.1:0".parse().unwrap()),
+            ..disabled
+        };
+        assert!(serve(
+            &tls,
+            tasks,
+            Arc::default(),
+            Arc::default(),
+            None,
+            64,
+            false,
+            None,
+            None,
+            Arc::default(),
+            CancellationToken::new()
+        )
+        .await
+        .is_err());
+    }
 }
@@ -0,0 +1,380 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    convert::Infallible,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use hyper::{
+    header::CONTENT_TYPE, server::conn::Http, service::service_fn, Body, Method, Request, Response,
+    StatusCode,
+};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+
+use crate::{cache::DnsCache, protocol::Rcode};
+
+/// the only path metrics are exported on
+const METRICS_PATH: &str = "/metrics";
+/// media type of the text exposition format of Prometheus
+const TEXT_FORMAT: &str = "text/plain; version=0.0.4";
+/// upper bounds of the upstream latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+/// RCODEs counted on their own, the others are counted together
+const RCODES: usize = 6;
+
+/// transports queries come by
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Udp,
+    Tcp,
+    Tls,
+    Quic,
+    Doh,
+}
+
+impl Transport {
+    const ALL: [Transport; 5] = [
+        Transport::Udp,
+        Transport::Tcp,
+        Transport::Tls,
+        Transport::Quic,
+        Transport::Doh,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Transport::Udp => "udp",
+            Transport::Tcp => "tcp",
+            Transport::Tls => "tls",
+            Transport::Quic => "quic",
+            Transport::Doh => "doh",
+        }
+    }
+}
+
+/// ## `Metrics`
+/// Counters of the server, exported in the text format of Prometheus.
+///
+/// Queries answered are counted by transport and RCODE, along with the connections
+/// of clients, the latency of upstream and the hits of the cache.
+/// Counting takes a few relaxed atomic operations and allocates nothing,
+/// the text is only built when exported.
+#[derive(Default)]
+pub struct Metrics {
+    /// responses by transport and RCODE, the last column for the other RCODEs
+    queries: [[AtomicU64; RCODES + 1]; Transport::ALL.len()],
+    connections: [AtomicI64; Transport::ALL.len()],
+    /// upstream queries by the first bucket they fall in, the last one for the slower
+    latency: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    latency_sum_micros: AtomicU64,
+    cache: Option<DnsCache>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// export the hits and misses of `cache` as well
+    pub fn with_cache(mut self, cache: DnsCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// count a query of `transport` answered with the 12-bit `rcode`
+    pub fn count_query(&self, transport: Transport, rcode: u16) {
+        let column = (rcode as usize).min(RCODES);
+        self.queries[transport as usize][column].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// count a connection of `transport` as active, until the guard is dropped
+    pub fn connection(self: &Arc<Self>, transport: Transport) -> ConnectionGuard {
+        self.connections[transport as usize].fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard {
+            metrics: self.clone(),
+            transport,
+        }
+    }
+
+    /// record how long upstream took to answer a query
+    pub fn observe_upstream(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|le| secs <= *le)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.latency[bucket].fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// the metrics in the text exposition format of Prometheus
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.render_queries(&mut out);
+        self.render_connections(&mut out);
+        self.render_latency(&mut out);
+        self.render_cache(&mut out);
+        out
+    }
+
+    fn render_queries(&self, out: &mut String) {
+        out.push_str("# HELP tsein_dns_queries_total Queries answered, by transport and RCODE.\n");
+        out.push_str("# TYPE tsein_dns_queries_total counter\n");
+        for transport in Transport::ALL {
+            let counts = &self.queries[transport as usize];
+            for (column, count) in counts.iter().enumerate() {
+                let rcode = match column {
+                    RCODES => String::from("OTHER"),
+                    rcode => Rcode::from(rcode as u8).to_string(),
+                };
+                let _ = writeln!(
+                    out,
+                    "tsein_dns_queries_total{{transport=\"{}\",rcode=\"{}\"}} {}",
+                    transport.name(),
+                    rcode,
+                    count.load(Ordering::Relaxed)
+                );
+            }
+        }
+    }
+
+    fn render_connections(&self, out: &mut String) {
+        out.push_str("# HELP tsein_dns_connections Active connections of clients, by transport.\n");
+        out.push_str("# TYPE tsein_dns_connections gauge\n");
+        // datagrams come without connections
+        for transport in Transport::ALL.into_iter().filter(|t| *t != Transport::Udp) {
+            let _ = writeln!(
+                out,
+                "tsein_dns_connections{{transport=\"{}\"}} {}",
+                transport.name(),
+                self.connections[transport as usize].load(Ordering::Relaxed)
+            );
+        }
+    }
+
+    fn render_latency(&self, out: &mut String) {
+        out.push_str("# HELP tsein_dns_upstream_latency_seconds Time upstream took to answer.\n");
+        out.push_str("# TYPE tsein_dns_upstream_latency_seconds histogram\n");
+        let mut count = 0;
+        for (le, bucket) in LATENCY_BUCKETS.iter().zip(self.latency.iter()) {
+            count += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "tsein_dns_upstream_latency_seconds_bucket{{le=\"{}\"}} {}",
+                le, count
+            );
+        }
+        count += self.latency[LATENCY_BUCKETS.len()].load(Ordering::Relaxed);
+        let sum = self.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(
+            out,
+            "tsein_dns_upstream_latency_seconds_bucket{{le=\"+Inf\"}} {}",
+            count
+        );
+        let _ = writeln!(out, "tsein_dns_upstream_latency_seconds_sum {}", sum);
+        let _ = writeln!(out, "tsein_dns_upstream_latency_seconds_count {}", count);
+    }
+
+    fn render_cache(&self, out: &mut String) {
+        let Some(cache) = &self.cache else {
+            return;
+        };
+        let stats = cache.stats();
+        let lookups = stats.hits + stats.misses;
+        let ratio = if lookups == 0 {
+            0.0
+        } else {
+            stats.hits as f64 / lookups as f64
+        };
+        let _ = write!(
+            out,
+            "# HELP tsein_dns_cache_hits_total Lookups answered by the cache.\n\
+             # TYPE tsein_dns_cache_hits_total counter\n\
+             tsein_dns_cache_hits_total {}\n\
+             # HELP tsein_dns_cache_misses_total Lookups forwarded to upstream.\n\
+             # TYPE tsein_dns_cache_misses_total counter\n\
+             tsein_dns_cache_misses_total {}\n\
+             # HELP tsein_dns_cache_hit_ratio Share of lookups answered by the cache.\n\
+             # TYPE tsein_dns_cache_hit_ratio gauge\n\
+             tsein_dns_cache_hit_ratio {}\n",
+            stats.hits, stats.misses, ratio
+        );
+    }
+}
+
+/// ## `ConnectionGuard`
+/// An active connection, counted until dropped.
+pub struct ConnectionGuard {
+    metrics: Arc<Metrics>,
+    transport: Transport,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.metrics.connections[self.transport as usize].fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// ## `MetricsService`
+/// Exports `Metrics` over plain HTTP, on `GET /metrics`.
+pub struct MetricsService {
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    shutdown: CancellationToken,
+}
+
+impl MetricsService {
+    pub fn new(listener: TcpListener, metrics: Arc<Metrics>) -> Self {
+        Self {
+            listener,
+            metrics,
+            shutdown: CancellationToken::new(),
+        }
+    }
+
+    /// stop accepting scrapes once `shutdown` is cancelled
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub async fn run(self) {
+        if let Ok(local) = self.listener.local_addr() {
+            tracing::info!("exporting metrics on: http://{}{}", local, METRICS_PATH);
+        }
+        let http = Http::new();
+        loop {
+            let (stream, client) = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                accepted = self.listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(_) => break,
+                },
+            };
+            let metrics = self.metrics.clone();
+            let service = service_fn(move |req| export(req, metrics.clone()));
+            let conn = http.serve_connection(stream, service);
+            tokio::spawn(async move {
+                if let Err(e) = conn.await {
+                    tracing::debug!("metrics connection to {} closed: {}", client, e);
+                }
+            });
+        }
+    }
+}
+
+async fn export(req: Request<Body>, metrics: Arc<Metrics>) -> Result<Response<Body>, Infallible> {
+    let mut resp = Response::new(Body::empty());
+    if req.uri().path() != METRICS_PATH {
+        *resp.status_mut() = StatusCode::NOT_FOUND;
+    } else if req.method() != Method::GET {
+        *resp.status_mut() = StatusCode::METHOD_NOT_ALLOWED;
+    } else {
+        *resp.body_mut() = Body::from(metrics.render());
+        resp.headers_mut()
+            .insert(CONTENT_TYPE, TEXT_FORMAT.parse().unwrap());
+    }
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, time::Duration};
+
+    use hyper::{body, client::conn, Body, Method, Request, StatusCode};
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{Metrics, MetricsService, Transport, TEXT_FORMAT};
+
+    #[test]
+    fn test_render() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.count_query(Transport::Udp, 0);
+        metrics.count_query(Transport::Udp, 0);
+        metrics.count_query(Transport::Tcp, 3);
+        // BADVERS and other RCODEs beyond REFUSED
+        metrics.count_query(Transport::Tls, 16);
+        metrics.count_query(Transport::Doh, 2);
+        let conn = metrics.connection(Transport::Tcp);
+        let quic = metrics.connection(Transport::Quic);
+        metrics.observe_upstream(Duration::from_millis(20));
+        metrics.observe_upstream(Duration::from_secs(10));
+
+        let text = metrics.render();
+        let lines: Vec<&str> = text.lines().collect();
+        for line in [
+            "# TYPE tsein_dns_queries_total counter",
+            "tsein_dns_queries_total{transport=\"udp\",rcode=\"NOERROR\"} 2",
+            "tsein_dns_queries_total{transport=\"tcp\",rcode=\"NXDOMAIN\"} 1",
+            "tsein_dns_queries_total{transport=\"tls\",rcode=\"OTHER\"} 1",
+            "tsein_dns_queries_total{transport=\"tls\",rcode=\"NOERROR\"} 0",
+            "tsein_dns_queries_total{transport=\"doh\",rcode=\"SERVFAIL\"} 1",
+            "tsein_dns_connections{transport=\"tcp\"} 1",
+            "tsein_dns_connections{transport=\"quic\"} 1",
+            "tsein_dns_connections{transport=\"doh\"} 0",
+            "tsein_dns_upstream_latency_seconds_bucket{le=\"0.01\"} 0",
+            "tsein_dns_upstream_latency_seconds_bucket{le=\"0.025\"} 1",
+            "tsein_dns_upstream_latency_seconds_bucket{le=\"5\"} 1",
+            "tsein_dns_upstream_latency_seconds_bucket{le=\"+Inf\"} 2",
+            "tsein_dns_upstream_latency_seconds_sum 10.02",
+            "tsein_dns_upstream_latency_seconds_count 2",
+        ] {
+            assert!(lines.contains(&line), "{} missing in\n{}", line, text);
+        }
+        // no cache, no cache metrics
+        assert!(!text.contains("tsein_dns_cache"));
+        // datagrams come without connections
+        assert!(!text.contains("tsein_dns_connections{transport=\"udp\"}"));
+
+        drop((conn, quic));
+        assert!(metrics
+            .render()
+            .contains("tsein_dns_connections{transport=\"tcp\"} 0"));
+    }
+
+    #[tokio::test]
+    async fn test_service() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metrics = Arc::new(Metrics::new());
+        metrics.count_query(Transport::Udp, 3);
+        tokio::spawn(MetricsService::new(listener, metrics).run());
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut sender, connection) = conn::handshake(stream).await.unwrap();
+        tokio::spawn(connection);
+        let mut get = |method, path| {
+            let req = Request::builder()
+                .method(method)
+                .uri(path)
+                .body(Body::empty())
+                .unwrap();
+            sender.send_request(req)
+        };
+
+        let resp = get(Method::GET, "/metrics").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()["content-type"], TEXT_FORMAT);
+        let text = body::to_bytes(resp.into_body()).await.unwrap();
+        let text = String::from_utf8(text.to_vec()).unwrap();
+        assert!(text
+            .lines()
+            .any(|l| l == "tsein_dns_queries_total{transport=\"udp\",rcode=\"NXDOMAIN\"} 1"));
+
+        let resp = get(Method::GET, "/").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let resp = get(Method::POST, "/metrics").await.unwrap();
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
@@ -4,20 +4,29 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::fmt::{Debug, Display, Write};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt::{Debug, Display, Write},
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
 
 use bytes::{BufMut, Bytes, BytesMut};
-use color_eyre::{eyre::eyre, Result};
 
-use crate::protocol::error::PacketError;
+use crate::protocol::{
+    error::{PacketError, ParseNameError},
+    PacketReader,
+};
 
 const MAX_LABEL_LENGTH: usize = 63;
 const MAX_NAME_LENGTH: usize = 253;
 
 pub const PTR_MASK: u8 = 0xc0;
+/// compression pointers are 14 bits long
+const MAX_PTR_OFFSET: usize = 0x3fff;
 
-// TODO: replace `Label` with bytes::Bytes to reduce memory usage.
-type Label = String;
+/// raw octets of a label, which are not necessarily UTF-8
+type Label = Bytes;
 
 /// ## `Name` represents domain name.
 /// `Name` stores domain name as a vector of `Label`s.
@@ -33,49 +42,157 @@ type Label = String;
 /// let name_root = Name::try_from(".").unwrap(); // Name {labels: vec![]};
 /// assert_eq!(name_root.len(), 1);
 /// ```
-#[derive(Clone, PartialEq, Eq, Hash)]
+///
+/// Names are compared and hashed ignoring ASCII case, see [RFC4343](https://datatracker.ietf.org/doc/html/rfc4343),
+/// but keep their case when displayed or written into packets.
+/// ```
+/// use tsein_dns::protocol::Name;
+/// let name = Name::try_from("Example.COM").unwrap();
+/// assert_eq!(name, Name::try_from("example.com").unwrap());
+/// assert_eq!(name.to_string(), "Example.COM.");
+/// ```
+#[derive(Clone)]
 pub struct Name {
     labels: Vec<Label>,
+    /// case-insensitive hash of the labels, computed once in `Name::from_labels`.
+    ///
+    /// Labels are never modified in place, names are rebuilt from them instead.
+    hash: u64,
+}
+
+impl PartialEq for Name {
+    fn eq(&self, other: &Self) -> bool {
+        // most names compared in lookups differ, reject them without comparing labels
+        self.hash == other.hash
+            && self.labels.len() == other.labels.len()
+            && self
+                .labels
+                .iter()
+                .zip(other.labels.iter())
+                .all(|(l, r)| l.eq_ignore_ascii_case(r))
+    }
+}
+
+impl Eq for Name {}
+
+impl Hash for Name {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
 }
 
 impl PartialOrd for Name {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.labels.partial_cmp(&other.labels)
+        Some(self.cmp(other))
     }
 }
 
 impl Ord for Name {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        use std::cmp::Ordering;
-        for (s, o) in self.labels.iter().zip(other.labels.iter()) {
-            match s.cmp(o) {
-                Ordering::Less => Ordering::Less,
-                Ordering::Equal => continue,
-                Ordering::Greater => Ordering::Greater,
-            };
-        }
-        Ordering::Equal
+        let lower = |name: &Self| -> Vec<Vec<u8>> {
+            name.labels.iter().map(|l| l.to_ascii_lowercase()).collect()
+        };
+        lower(self).cmp(&lower(other))
     }
 }
 
-impl Name {
-    pub fn try_from(s: &str) -> Result<Self> {
+impl TryFrom<&str> for Name {
+    type Error = ParseNameError;
+
+    /// parse a name in presentation format,
+    /// where `\.` is a dot inside a label and `\DDD` is an octet in decimal.
+    ///
+    /// Control characters should be escaped.
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
         let mut labels = vec![];
         let mut total_len = 0;
-        for l in s.split('.').filter(|p| !p.is_empty()) {
-            let len = l.len();
-            if len > MAX_LABEL_LENGTH {
-                return Err(eyre!("Label too long"));
+        let mut label = vec![];
+        let mut start = 0; // where the current label begins in `s`
+        let mut chars = s.char_indices().peekable();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '.' => {
+                    push_label(&mut labels, &mut label, &s[start..i], &mut total_len)?;
+                    start = i + 1;
+                }
+                '\\' => match chars.next() {
+                    Some((_, d)) if d.is_ascii_digit() => {
+                        let mut octet = d.to_digit(10).unwrap();
+                        for _ in 0..2 {
+                            match chars.next_if(|(_, d)| d.is_ascii_digit()) {
+                                Some((_, d)) => octet = octet * 10 + d.to_digit(10).unwrap(),
+                                None => return Err(ParseNameError::InvalidLabel(s.to_string())),
+                            }
+                        }
+                        let octet = u8::try_from(octet)
+                            .map_err(|_| ParseNameError::InvalidLabel(s.to_string()))?;
+                        label.push(octet);
+                    }
+                    Some((_, c)) if !c.is_control() => {
+                        label.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes())
+                    }
+                    _ => return Err(ParseNameError::InvalidLabel(s.to_string())),
+                },
+                c if c.is_control() => return Err(ParseNameError::InvalidLabel(s.to_string())),
+                c => label.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
             }
-            let label = Label::from(l);
-            labels.push(label);
-            total_len += len + 1;
         }
+        push_label(&mut labels, &mut label, &s[start..], &mut total_len)?;
         if total_len > MAX_NAME_LENGTH {
-            Err(eyre!("Label too long"))
+            Err(ParseNameError::NameTooLong)
         } else {
-            Ok(Self { labels })
+            Ok(Self::from_labels(labels))
+        }
+    }
+}
+
+/// end the label being parsed from `text`, empty labels are skipped
+fn push_label(
+    labels: &mut Vec<Label>,
+    label: &mut Vec<u8>,
+    text: &str,
+    total_len: &mut usize,
+) -> Result<(), ParseNameError> {
+    if label.is_empty() {
+        return Ok(());
+    }
+    if label.len() > MAX_LABEL_LENGTH {
+        return Err(ParseNameError::LabelTooLong(text.to_string()));
+    }
+    *total_len += label.len() + 1;
+    labels.push(Label::from(std::mem::take(label)));
+    Ok(())
+}
+
+impl TryFrom<String> for Name {
+    type Error = ParseNameError;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Self::try_from(s.as_str())
+    }
+}
+
+impl FromStr for Name {
+    type Err = ParseNameError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::try_from(s)
+    }
+}
+
+impl Name {
+    /// the only way to make a name, so that its hash always matches its labels
+    fn from_labels(labels: Vec<Label>) -> Self {
+        let mut hasher = DefaultHasher::new();
+        hasher.write_usize(labels.len());
+        for label in labels.iter() {
+            hasher.write_usize(label.len());
+            for b in label.iter() {
+                hasher.write_u8(b.to_ascii_lowercase());
+            }
         }
+        let hash = hasher.finish();
+        Self { labels, hash }
     }
 
     /// length of domain name string
@@ -93,6 +210,42 @@ impl Name {
         self.len() == 0
     }
 
+    /// length of the name in packets without compression
+    pub(crate) fn wire_len(&self) -> usize {
+        self.labels
+            .iter()
+            .map(|label| label.len() + 1)
+            .sum::<usize>()
+            + 1
+    }
+
+    /// number of labels, the root domain has none
+    pub fn label_count(&self) -> usize {
+        self.labels.len()
+    }
+
+    /// the labels from the leftmost, as they are
+    pub fn labels(&self) -> impl Iterator<Item = &[u8]> {
+        self.labels.iter().map(|label| &label[..])
+    }
+
+    /// the last `count` labels, like `example.com` of `www.example.com` by 2
+    pub fn suffix(&self, count: usize) -> Self {
+        let from = self.labels.len().saturating_sub(count);
+        Self::from_labels(self.labels[from..].to_vec())
+    }
+
+    /// append `suffix` to the name, `web` joined with `corp.internal.` is `web.corp.internal.`
+    pub fn join(&self, suffix: &Self) -> Result<Self, ParseNameError> {
+        let labels = [&self.labels[..], &suffix.labels[..]].concat();
+        let name = Self::from_labels(labels);
+        if name.len() > MAX_NAME_LENGTH {
+            Err(ParseNameError::NameTooLong)
+        } else {
+            Ok(name)
+        }
+    }
+
     /// parse `domain` from raw packet bytes
     ///
     /// If ok, return the Domain name and the end position of domain name in packet.
@@ -102,9 +255,22 @@ impl Name {
     where
         Self: Sized,
     {
-        let mut pos = pos;
-        const MAX_JUMPS: usize = 5;
-        let mut jumps = 0;
+        let mut reader = PacketReader::new(&packet, pos);
+        let name = Self::read(&mut reader)?;
+        Ok((name, reader.pos()))
+    }
+
+    /// read a name off `reader`, leaving it after the name
+    ///
+    /// Compression pointers must point before the labels they follow,
+    /// so forward and looping pointers are rejected.
+    pub(crate) fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let packet = reader.packet();
+        let mut pos = reader.pos();
+        // labels before the first pointer must lie within the cursor
+        let mut limit = reader.end();
+        // start of the labels being read, every pointer jumps strictly before it
+        let mut fragment = pos;
 
         let mut is_jumped = false;
         let mut domain_end = 0; // end of domain name data in packet
@@ -112,13 +278,14 @@ impl Name {
         let mut labels = vec![];
         let mut size = 0;
 
-        // empty domain
-        if packet[pos] == 0 {
-            return Ok((Self { labels: vec![] }, pos + 1));
+        // empty domain, a name out of the packet is rejected in the loop
+        if pos < limit && packet[pos] == 0 {
+            reader.seek(pos + 1);
+            return Ok(Self::from_labels(vec![]));
         }
 
         loop {
-            if jumps > MAX_JUMPS || pos >= packet.len() {
+            if pos >= limit {
                 return Err(PacketError::FormatError);
             }
 
@@ -131,7 +298,7 @@ impl Name {
                     }
                     is_jumped = true;
 
-                    if pos + 1 >= packet.len() {
+                    if pos + 1 >= limit {
                         return Err(PacketError::FormatError);
                     }
 
@@ -139,12 +306,13 @@ impl Name {
                     let jmp_low = packet[pos + 1] as usize;
                     let jmp_to = (jmp_high << 8) + jmp_low;
 
-                    if jmp_to >= packet.len() {
+                    if jmp_to >= fragment {
                         return Err(PacketError::FormatError);
                     }
 
                     pos = jmp_to;
-                    jumps += 1;
+                    fragment = jmp_to;
+                    limit = packet.len();
                 }
 
                 len => {
@@ -152,16 +320,12 @@ impl Name {
                     let begin = pos + 1;
                     let end = begin + len; // label: slc[begin, end)
 
-                    if end > packet.len() {
+                    if end > limit {
                         return Err(PacketError::FormatError);
                     }
 
-                    let label = match Label::from_utf8(packet[begin..end].to_vec()) {
-                        Ok(l) => l,
-                        Err(_) => return Err(PacketError::FormatError),
-                    };
-
-                    labels.push(label);
+                    // copied out, so that the packet could be dropped
+                    labels.push(Label::copy_from_slice(&packet[begin..end]));
                     size += len + 1;
 
                     pos = end;
@@ -174,41 +338,192 @@ impl Name {
         if size >= MAX_NAME_LENGTH {
             Err(PacketError::FormatError)
         } else {
-            Ok((Self { labels }, domain_end))
+            reader.seek(domain_end);
+            Ok(Self::from_labels(labels))
         }
     }
 
+    /// read a name which must not be compressed, like names in RDATA of DNSSEC records
+    pub(crate) fn read_uncompressed(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let pos = reader.pos();
+        let name = Self::read(reader)?;
+        // a pointer makes the name take fewer bytes than its labels
+        if reader.pos() - pos != name.wire_len() {
+            return Err(PacketError::FormatError);
+        }
+        Ok(name)
+    }
+
     pub fn as_bytes_uncompressed(&self) -> BytesMut {
         let mut buf = BytesMut::with_capacity(self.len() + 1);
         for label in self.labels.iter() {
             buf.put_u8(label.len() as u8);
-            for byte in label.as_bytes().iter() {
-                buf.put_u8(*byte);
-            }
+            buf.put_slice(label);
         }
         buf.put_u8(0);
         buf
     }
 
-    // TODO: implement fn as_bytes_compressed, require a `CompressWriter` struct.
+    /// write the name into `writer`, pointing to the longest suffix it has written before
+    pub fn as_bytes_compressed(&self, writer: &mut CompressWriter) {
+        let offset = writer.len();
+        let (written, pointer) = self.compress(&mut writer.names, offset);
+        for label in self.labels[..written].iter() {
+            writer.buf.put_u8(label.len() as u8);
+            writer.buf.put_slice(label);
+        }
+        match pointer {
+            Some(pointer) => writer.buf.put_u16((PTR_MASK as u16) << 8 | pointer),
+            None => writer.buf.put_u8(0),
+        }
+    }
+
+    /// length of the name written at `offset` by `as_bytes_compressed`, without writing it
+    pub(crate) fn compressed_len(&self, names: &mut NameOffsets, offset: usize) -> usize {
+        let (written, pointer) = self.compress(names, offset);
+        let labels: usize = self.labels[..written].iter().map(|l| l.len() + 1).sum();
+        labels + if pointer.is_some() { 2 } else { 1 }
+    }
 
+    /// count of leading labels to write in full at `offset`,
+    /// and the offset of the written suffix to point to for the rest.
+    ///
+    /// Suffixes written in full are remembered in `names`.
+    fn compress(&self, names: &mut NameOffsets, offset: usize) -> (usize, Option<u16>) {
+        let mut offset = offset;
+        for i in 0..self.labels.len() {
+            let suffix: Vec<Vec<u8>> = self.labels[i..]
+                .iter()
+                .map(|label| label.to_ascii_lowercase())
+                .collect();
+            if let Some(&pointer) = names.0.get(&suffix) {
+                return (i, Some(pointer));
+            }
+            if offset <= MAX_PTR_OFFSET {
+                names.0.insert(suffix, offset as u16);
+            }
+            offset += self.labels[i].len() + 1;
+        }
+        (self.labels.len(), None)
+    }
+
+    /// whether the name is `other` or under it, ignoring ASCII case
     pub fn is_subdomain_of(&self, other: &Self) -> bool {
-        other
+        self.labels.len() >= other.labels.len()
+            && other
+                .labels
+                .iter()
+                .rev()
+                .zip(self.labels.iter().rev())
+                .all(|(o, s)| o.eq_ignore_ascii_case(s))
+    }
+
+    /// the longest suffix shared by the names, ignoring ASCII case.
+    ///
+    /// The labels are taken from `self`, the root if nothing is shared.
+    pub fn common_suffix(&self, other: &Self) -> Self {
+        let shared = self
             .labels
             .iter()
             .rev()
-            .zip(self.labels.iter().rev())
-            .all(|(o, s)| *o == *s)
+            .zip(other.labels.iter().rev())
+            .take_while(|(s, o)| s.eq_ignore_ascii_case(o))
+            .count();
+        Self::from_labels(self.labels[self.labels.len() - shared..].to_vec())
+    }
+
+    /// lower cased labels from the root down, each led by its length.
+    ///
+    /// Keys of parent domains are prefixes of the key, like `com` of `example.com`.
+    pub(crate) fn reversed_key(&self) -> Vec<u8> {
+        let mut key = Vec::with_capacity(self.wire_len());
+        for label in self.labels.iter().rev() {
+            key.push(label.len() as u8);
+            key.extend(label.iter().map(u8::to_ascii_lowercase));
+        }
+        key
+    }
+
+    /// the labels before `suffix`, none if the name is not `suffix` or under it
+    pub fn strip_suffix(&self, suffix: &Self) -> Option<Self> {
+        if !self.is_subdomain_of(suffix) {
+            return None;
+        }
+        let labels = self.labels[..self.labels.len() - suffix.labels.len()].to_vec();
+        Some(Self::from_labels(labels))
     }
 
     pub fn get_parent_domain(&self) -> Self {
         if self.len() <= 1 {
-            Self { labels: vec![] }
+            Self::from_labels(vec![])
         } else {
-            Self {
-                labels: self.labels[1..].into(),
-            }
+            Self::from_labels(self.labels[1..].into())
+        }
+    }
+}
+
+/// offsets of name suffixes written in a packet, keyed by their lower cased labels
+#[derive(Debug, Default)]
+pub(crate) struct NameOffsets(HashMap<Vec<Vec<u8>>, u16>);
+
+/// ## `CompressWriter`
+/// `CompressWriter` serializes a packet, and remembers where each name was written,
+/// so names written later could point to their suffixes instead of repeating them.
+///
+/// Suffixes are matched ignoring ASCII case.
+#[derive(Debug, Default)]
+pub struct CompressWriter {
+    buf: BytesMut,
+    names: NameOffsets,
+    /// bytes reserved for the length of the message, pointers do not count them
+    prefix: usize,
+}
+
+impl CompressWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// writer for a message of about `capacity` bytes
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            buf: BytesMut::with_capacity(capacity),
+            ..Self::default()
+        }
+    }
+
+    /// writer for a message over streams, which is prefixed by its length
+    pub fn with_length_prefix(capacity: usize) -> Self {
+        let mut buf = BytesMut::with_capacity(capacity + 2);
+        buf.put_u16(0);
+        Self {
+            buf,
+            names: NameOffsets::default(),
+            prefix: 2,
+        }
+    }
+
+    /// bytes of the message written so far
+    pub fn len(&self) -> usize {
+        self.buf.len() - self.prefix
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// write bytes other than names, like the header or fixed fields of records
+    pub fn put_slice(&mut self, src: &[u8]) {
+        self.buf.put_slice(src);
+    }
+
+    /// the message, led by its length if reserved by `with_length_prefix`
+    pub fn into_bytes(mut self) -> Bytes {
+        if self.prefix != 0 {
+            let len = self.len() as u16;
+            self.buf[..2].copy_from_slice(&len.to_be_bytes());
         }
+        self.buf.freeze()
     }
 }
 
@@ -221,24 +536,50 @@ impl Debug for Name {
     }
 }
 
-// we trust that every label in `Name` is valid
+/// names in presentation format, octets other than printable ASCII are escaped as `\DDD`
 impl Display for Name {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         if self.labels.is_empty() {
             f.write_char('.')?;
         }
         for label in self.labels.iter() {
-            f.write_fmt(format_args!("{}.", label))?;
+            for &byte in label.iter() {
+                match byte {
+                    b'.' | b'\\' => write!(f, "\\{}", byte as char)?,
+                    0x21..=0x7e => f.write_char(byte as char)?,
+                    _ => write!(f, "\\{:03}", byte)?,
+                }
+            }
+            f.write_char('.')?;
         }
         Ok(())
     }
 }
 
+/// names are serialized in presentation format, which they are parsed back from losslessly
+#[cfg(feature = "serde")]
+impl serde::Serialize for Name {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Name {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Name::try_from(s.as_str()).map_err(serde::de::Error::custom)
+    }
+}
+
 #[cfg(test)]
 mod domain_test {
+    use std::hash::{Hash, Hasher};
+
     use bytes::{Buf, BufMut, Bytes, BytesMut};
 
-    use super::{Name, PTR_MASK};
+    use super::{CompressWriter, Name, PTR_MASK};
+    use crate::protocol::{PacketError, ParseNameError};
 
     #[test]
     fn test_len() {
@@ -253,6 +594,117 @@ mod domain_test {
         let domain = Name::try_from("example.com").unwrap();
         let subdomain = Name::try_from("example.example.com").unwrap();
         assert!(subdomain.is_subdomain_of(&domain));
+        assert!(domain.is_subdomain_of(&domain));
+        assert!(!domain.is_subdomain_of(&subdomain));
+        assert!(Name::try_from("WWW.Example.com")
+            .unwrap()
+            .is_subdomain_of(&domain));
+
+        let cases = [
+            ("a.example.com", "example.com", true),
+            ("example.com", "example.com", true),
+            ("foo.com", "foo.net", false),
+            ("com", "example.com", false),
+            ("example.net", "example.com", false),
+            ("example.com", ".", true),
+        ];
+        for (name, other, expected) in cases {
+            let name = Name::try_from(name).unwrap();
+            let other = Name::try_from(other).unwrap();
+            assert_eq!(name.is_subdomain_of(&other), expected, "{} {}", name, other);
+        }
+    }
+
+    #[test]
+    fn test_common_suffix() {
+        let name = |s: &str| Name::try_from(s).unwrap();
+        let cases = [
+            ("a.b.example.com", "c.example.com", "example.com."),
+            ("example.com", "example.org", "."),
+            ("www.Example.COM", "example.com", "Example.COM."),
+            ("example.com", ".", "."),
+        ];
+        for (l, r, suffix) in cases {
+            let common = name(l).common_suffix(&name(r));
+            assert_eq!(common.to_string(), suffix, "{} and {}", l, r);
+            assert_eq!(name(r).common_suffix(&name(l)), common);
+        }
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        use std::collections::hash_map::DefaultHasher;
+        let hash = |name: &Name| {
+            let mut hasher = DefaultHasher::new();
+            name.hash(&mut hasher);
+            hasher.finish()
+        };
+
+        let lower = Name::try_from("example.com").unwrap();
+        let mixed = Name::try_from("ExAmple.COM").unwrap();
+        assert_eq!(lower, mixed);
+        assert_eq!(hash(&lower), hash(&mixed));
+        assert_eq!(lower.cmp(&mixed), std::cmp::Ordering::Equal);
+        // the original case is kept
+        assert_eq!(mixed.to_string(), "ExAmple.COM.");
+        assert_eq!(&mixed.as_bytes_uncompressed()[1..8], b"ExAmple");
+
+        // names of different lengths are never equal
+        let longer = Name::try_from("www.example.com").unwrap();
+        assert_ne!(lower, longer);
+        assert_ne!(longer, lower);
+        assert_ne!(lower, Name::try_from("example").unwrap());
+        assert_ne!(lower, Name::try_from(".").unwrap());
+    }
+
+    #[test]
+    fn test_cached_hash() {
+        let name = Name::try_from("www.Example.com").unwrap();
+        // names built in every way carry the hash of their labels
+        let parsed = Name::parse(name.as_bytes_uncompressed().freeze(), 0)
+            .unwrap()
+            .0;
+        let parent = name.get_parent_domain();
+        let joined = Name::try_from("www").unwrap().join(&parent).unwrap();
+        let stripped = name.strip_suffix(&parent).unwrap();
+        let cases = [
+            (parsed.clone(), "www.example.com"),
+            (parent, "EXAMPLE.com"),
+            (joined.clone(), "www.example.com"),
+            (stripped, "WWW"),
+            (name.common_suffix(&joined), "www.example.com"),
+            (
+                parsed
+                    .clone()
+                    .get_parent_domain()
+                    .get_parent_domain()
+                    .get_parent_domain(),
+                ".",
+            ),
+        ];
+        for (built, text) in cases {
+            let expected = Name::try_from(text).unwrap();
+            assert_eq!(built.hash, expected.hash, "{}", text);
+            assert_eq!(built, expected);
+        }
+
+        // equality trusts a mismatching hash, without looking at the labels
+        let mut forged = parsed.clone();
+        forged.hash ^= 1;
+        assert_eq!(forged.labels, parsed.labels);
+        assert_ne!(forged, parsed);
+    }
+
+    #[test]
+    fn test_join() {
+        let name = Name::try_from("web").unwrap();
+        let suffix = Name::try_from("corp.internal.").unwrap();
+        let joined = name.join(&suffix).unwrap();
+        assert_eq!(joined.to_string(), "web.corp.internal.");
+        assert_eq!(joined.label_count(), 3);
+
+        let long = Name::try_from("a.".repeat(120)).unwrap();
+        assert!(long.join(&long).is_err());
     }
 
     #[test]
@@ -268,6 +720,48 @@ mod domain_test {
         assert_eq!(n.len(), 1);
     }
 
+    #[test]
+    fn test_from_str() {
+        let n: Name = "example.com".parse().unwrap();
+        assert_eq!(n.to_string(), "example.com.");
+        assert_eq!(Name::try_from(String::from("example.com.")), Ok(n));
+
+        let label = "a".repeat(64);
+        let rs = format!("{}.com", label).parse::<Name>();
+        assert_eq!(rs, Err(ParseNameError::LabelTooLong(label)));
+        let rs = Name::try_from("a.".repeat(127));
+        assert_eq!(rs, Err(ParseNameError::NameTooLong));
+    }
+
+    #[test]
+    fn test_escape() {
+        let n: Name = r"a\.b.\\\032\255.com".parse().unwrap();
+        assert_eq!(n.label_count(), 3);
+        assert_eq!(n.len(), 12);
+        assert_eq!(n.to_string(), r"a\.b.\\\032\255.com.");
+        assert_eq!(n.to_string().parse::<Name>(), Ok(n));
+
+        for invalid in [r"a\25", r"a\256.com", "a\\", "a\tb.com"] {
+            assert_eq!(
+                Name::try_from(invalid),
+                Err(ParseNameError::InvalidLabel(invalid.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_non_utf8() {
+        let wire = Bytes::from_static(b"\x03a\xffb\x07example\x00");
+        let (name, end) = Name::parse(wire.clone(), 0).unwrap();
+        assert_eq!(end, wire.len());
+        assert_eq!(name.to_string(), r"a\255b.example.");
+        assert_eq!(name.as_bytes_uncompressed().freeze(), wire);
+
+        let mut writer = CompressWriter::new();
+        name.as_bytes_compressed(&mut writer);
+        assert_eq!(writer.into_bytes(), wire);
+    }
+
     #[test]
     fn test_parse() {
         fn gen_simple_domain_name(domain: &str) -> Bytes {
@@ -320,6 +814,35 @@ mod domain_test {
         assert_eq!(end, packet.len());
     }
 
+    #[test]
+    fn test_parse_bad_pointer() {
+        let parse = |packet: &'static [u8], pos| Name::parse(Bytes::from_static(packet), pos);
+
+        // pointing to itself
+        assert!(matches!(
+            parse(b"\x03com\xc0\x04", 4),
+            Err(PacketError::FormatError)
+        ));
+        assert!(matches!(
+            parse(b"\x03com\x00\xc0\x05", 5),
+            Err(PacketError::FormatError)
+        ));
+        // pointing forward
+        assert!(matches!(
+            parse(b"\xc0\x02\x03com\x00", 0),
+            Err(PacketError::FormatError)
+        ));
+        // pointing back into labels which lead to the pointer again
+        assert!(matches!(
+            parse(b"\x01a\x03www\xc0\x00", 2),
+            Err(PacketError::FormatError)
+        ));
+        // pointing to the labels before it
+        let (name, end) = parse(b"\x03com\x00\x03www\xc0\x00", 5).unwrap();
+        assert_eq!(name.to_string(), "www.com.");
+        assert_eq!(end, 11);
+    }
+
     #[test]
     fn test_as_bytes_uncompressed() {
         // test empty domain
@@ -332,4 +855,40 @@ mod domain_test {
         let encoded: &[u8] = &[2, b's', b'm', 2, b'm', b's', 0];
         assert_eq!(name.as_bytes_uncompressed(), encoded);
     }
+
+    #[test]
+    fn test_as_bytes_compressed() {
+        let mut writer = CompressWriter::new();
+        let names = [
+            "example.com",
+            "www.example.com",
+            "EXAMPLE.com",
+            "www.example.org",
+            ".",
+        ];
+        for name in names {
+            Name::try_from(name)
+                .unwrap()
+                .as_bytes_compressed(&mut writer);
+        }
+        let encoded: &[u8] = &[
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm',
+            0, // example.com.
+            3, b'w', b'w', b'w', PTR_MASK, 0, // www.example.com.
+            PTR_MASK, 0, // EXAMPLE.com.
+            3, b'w', b'w', b'w', 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'o', b'r', b'g',
+            0, // www.example.org.
+            0, // .
+        ];
+        let bytes = writer.into_bytes();
+        assert_eq!(bytes, encoded);
+
+        let mut pos = 0;
+        for name in names {
+            let (parsed, end) = Name::parse(bytes.clone(), pos).unwrap();
+            assert_eq!(parsed, Name::try_from(name).unwrap());
+            pos = end;
+        }
+        assert_eq!(pos, bytes.len());
+    }
 }
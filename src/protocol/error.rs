@@ -23,6 +23,23 @@ pub enum PacketError {
     NotImpl(Op),
     #[error("Refused Connection from: {0}")]
     Refused(IpAddr),
+    #[error("Refused Query by Policy: {0}")]
+    Prohibited(Name),
+    #[error("Unsupported EDNS Version: {0}")]
+    BadVersion(u8),
+    #[error("Query Timed Out")]
+    TimedOut,
+}
+
+/// Error occurred in making domain names from strings
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ParseNameError {
+    #[error("Label too long: {0}")]
+    LabelTooLong(String),
+    #[error("Invalid label in name: {0}")]
+    InvalidLabel(String),
+    #[error("Name too long")]
+    NameTooLong,
 }
 
 #[derive(Error, Debug, Clone)]
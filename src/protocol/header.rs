@@ -9,7 +9,10 @@ use std::fmt::{Debug, Display};
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use tokio::io::AsyncReadExt;
 
-use super::error::{PacketError, TransactionError};
+use super::{
+    error::{PacketError, TransactionError},
+    PacketReader,
+};
 
 const QR_MASK: u8 = 0x80;
 const OP_MASK: u8 = 0x78;
@@ -17,11 +20,20 @@ const AA_MASK: u8 = 0x04;
 const TC_MASK: u8 = 0x02;
 const RD_MASK: u8 = 0x01;
 const RA_MASK: u8 = QR_MASK;
-const Z_MASK: u8 = 0x70;
+const Z_MASK: u8 = 0x40;
+const AD_MASK: u8 = 0x20;
+const CD_MASK: u8 = 0x10;
 const RC_MASK: u8 = 0x0f;
 
+/// extended rcode of unsupported EDNS versions, see [RFC6891](https://datatracker.ietf.org/doc/html/rfc6891#section-9)
+pub const BADVERS: u16 = 16;
+
+/// packets claiming more questions than this are malformed
+const MAX_QUESTIONS: u16 = 16;
+
 /// DNS Header described in [RFC1035](https://datatracker.ietf.org/doc/html/rfc1035)
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Header {
     /// transaction ID of the DNS packet
     id: u16,
@@ -39,8 +51,14 @@ pub struct Header {
     is_rec_avl: bool,
     /// reserved for further use.
     z: u8,
+    /// the answer is authenticated by DNSSEC, see [RFC4035](https://datatracker.ietf.org/doc/html/rfc4035#section-3.2.3)
+    is_authentic_data: bool,
+    /// the client disables DNSSEC validation, see [RFC4035](https://datatracker.ietf.org/doc/html/rfc4035#section-3.2.2)
+    is_checking_disabled: bool,
     /// response code of the packet
     response: Rcode,
+    /// upper 8 bits of the extended response code, carried by the `OPT` record
+    ext_rcode: u8,
     /// number of entries in question section
     questions: u16,
     /// number of Resource Records in answer section
@@ -62,7 +80,10 @@ impl Header {
             is_rec_des: true,
             is_rec_avl: false,
             z: 0,
+            is_authentic_data: false,
+            is_checking_disabled: false,
             response: Rcode::NoError,
+            ext_rcode: 0,
             questions: 1,
             answers: 0,
             authorities: 0,
@@ -80,7 +101,10 @@ impl Header {
             is_rec_des: true,
             is_rec_avl: true,
             z: 0,
+            is_authentic_data: false,
+            is_checking_disabled: false,
             response: Rcode::NoError,
+            ext_rcode: 0,
             questions: 0,
             answers,
             authorities,
@@ -89,13 +113,10 @@ impl Header {
     }
 
     pub fn new_failure(id: u16, error: PacketError) -> Self {
-        let rcode = match error {
-            PacketError::FormatError => Rcode::FormatError,
-            PacketError::ServFail => Rcode::ServFail,
-            PacketError::NameError(_) => Rcode::NameError,
-            PacketError::NotImpl(_) => Rcode::NotImpl,
-            PacketError::Refused(_) => Rcode::Refused,
-        };
+        let full_rcode = error.full_rcode();
+        let rcode = Rcode::from(full_rcode as u8 & RC_MASK);
+        // only the extended rcodes have upper bits
+        let ext_rcode = (full_rcode >> 4) as u8;
         Header {
             id,
             is_query: false,
@@ -105,7 +126,10 @@ impl Header {
             is_rec_des: false,
             is_rec_avl: false,
             z: 0,
+            is_authentic_data: false,
+            is_checking_disabled: false,
             response: rcode,
+            ext_rcode,
             questions: 0,
             answers: 0,
             authorities: 0,
@@ -158,17 +182,41 @@ impl Header {
     }
 
     #[inline]
-    /// get the z record of the dns server
+    /// get the z record of the dns server, the single reserved bit
     pub fn get_z(&self) -> u8 {
         self.z
     }
 
+    #[inline]
+    /// is the answer authenticated by DNSSEC (AD)
+    pub fn is_authentic_data(&self) -> bool {
+        self.is_authentic_data
+    }
+
+    #[inline]
+    /// is DNSSEC validation disabled by the client (CD)
+    pub fn is_checking_disabled(&self) -> bool {
+        self.is_checking_disabled
+    }
+
     #[inline]
     /// get the rcode in header
     pub fn get_rcode(&self) -> Rcode {
         self.response
     }
 
+    #[inline]
+    /// get the upper 8 bits of the extended rcode
+    pub fn get_ext_rcode(&self) -> u8 {
+        self.ext_rcode
+    }
+
+    #[inline]
+    /// the 12-bit extended rcode, combining the rcode in header and the upper bits in `OPT`
+    pub fn full_rcode(&self) -> u16 {
+        (self.ext_rcode as u16) << 4 | u8::from(self.response) as u16
+    }
+
     #[inline]
     /// how many questions are there in the packet
     pub fn question_count(&self) -> u16 {
@@ -210,23 +258,60 @@ impl Header {
     pub fn set_additional(&mut self, additional: u16) {
         self.additional = additional;
     }
+
+    pub fn set_authoritative(&mut self, is_auth: bool) {
+        self.is_auth = is_auth;
+    }
+
+    pub fn set_truncated(&mut self, is_trunc: bool) {
+        self.is_trunc = is_trunc;
+    }
+
+    pub fn set_rec_des(&mut self, is_rec_des: bool) {
+        self.is_rec_des = is_rec_des;
+    }
+
+    pub fn set_rec_avl(&mut self, is_rec_avl: bool) {
+        self.is_rec_avl = is_rec_avl;
+    }
+
+    pub fn set_authentic_data(&mut self, is_authentic_data: bool) {
+        self.is_authentic_data = is_authentic_data;
+    }
+
+    pub fn set_checking_disabled(&mut self, is_checking_disabled: bool) {
+        self.is_checking_disabled = is_checking_disabled;
+    }
+
+    /// set the upper 8 bits of the extended rcode, found in the `OPT` record
+    pub fn set_ext_rcode(&mut self, ext_rcode: u8) {
+        self.ext_rcode = ext_rcode;
+    }
 }
 
 impl Header {
+    #[allow(dead_code)]
     pub(crate) fn parse(packet: Bytes, pos: usize) -> Result<Self, TransactionError>
     where
         Self: Sized,
     {
-        let mut buf = packet;
-        if buf.len() - pos < 12 {
+        Self::read(&mut PacketReader::new(&packet, pos))
+    }
+
+    pub(crate) fn read(reader: &mut PacketReader) -> Result<Self, TransactionError> {
+        if reader.remaining() < 12 {
             let err = TransactionError {
                 id: None,
                 error: PacketError::FormatError,
             };
             return Err(err);
         }
+        // the length is checked above, none of the reads below fail
+        let mut buf = reader
+            .read_slice(12)
+            .map_err(|error| TransactionError { id: None, error })?;
 
-        let id = Some(buf.get_u16());
+        let id = buf.get_u16();
 
         let a = buf.get_u8();
         let is_query = a & QR_MASK != QR_MASK;
@@ -237,16 +322,17 @@ impl Header {
 
         let b = buf.get_u8();
         let is_rec_avl = b & RA_MASK == RA_MASK;
-        let z = (b & Z_MASK) >> 4;
+        let z = (b & Z_MASK) >> 6;
+        let is_authentic_data = b & AD_MASK == AD_MASK;
+        let is_checking_disabled = b & CD_MASK == CD_MASK;
         let response = Rcode::from(b & RC_MASK);
 
         let questions = buf.get_u16();
 
-        // Multiple queries within one packet is not allowed
-        if questions > 1 {
+        if questions > MAX_QUESTIONS {
             let err = TransactionError {
-                id,
-                error: PacketError::ServFail,
+                id: Some(id),
+                error: PacketError::FormatError,
             };
             return Err(err);
         }
@@ -255,7 +341,6 @@ impl Header {
         let authorities = buf.get_u16();
         let additional = buf.get_u16();
 
-        let id = id.unwrap();
         Ok(Self {
             id,
             is_query,
@@ -265,7 +350,10 @@ impl Header {
             is_rec_des,
             is_rec_avl,
             z,
+            is_authentic_data,
+            is_checking_disabled,
             response,
+            ext_rcode: 0,
             questions,
             answers,
             authorities,
@@ -301,15 +389,16 @@ impl Header {
             error: error.clone(),
         })?;
         let is_rec_avl = b & RA_MASK == RA_MASK;
-        let z = (b & Z_MASK) >> 4;
+        let z = (b & Z_MASK) >> 6;
+        let is_authentic_data = b & AD_MASK == AD_MASK;
+        let is_checking_disabled = b & CD_MASK == CD_MASK;
         let response = Rcode::from(b & RC_MASK);
 
         let questions = stream.read_u16().await.map_err(|_| TransactionError {
             id,
             error: error.clone(),
         })?;
-        // Multiple queries within one packet is not allowed
-        if questions > 1 {
+        if questions > MAX_QUESTIONS {
             let err = TransactionError {
                 id,
                 error: error.clone(),
@@ -339,7 +428,10 @@ impl Header {
             is_rec_des,
             is_rec_avl,
             z,
+            is_authentic_data,
+            is_checking_disabled,
             response,
+            ext_rcode: 0,
             questions,
             answers,
             authorities,
@@ -362,7 +454,9 @@ impl Header {
         let b = {
             let ra = u8::from(self.is_rec_avl);
             let rc: u8 = self.response.into();
-            (ra << 7) | (self.z << 4) | rc
+            let ad = u8::from(self.is_authentic_data);
+            let cd = u8::from(self.is_checking_disabled);
+            (ra << 7) | (self.z << 6) | (ad << 5) | (cd << 4) | rc
         };
         buf.put_u8(b);
         buf.put_u16(self.questions);
@@ -384,17 +478,31 @@ pub_map_enum! {
     Op<u8> {
         Query => 0,
         IQuery => 1,
-        Status => 2;
+        Status => 2,
+        Notify => 4,
+        Update => 5;
         Reserved
     }
 }
 
+impl Op {
+    /// whether queries of the operation may carry records in the answer section.
+    ///
+    /// NOTIFY may carry the changed RRset, see [RFC1996](https://datatracker.ietf.org/doc/html/rfc1996#section-3.7),
+    /// and the section of UPDATE holds prerequisites, see [RFC2136](https://datatracker.ietf.org/doc/html/rfc2136#section-2).
+    pub fn allows_answers(&self) -> bool {
+        matches!(self, Op::Notify | Op::Update)
+    }
+}
+
 impl Display for Op {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let operation = match *self {
             Op::Query => String::from("Query"),
             Op::IQuery => String::from("Inverse Query"),
             Op::Status => String::from("Status"),
+            Op::Notify => String::from("Notify"),
+            Op::Update => String::from("Update"),
             Op::Reserved(x) => format!("Unknown Operation Code: {}", x),
         };
         write!(f, "{}", operation)
@@ -413,6 +521,35 @@ pub_map_enum! {
     }
 }
 
+impl PacketError {
+    /// the 12-bit extended rcode failures by the error are answered with
+    pub fn full_rcode(&self) -> u16 {
+        let rcode = match self {
+            PacketError::FormatError => Rcode::FormatError,
+            PacketError::ServFail | PacketError::TimedOut => Rcode::ServFail,
+            PacketError::NameError(_) => Rcode::NameError,
+            PacketError::NotImpl(_) => Rcode::NotImpl,
+            PacketError::Refused(_) | PacketError::Prohibited(_) => Rcode::Refused,
+            PacketError::BadVersion(_) => return BADVERS,
+        };
+        u8::from(rcode) as u16
+    }
+}
+
+impl Display for Rcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Rcode::NoError => write!(f, "NOERROR"),
+            Rcode::FormatError => write!(f, "FORMERR"),
+            Rcode::ServFail => write!(f, "SERVFAIL"),
+            Rcode::NameError => write!(f, "NXDOMAIN"),
+            Rcode::NotImpl => write!(f, "NOTIMP"),
+            Rcode::Refused => write!(f, "REFUSED"),
+            Rcode::Reserved(x) => write!(f, "RCODE{}", x),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use bytes::{BufMut, Bytes, BytesMut};
@@ -425,7 +562,7 @@ mod test {
         // create header
         packet.put_u16(0); // id == 0;
         packet.put_u8(1); // query = True (0); Opcode = QUERY (0); AA = FALSE (0); TC = FALSE (0); RD = TRUE (1)
-        packet.put_u8(0x20); // z = 1; rcode = 0;
+        packet.put_u8(0x20); // z = 0; AD = 1; CD = 0; rcode = 0;
         packet.put_u16(1); // QDCOUNT = 1;
         packet.put_u16(0); // ANCOUNT = 0;
         packet.put_u16(0); // NSCOUNT = 0;
@@ -450,7 +587,9 @@ mod test {
         assert!(h.is_rec_des());
 
         assert!(!h.is_rec_avl());
-        assert_eq!(h.get_z(), 2);
+        assert_eq!(h.get_z(), 0);
+        assert!(h.is_authentic_data());
+        assert!(!h.is_checking_disabled());
         assert_eq!(h.get_rcode(), Rcode::NoError);
 
         assert_eq!(h.question_count(), 1);
@@ -468,6 +607,34 @@ mod test {
         assert_eq!(&bin[..], &raw[..]);
     }
 
+    #[tokio::test]
+    async fn test_dnssec_bits() {
+        let mut raw = BytesMut::from(&example_packet()[..]);
+        raw[3] = 0x30; // AD = 1; CD = 1;
+        let raw = raw.freeze();
+        let h = Header::parse(raw.clone(), 0).unwrap();
+        assert!(h.is_authentic_data());
+        assert!(h.is_checking_disabled());
+        assert_eq!(h.get_z(), 0);
+        assert_eq!(h.get_rcode(), Rcode::NoError);
+        assert_eq!(h.try_into_bytes().unwrap().freeze(), raw);
+
+        let h = Header::parse_stream(&mut &raw[..]).await.unwrap();
+        assert!(h.is_authentic_data() && h.is_checking_disabled());
+
+        // the reserved bit is kept apart from them
+        let mut h = Header::parse(example_packet(), 0).unwrap();
+        h.set_authentic_data(false);
+        h.set_checking_disabled(true);
+        let raw = h.try_into_bytes().unwrap();
+        assert_eq!(raw[3], 0x10);
+        let mut raw = BytesMut::from(&example_packet()[..]);
+        raw[3] = 0x50; // z = 1; AD = 0; CD = 1;
+        let h = Header::parse(raw.freeze(), 0).unwrap();
+        assert_eq!(h.get_z(), 1);
+        assert!(!h.is_authentic_data() && h.is_checking_disabled());
+    }
+
     #[tokio::test]
     async fn test_parse_stream() {
         let mut s = &example_packet()[..];
@@ -0,0 +1,123 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::Bytes;
+
+use super::{Op, Packet, Question, Rcode, TransactionError, RR};
+
+/// flags carried in the header of a DNS message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flags {
+    /// the message is a response (QR)
+    pub response: bool,
+    pub opcode: Op,
+    /// authoritative answer (AA)
+    pub authoritative: bool,
+    /// truncated (TC)
+    pub truncated: bool,
+    /// recursion desired (RD)
+    pub recursion_desired: bool,
+    /// recursion available (RA)
+    pub recursion_available: bool,
+    /// authentic data (AD)
+    pub authentic_data: bool,
+    /// checking disabled (CD)
+    pub checking_disabled: bool,
+}
+
+/// ## `Message` is a parsed DNS message.
+/// It wraps `Packet` with read-only accessors for each section.
+/// ```
+/// use std::net::Ipv4Addr;
+///
+/// use tsein_dns::protocol::{Message, RRData, Rcode};
+///
+/// // a response to `example.com. IN A`, captured from the wire
+/// let captured: &[u8] = &[
+///     0x12, 0x34, 0x81, 0x80, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, // header
+///     0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x03, b'c', b'o', b'm', 0x00, //
+///     0x00, 0x01, 0x00, 0x01, // question
+///     0xc0, 0x0c, 0x00, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0e, 0x10, //
+///     0x00, 0x04, 93, 184, 216, 34, // answer
+/// ];
+/// let message = Message::from_bytes(captured.to_vec().into()).unwrap();
+/// assert_eq!(message.id(), 0x1234);
+/// assert_eq!(message.rcode(), Rcode::NoError);
+/// assert!(message.flags().recursion_available);
+///
+/// let addrs: Vec<Ipv4Addr> = message
+///     .answers()
+///     .iter()
+///     .filter_map(|rr| match rr.clone().into_rdata() {
+///         RRData::A(a) => Some(a.into()),
+///         _ => None,
+///     })
+///     .collect();
+/// assert_eq!(addrs, vec![Ipv4Addr::new(93, 184, 216, 34)]);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Message {
+    packet: Packet,
+}
+
+impl Message {
+    pub fn from_bytes(bytes: Bytes) -> Result<Self, TransactionError> {
+        let packet = Packet::parse_packet(bytes, 0)?;
+        Ok(Self { packet })
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        self.packet.clone().into_bytes()
+    }
+
+    /// transaction id
+    pub fn id(&self) -> u16 {
+        self.packet.get_id()
+    }
+
+    pub fn questions(&self) -> &[Question] {
+        &self.packet.questions
+    }
+
+    pub fn answers(&self) -> &[RR] {
+        &self.packet.answers
+    }
+
+    pub fn authorities(&self) -> &[RR] {
+        &self.packet.authorities
+    }
+
+    pub fn additionals(&self) -> &[RR] {
+        &self.packet.additions
+    }
+
+    pub fn rcode(&self) -> Rcode {
+        self.packet.get_rcode()
+    }
+
+    pub fn flags(&self) -> Flags {
+        Flags {
+            response: !self.packet.is_query(),
+            opcode: self.packet.get_op(),
+            authoritative: self.packet.is_auth(),
+            truncated: self.packet.is_trunc(),
+            recursion_desired: self.packet.is_rec_des(),
+            recursion_available: self.packet.is_rec_avl(),
+            authentic_data: self.packet.is_authentic_data(),
+            checking_disabled: self.packet.is_checking_disabled(),
+        }
+    }
+
+    pub fn into_packet(self) -> Packet {
+        self.packet
+    }
+}
+
+impl From<Packet> for Message {
+    fn from(packet: Packet) -> Self {
+        Self { packet }
+    }
+}
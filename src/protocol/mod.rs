@@ -6,32 +6,50 @@
 
 use std::fmt::Display;
 
-use bytes::{BufMut, Bytes, BytesMut};
-use tokio::io::AsyncReadExt;
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+use self::domain::NameOffsets;
 pub use self::{
-    domain::Name,
-    error::{PacketError, TransactionError},
-    header::Header,
+    domain::{CompressWriter, Name},
+    error::{PacketError, ParseNameError, TransactionError},
+    header::{Header, Op, Rcode, BADVERS},
+    message::{Flags, Message},
     question::Question,
-    rr::{RRData, RR},
+    rr::{Aaaa, EdnsOption, Https, OptBuilder, RRData, Svcb, A, RR},
 };
-use crate::protocol::header::{Op, Rcode};
+pub(crate) use self::{reader::PacketReader, rr::min_ttl};
+
+/// maximum size of a DNS message carried over UDP, see [RFC1035](https://datatracker.ietf.org/doc/html/rfc1035#section-4.2.1)
+pub const MAX_UDP_SIZE: usize = 512;
+/// extended DNS error of unreachable authorities, see [RFC8914](https://datatracker.ietf.org/doc/html/rfc8914#section-4.23)
+pub const EDE_NO_REACHABLE_AUTHORITY: u16 = 22;
 
 trait PacketContent {
+    #[allow(dead_code)]
     fn size(&self) -> usize;
-    fn parse(packet: Bytes, pos: usize) -> Result<Self, PacketError>
+    /// read the content off `reader`, leaving it after the content
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError>
     where
         Self: Sized;
+    #[allow(dead_code)]
+    fn parse(packet: Bytes, pos: usize) -> Result<Self, PacketError>
+    where
+        Self: Sized,
+    {
+        Self::read(&mut PacketReader::new(&packet, pos))
+    }
+    #[allow(dead_code)]
     fn into_bytes(self) -> Result<BytesMut, PacketError>;
 }
 
 // Todo: refract Packet, it sucks
 /// DNS data get from primitive packet
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Packet {
     pub header: Header,
-    pub question: Option<Question>,
+    pub questions: Vec<Question>,
     pub answers: Vec<RR>,
     pub authorities: Vec<RR>,
     pub additions: Vec<RR>,
@@ -43,7 +61,7 @@ impl Packet {
         let h = Header::new_answer(id, 0, 0, 0);
         Self {
             header: h,
-            question: None,
+            questions: vec![],
             answers: vec![],
             authorities: vec![],
             additions: vec![],
@@ -54,7 +72,7 @@ impl Packet {
         let header = Header::new_query(id);
         Self {
             header,
-            question: Some(query),
+            questions: vec![query],
             answers: vec![],
             authorities: vec![],
             additions: vec![],
@@ -69,57 +87,10 @@ impl Packet {
             packet.len()
         );
 
-        let h = Header::parse(packet.clone(), offset)?;
-        tracing::trace!("parse header successful with header {:?}", h);
-
-        let id = Some(h.get_id());
-
-        let mut question = None;
-        let mut answers = vec![];
-        let mut offset = offset + 12;
-
-        if h.is_query() && h.answer_count() != 0 {
-            let err = TransactionError {
-                id,
-                error: PacketError::FormatError,
-            };
-            // no answer is expected in query packet.
-            return Err(err);
-        }
-        for _ in 0..h.question_count() {
-            let ques = Question::parse(packet.clone(), offset)
-                .map_err(|error| TransactionError { id, error })?;
-            offset += ques.size();
-            question = Some(ques);
-        }
-        for _ in 0..h.answer_count() {
-            let rr = RR::parse(packet.clone(), offset)
-                .map_err(|error| TransactionError { id, error })?;
-            offset += rr.size();
-            answers.push(rr);
-        }
-        let mut authorities = Vec::new();
-        for _ in 0..h.authority_count() {
-            let rr = RR::parse(packet.clone(), offset)
-                .map_err(|error| TransactionError { id, error })?;
-            offset += rr.size();
-            authorities.push(rr);
-        }
-        let mut additions = Vec::new();
-        for _ in 0..h.addition_count() {
-            let rr = RR::parse(packet.clone(), offset)
-                .map_err(|error| TransactionError { id, error })?;
-            offset += rr.size();
-            additions.push(rr);
-        }
-        let pkt = Packet {
-            header: h,
-            question,
-            answers,
-            authorities,
-            additions,
-        };
-        Ok(pkt)
+        let mut reader = PacketReader::new(&packet, offset);
+        let header = Header::read(&mut reader)?;
+        tracing::trace!("parse header successful with header {:?}", header);
+        Self::read_sections(header, &mut reader)
     }
 
     pub async fn parse_stream<S>(stream: &mut S) -> Result<Self, TransactionError>
@@ -143,110 +114,176 @@ impl Packet {
             return Err(err);
         }
 
-        let to_read = (len - 12) as usize;
-        let mut pkt = Vec::from([0; 12]);
-        let read = stream
-            .read_buf(&mut pkt)
+        // read exactly the rest of the framed message, in case of pipelined messages
+        // the header is already parsed, leave its space for the offsets
+        let mut pkt = vec![0; len as usize];
+        stream
+            .read_exact(&mut pkt[12..])
             .await
             .map_err(|_| TransactionError {
                 id,
                 error: PacketError::FormatError,
             })?;
-        if read < to_read {
-            let err = TransactionError {
-                id,
-                error: PacketError::FormatError,
-            };
-            return Err(err);
-        }
-
-        let mut question = None;
-        let mut answers = vec![];
-        let mut offset = 12;
 
         let packet = Bytes::from(pkt);
-        if header.is_query() && header.answer_count() != 0 {
+        // bytes left after the additional section within the frame are garbage, ignore them
+        Self::read_sections(header, &mut PacketReader::new(&packet, 12))
+    }
+
+    /// read the sections following `header` off `reader`
+    fn read_sections(
+        mut header: Header,
+        reader: &mut PacketReader,
+    ) -> Result<Self, TransactionError> {
+        let id = Some(header.get_id());
+        if header.is_query() && !header.get_op().allows_answers() && header.answer_count() != 0 {
             let err = TransactionError {
                 id,
                 error: PacketError::FormatError,
             };
-            // no answer is expected in query packet.
+            // no answer is expected in query packet, other than NOTIFY and UPDATE.
             return Err(err);
         }
+        let to_transaction = |error| TransactionError { id, error };
 
+        let mut questions = vec![];
         for _ in 0..header.question_count() {
-            let ques = Question::parse(packet.clone(), offset)
-                .map_err(|error| TransactionError { id, error })?;
-            offset += ques.size();
-            question = Some(ques);
+            questions.push(Question::read(reader).map_err(to_transaction)?);
         }
-
+        let mut answers = vec![];
         for _ in 0..header.answer_count() {
-            let rr = RR::parse(packet.clone(), offset)
-                .map_err(|error| TransactionError { id, error })?;
-            offset += rr.size();
-            answers.push(rr);
+            answers.push(RR::read(reader).map_err(to_transaction)?);
         }
-        let mut authorities = Vec::new();
+        let mut authorities = vec![];
         for _ in 0..header.authority_count() {
-            let rr = RR::parse(packet.clone(), offset)
-                .map_err(|error| TransactionError { id, error })?;
-            offset += rr.size();
-            authorities.push(rr);
+            authorities.push(RR::read(reader).map_err(to_transaction)?);
         }
-        let mut additions = Vec::new();
+        let mut additions = vec![];
         for _ in 0..header.addition_count() {
-            let rr = RR::parse(packet.clone(), offset)
-                .map_err(|error| TransactionError { id, error })?;
-            offset += rr.size();
-            additions.push(rr);
+            additions.push(RR::read(reader).map_err(to_transaction)?);
+        }
+        if let Some(ext_rcode) = additions.iter().find_map(RR::ext_rcode) {
+            header.set_ext_rcode(ext_rcode);
         }
-        let pkt = Packet {
+        Ok(Packet {
             header,
-            question,
+            questions,
             answers,
             authorities,
             additions,
-        };
-        Ok(pkt)
+        })
     }
 
     /// Generate DNS failure response
+    ///
+    /// Failures beyond the 4-bit rcode, like BADVERS, carry an `OPT` record for the upper bits.
     pub fn new_failure(id: u16, rcode: PacketError) -> Packet {
+        Self::failure(id, rcode, None)
+    }
+
+    /// Generate DNS failure response to a query carrying EDNS
+    ///
+    /// Besides `new_failure`, timeouts tell the reason by an extended DNS error.
+    /// Queries without an `OPT` record must not get one, see RFC6891.
+    pub fn new_edns_failure(id: u16, rcode: PacketError) -> Packet {
+        let ede = match rcode {
+            PacketError::TimedOut => Some(EdnsOption::ExtendedError {
+                info_code: EDE_NO_REACHABLE_AUTHORITY,
+                text: "query timed out".to_string(),
+            }),
+            _ => None,
+        };
+        Self::failure(id, rcode, ede)
+    }
+
+    fn failure(id: u16, rcode: PacketError, ede: Option<EdnsOption>) -> Packet {
         let header = Header::new_failure(id, rcode);
-        Packet {
+        let mut packet = Packet {
             header,
-            question: None,
+            questions: vec![],
             answers: vec![],
             authorities: vec![],
             additions: vec![],
+        };
+        let ext_rcode = header.get_ext_rcode();
+        if ext_rcode != 0 || ede.is_some() {
+            let opt = OptBuilder::new()
+                .udp_size(MAX_UDP_SIZE as u16)
+                .ext_rcode(ext_rcode);
+            let opt = ede.into_iter().fold(opt, OptBuilder::option);
+            packet.additions.push(opt.build());
+            packet.header.set_additional(1);
         }
+        packet
     }
 
-    // Todo: support domain name compressing
-    /// make a binary
+    /// make a binary, names repeated in the packet are compressed
     pub fn into_bytes(self) -> Bytes {
-        let mut buf = BytesMut::new();
-        let h = self.header.try_into_bytes().unwrap();
-        buf.put_slice(&h[..]);
-        if let Some(question) = self.question {
-            let q = question.into_bytes().unwrap();
-            buf.put_slice(&q[..]);
-        }
-        for answer in self.answers {
-            let a = answer.into_bytes().unwrap();
-            buf.put_slice(&a[..]);
+        let mut writer = CompressWriter::with_capacity(self.size());
+        self.write_into(&mut writer);
+        writer.into_bytes()
+    }
+
+    /// write the packet led by its length into a stream, like TCP, at once.
+    ///
+    /// Packets longer than 65535 bytes are not written, an `InvalidInput` error is returned.
+    pub async fn write_to<W>(self, writer: &mut W) -> std::io::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = CompressWriter::with_length_prefix(self.size());
+        self.write_into(&mut buf);
+        if buf.len() > u16::MAX as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "packet too long for a stream",
+            ));
         }
-        for authority in self.authorities {
-            let a = authority.into_bytes().unwrap();
-            buf.put_slice(&a[..]);
+        writer.write_all(&buf.into_bytes()).await
+    }
+
+    /// length of the binary made by `into_bytes`, without making it
+    pub fn size(&self) -> usize {
+        let mut names = NameOffsets::default();
+        let mut size = 12;
+        for question in self.questions.iter() {
+            size += question.compressed_size(&mut names, size);
         }
-        for addition in self.additions {
-            let a = addition.into_bytes().unwrap();
-            buf.put_slice(&a[..]);
+        for rr in self
+            .answers
+            .iter()
+            .chain(self.authorities.iter())
+            .chain(self.additions.iter())
+        {
+            size += rr.compressed_size(&mut names, size);
         }
+        size
+    }
 
-        Bytes::from(buf)
+    /// the least TTL of records in all sections, none if there are no records
+    pub fn min_ttl(&self) -> Option<std::time::Duration> {
+        min_ttl(
+            self.answers
+                .iter()
+                .chain(self.authorities.iter())
+                .chain(self.additions.iter()),
+        )
+    }
+
+    fn write_into(self, writer: &mut CompressWriter) {
+        let h = self.header.try_into_bytes().unwrap();
+        writer.put_slice(&h[..]);
+        for question in self.questions {
+            question.compress_into(writer);
+        }
+        for rr in self
+            .answers
+            .into_iter()
+            .chain(self.authorities)
+            .chain(self.additions)
+        {
+            rr.compress_into(writer).unwrap();
+        }
     }
 }
 
@@ -299,6 +336,18 @@ impl Packet {
         self.header.get_z()
     }
 
+    #[inline]
+    /// is the answer authenticated by DNSSEC (AD)
+    pub fn is_authentic_data(&self) -> bool {
+        self.header.is_authentic_data()
+    }
+
+    #[inline]
+    /// is DNSSEC validation disabled by the client (CD)
+    pub fn is_checking_disabled(&self) -> bool {
+        self.header.is_checking_disabled()
+    }
+
     #[inline]
     /// get the rcode in header
     pub fn get_rcode(&self) -> Rcode {
@@ -311,6 +360,12 @@ impl Packet {
         self.header.question_count()
     }
 
+    #[inline]
+    /// the first question, the only one in queries answered by this server
+    pub fn question(&self) -> Option<&Question> {
+        self.questions.first()
+    }
+
     #[inline]
     /// how many answers are there in the packet
     pub fn answer_count(&self) -> u16 {
@@ -328,12 +383,25 @@ impl Packet {
     pub fn addition_count(&self) -> u16 {
         self.header.addition_count()
     }
+
+    /// does the packet carry an `OPT` record, i.e. its sender speaks EDNS
+    pub fn has_edns(&self) -> bool {
+        self.additions.iter().any(|rr| rr.get_type() == RRType::Opt)
+    }
+
+    /// the largest response the sender takes over UDP, by the payload size of its `OPT` record.
+    ///
+    /// Sizes below 512 bytes are taken as 512, see [RFC6891](https://datatracker.ietf.org/doc/html/rfc6891#section-6.2.5).
+    pub fn udp_payload_size(&self) -> usize {
+        let size = self.additions.iter().find_map(RR::udp_size);
+        size.map_or(MAX_UDP_SIZE, |size| (size as usize).max(MAX_UDP_SIZE))
+    }
 }
 
 impl Packet {
     pub fn set_question(&mut self, question: Question) {
         self.header.set_questions(1);
-        self.question = Some(question);
+        self.questions = vec![question];
     }
 
     pub fn set_answers(&mut self, answers: Vec<RR>) {
@@ -353,6 +421,73 @@ impl Packet {
 }
 
 impl Packet {
+    /// Trim the packet until its wire form fits into `max_size` bytes.
+    ///
+    /// Additional records are dropped first, since losing them does not make the response
+    /// incomplete. If the packet still does not fit, authority and answer records are
+    /// dropped as well and the TC bit is set, so that the client could retry over TCP.
+    /// The `OPT` record is always kept, along with the cookie in it, see RFC6891 and RFC7873.
+    pub fn truncate(&mut self, max_size: usize) {
+        let opt = self
+            .additions
+            .iter()
+            .position(|rr| rr.get_type() == RRType::Opt)
+            .map(|i| self.additions.remove(i));
+        // names are only compressed by pointers to earlier ones,
+        // so dropping the last record saves just what it takes on the wire
+        let mut names = NameOffsets::default();
+        let mut size = 12;
+        for question in self.questions.iter() {
+            size += question.compressed_size(&mut names, size);
+        }
+        let mut rr_sizes = vec![];
+        for rr in self
+            .answers
+            .iter()
+            .chain(self.authorities.iter())
+            .chain(self.additions.iter())
+        {
+            let rr_size = rr.compressed_size(&mut names, size);
+            rr_sizes.push(rr_size);
+            size += rr_size;
+        }
+        // the owner of `OPT` is the root, never compressed wherever it goes
+        if let Some(opt) = &opt {
+            size += opt.compressed_size(&mut names, size);
+        }
+        let mut rr_size = || rr_sizes.pop().unwrap_or_default();
+
+        while size > max_size {
+            if self.additions.pop().is_none() {
+                break;
+            }
+            size -= rr_size();
+        }
+        self.additions.extend(opt);
+        self.header.set_additional(self.additions.len() as u16);
+
+        let mut is_trunc = false;
+        while size > max_size {
+            if self.authorities.pop().is_none() && self.answers.pop().is_none() {
+                break;
+            }
+            size -= rr_size();
+            is_trunc = true;
+        }
+        self.header.set_authorities(self.authorities.len() as u16);
+        self.header.set_answers(self.answers.len() as u16);
+        if is_trunc {
+            self.header.set_truncated(true);
+        }
+    }
+}
+
+impl Packet {
+    pub fn add_question(&mut self, question: Question) {
+        self.questions.push(question);
+        self.header.set_questions(self.header.question_count() + 1);
+    }
+
     pub fn add_answer(&mut self, answer: RR) {
         self.answers.push(answer);
         self.header.set_answers(self.header.answer_count() + 1);
@@ -364,9 +499,112 @@ impl Packet {
             .set_authorities(self.header.authority_count() + 1);
     }
 
-    pub fn add_addition(&mut self, additional: RR) {
+    /// add a record to the additional section, a packet carries at most one `OPT` record
+    pub fn add_addition(&mut self, additional: RR) -> Result<(), PacketError> {
+        let is_opt = |rr: &RR| rr.get_type() == RRType::Opt;
+        if is_opt(&additional) && self.additions.iter().any(is_opt) {
+            return Err(PacketError::FormatError);
+        }
         self.additions.push(additional);
         self.header.set_additional(self.header.addition_count() + 1);
+        Ok(())
+    }
+}
+
+/// the layout of `dig`: header, flags, then the sections.
+///
+/// `OPT` is shown in a pseudo section of its own, not among the additional records.
+impl Display for Packet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let opcode = match self.get_op() {
+            Op::Query => String::from("QUERY"),
+            Op::IQuery => String::from("IQUERY"),
+            Op::Status => String::from("STATUS"),
+            Op::Notify => String::from("NOTIFY"),
+            Op::Update => String::from("UPDATE"),
+            Op::Reserved(x) => format!("OPCODE{}", x),
+        };
+        let status = match self.header.full_rcode() {
+            BADVERS => String::from("BADVERS"),
+            rcode if rcode > 0xf => format!("RCODE{}", rcode),
+            _ => self.get_rcode().to_string(),
+        };
+        writeln!(
+            f,
+            ";; ->>HEADER<<- opcode: {}, status: {}, id: {}",
+            opcode,
+            status,
+            self.get_id()
+        )?;
+
+        let flags = [
+            (!self.is_query(), "qr"),
+            (self.is_auth(), "aa"),
+            (self.is_trunc(), "tc"),
+            (self.is_rec_des(), "rd"),
+            (self.is_rec_avl(), "ra"),
+            (self.is_authentic_data(), "ad"),
+            (self.is_checking_disabled(), "cd"),
+        ];
+        write!(f, ";; flags:")?;
+        for (_, flag) in flags.iter().filter(|(set, _)| *set) {
+            write!(f, " {}", flag)?;
+        }
+        writeln!(
+            f,
+            "; QUERY: {}, ANSWER: {}, AUTHORITY: {}, ADDITIONAL: {}",
+            self.question_count(),
+            self.answer_count(),
+            self.authority_count(),
+            self.addition_count()
+        )?;
+
+        let (opt, additions): (Vec<&RR>, Vec<&RR>) = self
+            .additions
+            .iter()
+            .partition(|rr| rr.get_type() == RRType::Opt);
+        if let Some(opt) = opt.first() {
+            writeln!(f, "\n;; OPT PSEUDOSECTION:")?;
+            let dnssec_ok = if opt.dnssec_ok() == Some(true) {
+                " do"
+            } else {
+                ""
+            };
+            writeln!(
+                f,
+                "; EDNS: version: {}, flags:{}; udp: {}",
+                opt.edns_version().unwrap_or_default(),
+                dnssec_ok,
+                opt.udp_size().unwrap_or_default()
+            )?;
+        }
+        if !self.questions.is_empty() {
+            writeln!(f, "\n;; QUESTION SECTION:")?;
+            for q in self.questions.iter() {
+                writeln!(
+                    f,
+                    ";{}\t\t{}\t{}",
+                    q.get_name(),
+                    q.get_class(),
+                    q.get_type()
+                )?;
+            }
+        }
+        let sections = [
+            ("ANSWER", self.answers.iter().collect()),
+            ("AUTHORITY", self.authorities.iter().collect()),
+            ("ADDITIONAL", additions),
+        ];
+        for (section, rrs) in sections {
+            if rrs.is_empty() {
+                continue;
+            }
+            writeln!(f, "\n;; {} SECTION:", section)?;
+            for rr in rrs {
+                writeln!(f, "{}", rr)?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -395,6 +633,7 @@ impl Packet {
 macro_rules! pub_map_enum {
     ($name:ident <$t:ty> {$($key: ident => $value: expr),*; $fallback:ident}) => {
         #[derive(PartialEq, Eq, Debug, Copy, Clone, Hash)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
         pub enum $name {
             $($key,)*
             $fallback($t),
@@ -436,7 +675,20 @@ pub_map_enum! {RRType<u16> {
     MInfo => 14,
     Mx => 15,
     Txt => 16,
-    Aaaa => 28;
+    Aaaa => 28,
+    Naptr => 35,
+    Dname => 39,
+    Opt => 41,
+    Ds => 43,
+    Rrsig => 46,
+    Nsec => 47,
+    Dnskey => 48,
+    Svcb => 64,
+    Https => 65,
+    Ixfr => 251,
+    Axfr => 252,
+    Any => 255,
+    Uri => 256;
     UNKNOWN
 }}
 
@@ -458,6 +710,19 @@ impl Display for RRType {
             RRType::MInfo => String::from("MINFO"),
             RRType::Txt => String::from("TXT"),
             RRType::Aaaa => String::from("AAAA"),
+            RRType::Naptr => String::from("NAPTR"),
+            RRType::Dname => String::from("DNAME"),
+            RRType::Opt => String::from("OPT"),
+            RRType::Ds => String::from("DS"),
+            RRType::Rrsig => String::from("RRSIG"),
+            RRType::Nsec => String::from("NSEC"),
+            RRType::Dnskey => String::from("DNSKEY"),
+            RRType::Svcb => String::from("SVCB"),
+            RRType::Https => String::from("HTTPS"),
+            RRType::Ixfr => String::from("IXFR"),
+            RRType::Axfr => String::from("AXFR"),
+            RRType::Any => String::from("ANY"),
+            RRType::Uri => String::from("URI"),
             RRType::UNKNOWN(val) => format!("UNKNOWN({})", val),
         };
         write!(f, "{}", s)
@@ -469,10 +734,26 @@ pub_map_enum! {RRClass<u16> {
     Reserved => 0,
     Internet => 1,
     Chaos => 3,
-    Hesiod => 4;
+    Hesiod => 4,
+    None => 254,
+    Any => 255;
     Unknown
 }}
 
+impl Display for RRClass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            RRClass::Internet => write!(f, "IN"),
+            RRClass::Chaos => write!(f, "CH"),
+            RRClass::Hesiod => write!(f, "HS"),
+            RRClass::None => write!(f, "NONE"),
+            RRClass::Any => write!(f, "ANY"),
+            RRClass::Reserved => write!(f, "CLASS0"),
+            RRClass::Unknown(x) => write!(f, "CLASS{}", x),
+        }
+    }
+}
+
 // testing macron is enough
 #[test]
 fn test_pub_map_enum() {
@@ -495,17 +776,28 @@ mod domain;
 mod error;
 /// DNS packet header
 mod header;
+/// typed view of DNS packets for library users
+mod message;
 /// DNS packet question
 mod question;
+/// cursor over packets being parsed
+mod reader;
 /// DNS Resource Record
 mod rr;
 
 #[cfg(test)]
 mod integrated_test {
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr},
+        time::Duration,
+    };
+
     use bytes::{BufMut, Bytes, BytesMut};
 
     use crate::protocol::{
-        header::Header, question::Question, Packet, PacketContent, RRClass, RRType, RR,
+        header::Header, question::Question, EdnsOption, Name, Op, OptBuilder, Packet,
+        PacketContent, PacketError, RRClass, RRData, RRType, Rcode, TransactionError, BADVERS,
+        MAX_UDP_SIZE, RR,
     };
 
     fn example_lookup_raw() -> Bytes {
@@ -513,7 +805,7 @@ mod integrated_test {
         // create header
         packet.put_u16(0); // id == 0;
         packet.put_u8(1); // query = True (0); Opcode = QUERY (0); AA = FALSE (0); TC = FALSE (0); RD = TRUE (1)
-        packet.put_u8(0x20); // z = 1; rcode = 0;
+        packet.put_u8(0x20); // z = 0; AD = 1; rcode = 0;
         packet.put_u16(1); // QDCOUNT = 1;
         packet.put_u16(0); // ANCOUNT = 0;
         packet.put_u16(0); // NSCOUNT = 0;
@@ -543,7 +835,7 @@ mod integrated_test {
         let answer = RR::parse(ans_raw, 0).unwrap();
         p.add_answer(answer);
         assert!(!p.is_query());
-        assert!(p.question.is_none());
+        assert!(p.questions.is_empty());
         assert_eq!(p.answers.len(), 1);
     }
 
@@ -577,7 +869,7 @@ mod integrated_test {
         // create header
         packet.put_u16(0); // id == 0;
         packet.put_u8(1); // query = True (0); Opcode = QUERY (0); AA = FALSE (0); TC = FALSE (0); RD = TRUE (1)
-        packet.put_u8(0x20); // z = 1; rcode = 0;
+        packet.put_u8(0x20); // z = 0; AD = 1; rcode = 0;
         packet.put_u16(1); // QDCOUNT = 2;
         packet.put_u16(1); // ANCOUNT = 1;
         packet.put_u16(0); // NSCOUNT = 0;
@@ -604,12 +896,44 @@ mod integrated_test {
         let outcome = Packet::parse_packet(p, 0);
         assert!(outcome.is_ok());
         let pkt = outcome.unwrap();
-        assert!(pkt.question.is_some());
+        assert_eq!(pkt.questions.len(), 1);
         assert_eq!(pkt.answers.len(), 0);
         assert_eq!(pkt.authorities.len(), 0);
         assert_eq!(pkt.additions.len(), 0);
         assert_eq!(pkt.header.get_id(), 0);
-        assert_eq!(pkt.question.unwrap().get_name().to_string(), "example.com.");
+        assert_eq!(pkt.questions[0].get_name().to_string(), "example.com.");
+    }
+
+    #[test]
+    fn test_multiple_questions() {
+        let mut packet = BytesMut::from(&example_lookup_raw()[..]);
+        packet[5] = 2; // QDCOUNT = 2;
+                       // www.example.com AAAA, pointing to the first question name
+        packet.put_slice(&[3, b'w', b'w', b'w', 0xc0, 12]);
+        packet.put_u16(u16::from(RRType::Aaaa));
+        packet.put_u16(u16::from(RRClass::Internet));
+
+        let pkt = Packet::parse_packet(Bytes::from(packet.clone()), 0).unwrap();
+        assert_eq!(pkt.question_count(), 2);
+        assert_eq!(pkt.questions.len(), 2);
+        assert_eq!(
+            pkt.question().unwrap().get_name().to_string(),
+            "example.com."
+        );
+        assert_eq!(pkt.questions[1].get_name().to_string(), "www.example.com.");
+        assert_eq!(pkt.questions[1].get_type(), RRType::Aaaa);
+        assert_eq!(pkt.into_bytes(), packet);
+
+        // too many questions are rejected before parsing them
+        packet[5] = 17;
+        let outcome = Packet::parse_packet(Bytes::from(packet), 0);
+        assert!(matches!(
+            outcome,
+            Err(TransactionError {
+                error: PacketError::FormatError,
+                ..
+            })
+        ));
     }
 
     #[test]
@@ -619,6 +943,430 @@ mod integrated_test {
         assert_eq!(p, parsed);
     }
 
+    #[test]
+    fn test_truncate() {
+        let slc = &[
+            7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 0, 1, 191, 82, 0,
+            4, 19, 19, 81, 0,
+        ][..];
+        let answer = RR::parse(Bytes::from(slc), 0).unwrap();
+        let question = Question::parse(example_lookup_raw(), 12).unwrap();
+
+        // 12 bytes header, 17 bytes question and 16 bytes for each answer, its name compressed
+        let mut p = Packet::new_plain_answer(0);
+        p.set_question(question);
+        p.set_answers(vec![answer; 40]);
+        p.add_addition(p.answers[0].clone()).unwrap();
+        assert!(p.size() > MAX_UDP_SIZE);
+
+        p.truncate(MAX_UDP_SIZE);
+        assert!(p.is_trunc());
+        assert!(p.additions.is_empty());
+        assert_eq!(p.answers.len(), (MAX_UDP_SIZE - 12 - 17) / 16);
+        let buf = p.clone().into_bytes();
+        assert!(buf.len() <= MAX_UDP_SIZE);
+        assert_eq!(buf.len(), p.size());
+        // as many answers as fit are kept
+        let mut more = p.clone();
+        more.add_answer(p.answers[0].clone());
+        assert!(more.size() > MAX_UDP_SIZE);
+
+        let parsed = Packet::parse_packet(buf, 0).unwrap();
+        assert!(parsed.is_trunc());
+        assert_eq!(parsed.answer_count() as usize, p.answers.len());
+        assert_eq!(parsed.addition_count(), 0);
+
+        // the OPT record is kept, in place of answers
+        let mut with_opt = Packet::new_plain_answer(0);
+        with_opt.set_question(parsed.questions[0].clone());
+        with_opt.set_answers(vec![parsed.answers[0].clone(); 40]);
+        with_opt.add_addition(parsed.answers[0].clone()).unwrap();
+        let opt = OptBuilder::new().option(EdnsOption::Cookie(vec![7; 24]));
+        with_opt.add_addition(opt.build()).unwrap();
+        with_opt.truncate(MAX_UDP_SIZE);
+        assert!(with_opt.is_trunc());
+        assert!(with_opt.has_edns());
+        assert_eq!(with_opt.additions.len(), 1);
+        assert!(with_opt.size() <= MAX_UDP_SIZE);
+        assert!(with_opt.answers.len() < p.answers.len());
+        let parsed_opt = Packet::parse_packet(with_opt.clone().into_bytes(), 0).unwrap();
+        assert_eq!(parsed_opt.addition_count(), 1);
+        assert_eq!(parsed_opt.answer_count() as usize, with_opt.answers.len());
+
+        // small packets are left untouched
+        let mut p = Packet::new_plain_answer(0);
+        p.add_answer(parsed.answers[0].clone());
+        p.add_addition(parsed.answers[0].clone()).unwrap();
+        p.truncate(MAX_UDP_SIZE);
+        assert!(!p.is_trunc());
+        assert_eq!(p.answers.len(), 1);
+        assert_eq!(p.additions.len(), 1);
+    }
+
+    #[test]
+    fn test_single_opt() {
+        let mut p = Packet::new_plain_answer(0);
+        p.add_addition(OptBuilder::new().build()).unwrap();
+        let opt = OptBuilder::new().dnssec_ok(true).build();
+        assert!(matches!(p.add_addition(opt), Err(PacketError::FormatError)));
+        assert_eq!(p.additions.len(), 1);
+        assert_eq!(p.addition_count(), 1);
+    }
+
+    #[test]
+    fn test_compression() {
+        let slc = &[
+            7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 0, 1, 191, 82, 0,
+            4, 19, 19, 81, 0,
+        ][..];
+        let answer = RR::parse(Bytes::from(slc), 0).unwrap();
+        let question = Question::parse(example_lookup_raw(), 12).unwrap();
+        let mut p = Packet::new_plain_answer(0);
+        p.set_question(question.clone());
+        p.set_answers(vec![answer.clone(); 4]);
+
+        let uncompressed = 12
+            + question.clone().into_bytes().unwrap().len()
+            + 4 * answer.clone().into_bytes().unwrap().len();
+        let buf = p.into_bytes();
+        // each owner name is replaced by a 2 bytes pointer to the question
+        assert_eq!(buf.len(), uncompressed - 4 * (13 - 2));
+
+        let parsed = Packet::parse_packet(buf, 0).unwrap();
+        assert_eq!(parsed.questions[0].get_name(), question.get_name());
+        assert_eq!(parsed.answers.len(), 4);
+        for rr in parsed.answers {
+            assert_eq!(rr.get_domain(), answer.get_domain());
+            assert!(matches!(rr.into_rdata(), RRData::A(_)));
+        }
+    }
+
+    #[test]
+    fn test_size() {
+        let name = |name: &str| Name::try_from(name).unwrap();
+        let rr = |owner: &str, rdata: RRData| {
+            RR::new(
+                name(owner),
+                Duration::from_secs(60),
+                RRClass::Internet,
+                rdata,
+            )
+        };
+        let question = Question::parse(example_lookup_raw(), 12).unwrap();
+
+        let mut answer = Packet::new_plain_answer(3);
+        answer.set_question(question.clone());
+        answer.set_answers(vec![
+            rr("example.com", RRData::Cname(name("www.example.com").into())),
+            rr(
+                "www.example.com",
+                RRData::A(Ipv4Addr::new(192, 0, 2, 1).into()),
+            ),
+            rr("www.example.com", RRData::Aaaa(Ipv6Addr::LOCALHOST.into())),
+            rr(
+                "www.example.com",
+                RRData::Txt(String::from("v=spf1 -all").into()),
+            ),
+        ]);
+        answer.set_authorities(vec![rr(
+            "example.com",
+            RRData::Ns(name("ns.example.net").into()),
+        )]);
+        answer.set_addtionals(vec![rr(
+            "ns.example.net",
+            RRData::A(Ipv4Addr::new(192, 0, 2, 53).into()),
+        )]);
+
+        let packets = [
+            Packet::new_failure(1, PacketError::ServFail),
+            Packet::new_query(2, question),
+            Packet::parse_packet(example_answer(), 0).unwrap(),
+            answer,
+        ];
+        for p in packets {
+            assert_eq!(p.size(), p.clone().into_bytes().len(), "{:?}", p);
+        }
+    }
+
+    /// owner, type, class and RDATA of a record
+    type RawRecord<'a> = (&'a str, RRType, RRClass, &'a [u8]);
+
+    /// a query packet of `opcode` with a zone `example.com. SOA` and records of `sections`
+    fn query_with_records(opcode: u8, sections: [&[RawRecord]; 3]) -> Bytes {
+        let mut pkt = BytesMut::new();
+        pkt.put_u16(0x1234);
+        pkt.put_u8(opcode << 3);
+        pkt.put_u8(0);
+        pkt.put_u16(1);
+        for section in sections {
+            pkt.put_u16(section.len() as u16);
+        }
+        pkt.put(
+            Name::try_from("example.com")
+                .unwrap()
+                .as_bytes_uncompressed(),
+        );
+        pkt.put_u16(RRType::Soa.into());
+        pkt.put_u16(RRClass::Internet.into());
+        for (name, ty, class, rdata) in sections.into_iter().flatten() {
+            pkt.put(Name::try_from(*name).unwrap().as_bytes_uncompressed());
+            pkt.put_u16((*ty).into());
+            pkt.put_u16((*class).into());
+            pkt.put_u32(if *class == RRClass::Internet { 300 } else { 0 });
+            pkt.put_u16(rdata.len() as u16);
+            pkt.put_slice(rdata);
+        }
+        pkt.freeze()
+    }
+
+    #[test]
+    fn test_parse_opcode_sections() {
+        let addr: &[u8] = &[192, 0, 2, 1];
+        let answer = [("www.example.com", RRType::A, RRClass::Internet, addr)];
+
+        // answers are unexpected in plain queries
+        let query = query_with_records(0, [&answer, &[], &[]]);
+        assert!(Packet::parse_packet(query, 0).is_err());
+
+        // NOTIFY may carry the changed RRset
+        let notify = Packet::parse_packet(query_with_records(4, [&answer, &[], &[]]), 0).unwrap();
+        assert_eq!(notify.get_op(), Op::Notify);
+        assert_eq!(notify.answers.len(), 1);
+
+        // UPDATE: prerequisites, updates and additional records
+        let prerequisites = [("www.example.com", RRType::A, RRClass::Any, &[][..])];
+        let updates = [
+            ("old.example.com", RRType::A, RRClass::Any, &[][..]),
+            ("www.example.com", RRType::A, RRClass::Internet, addr),
+        ];
+        let raw = query_with_records(5, [&prerequisites, &updates, &[]]);
+        let update = Packet::parse_packet(raw.clone(), 0).unwrap();
+        assert_eq!(update.get_op(), Op::Update);
+        assert_eq!(update.answers.len(), 1);
+        assert_eq!(update.authorities.len(), 2);
+        assert_eq!(update.answers[0].get_type(), RRType::A);
+        assert_eq!(update.size(), update.clone().into_bytes().len());
+
+        let reparsed = Packet::parse_packet(update.into_bytes(), 0).unwrap();
+        assert_eq!(reparsed.authorities.len(), 2);
+    }
+
+    #[test]
+    fn test_min_ttl() {
+        let name = Name::try_from("example.com").unwrap();
+        let rr = |secs| {
+            let a = RRData::A(Ipv4Addr::new(192, 0, 2, 1).into());
+            RR::new(
+                name.clone(),
+                Duration::from_secs(secs),
+                RRClass::Internet,
+                a,
+            )
+        };
+        let mut p = Packet::new_plain_answer(1);
+        assert_eq!(p.min_ttl(), None);
+
+        p.set_answers(vec![rr(300)]);
+        p.set_authorities(vec![rr(60)]);
+        p.set_addtionals(vec![rr(3600)]);
+        assert_eq!(p.min_ttl(), Some(Duration::from_secs(60)));
+    }
+
+    #[tokio::test]
+    async fn test_bad_version() {
+        let failure = Packet::new_failure(1, PacketError::BadVersion(1));
+        assert_eq!(failure.header.full_rcode(), BADVERS);
+        assert_eq!(failure.get_rcode(), Rcode::NoError);
+
+        let bytes = failure.into_bytes();
+        // the OPT record of the root: TYPE 41, CLASS 512, TTL with the upper rcode bits
+        assert_eq!(
+            &bytes[12..][..11],
+            b"\x00\x00\x29\x02\x00\x01\x00\x00\x00\x00\x00"
+        );
+        let parsed = Packet::parse_packet(bytes.clone(), 0).unwrap();
+        assert_eq!(parsed.header.full_rcode(), 16);
+        assert_eq!(parsed.header.get_ext_rcode(), 1);
+        assert_eq!(parsed.additions[0].ext_rcode(), Some(1));
+
+        let mut stream = BytesMut::new();
+        stream.put_u16(bytes.len() as u16);
+        stream.put(bytes);
+        let parsed = Packet::parse_stream(&mut &stream[..]).await.unwrap();
+        assert_eq!(parsed.header.full_rcode(), 16);
+
+        // failures within 4 bits carry no OPT record
+        let failure = Packet::new_failure(1, PacketError::ServFail);
+        assert_eq!(failure.header.full_rcode(), 2);
+        assert_eq!(failure.addition_count(), 0);
+    }
+
+    #[test]
+    fn test_timed_out() {
+        // no OPT record to queries without EDNS
+        let failure = Packet::new_failure(1, PacketError::TimedOut);
+        assert_eq!(failure.get_rcode(), Rcode::ServFail);
+        assert_eq!(failure.addition_count(), 0);
+        assert!(!failure.has_edns());
+
+        let failure = Packet::new_edns_failure(1, PacketError::TimedOut);
+        assert!(failure.has_edns());
+        let parsed = Packet::parse_packet(failure.into_bytes(), 0).unwrap();
+        assert_eq!(parsed.get_rcode(), Rcode::ServFail);
+        assert_eq!(parsed.header.full_rcode(), 2);
+        match parsed.additions[0].clone().into_rdata() {
+            RRData::Opt(opt) => {
+                assert_eq!(opt.get_option(15), Some(&b"\x00\x16query timed out"[..]))
+            }
+            rdata => panic!("unexpected additional record: {:?}", rdata),
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        let name = |name: &str| Name::try_from(name).unwrap();
+        let rr = |owner: &str, rdata: RRData| {
+            RR::new(
+                name(owner),
+                Duration::from_secs(300),
+                RRClass::Internet,
+                rdata,
+            )
+        };
+        let mut answer = Packet::new_plain_answer(4660);
+        answer.set_question(Question::parse(example_lookup_raw(), 12).unwrap());
+        answer.set_answers(vec![
+            rr("example.com", RRData::Cname(name("www.example.com").into())),
+            rr(
+                "www.example.com",
+                RRData::A(Ipv4Addr::new(192, 0, 2, 1).into()),
+            ),
+            rr(
+                "www.example.com",
+                RRData::Txt(String::from("v=spf1 -all").into()),
+            ),
+        ]);
+        answer
+            .add_addition(OptBuilder::new().udp_size(1232).dnssec_ok(true).build())
+            .unwrap();
+
+        let expected = "\
+;; ->>HEADER<<- opcode: QUERY, status: NOERROR, id: 4660
+;; flags: qr rd ra; QUERY: 1, ANSWER: 3, AUTHORITY: 0, ADDITIONAL: 1
+
+;; OPT PSEUDOSECTION:
+; EDNS: version: 0, flags: do; udp: 1232
+
+;; QUESTION SECTION:
+;example.com.\t\tIN\tA
+
+;; ANSWER SECTION:
+example.com.\t300\tIN\tCNAME\twww.example.com.
+www.example.com.\t300\tIN\tA\t192.0.2.1
+www.example.com.\t300\tIN\tTXT\t\"v=spf1\" \"-all\"
+";
+        assert_eq!(answer.to_string(), expected);
+
+        let failure = Packet::new_failure(7, PacketError::ServFail);
+        assert_eq!(
+            failure.to_string(),
+            ";; ->>HEADER<<- opcode: QUERY, status: SERVFAIL, id: 7\n\
+             ;; flags: qr; QUERY: 0, ANSWER: 0, AUTHORITY: 0, ADDITIONAL: 0\n"
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let name = |name: &str| Name::try_from(name).unwrap();
+        let rr = |owner: &str, rdata: RRData| {
+            RR::new(
+                name(owner),
+                Duration::from_secs(300),
+                RRClass::Internet,
+                rdata,
+            )
+        };
+        // MX has no constructor, take it from the wire
+        let mut mx = BytesMut::new();
+        mx.put(name("example.com").as_bytes_uncompressed());
+        mx.put_u16(RRType::Mx.into());
+        mx.put_u16(RRClass::Internet.into());
+        mx.put_u32(300);
+        mx.put_u16(2 + 18);
+        mx.put_u16(10);
+        mx.put(name("mail.example.com").as_bytes_uncompressed());
+        let mx = RR::parse(mx.freeze(), 0).unwrap();
+
+        let mut answer = Packet::new_plain_answer(4660);
+        answer.set_question(Question::parse(example_lookup_raw(), 12).unwrap());
+        answer.set_answers(vec![
+            rr("example.com", RRData::A(Ipv4Addr::new(192, 0, 2, 1).into())),
+            rr(
+                "example.com",
+                RRData::Aaaa("2001:db8::1".parse::<Ipv6Addr>().unwrap().into()),
+            ),
+            mx,
+            rr(
+                "example.com",
+                RRData::Txt(String::from("v=spf1 -all").into()),
+            ),
+        ]);
+        answer.add_addition(OptBuilder::new().build()).unwrap();
+        let parsed = Packet::parse_packet(answer.into_bytes(), 0).unwrap();
+
+        let json = serde_json::to_value(&parsed).unwrap();
+        assert_eq!(json["header"]["id"], 4660);
+        assert_eq!(json["questions"][0]["name"], "example.com.");
+        assert_eq!(json["questions"][0]["type"], "A");
+        let answers = &json["answers"];
+        assert_eq!(answers[0]["domain"], "example.com.");
+        assert_eq!(answers[0]["ttl"], 300);
+        assert_eq!(answers[0]["class"], "Internet");
+        assert_eq!(answers[0]["rdata"]["A"], "192.0.2.1");
+        assert_eq!(answers[1]["rdata"]["Aaaa"], "2001:db8::1");
+        assert_eq!(answers[2]["type"], "Mx");
+        assert_eq!(answers[2]["rdata"]["Mx"]["preference"], 10);
+        assert_eq!(answers[2]["rdata"]["Mx"]["domain"], "mail.example.com.");
+        assert_eq!(
+            answers[3]["rdata"]["Txt"]["text"],
+            serde_json::json!(["v=spf1", "-all"])
+        );
+        assert!(json["additions"][0]["rdata"]["Opt"]["options"].is_array());
+
+        // nothing is lost on the way back
+        let back: Packet = serde_json::from_value(json).unwrap();
+        assert_eq!(back.to_string(), parsed.to_string());
+        assert_eq!(back.into_bytes(), parsed.into_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_write_to() {
+        let slc = &[
+            7, 101, 120, 97, 109, 112, 108, 101, 3, 99, 111, 109, 0, 0, 1, 0, 1, 0, 1, 191, 82, 0,
+            4, 19, 19, 81, 0,
+        ][..];
+        let answer = RR::parse(Bytes::from(slc), 0).unwrap();
+        let mut p = Packet::new_plain_answer(0);
+        p.set_question(Question::parse(example_lookup_raw(), 12).unwrap());
+        p.set_answers(vec![answer; 4]);
+
+        let bytes = p.clone().into_bytes();
+        let mut expected = BytesMut::new();
+        expected.put_u16(bytes.len() as u16);
+        expected.put(bytes);
+        let mut written = vec![];
+        p.clone().write_to(&mut written).await.unwrap();
+        assert_eq!(written, expected);
+
+        // too long to be framed
+        let answers = p.answers[0].clone();
+        p.set_answers(vec![answers; 5000]);
+        let mut written = vec![];
+        assert!(p.write_to(&mut written).await.is_err());
+        assert!(written.is_empty());
+    }
+
     #[tokio::test]
     async fn test_parse_stream() {
         let mut packet = BytesMut::new();
@@ -631,4 +1379,31 @@ mod integrated_test {
         let sr = r.unwrap();
         assert_eq!(sr.into_bytes(), example_answer());
     }
+
+    #[tokio::test]
+    async fn test_parse_stream_trailing_garbage() {
+        let query = Packet::new_query(
+            0x1234,
+            Question::build(
+                Name::try_from("example.com").unwrap(),
+                RRType::A,
+                RRClass::Internet,
+            ),
+        )
+        .into_bytes();
+        let mut packet = BytesMut::new();
+        packet.put_u16(query.len() as u16 + 3);
+        packet.put(&query[..]);
+        packet.put(&b"\xde\xad\x00"[..]);
+        // the next message follows the garbage
+        packet.put_u16(query.len() as u16);
+        packet.put(&query[..]);
+
+        let mut stream = &packet[..];
+        let first = Packet::parse_stream(&mut stream).await.unwrap();
+        assert_eq!(first.into_bytes(), query);
+        let second = Packet::parse_stream(&mut stream).await.unwrap();
+        assert_eq!(second.into_bytes(), query);
+        assert!(stream.is_empty());
+    }
 }
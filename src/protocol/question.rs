@@ -4,18 +4,47 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use bytes::{Buf, BufMut, BytesMut};
+use std::hash::{Hash, Hasher};
 
-use super::{domain::Name, error::PacketError, PacketContent, RRClass, RRType};
+use bytes::{BufMut, BytesMut};
 
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+use super::{
+    domain::{CompressWriter, Name, NameOffsets},
+    error::PacketError,
+    PacketContent, RRClass, RRType,
+};
+use crate::protocol::PacketReader;
+
+/// ## `Question`
+/// Questions are equal when they ask for the same type and class of the same name,
+/// case of the name and where it was parsed from do not matter.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Question {
     name: Name,
+    #[cfg_attr(feature = "serde", serde(rename = "type"))]
     ty: RRType,
     class: RRClass,
+    #[cfg_attr(feature = "serde", serde(skip))]
     size: usize,
 }
 
+impl PartialEq for Question {
+    fn eq(&self, other: &Self) -> bool {
+        self.ty == other.ty && self.class == other.class && self.name == other.name
+    }
+}
+
+impl Eq for Question {}
+
+impl Hash for Question {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        self.ty.hash(state);
+        self.class.hash(state);
+    }
+}
+
 impl Question {
     pub fn build(name: Name, ty: RRType, class: RRClass) -> Self {
         let size = name.len() + 1 + 2 * 2;
@@ -44,6 +73,18 @@ impl Question {
         let name = Name::try_from(name).unwrap();
         self.name = name;
     }
+
+    /// length of the question written at `offset` by `compress_into`
+    pub(crate) fn compressed_size(&self, names: &mut NameOffsets, offset: usize) -> usize {
+        self.name.compressed_len(names, offset) + 2 + 2
+    }
+
+    /// write the question into a packet, compressing its name
+    pub(crate) fn compress_into(self, writer: &mut CompressWriter) {
+        self.name.as_bytes_compressed(writer);
+        writer.put_slice(&u16::from(self.ty).to_be_bytes());
+        writer.put_slice(&u16::from(self.class).to_be_bytes());
+    }
 }
 
 impl PacketContent for Question {
@@ -51,21 +92,16 @@ impl PacketContent for Question {
         self.size
     }
 
-    fn parse(packet: bytes::Bytes, pos: usize) -> Result<Self, PacketError>
-    where
-        Self: Sized,
-    {
-        let (name, end) = Name::parse(packet.clone(), pos)?;
-        let mut p = packet;
-        p.advance(end);
-        let ty = RRType::from(p.get_u16());
-        let class = RRClass::from(p.get_u16());
-        let size = end + 4 - pos;
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let start = reader.pos();
+        let name = Name::read(reader)?;
+        let ty = RRType::from(reader.read_u16()?);
+        let class = RRClass::from(reader.read_u16()?);
         Ok(Self {
             name,
             ty,
             class,
-            size,
+            size: reader.pos() - start,
         })
     }
 
@@ -113,6 +149,55 @@ fn test_parse() {
     assert_eq!(size, ques.size());
 }
 
+#[test]
+fn test_eq_ignore_case() {
+    use std::collections::hash_map::DefaultHasher;
+
+    fn hash_of(q: &Question) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        q.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    let lower = Question::build(
+        Name::try_from("example.com").unwrap(),
+        RRType::A,
+        RRClass::Internet,
+    );
+    let mixed = Question::build(
+        Name::try_from("ExAmPlE.CoM").unwrap(),
+        RRType::A,
+        RRClass::Internet,
+    );
+    assert_eq!(lower, mixed);
+    assert_eq!(hash_of(&lower), hash_of(&mixed));
+
+    // the compressed size of parsed questions is ignored
+    let mut parsed = Question::parse(
+        bytes::Bytes::from(vec![
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 3, b'c', b'o', b'm',
+            0, // domain name
+            0xc0, 0x00, // pointer to the name
+            0, 1, // type
+            0, 1, // class
+        ]),
+        13,
+    )
+    .unwrap();
+    assert_ne!(parsed.size(), lower.size());
+    assert_eq!(parsed, lower);
+    assert_eq!(hash_of(&parsed), hash_of(&lower));
+
+    parsed.set_name(Name::try_from("example.org").unwrap());
+    assert_ne!(parsed, lower);
+    let aaaa = Question::build(
+        Name::try_from("example.com").unwrap(),
+        RRType::Aaaa,
+        RRClass::Internet,
+    );
+    assert_ne!(aaaa, lower);
+}
+
 #[test]
 fn test_to_bytes() {
     let bytes = bytes::Bytes::from(vec![
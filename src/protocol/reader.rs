@@ -0,0 +1,173 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::Bytes;
+
+use super::PacketError;
+
+/// ## `PacketReader`
+/// A cursor over a whole packet, which parsers read fields off in turn.
+///
+/// The packet is borrowed, not cloned, so names may still follow compression
+/// pointers to anywhere before them.
+/// Reads fail with `PacketError::FormatError` past the end of the cursor,
+/// which is the end of the packet, or of the RDATA taken by `rdata`.
+#[derive(Debug, Clone)]
+pub struct PacketReader<'a> {
+    packet: &'a Bytes,
+    pos: usize,
+    end: usize,
+}
+
+impl<'a> PacketReader<'a> {
+    /// read `packet` from `pos` on
+    pub fn new(packet: &'a Bytes, pos: usize) -> Self {
+        Self {
+            packet,
+            pos,
+            end: packet.len(),
+        }
+    }
+
+    /// the whole packet, compression pointers point into it
+    pub fn packet(&self) -> &'a Bytes {
+        self.packet
+    }
+
+    /// position of the cursor in the packet
+    pub fn pos(&self) -> usize {
+        self.pos
+    }
+
+    /// bytes left before the end of the cursor
+    pub fn remaining(&self) -> usize {
+        self.end.saturating_sub(self.pos)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, PacketError> {
+        self.read_array().map(u8::from_be_bytes)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, PacketError> {
+        self.read_array().map(u16::from_be_bytes)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, PacketError> {
+        self.read_array().map(u32::from_be_bytes)
+    }
+
+    pub fn read_u128(&mut self) -> Result<u128, PacketError> {
+        self.read_array().map(u128::from_be_bytes)
+    }
+
+    /// the next `len` bytes, borrowed from the packet
+    pub fn read_slice(&mut self, len: usize) -> Result<&'a [u8], PacketError> {
+        if len > self.remaining() {
+            return Err(PacketError::FormatError);
+        }
+        let slice = &self.packet[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(slice)
+    }
+
+    /// the next `len` bytes, sharing the buffer of the packet
+    pub fn read_bytes(&mut self, len: usize) -> Result<Bytes, PacketError> {
+        let start = self.pos;
+        self.read_slice(len)?;
+        Ok(self.packet.slice(start..self.pos))
+    }
+
+    /// all bytes left before the end of the cursor
+    pub fn read_rest(&mut self) -> &'a [u8] {
+        let rest = &self.packet[self.pos.min(self.end)..self.end];
+        self.pos = self.end;
+        rest
+    }
+
+    /// a character-string, prefixed by its length
+    pub fn read_character_string(&mut self) -> Result<&'a [u8], PacketError> {
+        let len = self.read_u8()? as usize;
+        self.read_slice(len)
+    }
+
+    /// take RDATA prefixed by RDLENGTH, returning a cursor that ends with it.
+    ///
+    /// This cursor moves past the RDATA at once.
+    pub fn rdata(&mut self) -> Result<Self, PacketError> {
+        let len = self.read_u16()? as usize;
+        if len > self.remaining() {
+            return Err(PacketError::FormatError);
+        }
+        let rdata = Self {
+            packet: self.packet,
+            pos: self.pos,
+            end: self.pos + len,
+        };
+        self.pos += len;
+        Ok(rdata)
+    }
+
+    /// check that nothing is left, like fields that should fill RDATA exactly
+    pub fn finish(&self) -> Result<(), PacketError> {
+        if self.remaining() == 0 {
+            Ok(())
+        } else {
+            Err(PacketError::FormatError)
+        }
+    }
+
+    pub(crate) fn end(&self) -> usize {
+        self.end
+    }
+
+    pub(crate) fn seek(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], PacketError> {
+        let slice = self.read_slice(N)?;
+        Ok(slice.try_into().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::Bytes;
+
+    use super::PacketReader;
+
+    #[test]
+    fn test_read() {
+        let packet = Bytes::from_static(b"\x00\x01\x00\x02\x00\x00\x00\x03\x02hi");
+        let mut reader = PacketReader::new(&packet, 1);
+        assert_eq!(reader.read_u8().unwrap(), 1);
+        assert_eq!(reader.read_u16().unwrap(), 2);
+        assert_eq!(reader.read_u32().unwrap(), 3);
+        assert_eq!(reader.read_character_string().unwrap(), b"hi");
+        assert_eq!(reader.pos(), packet.len());
+        assert!(reader.finish().is_ok());
+        assert!(reader.read_u8().is_err());
+    }
+
+    #[test]
+    fn test_rdata() {
+        let packet = Bytes::from_static(b"\x00\x03abcd");
+        let mut reader = PacketReader::new(&packet, 0);
+        let mut rdata = reader.rdata().unwrap();
+        assert_eq!(reader.pos(), 5);
+        assert_eq!(reader.remaining(), 1);
+        assert!(rdata.finish().is_err());
+        assert_eq!(rdata.read_bytes(2).unwrap(), &b"ab"[..]);
+        // reads stop at the end of RDATA
+        assert!(rdata.read_u16().is_err());
+        assert_eq!(rdata.read_rest(), b"c");
+        assert!(rdata.finish().is_ok());
+
+        // RDLENGTH past the end of the packet
+        let packet = Bytes::from_static(b"\x00\x05abcd");
+        assert!(PacketReader::new(&packet, 0).rdata().is_err());
+    }
+}
@@ -4,17 +4,30 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::fmt::Display;
+
+use bytes::{BufMut, BytesMut};
 use rdata::{
-    a::A, aaaa::Aaaa, cname::Cname, hinfo::HInfo, mg::Mg, minfo::MInfo, mx::Mx, nl::Null, ns::Ns,
-    pt::Ptr, soa::Soa, txt::Txt, unknown::Unknown, wks::Wks, Rdata,
+    cname::Cname, dname::Dname, dnskey::Dnskey, ds::Ds, hinfo::HInfo, mg::Mg, minfo::MInfo, mx::Mx,
+    naptr::Naptr, nl::Null, ns::Ns, nsec::Nsec, opt::Opt, pt::Ptr, rrsig::Rrsig, soa::Soa,
+    txt::Txt, unknown::Unknown, uri::Uri, wks::Wks, Rdata,
 };
 use tokio::time;
 
-use super::{domain::Name, error::PacketError, RRClass};
+pub use self::rdata::{
+    a::A,
+    aaaa::Aaaa,
+    opt::{EdnsOption, OptBuilder},
+    svcb::{Https, Svcb},
+};
+use super::{
+    domain::{CompressWriter, Name, NameOffsets},
+    error::PacketError,
+    RRClass,
+};
 use crate::protocol::{
     rr::rdata::{mb::Mb, mr::Mr},
-    PacketContent, RRType,
+    PacketContent, PacketReader, RRType,
 };
 
 mod rdata;
@@ -45,13 +58,17 @@ mod rdata;
 /// +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
 /// ```
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RR {
     domain: Name,
     ttl: u32,
+    #[cfg_attr(feature = "serde", serde(rename = "type"))]
     ty: RRType,
     class: RRClass,
+    #[cfg_attr(feature = "serde", serde(skip))]
     size: usize,
     // total length of RR in packet
+    #[cfg_attr(feature = "serde", serde(rename = "rdata"))]
     r_data: RRData,
 }
 
@@ -83,6 +100,80 @@ impl RR {
     pub fn set_ttl(&mut self, ttl: time::Duration) {
         self.ttl = ttl.as_secs() as u32;
     }
+
+    /// the `OPT` pseudo record of EDNS(0), owned by the root.
+    ///
+    /// `udp_size` goes into CLASS, the other fields into TTL.
+    pub fn new_opt(udp_size: u16, ext_rcode: u8, version: u8, dnssec_ok: bool, opt: Opt) -> Self {
+        let flags: u32 = if dnssec_ok { 0x8000 } else { 0 };
+        RR {
+            domain: Name::try_from(".").unwrap(),
+            ttl: (ext_rcode as u32) << 24 | (version as u32) << 16 | flags,
+            ty: RRType::Opt,
+            class: RRClass::from(udp_size),
+            size: 0,
+            r_data: RRData::Opt(opt),
+        }
+    }
+
+    /// upper 8 bits of the extended RCODE, if the record is `OPT`
+    pub fn ext_rcode(&self) -> Option<u8> {
+        (self.ty == RRType::Opt).then_some((self.ttl >> 24) as u8)
+    }
+
+    /// payload size advertised by the sender, if the record is `OPT`
+    pub fn udp_size(&self) -> Option<u16> {
+        (self.ty == RRType::Opt).then_some(u16::from(self.class))
+    }
+
+    /// EDNS version of the sender, if the record is `OPT`
+    pub fn edns_version(&self) -> Option<u8> {
+        (self.ty == RRType::Opt).then_some((self.ttl >> 16) as u8)
+    }
+
+    /// the DO bit, if the record is `OPT`
+    pub fn dnssec_ok(&self) -> Option<bool> {
+        (self.ty == RRType::Opt).then_some(self.ttl & 0x8000 != 0)
+    }
+
+    /// length of the record written at `offset` by `compress_into`
+    pub(crate) fn compressed_size(&self, names: &mut NameOffsets, offset: usize) -> usize {
+        self.domain.compressed_len(names, offset) + 2 + 2 + 4 + self.r_data.size()
+    }
+
+    /// write the record into a packet, compressing its owner name.
+    ///
+    /// Names in RDATA are left uncompressed, so RDLENGTH stays as is.
+    pub(crate) fn compress_into(self, writer: &mut CompressWriter) -> Result<(), PacketError> {
+        let rdata = self.r_data.try_into_bytes()?;
+        self.domain.as_bytes_compressed(writer);
+        writer.put_slice(&u16::from(self.ty).to_be_bytes());
+        writer.put_slice(&u16::from(self.class).to_be_bytes());
+        writer.put_slice(&self.ttl.to_be_bytes());
+        writer.put_slice(&rdata[..]);
+        Ok(())
+    }
+}
+
+/// a line of master files: owner, TTL, class, type and RDATA, separated by tabs
+impl Display for RR {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}\t{}\t{}\t{}\t{}",
+            self.domain, self.ttl, self.class, self.ty, self.r_data
+        )
+    }
+}
+
+/// the least TTL of `rrs`, none if there are no records.
+///
+/// `OPT` records are skipped, their TTL field holds flags.
+pub(crate) fn min_ttl<'a>(rrs: impl IntoIterator<Item = &'a RR>) -> Option<time::Duration> {
+    rrs.into_iter()
+        .filter(|rr| rr.ty != RRType::Opt)
+        .map(RR::get_ttl)
+        .min()
 }
 
 // TODO: replace redundant code with macron
@@ -90,6 +181,7 @@ impl RR {
 /// The `RRData` section of `RR`.
 /// It also implicitly points out the `TYPE` of `RR`.
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RRData {
     A(A),
     Aaaa(Aaaa),
@@ -106,6 +198,16 @@ pub enum RRData {
     Ns(Ns),
     Soa(Soa),
     Txt(Txt),
+    Naptr(Naptr),
+    Svcb(Svcb),
+    Https(Https),
+    Ds(Ds),
+    Rrsig(Rrsig),
+    Nsec(Nsec),
+    Dnskey(Dnskey),
+    Uri(Uri),
+    Dname(Dname),
+    Opt(Opt),
     Unknown(Unknown),
 }
 
@@ -127,9 +229,50 @@ impl RRData {
             Self::MInfo(_) => RRType::MInfo,
             Self::HInfo(_) => RRType::HInfo,
             Self::Null(_) => RRType::Null,
+            Self::Naptr(_) => RRType::Naptr,
+            Self::Svcb(_) => RRType::Svcb,
+            Self::Https(_) => RRType::Https,
+            Self::Ds(_) => RRType::Ds,
+            Self::Rrsig(_) => RRType::Rrsig,
+            Self::Nsec(_) => RRType::Nsec,
+            Self::Dnskey(_) => RRType::Dnskey,
+            Self::Uri(_) => RRType::Uri,
+            Self::Dname(_) => RRType::Dname,
+            Self::Opt(_) => RRType::Opt,
             Self::Unknown(unknown) => unknown.get_type(),
         }
     }
+    /// length of the RDATA in packets, RDLENGTH included
+    pub(crate) fn size(&self) -> usize {
+        match self {
+            Self::A(a) => a.size(),
+            Self::Aaaa(aaaa) => aaaa.size(),
+            Self::Cname(cname) => cname.size(),
+            Self::Mx(mx) => mx.size(),
+            Self::Mb(mb) => mb.size(),
+            Self::Mg(mg) => mg.size(),
+            Self::Ns(ns) => ns.size(),
+            Self::Soa(soa) => soa.size(),
+            Self::Ptr(ptr) => ptr.size(),
+            Self::Mr(mr) => mr.size(),
+            Self::Wks(wks) => wks.size(),
+            Self::MInfo(m_info) => m_info.size(),
+            Self::HInfo(h_info) => h_info.size(),
+            Self::Null(null) => null.size(),
+            Self::Txt(txt) => txt.size(),
+            Self::Naptr(naptr) => naptr.size(),
+            Self::Svcb(svcb) => svcb.size(),
+            Self::Https(https) => https.size(),
+            Self::Ds(ds) => ds.size(),
+            Self::Rrsig(rrsig) => rrsig.size(),
+            Self::Nsec(nsec) => nsec.size(),
+            Self::Dnskey(dnskey) => dnskey.size(),
+            Self::Uri(uri) => uri.size(),
+            Self::Dname(dname) => dname.size(),
+            Self::Opt(opt) => opt.size(),
+            Self::Unknown(unknown) => unknown.size(),
+        }
+    }
     pub fn try_into_bytes(self) -> Result<BytesMut, PacketError> {
         match self {
             Self::A(a) => a.try_into_bytes(),
@@ -147,36 +290,83 @@ impl RRData {
             Self::HInfo(h_info) => h_info.try_into_bytes(),
             Self::Null(null) => null.try_into_bytes(),
             Self::Txt(txt) => txt.try_into_bytes(),
+            Self::Naptr(naptr) => naptr.try_into_bytes(),
+            Self::Svcb(svcb) => svcb.try_into_bytes(),
+            Self::Https(https) => https.try_into_bytes(),
+            Self::Ds(ds) => ds.try_into_bytes(),
+            Self::Rrsig(rrsig) => rrsig.try_into_bytes(),
+            Self::Nsec(nsec) => nsec.try_into_bytes(),
+            Self::Dnskey(dnskey) => dnskey.try_into_bytes(),
+            Self::Uri(uri) => uri.try_into_bytes(),
+            Self::Dname(dname) => dname.try_into_bytes(),
+            Self::Opt(opt) => opt.try_into_bytes(),
             Self::Unknown(unknown) => unknown.try_into_bytes(),
         }
     }
 }
 
-// Parse RDATA
-macro_rules! parse_rdata {
-    ($rtype:expr, $packet:expr, $begin:expr, $($t:ident),*) => {
+// Read RDATA
+macro_rules! read_rdata {
+    ($rtype:expr, $reader:expr, $($t:ident),*) => {
         match $rtype {
         $(
-            RRType::$t => {
-                let (rdata, end) = $t::parse($packet, $begin)?;
-                (RRData::$t(rdata), end)
-            }
+            RRType::$t => RRData::$t($t::read($reader)?),
         )*
-            RRType::UNKNOWN(x) => {
-                let (mut unknown, end) = Unknown::parse_typeless($packet, $begin)?;
-                unknown.set_type(x);
-                (RRData::Unknown(unknown), end)
+            // unknown types, and the query only ones like `ANY`
+            ty => {
+                let mut unknown = Unknown::read($reader)?;
+                unknown.set_type(u16::from(ty));
+                RRData::Unknown(unknown)
             }
     }
     }
 }
 
-fn rdata_parse(ty: RRType, packet: Bytes, offset: usize) -> Result<(RRData, usize), PacketError> {
-    let (rdata, end) = parse_rdata!(
-        ty, packet, offset, A, Aaaa, Ns, Cname, Mb, Mg, Mr, MInfo, HInfo, Null, Ptr, Wks, Soa, Txt,
-        Mx
+/// RDATA in presentation format, or in the generic form of
+/// [RFC3597](https://datatracker.ietf.org/doc/html/rfc3597#section-5) for types without one
+impl Display for RRData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::A(a) => a.fmt(f),
+            Self::Aaaa(aaaa) => aaaa.fmt(f),
+            Self::Cname(cname) => cname.fmt(f),
+            Self::Mx(mx) => mx.fmt(f),
+            Self::Mb(mb) => mb.fmt(f),
+            Self::Mg(mg) => mg.fmt(f),
+            Self::Ns(ns) => ns.fmt(f),
+            Self::Soa(soa) => soa.fmt(f),
+            Self::Ptr(ptr) => ptr.fmt(f),
+            Self::Mr(mr) => mr.fmt(f),
+            Self::MInfo(m_info) => m_info.fmt(f),
+            Self::HInfo(h_info) => h_info.fmt(f),
+            Self::Txt(txt) => txt.fmt(f),
+            Self::Uri(uri) => uri.fmt(f),
+            Self::Dname(dname) => dname.fmt(f),
+            _ => {
+                let bytes = match self.clone().try_into_bytes() {
+                    Ok(bytes) => bytes,
+                    Err(_) => return write!(f, "{:?}", self),
+                };
+                let rdata = &bytes[2..];
+                write!(f, "\\# {}", rdata.len())?;
+                if !rdata.is_empty() {
+                    write!(f, " ")?;
+                    for b in rdata {
+                        write!(f, "{:02x}", b)?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn rdata_read(ty: RRType, reader: &mut PacketReader) -> Result<RRData, PacketError> {
+    let rdata = read_rdata!(
+        ty, reader, A, Aaaa, Ns, Cname, Mb, Mg, Mr, MInfo, HInfo, Null, Ptr, Wks, Soa, Txt, Mx,
+        Naptr, Svcb, Https, Ds, Rrsig, Nsec, Dnskey, Uri, Dname, Opt
     );
-    Ok((rdata, end))
+    Ok(rdata)
 }
 
 impl PacketContent for RR {
@@ -185,26 +375,29 @@ impl PacketContent for RR {
         self.size
     }
 
-    fn parse(packet: Bytes, pos: usize) -> Result<Self, PacketError>
-    where
-        Self: Sized,
-    {
-        let mut p = packet.clone();
-        let (domain, name_end) = Name::parse(packet.clone(), pos)?;
-        p.advance(name_end);
-        let ty = RRType::from(p.get_u16());
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let start = reader.pos();
+        let domain = Name::read(reader)?;
+        let ty = RRType::from(reader.read_u16()?);
         tracing::trace!("parsed with type:{}", ty);
-        let class = RRClass::from(p.get_u16());
-        let ttl = p.get_u32();
-        let rdata_begin = name_end + 8;
-        let (rdata, rdata_end) = rdata_parse(ty, packet, rdata_begin)?;
-        let size = rdata_end - pos;
+        let class = RRClass::from(reader.read_u16()?);
+        let ttl = reader.read_u32()?;
+        // UPDATE prerequisites and deletions of class ANY or NONE carry no RDATA
+        let no_rdata = matches!(class, RRClass::Any | RRClass::None)
+            && reader.clone().read_u16().ok() == Some(0);
+        let rdata = if no_rdata {
+            let mut unknown = Unknown::read(reader)?;
+            unknown.set_type(u16::from(ty));
+            RRData::Unknown(unknown)
+        } else {
+            rdata_read(ty, reader)?
+        };
         Ok(Self {
             domain,
             ty,
             class,
             ttl,
-            size,
+            size: reader.pos() - start,
             r_data: rdata,
         })
     }
@@ -225,6 +418,9 @@ impl PacketContent for RR {
 mod rr_test {
     use std::{net::Ipv4Addr, time};
 
+    use bytes::Bytes;
+
+    use super::rdata::Rdata;
     use crate::protocol::{Name, PacketContent, RRClass, RRData, RRType, RR};
 
     #[test]
@@ -251,6 +447,22 @@ mod rr_test {
         assert_eq!(rr.get_ttl(), new_du);
     }
 
+    #[test]
+    fn test_display() {
+        let name = Name::try_from("example.com").unwrap();
+        let du = time::Duration::from_secs(3600);
+        let a = super::A::new(Ipv4Addr::new(192, 0, 2, 1));
+        let rr = RR::new(name.clone(), du, RRClass::Internet, RRData::A(a));
+        assert_eq!(rr.to_string(), "example.com.\t3600\tIN\tA\t192.0.2.1");
+
+        // types without a presentation format of their own
+        let null = super::Null::parse(Bytes::from_static(b"\x00\x03\xde\xad\x01"), 0)
+            .unwrap()
+            .0;
+        let rr = RR::new(name, du, RRClass::Chaos, RRData::Null(null));
+        assert_eq!(rr.to_string(), "example.com.\t3600\tCH\tNULL\t\\# 3 dead01");
+    }
+
     #[test]
     fn test_to_bytes_and_parse() {
         let a = super::A::from("19.19.81.0".parse::<Ipv4Addr>().unwrap());
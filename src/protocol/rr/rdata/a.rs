@@ -6,36 +6,36 @@
 
 use std::{fmt::Display, net::Ipv4Addr};
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+#[cfg(test)]
+use bytes::Bytes;
+use bytes::{BufMut, BytesMut};
 
 use super::Rdata;
-use crate::protocol::error::PacketError;
+use crate::protocol::{error::PacketError, PacketReader};
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(from = "Ipv4Addr", into = "Ipv4Addr"))]
 pub struct A {
     addr: u32,
 }
 
+impl A {
+    pub fn new(addr: Ipv4Addr) -> Self {
+        Self::from(addr)
+    }
+
+    pub fn addr(&self) -> Ipv4Addr {
+        Ipv4Addr::from(self.addr)
+    }
+}
+
 impl Rdata for A {
-    fn parse(packet: Bytes, pos: usize) -> Result<(Self, usize), PacketError> {
-        if pos + 6 > packet.len() {
-            return Err(PacketError::FormatError);
-        }
-
-        let mut data = packet;
-        data.advance(pos);
-        let len = data.get_u16();
-        if len != 4 {
-            Err(PacketError::FormatError)
-        } else {
-            let end = pos + 6;
-            Ok((
-                Self {
-                    addr: data.get_u32(),
-                },
-                end,
-            ))
-        }
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        let addr = rdata.read_u32()?;
+        rdata.finish()?;
+        Ok(Self { addr })
     }
 
     fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
@@ -44,6 +44,10 @@ impl Rdata for A {
         buf.put_u32(self.addr);
         Ok(buf)
     }
+
+    fn size(&self) -> usize {
+        2 + 4
+    }
 }
 
 impl From<Ipv4Addr> for A {
@@ -90,3 +94,12 @@ fn test_to_bytes() {
     let bytes = result.unwrap();
     assert_eq!(bytes[..], rdata[..]);
 }
+
+#[test]
+fn test_addr() {
+    let addr = "192.0.2.1".parse::<Ipv4Addr>().unwrap();
+    let record = A::new(addr);
+    assert_eq!(record, A::from(addr));
+    assert_eq!(record.addr(), addr);
+    assert_eq!(record.addr(), Ipv4Addr::from(record));
+}
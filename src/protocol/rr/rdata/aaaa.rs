@@ -6,36 +6,36 @@
 
 use std::{fmt::Display, net::Ipv6Addr};
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+#[cfg(test)]
+use bytes::Bytes;
+use bytes::{BufMut, BytesMut};
 
 use super::Rdata;
-use crate::protocol::error::PacketError;
+use crate::protocol::{error::PacketError, PacketReader};
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(from = "Ipv6Addr", into = "Ipv6Addr"))]
 pub struct Aaaa {
     addr: u128,
 }
 
+impl Aaaa {
+    pub fn new(addr: Ipv6Addr) -> Self {
+        Self::from(addr)
+    }
+
+    pub fn addr(&self) -> Ipv6Addr {
+        Ipv6Addr::from(self.addr)
+    }
+}
+
 impl Rdata for Aaaa {
-    fn parse(packet: Bytes, pos: usize) -> Result<(Self, usize), PacketError> {
-        if pos + (16 + 128) / 8 > packet.len() {
-            return Err(PacketError::FormatError);
-        }
-
-        let mut buf = packet;
-        buf.advance(pos);
-        let len = buf.get_u16();
-        if len != 16 {
-            Err(PacketError::FormatError)
-        } else {
-            let end = pos + (16 + 128) / 8;
-            Ok((
-                Self {
-                    addr: buf.get_u128(),
-                },
-                end,
-            ))
-        }
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        let addr = rdata.read_u128()?;
+        rdata.finish()?;
+        Ok(Self { addr })
     }
 
     fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
@@ -44,6 +44,10 @@ impl Rdata for Aaaa {
         buf.put_u128(self.addr);
         Ok(buf)
     }
+
+    fn size(&self) -> usize {
+        2 + 16
+    }
 }
 
 impl From<Ipv6Addr> for Aaaa {
@@ -100,3 +104,12 @@ fn test_to_bytes() {
     let rdata = [0_u8, 16, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1];
     assert_eq!(bytes[..], rdata[..]);
 }
+
+#[test]
+fn test_addr() {
+    let addr = "2001:db8::1".parse::<Ipv6Addr>().unwrap();
+    let record = Aaaa::new(addr);
+    assert_eq!(record, Aaaa::from(addr));
+    assert_eq!(record.addr(), addr);
+    assert_eq!(record.addr(), Ipv6Addr::from(record));
+}
@@ -0,0 +1,128 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fmt::Display;
+
+use bytes::{BufMut, BytesMut};
+
+use super::{try_into_rdata_length, Name, Rdata};
+use crate::protocol::{error::PacketError, PacketReader, ParseNameError};
+
+/// ## `Dname`
+/// Delegation Name, see [RFC6672](https://datatracker.ietf.org/doc/html/rfc6672#section-2.1)
+///
+/// Redirects the subtree below its owner, but not the owner itself, to the target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Dname {
+    target: Name,
+}
+
+impl Dname {
+    pub fn new(target: Name) -> Self {
+        Self { target }
+    }
+    pub fn get_target(&self) -> Name {
+        self.target.clone()
+    }
+
+    /// target of the CNAME synthesized for `qname` by the DNAME at `owner`.
+    ///
+    /// None if `qname` is not below `owner`,
+    /// and `NameTooLong` if the substituted name is too long, which is answered by YXDOMAIN.
+    pub fn synthesize(&self, owner: &Name, qname: &Name) -> Option<Result<Name, ParseNameError>> {
+        if qname == owner {
+            return None;
+        }
+        let prefix = qname.strip_suffix(owner)?;
+        Some(prefix.join(&self.target))
+    }
+}
+
+impl Display for Dname {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.target)
+    }
+}
+
+impl Rdata for Dname {
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        // the target is never compressed
+        let target = Name::read_uncompressed(&mut rdata)?;
+        rdata.finish()?;
+        Ok(Self { target })
+    }
+
+    fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
+        let v = self.target.as_bytes_uncompressed();
+        let mut buf = BytesMut::with_capacity(2 + v.len());
+        buf.put_u16(try_into_rdata_length(v.len())?);
+        buf.put_slice(&v);
+        Ok(buf)
+    }
+
+    fn size(&self) -> usize {
+        2 + self.target.wire_len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{BufMut, Bytes, BytesMut};
+
+    use super::{Dname, Rdata};
+    use crate::protocol::{Name, ParseNameError};
+
+    fn name(s: &str) -> Name {
+        Name::try_from(s).unwrap()
+    }
+
+    #[test]
+    fn test_synthesize() {
+        let dname = Dname::new(name("example.net"));
+        let owner = name("old.example.com");
+
+        let cases = [
+            ("www.old.example.com", Some("www.example.net.")),
+            ("a.b.OLD.example.com", Some("a.b.example.net.")),
+            // the owner itself is not redirected
+            ("old.example.com", None),
+            ("new.example.com", None),
+            ("example.com", None),
+        ];
+        for (qname, target) in cases {
+            let synthesized = dname
+                .synthesize(&owner, &name(qname))
+                .map(|target| target.unwrap().to_string());
+            assert_eq!(synthesized.as_deref(), target, "{}", qname);
+        }
+
+        let label = "a".repeat(63);
+        let long = name(&format!("{0}.{0}.{0}", label));
+        let dname = Dname::new(long.clone());
+        let qname = name(&format!("{0}.{0}.old.example.com", label));
+        assert_eq!(
+            dname.synthesize(&owner, &qname),
+            Some(Err(ParseNameError::NameTooLong))
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let rdata = Bytes::from(b"\x00\x0d\x07example\x03net\x00".to_vec());
+        let (dname, end) = Dname::parse(rdata.clone(), 0).unwrap();
+        assert_eq!(end, rdata.len());
+        assert_eq!(dname, Dname::new(name("example.net")));
+        assert_eq!(dname.size(), rdata.len());
+        assert_eq!(dname.try_into_bytes().unwrap().freeze(), rdata);
+
+        // a compressed target pointing to `example.net` before the RDATA
+        let mut packet = BytesMut::from(&b"\x07example\x03net\x00"[..]);
+        packet.put(&b"\x00\x02\xc0\x00"[..]);
+        assert!(Dname::parse(packet.freeze(), 13).is_err());
+    }
+}
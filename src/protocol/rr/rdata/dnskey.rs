@@ -0,0 +1,124 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::{BufMut, BytesMut};
+
+use super::{try_into_rdata_length, Rdata};
+use crate::protocol::{error::PacketError, PacketReader};
+
+/// ## `Dnskey`
+/// DNS Public Key, see [RFC4034](https://datatracker.ietf.org/doc/html/rfc4034#section-2.1)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Dnskey {
+    flags: u16,
+    protocol: u8,
+    algorithm: u8,
+    #[cfg_attr(feature = "serde", serde(with = "super::encoding::base64_bytes"))]
+    public_key: Vec<u8>,
+}
+
+impl Dnskey {
+    pub fn new(flags: u16, protocol: u8, algorithm: u8, public_key: Vec<u8>) -> Self {
+        Self {
+            flags,
+            protocol,
+            algorithm,
+            public_key,
+        }
+    }
+    pub fn get_flags(&self) -> u16 {
+        self.flags
+    }
+    pub fn get_protocol(&self) -> u8 {
+        self.protocol
+    }
+    pub fn get_algorithm(&self) -> u8 {
+        self.algorithm
+    }
+    pub fn get_public_key(&self) -> &[u8] {
+        &self.public_key
+    }
+
+    /// key tag identifying the key in `DS` and `RRSIG` records,
+    /// see [RFC4034](https://datatracker.ietf.org/doc/html/rfc4034#appendix-B)
+    pub fn key_tag(&self) -> u16 {
+        let mut rdata = vec![];
+        rdata.extend_from_slice(&self.flags.to_be_bytes());
+        rdata.push(self.protocol);
+        rdata.push(self.algorithm);
+        rdata.extend_from_slice(&self.public_key);
+        let mut acc: u32 = 0;
+        for (i, byte) in rdata.iter().enumerate() {
+            acc += if i % 2 == 0 {
+                (*byte as u32) << 8
+            } else {
+                *byte as u32
+            };
+        }
+        acc += (acc >> 16) & 0xffff;
+        (acc & 0xffff) as u16
+    }
+}
+
+impl Rdata for Dnskey {
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        let key = Dnskey {
+            flags: rdata.read_u16()?,
+            protocol: rdata.read_u8()?,
+            algorithm: rdata.read_u8()?,
+            public_key: rdata.read_rest().to_vec(),
+        };
+        Ok(key)
+    }
+
+    fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
+        let size = self.size();
+        let mut buf = BytesMut::with_capacity(size);
+        buf.put_u16(try_into_rdata_length(size - 2)?);
+        buf.put_u16(self.flags);
+        buf.put_u8(self.protocol);
+        buf.put_u8(self.algorithm);
+        buf.put_slice(&self.public_key);
+        Ok(buf)
+    }
+
+    fn size(&self) -> usize {
+        2 + 2 + 1 + 1 + self.public_key.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{BufMut, BytesMut};
+
+    use super::{Dnskey, Rdata};
+
+    #[test]
+    fn test_round_trip() {
+        // dskey.example.com. DNSKEY of RFC4034 section 5.4
+        let public_key = base64::decode(
+            "AQOeiiR0GOMYkDshWoSKz9XzfwJr1AYtsmx3TGkJaNXVbfi/2pHm822aJ5iI9BMzNXxeYCmZ\
+             DRD99WYwYqUSdjMmmAphXdvxegXd/M5+X7OrzKBaMbCVdFLUUh6DhweJBjEVv5f2wwjM9Xzc\
+             nOf+EPbtG9DMBmADjFDc2w/rljwvFw==",
+        )
+        .unwrap();
+        let mut target = BytesMut::new();
+        target.put_u16(4 + public_key.len() as u16);
+        target.put(&b"\x01\x00\x03\x05"[..]);
+        target.put(&public_key[..]);
+        let target = target.freeze();
+
+        let (key, end) = Dnskey::parse(target.clone(), 0).unwrap();
+        assert_eq!(end, target.len());
+        assert_eq!(key, Dnskey::new(256, 3, 5, public_key));
+        assert_eq!(key.key_tag(), 60485);
+        assert_eq!(key.try_into_bytes().unwrap().freeze(), target);
+
+        assert!(Dnskey::parse(target.slice(..3), 0).is_err());
+    }
+}
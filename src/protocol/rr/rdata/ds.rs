@@ -0,0 +1,99 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::{BufMut, BytesMut};
+
+use super::{try_into_rdata_length, Rdata};
+use crate::protocol::{error::PacketError, PacketReader};
+
+/// ## `Ds`
+/// Delegation Signer, see [RFC4034](https://datatracker.ietf.org/doc/html/rfc4034#section-5.1)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Ds {
+    key_tag: u16,
+    algorithm: u8,
+    digest_type: u8,
+    #[cfg_attr(feature = "serde", serde(with = "super::encoding::base64_bytes"))]
+    digest: Vec<u8>,
+}
+
+impl Ds {
+    pub fn new(key_tag: u16, algorithm: u8, digest_type: u8, digest: Vec<u8>) -> Self {
+        Self {
+            key_tag,
+            algorithm,
+            digest_type,
+            digest,
+        }
+    }
+    pub fn get_key_tag(&self) -> u16 {
+        self.key_tag
+    }
+    pub fn get_algorithm(&self) -> u8 {
+        self.algorithm
+    }
+    pub fn get_digest_type(&self) -> u8 {
+        self.digest_type
+    }
+    pub fn get_digest(&self) -> &[u8] {
+        &self.digest
+    }
+}
+
+impl Rdata for Ds {
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        let ds = Ds {
+            key_tag: rdata.read_u16()?,
+            algorithm: rdata.read_u8()?,
+            digest_type: rdata.read_u8()?,
+            digest: rdata.read_rest().to_vec(),
+        };
+        Ok(ds)
+    }
+
+    fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
+        let size = self.size();
+        let mut buf = BytesMut::with_capacity(size);
+        buf.put_u16(try_into_rdata_length(size - 2)?);
+        buf.put_u16(self.key_tag);
+        buf.put_u8(self.algorithm);
+        buf.put_u8(self.digest_type);
+        buf.put_slice(&self.digest);
+        Ok(buf)
+    }
+
+    fn size(&self) -> usize {
+        2 + 2 + 1 + 1 + self.digest.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{BufMut, BytesMut};
+
+    use super::{Ds, Rdata};
+
+    #[test]
+    fn test_round_trip() {
+        // dskey.example.com. DS of RFC4034 section 5.4
+        let digest =
+            b"\x2b\xb1\x83\xaf\x5f\x22\x58\x81\x79\xa5\x3b\x0a\x98\x63\x1f\xad\x1a\x29\x21\x18";
+        let mut target = BytesMut::new();
+        target.put_u16(4 + 20);
+        target.put(&b"\xec\x45\x05\x01"[..]);
+        target.put(&digest[..]);
+        let target = target.freeze();
+
+        let (ds, end) = Ds::parse(target.clone(), 0).unwrap();
+        assert_eq!(end, target.len());
+        assert_eq!(ds, Ds::new(60485, 5, 1, digest.to_vec()));
+        assert_eq!(ds.try_into_bytes().unwrap().freeze(), target);
+
+        assert!(Ds::parse(target.slice(..10), 0).is_err());
+    }
+}
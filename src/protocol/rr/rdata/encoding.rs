@@ -0,0 +1,159 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! serde helpers for the fields of RDATA.
+//!
+//! Opaque data is written in base64, character-strings as escaped text
+//! and addresses as their usual strings, all parsed back as they were.
+
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+use super::Escaped;
+
+/// opaque data in base64
+pub mod base64_bytes {
+    use super::*;
+
+    pub fn serialize<S, T>(data: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: AsRef<[u8]>,
+    {
+        serializer.serialize_str(&base64::encode(data))
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: From<Vec<u8>>,
+    {
+        let s = String::deserialize(deserializer)?;
+        base64::decode(s).map(T::from).map_err(Error::custom)
+    }
+}
+
+/// a character-string, with the escapes of master files but no quotes
+pub mod character_string {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(text: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&Escaped(text))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        unescape(&s).map_err(Error::custom)
+    }
+}
+
+/// character-strings, as a list of those of `character_string`
+pub mod character_strings {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct CharacterString(#[serde(with = "character_string")] Vec<u8>);
+
+    pub fn serialize<S: Serializer>(texts: &[Vec<u8>], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(texts.iter().map(|t| CharacterString(t.clone())))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let texts = Vec::<CharacterString>::deserialize(deserializer)?;
+        Ok(texts.into_iter().map(|t| t.0).collect())
+    }
+}
+
+/// options of `OPT` and parameters of `SVCB`, as a list of codes and data in base64
+pub mod options {
+    use super::*;
+
+    #[derive(Serialize, Deserialize)]
+    struct Param {
+        code: u16,
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    }
+
+    pub fn serialize<S>(options: &[(u16, Vec<u8>)], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_seq(options.iter().map(|(code, data)| Param {
+            code: *code,
+            data: data.clone(),
+        }))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<(u16, Vec<u8>)>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let options = Vec::<Param>::deserialize(deserializer)?;
+        Ok(options.into_iter().map(|o| (o.code, o.data)).collect())
+    }
+}
+
+/// an IPv4 address kept as a number
+pub mod ipv4 {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    pub fn serialize<S: Serializer>(addr: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+        Ipv4Addr::from(*addr).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+        Ipv4Addr::deserialize(deserializer).map(u32::from)
+    }
+}
+
+/// undo the escapes of `Escaped`, `\X` for a character and `\DDD` for an octet in decimal
+fn unescape(s: &str) -> Result<Vec<u8>, String> {
+    let mut text = vec![];
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            text.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(d) if d.is_ascii_digit() => {
+                let mut octet = (d - b'0') as u32;
+                for _ in 0..2 {
+                    match bytes.next() {
+                        Some(d) if d.is_ascii_digit() => octet = octet * 10 + (d - b'0') as u32,
+                        _ => return Err(format!("invalid escape in {:?}", s)),
+                    }
+                }
+                let octet =
+                    u8::try_from(octet).map_err(|_| format!("invalid escape in {:?}", s))?;
+                text.push(octet);
+            }
+            Some(c) => text.push(c),
+            None => return Err(format!("dangling backslash in {:?}", s)),
+        }
+    }
+    Ok(text)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{unescape, Escaped};
+
+    #[test]
+    fn test_unescape() {
+        let text = b"say \"hi\\there\"\x00\xff";
+        assert_eq!(unescape(&Escaped(text).to_string()).unwrap(), text);
+        assert_eq!(unescape("\\065\\.").unwrap(), b"A.");
+        assert!(unescape("\\256").is_err());
+        assert!(unescape("\\6").is_err());
+        assert!(unescape("end\\").is_err());
+    }
+}
@@ -1,43 +1,31 @@
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::fmt::Display;
 
-use crate::protocol::{rr::rdata::Rdata, PacketError};
+#[cfg(test)]
+use bytes::Bytes;
+use bytes::{BufMut, BytesMut};
+
+use crate::protocol::{
+    rr::rdata::{fmt_character_string, Rdata},
+    PacketError, PacketReader,
+};
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct HInfo {
+    #[cfg_attr(feature = "serde", serde(with = "super::encoding::character_string"))]
     cpu: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(with = "super::encoding::character_string"))]
     os: Vec<u8>,
 }
 
 impl Rdata for HInfo {
-    fn parse(packet: Bytes, pos: usize) -> Result<(Self, usize), PacketError>
-    where
-        Self: Sized,
-    {
-        if pos + 4 > packet.len() {
-            return Err(PacketError::FormatError);
-        }
-
-        let mut p = packet.clone();
-
-        p.advance(pos);
-        let rdlen = p.get_u16() as usize;
-        let end = pos + 2 + rdlen;
-
-        if end > packet.len() {
-            return Err(PacketError::FormatError);
-        }
-
-        let c_len = p.get_u8();
-        if (c_len + 1) as usize >= rdlen {
-            return Err(PacketError::FormatError);
-        }
-        let cpu = Vec::from(&p[..(c_len as usize)]);
-        let o_len = p.get_u8();
-        if (c_len + 1 + o_len + 1) as usize > rdlen {
-            return Err(PacketError::FormatError);
-        }
-        let os = Vec::from(&p[..(o_len as usize)]);
-        Ok((Self { cpu, os }, end))
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        // both character-strings fill RDATA exactly
+        let cpu = rdata.read_character_string()?.to_vec();
+        let os = rdata.read_character_string()?.to_vec();
+        rdata.finish()?;
+        Ok(Self { cpu, os })
     }
 
     fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
@@ -45,10 +33,68 @@ impl Rdata for HInfo {
         let mut buf = BytesMut::with_capacity(total_len);
         let len = u16::try_from(total_len).map_err(|_| PacketError::FormatError)?;
         buf.put_u16(len);
-        buf.put_u8(self.cpu.len() as u8);
-        buf.put(&self.cpu[..]);
-        buf.put_u8(self.os.len() as u8);
-        buf.put(&self.os[..]);
+        for string in [&self.cpu, &self.os] {
+            // a character-string is at most 255 bytes long
+            let len = u8::try_from(string.len()).map_err(|_| PacketError::FormatError)?;
+            buf.put_u8(len);
+            buf.put(&string[..]);
+        }
         Ok(buf)
     }
+
+    fn size(&self) -> usize {
+        2 + 1 + self.cpu.len() + 1 + self.os.len()
+    }
+}
+
+impl Display for HInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt_character_string(f, &self.cpu)?;
+        write!(f, " ")?;
+        fmt_character_string(f, &self.os)
+    }
+}
+
+/// take a character-string led by its length from `rdata`, which it must not run past
+#[test]
+fn test_parse_and_to_bytes() {
+    let rdata = Bytes::from(b"\x00\x0c\x05AMD64\x05Linux".to_vec());
+    let (hinfo, end) = HInfo::parse(rdata.clone(), 0).unwrap();
+    assert_eq!(hinfo.cpu, b"AMD64");
+    assert_eq!(hinfo.os, b"Linux");
+    assert_eq!(end, rdata.len());
+    assert_eq!(hinfo.size(), rdata.len());
+    assert_eq!(hinfo.try_into_bytes().unwrap()[..], rdata[..]);
+
+    // the longest CPU string
+    let mut rdata = BytesMut::new();
+    rdata.put_u16(255 + 1 + 1 + 1);
+    rdata.put_u8(255);
+    rdata.put_bytes(b'x', 255);
+    rdata.put_u8(1);
+    rdata.put_u8(b'y');
+    let rdata = rdata.freeze();
+    let (hinfo, end) = HInfo::parse(rdata.clone(), 0).unwrap();
+    assert_eq!((hinfo.cpu.len(), &hinfo.os[..]), (255, &b"y"[..]));
+    assert_eq!(end, rdata.len());
+}
+
+#[test]
+fn test_overflow() {
+    // lengths of 255 and 2 wrap around in u8, yet run past RDATA
+    let mut rdata = BytesMut::new();
+    rdata.put_u16(255 + 1 + 1);
+    rdata.put_u8(255);
+    rdata.put_bytes(b'x', 255);
+    rdata.put_u8(2);
+    // the next record
+    rdata.put_bytes(0, 16);
+    assert!(HInfo::parse(rdata.freeze(), 0).is_err());
+
+    // the CPU string runs past RDATA
+    let rdata = Bytes::from(b"\x00\x04\xff\x01\x02\x03\x00\x00\x00\x00".to_vec());
+    assert!(HInfo::parse(rdata, 0).is_err());
+    // trailing bytes after the OS string
+    let rdata = Bytes::from(b"\x00\x05\x01a\x01bc".to_vec());
+    assert!(HInfo::parse(rdata, 0).is_err());
 }
@@ -1,41 +1,26 @@
 use std::fmt::Display;
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+#[cfg(test)]
+use bytes::Bytes;
+use bytes::{BufMut, BytesMut};
 
 use crate::protocol::{
     rr::rdata::{try_into_rdata_length, Rdata},
-    Name, PacketError,
+    Name, PacketError, PacketReader,
 };
 
 #[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Mb {
     domain: Name,
 }
 
 impl Rdata for Mb {
-    fn parse(packet: Bytes, pos: usize) -> Result<(Self, usize), PacketError>
-    where
-        Self: Sized,
-    {
-        if pos + 4 > packet.len() {
-            return Err(PacketError::FormatError);
-        }
-
-        let mut pos = pos;
-        let mut p = packet.clone();
-        if pos + 1 >= p.len() {
-            return Err(PacketError::FormatError);
-        }
-        p.advance(pos);
-        pos += 2;
-        let end = p.get_u16() as usize + pos;
-
-        let (domain, domain_end) = Name::parse(packet, pos)?;
-        if end == domain_end {
-            Ok((Self { domain }, end))
-        } else {
-            Err(PacketError::FormatError)
-        }
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        let domain = Name::read(&mut rdata)?;
+        rdata.finish()?;
+        Ok(Self { domain })
     }
 
     fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
@@ -46,6 +31,10 @@ impl Rdata for Mb {
         buf.put_slice(&v[..]);
         Ok(buf)
     }
+
+    fn size(&self) -> usize {
+        2 + self.domain.wire_len()
+    }
 }
 
 impl From<Name> for Mb {
@@ -1,44 +1,126 @@
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::fmt::Display;
 
-use crate::protocol::{rr::rdata::Rdata, Name, PacketError};
+#[cfg(test)]
+use bytes::Bytes;
+use bytes::{BufMut, BytesMut};
 
-#[derive(Clone, Debug)]
+use crate::protocol::{
+    rr::rdata::{try_into_rdata_length, Rdata},
+    Name, PacketError, PacketReader,
+};
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct MInfo {
     r_mail_box: Name,
     e_mail_box: Name,
 }
 
-impl Rdata for MInfo {
-    fn parse(packet: Bytes, pos: usize) -> Result<(Self, usize), PacketError>
-    where
-        Self: Sized,
-    {
-        if pos + 2 > packet.len() {
-            return Err(PacketError::FormatError);
-        }
-
-        let mut pos = pos;
-        let mut p = packet.clone();
-        p.advance(pos);
-        pos += 2;
+impl Display for MInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.r_mail_box, self.e_mail_box)
+    }
+}
 
-        let (r_mail_box, m_end) = Name::parse(packet.clone(), pos)?;
-        let (e_mail_box, end) = Name::parse(packet, m_end)?;
-        let m_info = MInfo {
+impl Rdata for MInfo {
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        let r_mail_box = Name::read(&mut rdata)?;
+        let e_mail_box = Name::read(&mut rdata)?;
+        // both names must fill RDLENGTH exactly
+        rdata.finish()?;
+        Ok(MInfo {
             r_mail_box,
             e_mail_box,
-        };
-        Ok((m_info, end))
+        })
     }
 
     fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
         let n1 = self.r_mail_box.as_bytes_uncompressed();
         let n2 = self.e_mail_box.as_bytes_uncompressed();
-        let len = (n1.len() + n2.len()) as u16;
-        let mut buf = BytesMut::new();
-        buf.put_u16(len);
+        let rdlength = try_into_rdata_length(n1.len() + n2.len())?;
+        let mut buf = BytesMut::with_capacity(2 + n1.len() + n2.len());
+        buf.put_u16(rdlength);
         buf.put(n1);
         buf.put(n2);
         Ok(buf)
     }
+
+    fn size(&self) -> usize {
+        2 + self.r_mail_box.wire_len() + self.e_mail_box.wire_len()
+    }
+}
+
+#[test]
+fn test_parse_and_to_bytes() {
+    let r_mail_box = Name::try_from("admin.example.com").unwrap();
+    let e_mail_box = Name::try_from("errors.example.com").unwrap();
+    let rname = r_mail_box.as_bytes_uncompressed();
+    let ename = e_mail_box.as_bytes_uncompressed();
+
+    let mut buf = BytesMut::new();
+    buf.put_u16(try_into_rdata_length(rname.len() + ename.len()).unwrap());
+    buf.put_slice(&rname[..]);
+    buf.put_slice(&ename[..]);
+    let buf = Bytes::from(buf);
+
+    let (m_info, end) = MInfo::parse(buf.clone(), 0).unwrap();
+    assert_eq!(end, buf.len());
+    assert_eq!(
+        m_info,
+        MInfo {
+            r_mail_box,
+            e_mail_box
+        }
+    );
+    assert_eq!(m_info.size(), buf.len());
+    let bytes = m_info.try_into_bytes().unwrap();
+    assert_eq!(bytes[..], buf[..]);
+
+    // parsed again from its own bytes
+    let (parsed, _) = MInfo::parse(bytes.freeze(), 0).unwrap();
+    assert_eq!(parsed, m_info);
+}
+
+#[test]
+fn test_invalid_length() {
+    let rname = Name::try_from("admin.example.com")
+        .unwrap()
+        .as_bytes_uncompressed();
+    let ename = Name::try_from("errors.example.com")
+        .unwrap()
+        .as_bytes_uncompressed();
+    let length = rname.len() + ename.len();
+    for declared in [length - 1, length + 1] {
+        let mut buf = BytesMut::new();
+        buf.put_u16(declared as u16);
+        buf.put_slice(&rname[..]);
+        buf.put_slice(&ename[..]);
+        // trailing bytes of the next record
+        buf.put_slice(&[0, 1]);
+        assert!(MInfo::parse(buf.freeze(), 0).is_err());
+    }
+
+    // the second name is missing
+    let mut buf = BytesMut::new();
+    buf.put_u16(rname.len() as u16);
+    buf.put_slice(&rname[..]);
+    assert!(MInfo::parse(buf.freeze(), 0).is_err());
+}
+
+#[test]
+fn test_truncated() {
+    // RDLENGTH cut short, or no RDLENGTH at all
+    assert!(MInfo::parse(Bytes::from_static(b"\x00"), 0).is_err());
+    assert!(MInfo::parse(Bytes::new(), 0).is_err());
+    // the record starts beyond the packet
+    assert!(MInfo::parse(Bytes::from_static(b"\x00\x02\x00\x00"), 5).is_err());
+    // names cut by the end of the packet
+    let name = Name::try_from("admin.example.com")
+        .unwrap()
+        .as_bytes_uncompressed();
+    let mut buf = BytesMut::new();
+    buf.put_u16(2 * name.len() as u16);
+    buf.put_slice(&name[..name.len() - 3]);
+    assert!(MInfo::parse(buf.freeze(), 0).is_err());
 }
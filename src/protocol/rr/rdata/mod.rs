@@ -4,39 +4,88 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::fmt;
+
 use bytes::{Bytes, BytesMut};
 
-use crate::protocol::{domain::Name, error::PacketError};
+use crate::protocol::{domain::Name, error::PacketError, PacketReader};
 
 pub mod a;
 pub mod aaaa;
 pub mod cname;
+pub mod dname;
+pub mod dnskey;
+pub mod ds;
 pub mod hinfo;
 pub mod mb;
 pub mod mg;
 pub mod minfo;
 pub mod mr;
 pub mod mx;
+pub mod naptr;
 pub mod nl;
 pub mod ns;
+pub mod nsec;
+pub mod opt;
 pub mod pt; // PTR
+pub mod rrsig;
 pub mod soa;
+pub mod svcb;
 pub mod txt;
+pub mod uri;
 pub mod wks;
 
 pub mod unknown;
 
+#[cfg(feature = "serde")]
+mod encoding;
+
 pub trait Rdata {
+    /// read RDLENGTH and RDATA off `reader`
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError>
+    where
+        Self: Sized;
     /// Parse packet data, returning a valid object, and its end in packet.
+    #[allow(dead_code)]
     fn parse(packet: Bytes, pos: usize) -> Result<(Self, usize), PacketError>
     where
-        Self: Sized;
+        Self: Sized,
+    {
+        let mut reader = PacketReader::new(&packet, pos);
+        let rdata = Self::read(&mut reader)?;
+        Ok((rdata, reader.pos()))
+    }
     fn try_into_bytes(&self) -> Result<BytesMut, PacketError>;
+    /// length of the bytes made by `try_into_bytes`, RDLENGTH included
+    fn size(&self) -> usize {
+        self.try_into_bytes().map_or(0, |buf| buf.len())
+    }
 }
 
-pub(self) fn try_into_rdata_length<N>(rdata_length: N) -> Result<u16, PacketError>
+fn try_into_rdata_length<N>(rdata_length: N) -> Result<u16, PacketError>
 where
     N: TryInto<u16>,
 {
     rdata_length.try_into().map_err(|_| PacketError::ServFail)
 }
+
+/// write a character-string quoted as in master files
+fn fmt_character_string(f: &mut fmt::Formatter<'_>, text: &[u8]) -> fmt::Result {
+    write!(f, "\"{}\"", Escaped(text))
+}
+
+/// bytes with quotes, backslashes and unprintable octets escaped
+struct Escaped<'a>(&'a [u8]);
+
+impl fmt::Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for &b in self.0 {
+            match b {
+                b'"' | b'\\' => write!(f, "\\{}", b as char)?,
+                0x20..=0x7e => write!(f, "{}", b as char)?,
+                _ => write!(f, "\\{:03}", b)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -4,12 +4,17 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::fmt::Display;
+
+#[cfg(test)]
+use bytes::Bytes;
+use bytes::{BufMut, BytesMut};
 
 use super::{try_into_rdata_length, Name, Rdata};
-use crate::protocol::error::PacketError;
+use crate::protocol::{error::PacketError, PacketReader};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Mx {
     preference: u16,
     domain: Name,
@@ -24,29 +29,19 @@ impl Mx {
     }
 }
 
-impl Rdata for Mx {
-    fn parse(packet: Bytes, pos: usize) -> Result<(Self, usize), PacketError> {
-        if pos + (2 + 2 + 2) > packet.len() {
-            return Err(PacketError::FormatError);
-        }
-
-        let mut p = packet.clone();
-        p.advance(pos);
-
-        let length = p.get_u16() as usize;
-        let preference = p.get_u16();
-
-        let end = length + pos + 2;
-
-        let pos = pos + 4;
+impl Display for Mx {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {}", self.preference, self.domain)
+    }
+}
 
-        let (domain, domain_end) = Name::parse(packet, pos)?;
-        let mx = Mx { preference, domain };
-        if domain_end == end {
-            Ok((mx, end))
-        } else {
-            Err(PacketError::FormatError)
-        }
+impl Rdata for Mx {
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        let preference = rdata.read_u16()?;
+        let domain = Name::read(&mut rdata)?;
+        rdata.finish()?;
+        Ok(Mx { preference, domain })
     }
 
     fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
@@ -60,6 +55,10 @@ impl Rdata for Mx {
         buf.put_slice(&self.domain.as_bytes_uncompressed()[..]);
         Ok(buf)
     }
+
+    fn size(&self) -> usize {
+        2 + 2 + self.domain.wire_len()
+    }
 }
 
 #[test]
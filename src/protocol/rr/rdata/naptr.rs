@@ -0,0 +1,176 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::{BufMut, BytesMut};
+
+use super::{try_into_rdata_length, Name, Rdata};
+use crate::protocol::{error::PacketError, PacketReader};
+
+/// ## `Naptr`
+/// Naming Authority Pointer, see [RFC3403](https://datatracker.ietf.org/doc/html/rfc3403#section-4.1)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Naptr {
+    order: u16,
+    preference: u16,
+    #[cfg_attr(feature = "serde", serde(with = "super::encoding::character_string"))]
+    flags: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(with = "super::encoding::character_string"))]
+    services: Vec<u8>,
+    #[cfg_attr(feature = "serde", serde(with = "super::encoding::character_string"))]
+    regexp: Vec<u8>,
+    replacement: Name,
+}
+
+impl Naptr {
+    pub fn new(
+        order: u16,
+        preference: u16,
+        flags: Vec<u8>,
+        services: Vec<u8>,
+        regexp: Vec<u8>,
+        replacement: Name,
+    ) -> Self {
+        Self {
+            order,
+            preference,
+            flags,
+            services,
+            regexp,
+            replacement,
+        }
+    }
+    pub fn get_order(&self) -> u16 {
+        self.order
+    }
+    pub fn get_preference(&self) -> u16 {
+        self.preference
+    }
+    pub fn get_flags(&self) -> &[u8] {
+        &self.flags
+    }
+    pub fn get_services(&self) -> &[u8] {
+        &self.services
+    }
+    pub fn get_regexp(&self) -> &[u8] {
+        &self.regexp
+    }
+    pub fn get_replacement(&self) -> Name {
+        self.replacement.clone()
+    }
+}
+
+impl Rdata for Naptr {
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        let naptr = Naptr {
+            order: rdata.read_u16()?,
+            preference: rdata.read_u16()?,
+            flags: rdata.read_character_string()?.to_vec(),
+            services: rdata.read_character_string()?.to_vec(),
+            regexp: rdata.read_character_string()?.to_vec(),
+            replacement: Name::read(&mut rdata)?,
+        };
+        rdata.finish()?;
+        Ok(naptr)
+    }
+
+    fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
+        let size = self.size();
+        let rdlength = try_into_rdata_length(size - 2)?;
+        let mut buf = BytesMut::with_capacity(size);
+        buf.put_u16(rdlength);
+        buf.put_u16(self.order);
+        buf.put_u16(self.preference);
+        for s in [&self.flags, &self.services, &self.regexp] {
+            let len = u8::try_from(s.len()).map_err(|_| PacketError::ServFail)?;
+            buf.put_u8(len);
+            buf.put_slice(s);
+        }
+        // the replacement is never compressed
+        buf.put_slice(&self.replacement.as_bytes_uncompressed());
+        Ok(buf)
+    }
+
+    fn size(&self) -> usize {
+        let strings: usize = [&self.flags, &self.services, &self.regexp]
+            .iter()
+            .map(|s| 1 + s.len())
+            .sum();
+        2 + 2 + 2 + strings + self.replacement.wire_len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{BufMut, Bytes, BytesMut};
+
+    use super::{Naptr, Rdata};
+    use crate::protocol::Name;
+
+    fn sip_naptr() -> Naptr {
+        Naptr::new(
+            100,
+            10,
+            b"u".to_vec(),
+            b"E2U+sip".to_vec(),
+            b"!^.*$!sip:info@example.com!".to_vec(),
+            Name::try_from(".").unwrap(),
+        )
+    }
+
+    fn sip_rdata() -> Bytes {
+        let mut buf = BytesMut::new();
+        buf.put_u16(2 + 2 + (1 + 1) + (1 + 7) + (1 + 27) + 1);
+        buf.put_u16(100);
+        buf.put_u16(10);
+        buf.put(&b"\x01u\x07E2U+sip"[..]);
+        buf.put_u8(27);
+        buf.put(&b"!^.*$!sip:info@example.com!"[..]);
+        buf.put_u8(0);
+        buf.freeze()
+    }
+
+    #[test]
+    fn test_parse() {
+        let target = sip_rdata();
+        let (naptr, end) = Naptr::parse(target.clone(), 0).unwrap();
+        assert_eq!(end, target.len());
+        assert_eq!(naptr, sip_naptr());
+        assert_eq!(naptr.get_regexp(), b"!^.*$!sip:info@example.com!");
+
+        let replaced = Naptr::new(
+            10,
+            0,
+            b"s".to_vec(),
+            b"SIP+D2U".to_vec(),
+            vec![],
+            Name::try_from("_sip._udp.example.com").unwrap(),
+        );
+        let bytes = replaced.try_into_bytes().unwrap().freeze();
+        assert_eq!(Naptr::parse(bytes, 0).unwrap().0, replaced);
+
+        // character strings overflowing RDLENGTH
+        let mut invalid = BytesMut::from(&target[..]);
+        invalid[1] = 6;
+        assert!(Naptr::parse(invalid.freeze(), 0).is_err());
+        // replacement not ending at RDLENGTH
+        let mut invalid = BytesMut::from(&target[..]);
+        invalid[1] += 1;
+        invalid.put_u8(0);
+        assert!(Naptr::parse(invalid.freeze(), 0).is_err());
+        // truncated
+        assert!(Naptr::parse(target.slice(..20), 0).is_err());
+    }
+
+    #[test]
+    fn test_to_bytes() {
+        let naptr = sip_naptr();
+        let bytes = naptr.try_into_bytes().unwrap();
+        assert_eq!(bytes.len(), naptr.size());
+        assert_eq!(bytes.freeze(), sip_rdata());
+    }
+}
@@ -1,29 +1,20 @@
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+#[cfg(test)]
+use bytes::Bytes;
+use bytes::{BufMut, BytesMut};
 
-use crate::protocol::{rr::rdata::Rdata, PacketError};
+use crate::protocol::{rr::rdata::Rdata, PacketError, PacketReader};
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Null {
+    #[cfg_attr(feature = "serde", serde(with = "super::encoding::base64_bytes"))]
     data: Vec<u8>,
 }
 
 impl Rdata for Null {
-    fn parse(packet: Bytes, pos: usize) -> Result<(Self, usize), PacketError>
-    where
-        Self: Sized,
-    {
-        if pos + 2 >= packet.len() {
-            return Err(PacketError::FormatError);
-        }
-
-        let mut p = packet;
-        p.advance(pos);
-        let len = p.get_u16() as usize;
-        let end = len + pos + 2;
-
-        let data = Vec::from(&p[..len]);
-        let null = Null { data };
-        Ok((null, end))
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let data = reader.rdata()?.read_rest().to_vec();
+        Ok(Null { data })
     }
 
     fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
@@ -33,4 +24,31 @@ impl Rdata for Null {
         buf.put(&self.data[..]);
         Ok(buf)
     }
+
+    fn size(&self) -> usize {
+        2 + self.data.len()
+    }
+}
+
+#[test]
+fn test_parse() {
+    // zero-length data, RDLENGTH ends the buffer
+    let (null, end) = Null::parse(Bytes::from_static(b"\x00\x00"), 0).unwrap();
+    assert!(null.data.is_empty());
+    assert_eq!(end, 2);
+
+    // the longest data, ending at the buffer boundary
+    let mut rdata = BytesMut::new();
+    rdata.put_u16(u16::MAX);
+    rdata.put_bytes(0xab, u16::MAX as usize);
+    let rdata = rdata.freeze();
+    let (null, end) = Null::parse(rdata.clone(), 0).unwrap();
+    assert_eq!(null.data.len(), u16::MAX as usize);
+    assert_eq!(end, rdata.len());
+    assert_eq!(null.try_into_bytes().unwrap().freeze(), rdata);
+
+    // data shorter than RDLENGTH, or no RDLENGTH at all
+    let truncated = Bytes::from_static(b"\x00\x04\x01\x02\x03");
+    assert!(Null::parse(truncated, 0).is_err());
+    assert!(Null::parse(Bytes::from_static(b"\x00"), 0).is_err());
 }
@@ -6,36 +6,23 @@
 
 use std::fmt::Display;
 
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{BufMut, BytesMut};
 
 use super::{try_into_rdata_length, Rdata};
-use crate::protocol::{domain::Name, error::PacketError};
+use crate::protocol::{domain::Name, error::PacketError, PacketReader};
 
 #[derive(PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Ns {
     domain: Name,
 }
 
 impl Rdata for Ns {
-    fn parse(packet: bytes::Bytes, pos: usize) -> Result<(Self, usize), PacketError>
-    where
-        Self: Sized,
-    {
-        if pos + 4 > packet.len() {
-            return Err(PacketError::FormatError);
-        }
-        let mut p = packet.clone();
-        let length = p.get_u16() as usize;
-        let pos = pos + 2;
-        let end = pos + length;
-
-        let (domain, domain_end) = Name::parse(packet, pos)?;
-        let ns = Ns { domain };
-        if domain_end == end {
-            Ok((ns, end))
-        } else {
-            Err(PacketError::FormatError)
-        }
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        let domain = Name::read(&mut rdata)?;
+        rdata.finish()?;
+        Ok(Self { domain })
     }
 
     fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
@@ -46,6 +33,10 @@ impl Rdata for Ns {
         buf.put_slice(&self.domain.as_bytes_uncompressed()[..]);
         Ok(buf)
     }
+
+    fn size(&self) -> usize {
+        2 + self.domain.wire_len()
+    }
 }
 
 impl From<Name> for Ns {
@@ -86,6 +77,12 @@ mod ns_tests {
         let target = Ns::from(Name::try_from("example.com").unwrap());
         assert_eq!(end, rdata.len());
         assert_eq!(ns, target);
+
+        // RDLENGTH is read at the record, not at the start of the packet
+        let rdata = Bytes::from(b"\x00\x01\x00\x0d\x07example\x03com\x00".to_vec());
+        let (ns, end) = Ns::parse(rdata.clone(), 2).unwrap();
+        assert_eq!(end, rdata.len());
+        assert_eq!(ns, target);
     }
 
     #[test]
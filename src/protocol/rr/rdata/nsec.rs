@@ -0,0 +1,164 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::{BufMut, BytesMut};
+
+use super::{try_into_rdata_length, Name, Rdata};
+use crate::protocol::{error::PacketError, PacketReader, RRType};
+
+/// ## `Nsec`
+/// Next Secure, see [RFC4034](https://datatracker.ietf.org/doc/html/rfc4034#section-4.1)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Nsec {
+    next: Name,
+    /// in ascending order
+    types: Vec<RRType>,
+}
+
+impl Nsec {
+    pub fn new(next: Name, types: Vec<RRType>) -> Self {
+        let mut types = types;
+        types.sort_by_key(|ty| u16::from(*ty));
+        types.dedup();
+        Self { next, types }
+    }
+    pub fn get_next(&self) -> Name {
+        self.next.clone()
+    }
+    pub fn get_types(&self) -> &[RRType] {
+        &self.types
+    }
+}
+
+/// read a type bitmap filling the rest of `bitmap`, see [RFC4034](https://datatracker.ietf.org/doc/html/rfc4034#section-4.1.2)
+pub(crate) fn read_type_bitmap(bitmap: &mut PacketReader) -> Result<Vec<RRType>, PacketError> {
+    let mut types = vec![];
+    let mut last_window = None;
+    while bitmap.remaining() > 0 {
+        let window = bitmap.read_u8()?;
+        let len = bitmap.read_u8()? as usize;
+        // windows come in ascending order, each with 1 to 32 octets
+        if last_window.is_some_and(|last| last >= window) || !(1..=32).contains(&len) {
+            return Err(PacketError::FormatError);
+        }
+        last_window = Some(window);
+        for (i, octet) in bitmap.read_slice(len)?.iter().enumerate() {
+            for bit in 0..8 {
+                if octet & (0x80 >> bit) != 0 {
+                    let ty = (window as u16) << 8 | (i * 8 + bit) as u16;
+                    types.push(RRType::from(ty));
+                }
+            }
+        }
+    }
+    Ok(types)
+}
+
+/// make a type bitmap of `types` in ascending order
+pub(crate) fn type_bitmap(types: &[RRType]) -> Vec<u8> {
+    let mut bitmap = vec![];
+    // start of the current window in `bitmap`
+    let mut window_start = None;
+    for ty in types.iter().map(|ty| u16::from(*ty)) {
+        let window = (ty >> 8) as u8;
+        let octet = (ty & 0xff) as usize / 8;
+        let start = match window_start {
+            Some(start) if bitmap[start] == window => start,
+            _ => {
+                bitmap.extend_from_slice(&[window, 0]);
+                window_start = Some(bitmap.len() - 2);
+                bitmap.len() - 2
+            }
+        };
+        let len = bitmap[start + 1] as usize;
+        if octet >= len {
+            bitmap.resize(start + 2 + octet + 1, 0);
+            bitmap[start + 1] = octet as u8 + 1;
+        }
+        bitmap[start + 2 + octet] |= 0x80 >> (ty % 8);
+    }
+    bitmap
+}
+
+impl Rdata for Nsec {
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        let next = Name::read_uncompressed(&mut rdata)?;
+        let types = read_type_bitmap(&mut rdata)?;
+        Ok(Nsec { next, types })
+    }
+
+    fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
+        let bitmap = type_bitmap(&self.types);
+        let length = self.next.wire_len() + bitmap.len();
+        let mut buf = BytesMut::with_capacity(2 + length);
+        buf.put_u16(try_into_rdata_length(length)?);
+        buf.put_slice(&self.next.as_bytes_uncompressed());
+        buf.put_slice(&bitmap);
+        Ok(buf)
+    }
+
+    fn size(&self) -> usize {
+        2 + self.next.wire_len() + type_bitmap(&self.types).len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{BufMut, BytesMut};
+
+    use super::{Nsec, Rdata};
+    use crate::protocol::{Name, RRType};
+
+    #[test]
+    fn test_round_trip() {
+        // alfa.example.com. NSEC of RFC4034 section 4.3
+        let mut target = BytesMut::new();
+        target.put_u16(18 + 8 + 29);
+        target.put(&b"\x04host\x07example\x03com\x00"[..]);
+        target.put(&b"\x00\x06\x40\x01\x00\x00\x00\x03"[..]);
+        target.put(&b"\x04\x1b"[..]);
+        target.put(&[0; 26][..]);
+        target.put_u8(0x20);
+        let target = target.freeze();
+
+        let (nsec, end) = Nsec::parse(target.clone(), 0).unwrap();
+        assert_eq!(end, target.len());
+        let types = vec![
+            RRType::A,
+            RRType::Mx,
+            RRType::Rrsig,
+            RRType::Nsec,
+            RRType::UNKNOWN(1234),
+        ];
+        assert_eq!(nsec.get_next(), Name::try_from("host.example.com").unwrap());
+        assert_eq!(nsec.get_types(), &types[..]);
+
+        let made = Nsec::new(Name::try_from("host.example.com").unwrap(), types);
+        assert_eq!(made, nsec);
+        assert_eq!(made.size(), target.len());
+        assert_eq!(made.try_into_bytes().unwrap().freeze(), target);
+    }
+
+    #[test]
+    fn test_invalid() {
+        // the next name is compressed
+        let mut compressed = BytesMut::new();
+        compressed.put(&b"\x04host\x07example\x03com\x00"[..]);
+        compressed.put_u16(2 + 3);
+        compressed.put(&b"\xc0\x00\x00\x01\x40"[..]);
+        assert!(Nsec::parse(compressed.freeze(), 18).is_err());
+
+        // descending windows
+        let descending = &b"\x00\x07\x00\x01\x01\x40\x00\x01\x40"[..];
+        assert!(Nsec::parse(descending.into(), 0).is_err());
+
+        // empty window
+        let empty = &b"\x00\x03\x00\x00\x00"[..];
+        assert!(Nsec::parse(empty.into(), 0).is_err());
+    }
+}
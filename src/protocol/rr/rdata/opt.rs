@@ -0,0 +1,290 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::net::IpAddr;
+
+use bytes::{BufMut, BytesMut};
+
+use super::{try_into_rdata_length, Rdata};
+use crate::protocol::{error::PacketError, PacketReader, RR};
+
+/// payload size advertised by default, avoiding fragmentation, see [DNS Flag Day 2020](https://www.dnsflagday.net/2020/)
+const DEFAULT_UDP_SIZE: u16 = 1232;
+
+/// ## `Opt`
+/// EDNS(0) options, see [RFC6891](https://datatracker.ietf.org/doc/html/rfc6891#section-6.1.2)
+///
+/// The payload size, extended RCODE and flags live in the CLASS and TTL of the record.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Opt {
+    #[cfg_attr(feature = "serde", serde(with = "super::encoding::options"))]
+    options: Vec<(u16, Vec<u8>)>,
+}
+
+impl Opt {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// append option `code` with `data`
+    pub fn with_option(mut self, code: u16, data: Vec<u8>) -> Self {
+        self.options.push((code, data));
+        self
+    }
+
+    pub fn get_options(&self) -> &[(u16, Vec<u8>)] {
+        &self.options
+    }
+    pub fn get_option(&self, code: u16) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, data)| data.as_slice())
+    }
+}
+
+/// ## `EdnsOption`
+/// Typed EDNS(0) options, made into the code and data of an option in `Opt`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EdnsOption {
+    /// the subnet of the client, see [RFC7871](https://datatracker.ietf.org/doc/html/rfc7871#section-6).
+    ///
+    /// Only the first `source_prefix` bits of the address are sent,
+    /// prefixes longer than the address are cut to its length.
+    ClientSubnet {
+        address: IpAddr,
+        source_prefix: u8,
+        scope_prefix: u8,
+    },
+    /// client cookie, optionally followed by the server cookie, see [RFC7873](https://datatracker.ietf.org/doc/html/rfc7873#section-4)
+    Cookie(Vec<u8>),
+    /// zero bytes padding the message, see [RFC7830](https://datatracker.ietf.org/doc/html/rfc7830#section-3)
+    Padding(u16),
+    /// reason of a failure, see [RFC8914](https://datatracker.ietf.org/doc/html/rfc8914#section-2)
+    ExtendedError { info_code: u16, text: String },
+    /// other options by their codes
+    Other(u16, Vec<u8>),
+}
+
+impl EdnsOption {
+    pub fn code(&self) -> u16 {
+        match self {
+            EdnsOption::ClientSubnet { .. } => 8,
+            EdnsOption::Cookie(_) => 10,
+            EdnsOption::Padding(_) => 12,
+            EdnsOption::ExtendedError { .. } => 15,
+            EdnsOption::Other(code, _) => *code,
+        }
+    }
+
+    fn into_data(self) -> Vec<u8> {
+        match self {
+            EdnsOption::ClientSubnet {
+                address,
+                source_prefix,
+                scope_prefix,
+            } => {
+                let (family, octets): (u16, Vec<u8>) = match address {
+                    IpAddr::V4(v4) => (1, v4.octets().to_vec()),
+                    IpAddr::V6(v6) => (2, v6.octets().to_vec()),
+                };
+                let prefix = (source_prefix as usize).min(octets.len() * 8);
+                let mut address = octets[..prefix.div_ceil(8)].to_vec();
+                // bits beyond the prefix must be zero
+                if let (Some(last), 1..) = (address.last_mut(), prefix % 8) {
+                    *last &= 0xff << (8 - prefix % 8);
+                }
+                let mut data = Vec::with_capacity(4 + address.len());
+                data.extend_from_slice(&family.to_be_bytes());
+                data.push(prefix as u8);
+                data.push(scope_prefix);
+                data.extend(address);
+                data
+            }
+            EdnsOption::Cookie(cookie) => cookie,
+            EdnsOption::Padding(len) => vec![0; len as usize],
+            EdnsOption::ExtendedError { info_code, text } => {
+                [&info_code.to_be_bytes()[..], text.as_bytes()].concat()
+            }
+            EdnsOption::Other(_, data) => data,
+        }
+    }
+}
+
+/// ## `OptBuilder`
+/// Builds the `OPT` record of a packet, to be added by `Packet::add_addition`.
+///
+/// The payload size defaults to 1232 bytes, the other fields to zero.
+#[derive(Debug, Clone)]
+pub struct OptBuilder {
+    udp_size: u16,
+    ext_rcode: u8,
+    version: u8,
+    dnssec_ok: bool,
+    opt: Opt,
+}
+
+impl Default for OptBuilder {
+    fn default() -> Self {
+        Self {
+            udp_size: DEFAULT_UDP_SIZE,
+            ext_rcode: 0,
+            version: 0,
+            dnssec_ok: false,
+            opt: Opt::new(),
+        }
+    }
+}
+
+impl OptBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the largest UDP payload accepted by the sender
+    pub fn udp_size(mut self, size: u16) -> Self {
+        self.udp_size = size;
+        self
+    }
+
+    /// the upper 8 bits of the 12-bit RCODE
+    pub fn ext_rcode(mut self, ext_rcode: u8) -> Self {
+        self.ext_rcode = ext_rcode;
+        self
+    }
+
+    pub fn version(mut self, version: u8) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// the DO bit, DNSSEC records are wanted
+    pub fn dnssec_ok(mut self, dnssec_ok: bool) -> Self {
+        self.dnssec_ok = dnssec_ok;
+        self
+    }
+
+    /// append `option`, in the order of calls
+    pub fn option(mut self, option: EdnsOption) -> Self {
+        let code = option.code();
+        self.opt = self.opt.with_option(code, option.into_data());
+        self
+    }
+
+    pub fn build(self) -> RR {
+        RR::new_opt(
+            self.udp_size,
+            self.ext_rcode,
+            self.version,
+            self.dnssec_ok,
+            self.opt,
+        )
+    }
+}
+
+impl Rdata for Opt {
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        let mut options = vec![];
+        while rdata.remaining() > 0 {
+            let code = rdata.read_u16()?;
+            let len = rdata.read_u16()? as usize;
+            options.push((code, rdata.read_slice(len)?.to_vec()));
+        }
+        Ok(Self { options })
+    }
+
+    fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
+        let size = self.size();
+        let mut buf = BytesMut::with_capacity(size);
+        buf.put_u16(try_into_rdata_length(size - 2)?);
+        for (code, data) in self.options.iter() {
+            buf.put_u16(*code);
+            buf.put_u16(try_into_rdata_length(data.len())?);
+            buf.put_slice(data);
+        }
+        Ok(buf)
+    }
+
+    fn size(&self) -> usize {
+        let options: usize = self.options.iter().map(|(_, d)| 2 + 2 + d.len()).sum();
+        2 + options
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::Ipv4Addr;
+
+    use bytes::Bytes;
+
+    use super::{EdnsOption, Opt, OptBuilder, Rdata};
+    use crate::protocol::{PacketContent, RRType};
+
+    #[test]
+    fn test_round_trip() {
+        // a client cookie and an empty padding option
+        let rdata = Bytes::from(
+            b"\x00\x10\x00\x0a\x00\x08\x01\x02\x03\x04\x05\x06\x07\x08\x00\x0c\x00\x00".to_vec(),
+        );
+        let (opt, end) = Opt::parse(rdata.clone(), 0).unwrap();
+        assert_eq!(end, rdata.len());
+        let cookie = Opt::new()
+            .with_option(10, vec![1, 2, 3, 4, 5, 6, 7, 8])
+            .with_option(12, vec![]);
+        assert_eq!(opt, cookie);
+        assert_eq!(opt.get_option(10), Some(&[1, 2, 3, 4, 5, 6, 7, 8][..]));
+        assert_eq!(opt.get_option(12), Some(&[][..]));
+        assert_eq!(opt.size(), rdata.len());
+        assert_eq!(opt.try_into_bytes().unwrap().freeze(), rdata);
+
+        // option overflowing RDLENGTH
+        let mut invalid = rdata.to_vec();
+        invalid[1] -= 1;
+        assert!(Opt::parse(Bytes::from(invalid), 0).is_err());
+    }
+
+    #[test]
+    fn test_builder() {
+        let opt = OptBuilder::new()
+            .dnssec_ok(true)
+            .option(EdnsOption::ClientSubnet {
+                address: Ipv4Addr::new(198, 51, 100, 77).into(),
+                source_prefix: 20,
+                scope_prefix: 0,
+            })
+            .build();
+        assert_eq!(opt.get_type(), RRType::Opt);
+        let wire = opt.into_bytes().unwrap().freeze();
+        let expected: &[u8] = &[
+            0, // root
+            0x00, 0x29, // OPT
+            0x04, 0xd0, // payload size 1232
+            0, 0, 0x80, 0x00, // extended RCODE, version and DO
+            0x00, 0x0b, // RDLENGTH
+            0x00, 0x08, 0x00, 0x07, // client subnet
+            0x00, 0x01, 20, 0, // IPv4, source and scope prefixes
+            198, 51, 0x60, // bits beyond the prefix are cleared
+        ];
+        assert_eq!(&wire[..], expected);
+
+        let opt = OptBuilder::new()
+            .udp_size(4096)
+            .version(1)
+            .option(EdnsOption::Cookie(vec![1, 2, 3, 4, 5, 6, 7, 8]))
+            .option(EdnsOption::Padding(2))
+            .build();
+        let wire = opt.into_bytes().unwrap().freeze();
+        let expected: &[u8] = &[
+            0, 0x00, 0x29, 0x10, 0x00, 0, 1, 0, 0, // owner, type, class and TTL
+            0x00, 0x12, // RDLENGTH
+            0x00, 0x0a, 0x00, 0x08, 1, 2, 3, 4, 5, 6, 7, 8, // cookie
+            0x00, 0x0c, 0x00, 0x02, 0, 0, // padding
+        ];
+        assert_eq!(&wire[..], expected);
+    }
+}
@@ -0,0 +1,177 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use bytes::{BufMut, BytesMut};
+
+use super::{try_into_rdata_length, Name, Rdata};
+use crate::protocol::{error::PacketError, PacketReader, RRType};
+
+/// ## `Rrsig`
+/// Resource Record Signature, see [RFC4034](https://datatracker.ietf.org/doc/html/rfc4034#section-3.1)
+///
+/// Expiration and inception are seconds since the epoch, in serial number arithmetic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rrsig {
+    type_covered: RRType,
+    algorithm: u8,
+    labels: u8,
+    original_ttl: u32,
+    expiration: u32,
+    inception: u32,
+    key_tag: u16,
+    signer: Name,
+    #[cfg_attr(feature = "serde", serde(with = "super::encoding::base64_bytes"))]
+    signature: Vec<u8>,
+}
+
+impl Rrsig {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        type_covered: RRType,
+        algorithm: u8,
+        labels: u8,
+        original_ttl: u32,
+        expiration: u32,
+        inception: u32,
+        key_tag: u16,
+        signer: Name,
+        signature: Vec<u8>,
+    ) -> Self {
+        Self {
+            type_covered,
+            algorithm,
+            labels,
+            original_ttl,
+            expiration,
+            inception,
+            key_tag,
+            signer,
+            signature,
+        }
+    }
+    pub fn get_type_covered(&self) -> RRType {
+        self.type_covered
+    }
+    pub fn get_algorithm(&self) -> u8 {
+        self.algorithm
+    }
+    pub fn get_labels(&self) -> u8 {
+        self.labels
+    }
+    pub fn get_original_ttl(&self) -> u32 {
+        self.original_ttl
+    }
+    pub fn get_expiration(&self) -> u32 {
+        self.expiration
+    }
+    pub fn get_inception(&self) -> u32 {
+        self.inception
+    }
+    pub fn get_key_tag(&self) -> u16 {
+        self.key_tag
+    }
+    pub fn get_signer(&self) -> Name {
+        self.signer.clone()
+    }
+    pub fn get_signature(&self) -> &[u8] {
+        &self.signature
+    }
+}
+
+impl Rdata for Rrsig {
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        let rrsig = Rrsig {
+            type_covered: RRType::from(rdata.read_u16()?),
+            algorithm: rdata.read_u8()?,
+            labels: rdata.read_u8()?,
+            original_ttl: rdata.read_u32()?,
+            expiration: rdata.read_u32()?,
+            inception: rdata.read_u32()?,
+            key_tag: rdata.read_u16()?,
+            signer: Name::read_uncompressed(&mut rdata)?,
+            signature: rdata.read_rest().to_vec(),
+        };
+        Ok(rrsig)
+    }
+
+    fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
+        let size = self.size();
+        let mut buf = BytesMut::with_capacity(size);
+        buf.put_u16(try_into_rdata_length(size - 2)?);
+        buf.put_u16(self.type_covered.into());
+        buf.put_u8(self.algorithm);
+        buf.put_u8(self.labels);
+        buf.put_u32(self.original_ttl);
+        buf.put_u32(self.expiration);
+        buf.put_u32(self.inception);
+        buf.put_u16(self.key_tag);
+        buf.put_slice(&self.signer.as_bytes_uncompressed());
+        buf.put_slice(&self.signature);
+        Ok(buf)
+    }
+
+    fn size(&self) -> usize {
+        2 + 2 + 1 + 1 + 4 + 4 + 4 + 2 + self.signer.wire_len() + self.signature.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{BufMut, BytesMut};
+
+    use super::{Rdata, Rrsig};
+    use crate::protocol::{Name, RRType};
+
+    #[test]
+    fn test_round_trip() {
+        // host.example.com. RRSIG of RFC4034 section 3.3
+        let signature = base64::decode(
+            "oJB1W6WNGv+ldvQ3WDG0MQkg5IEhjRip8WTrPYGv07h108dUKGMeDPKijVCHX3DDKdfb+v6o\
+             B9wfuh3DTJXUAfI/M0zmO/zz8bW0Rznl8O3tGNazPwQKkRN20XPXV6nwwfoXmJQbsLNrLfkG\
+             J5D6fwFm8nN+6pBzeDQfsS3Ap3o=",
+        )
+        .unwrap();
+        let signer = Name::try_from("example.com").unwrap();
+        let mut target = BytesMut::new();
+        target.put_u16((18 + signer.len() + 1 + signature.len()) as u16);
+        target.put_u16(1);
+        target.put_u8(5);
+        target.put_u8(3);
+        target.put_u32(86400);
+        target.put_u32(1048354263);
+        target.put_u32(1045762263);
+        target.put_u16(2642);
+        target.put(signer.as_bytes_uncompressed());
+        target.put(&signature[..]);
+        let target = target.freeze();
+
+        let (rrsig, end) = Rrsig::parse(target.clone(), 0).unwrap();
+        assert_eq!(end, target.len());
+        let expected = Rrsig::new(
+            RRType::A,
+            5,
+            3,
+            86400,
+            1048354263,
+            1045762263,
+            2642,
+            signer,
+            signature,
+        );
+        assert_eq!(rrsig, expected);
+        assert_eq!(rrsig.size(), target.len());
+        assert_eq!(rrsig.try_into_bytes().unwrap().freeze(), target);
+
+        // the signer is compressed
+        let mut compressed = BytesMut::from(&b"\x07example\x03com\x00"[..]);
+        compressed.put_u16(18 + 2 + 4);
+        compressed.put(&target[2..20]);
+        compressed.put(&b"\xc0\x00\xde\xad\xbe\xef"[..]);
+        assert!(Rrsig::parse(compressed.freeze(), 13).is_err());
+    }
+}
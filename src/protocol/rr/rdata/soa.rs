@@ -4,12 +4,17 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::fmt::Display;
+
+#[cfg(test)]
+use bytes::Bytes;
+use bytes::{BufMut, BytesMut};
 
 use super::{try_into_rdata_length, Rdata};
-use crate::protocol::{domain::Name, error::PacketError};
+use crate::protocol::{domain::Name, error::PacketError, PacketReader};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Soa {
     mname: Name,
     rname: Name,
@@ -20,50 +25,73 @@ pub struct Soa {
     minimum: u32,
 }
 
-impl Rdata for Soa {
-    fn parse(packet: Bytes, pos: usize) -> Result<(Self, usize), PacketError> {
-        let packet_len = packet.len();
-        if pos + (2 + 2 * 2 + 4 * 5) > packet_len {
-            return Err(PacketError::FormatError);
-        }
-
-        let mut pos = pos;
-        let mut p = packet.clone();
-        p.advance(pos);
-
-        let length = p.get_u16() as usize;
-        pos += 2;
-        let (mname, m_end) = Name::parse(packet.clone(), pos)?;
-        let (rname, r_end) = Name::parse(packet, m_end)?;
-
-        p.advance(r_end - pos);
-        if r_end + 20 > packet_len {
-            return Err(PacketError::FormatError);
-        }
-
-        let serial = p.get_u32();
-        let refresh = p.get_u32();
-        let retry = p.get_u32();
-        let expires = p.get_u32();
-        let minimum = p.get_u32();
+impl Soa {
+    /// the primary name server of the zone
+    pub fn mname(&self) -> &Name {
+        &self.mname
+    }
 
-        let soa = Soa {
-            mname,
-            rname,
-            serial,
-            refresh,
-            retry,
-            expires,
-            minimum,
-        };
+    /// the mailbox of the person responsible for the zone, its first label is the user
+    pub fn rname(&self) -> &Name {
+        &self.rname
+    }
+
+    pub fn serial(&self) -> u32 {
+        self.serial
+    }
+
+    /// seconds before secondaries check the serial again
+    pub fn refresh(&self) -> u32 {
+        self.refresh
+    }
+
+    /// seconds before secondaries retry a failed refresh
+    pub fn retry(&self) -> u32 {
+        self.retry
+    }
+
+    /// seconds after which secondaries stop answering without a refresh
+    pub fn expires(&self) -> u32 {
+        self.expires
+    }
 
-        let end = r_end + 20;
+    /// TTL for caching negative answers from the zone, see [RFC2308](https://datatracker.ietf.org/doc/html/rfc2308#section-4)
+    pub fn minimum(&self) -> u32 {
+        self.minimum
+    }
+}
 
-        if end - pos != length {
-            Err(PacketError::FormatError)
-        } else {
-            Ok((soa, end))
-        }
+/// fields in the order of master files, see [RFC1035](https://datatracker.ietf.org/doc/html/rfc1035#section-5.1)
+impl Display for Soa {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} {} {} {}",
+            self.mname,
+            self.rname,
+            self.serial,
+            self.refresh,
+            self.retry,
+            self.expires,
+            self.minimum
+        )
+    }
+}
+
+impl Rdata for Soa {
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        let soa = Soa {
+            mname: Name::read(&mut rdata)?,
+            rname: Name::read(&mut rdata)?,
+            serial: rdata.read_u32()?,
+            refresh: rdata.read_u32()?,
+            retry: rdata.read_u32()?,
+            expires: rdata.read_u32()?,
+            minimum: rdata.read_u32()?,
+        };
+        rdata.finish()?;
+        Ok(soa)
     }
 
     fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
@@ -82,6 +110,10 @@ impl Rdata for Soa {
         buf.put_u32(self.minimum);
         Ok(buf)
     }
+
+    fn size(&self) -> usize {
+        2 + self.mname.wire_len() + self.rname.wire_len() + 4 * 5
+    }
 }
 
 #[test]
@@ -139,3 +171,27 @@ fn test_parse_and_to_bytes() {
     let bytes = bytes.unwrap();
     assert_eq!(bytes[..], buf[..]);
 }
+
+#[test]
+fn test_getters() {
+    let soa = Soa {
+        mname: Name::try_from("ns.example.com").unwrap(),
+        rname: Name::try_from("admin.example.com").unwrap(),
+        serial: 2022010101,
+        refresh: 3600,
+        retry: 600,
+        expires: 86400,
+        minimum: 300,
+    };
+    assert_eq!(soa.mname(), &Name::try_from("ns.example.com").unwrap());
+    assert_eq!(soa.rname(), &Name::try_from("admin.example.com").unwrap());
+    assert_eq!(soa.serial(), 2022010101);
+    assert_eq!(soa.refresh(), 3600);
+    assert_eq!(soa.retry(), 600);
+    assert_eq!(soa.expires(), 86400);
+    assert_eq!(soa.minimum(), 300);
+    assert_eq!(
+        soa.to_string(),
+        "ns.example.com. admin.example.com. 2022010101 3600 600 86400 300"
+    );
+}
@@ -0,0 +1,236 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use bytes::{BufMut, BytesMut};
+
+use super::{try_into_rdata_length, Name, Rdata};
+use crate::protocol::{error::PacketError, PacketReader};
+
+const KEY_ALPN: u16 = 1;
+const KEY_IPV4HINT: u16 = 4;
+const KEY_IPV6HINT: u16 = 6;
+
+/// `HTTPS` records share the format of `SVCB` records
+pub type Https = Svcb;
+
+/// ## `Svcb`
+/// Service Binding, see [RFC9460](https://datatracker.ietf.org/doc/html/rfc9460#section-2.2)
+///
+/// SvcParams are kept in strictly ascending order of their keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Svcb {
+    priority: u16,
+    target: Name,
+    #[cfg_attr(feature = "serde", serde(with = "super::encoding::options"))]
+    params: Vec<(u16, Vec<u8>)>,
+}
+
+impl Svcb {
+    pub fn new(priority: u16, target: Name) -> Self {
+        Self {
+            priority,
+            target,
+            params: vec![],
+        }
+    }
+
+    /// set SvcParam `key` to `value`, replacing the former one
+    pub fn with_param(mut self, key: u16, value: Vec<u8>) -> Self {
+        match self.params.binary_search_by_key(&key, |(k, _)| *k) {
+            Ok(i) => self.params[i].1 = value,
+            Err(i) => self.params.insert(i, (key, value)),
+        }
+        self
+    }
+
+    pub fn get_priority(&self) -> u16 {
+        self.priority
+    }
+    pub fn get_target(&self) -> Name {
+        self.target.clone()
+    }
+    pub fn get_params(&self) -> &[(u16, Vec<u8>)] {
+        &self.params
+    }
+    pub fn get_param(&self, key: u16) -> Option<&[u8]> {
+        self.params
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    /// protocol IDs in the `alpn` param, none if it is absent or malformed
+    pub fn alpn(&self) -> Option<Vec<Vec<u8>>> {
+        let mut value = self.get_param(KEY_ALPN)?;
+        let mut ids = vec![];
+        while let Some((&len, rest)) = value.split_first() {
+            let len = len as usize;
+            if len == 0 || len > rest.len() {
+                return None;
+            }
+            ids.push(rest[..len].to_vec());
+            value = &rest[len..];
+        }
+        Some(ids)
+    }
+
+    /// addresses in the `ipv4hint` param, none if it is absent or malformed
+    pub fn ipv4hint(&self) -> Option<Vec<Ipv4Addr>> {
+        let value = self.get_param(KEY_IPV4HINT)?;
+        if value.len() % 4 != 0 {
+            return None;
+        }
+        let hints = value
+            .chunks(4)
+            .map(|octets| Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+            .collect();
+        Some(hints)
+    }
+
+    /// addresses in the `ipv6hint` param, none if it is absent or malformed
+    pub fn ipv6hint(&self) -> Option<Vec<Ipv6Addr>> {
+        let value = self.get_param(KEY_IPV6HINT)?;
+        if value.len() % 16 != 0 {
+            return None;
+        }
+        let hints = value
+            .chunks(16)
+            .map(|octets| Ipv6Addr::from(<[u8; 16]>::try_from(octets).unwrap()))
+            .collect();
+        Some(hints)
+    }
+}
+
+impl Rdata for Svcb {
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        let priority = rdata.read_u16()?;
+        let target = Name::read(&mut rdata)?;
+
+        let mut params: Vec<(u16, Vec<u8>)> = vec![];
+        while rdata.remaining() > 0 {
+            let key = rdata.read_u16()?;
+            let len = rdata.read_u16()? as usize;
+            // keys must strictly ascend, which rules out duplicates
+            if params.last().is_some_and(|(last, _)| *last >= key) {
+                return Err(PacketError::FormatError);
+            }
+            params.push((key, rdata.read_slice(len)?.to_vec()));
+        }
+
+        let svcb = Svcb {
+            priority,
+            target,
+            params,
+        };
+        Ok(svcb)
+    }
+
+    fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
+        let size = self.size();
+        let rdlength = try_into_rdata_length(size - 2)?;
+        let mut buf = BytesMut::with_capacity(size);
+        buf.put_u16(rdlength);
+        buf.put_u16(self.priority);
+        // the target is never compressed
+        buf.put_slice(&self.target.as_bytes_uncompressed());
+        for (key, value) in self.params.iter() {
+            buf.put_u16(*key);
+            buf.put_u16(try_into_rdata_length(value.len())?);
+            buf.put_slice(value);
+        }
+        Ok(buf)
+    }
+
+    fn size(&self) -> usize {
+        let params: usize = self.params.iter().map(|(_, v)| 2 + 2 + v.len()).sum();
+        2 + 2 + self.target.wire_len() + params
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    use bytes::{BufMut, Bytes, BytesMut};
+
+    use super::{Https, Rdata, Svcb};
+    use crate::protocol::{Name, PacketError};
+
+    /// `1 . alpn="h3,h2" ipv4hint=192.0.2.1 ipv6hint=2001:db8::1`
+    fn https_rdata() -> Bytes {
+        let mut buf = BytesMut::new();
+        buf.put_u16(2 + 1 + (4 + 6) + (4 + 4) + (4 + 16));
+        buf.put_u16(1);
+        buf.put_u8(0);
+        buf.put(&b"\x00\x01\x00\x06\x02h3\x02h2"[..]);
+        buf.put(&b"\x00\x04\x00\x04"[..]);
+        buf.put(&Ipv4Addr::new(192, 0, 2, 1).octets()[..]);
+        buf.put(&b"\x00\x06\x00\x10"[..]);
+        buf.put(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets()[..]);
+        buf.freeze()
+    }
+
+    #[test]
+    fn test_parse() {
+        let target = https_rdata();
+        let (https, end) = Https::parse(target.clone(), 0).unwrap();
+        assert_eq!(end, target.len());
+        assert_eq!(https.get_priority(), 1);
+        assert_eq!(https.get_target(), Name::try_from(".").unwrap());
+        assert_eq!(https.alpn(), Some(vec![b"h3".to_vec(), b"h2".to_vec()]));
+        assert_eq!(https.ipv4hint(), Some(vec![Ipv4Addr::new(192, 0, 2, 1)]));
+        assert_eq!(
+            https.ipv6hint(),
+            Some(vec!["2001:db8::1".parse::<Ipv6Addr>().unwrap()])
+        );
+
+        // descending and duplicated keys
+        for keys in [[4, 1], [1, 1]] {
+            let mut buf = BytesMut::new();
+            buf.put_u16(2 + 1 + (4 + 1) * 2);
+            buf.put_u16(1);
+            buf.put_u8(0);
+            for key in keys {
+                buf.put_u16(key);
+                buf.put_u16(1);
+                buf.put_u8(0);
+            }
+            let parsed = Svcb::parse(buf.freeze(), 0);
+            assert!(
+                matches!(parsed, Err(PacketError::FormatError)),
+                "{:?}",
+                keys
+            );
+        }
+
+        // param overflowing RDLENGTH
+        let mut invalid = BytesMut::from(&target[..]);
+        invalid[1] -= 1;
+        assert!(Svcb::parse(invalid.freeze(), 0).is_err());
+    }
+
+    #[test]
+    fn test_to_bytes() {
+        let https = Https::new(1, Name::try_from(".").unwrap())
+            .with_param(
+                6,
+                "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec(),
+            )
+            .with_param(1, b"\x02h3\x02h2".to_vec())
+            .with_param(4, vec![192, 0, 2, 1]);
+        let bytes = https.try_into_bytes().unwrap();
+        assert_eq!(bytes.len(), https.size());
+        assert_eq!(bytes.freeze(), https_rdata());
+
+        let alias = Svcb::new(0, Name::try_from("svc.example.net").unwrap());
+        let bytes = alias.try_into_bytes().unwrap().freeze();
+        assert_eq!(Svcb::parse(bytes, 0).unwrap().0, alias);
+    }
+}
@@ -1,39 +1,30 @@
-use std::fmt::{Debug, Display};
+use std::fmt::Display;
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+#[cfg(test)]
+use bytes::Bytes;
+use bytes::{BufMut, BytesMut};
 
-use crate::protocol::{rr::rdata::Rdata, PacketError};
+use crate::protocol::{
+    rr::rdata::{fmt_character_string, Rdata},
+    PacketError, PacketReader,
+};
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Txt {
+    #[cfg_attr(feature = "serde", serde(with = "super::encoding::character_strings"))]
     text: Vec<Vec<u8>>,
 }
 
 impl Rdata for Txt {
-    fn parse(packet: Bytes, pos: usize) -> Result<(Self, usize), PacketError> {
-        if pos + 2 > packet.len() {
-            return Err(PacketError::FormatError);
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        // character-strings must not run past RDATA, empty ones are kept as they are
+        let mut text = vec![];
+        while rdata.remaining() > 0 {
+            text.push(rdata.read_character_string()?.to_vec());
         }
-
-        let mut data = packet.clone();
-        data.advance(pos);
-        let len = data.get_u16() as usize;
-        if pos + 2 + len > packet.len() {
-            return Err(PacketError::FormatError);
-        }
-        let end = pos + 2 + len;
-
-        let mut v = vec![];
-        let mut read = 0;
-        while read < len {
-            let m_len = data.get_u8() as usize;
-            read += m_len + 1;
-
-            let txt = Vec::from(&data[..m_len]);
-            data.advance(m_len);
-            v.push(txt);
-        }
-        Ok((Self { text: v }, end))
+        Ok(Self { text })
     }
 
     fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
@@ -42,14 +33,17 @@ impl Rdata for Txt {
         let rdlen = u16::try_from(total_len).map_err(|_| PacketError::FormatError)?;
         buf.put_u16(rdlen);
         for txt in self.text.iter() {
-            let mut sub_buf = BytesMut::new();
-            let len = txt.len() as u8;
-            sub_buf.put_u8(len);
-            sub_buf.put(txt.as_slice());
-            buf.put(sub_buf);
+            // a character-string is at most 255 bytes long
+            let len = u8::try_from(txt.len()).map_err(|_| PacketError::FormatError)?;
+            buf.put_u8(len);
+            buf.put(txt.as_slice());
         }
         Ok(buf)
     }
+
+    fn size(&self) -> usize {
+        2 + self.text.iter().map(|t| t.len() + 1).sum::<usize>()
+    }
 }
 
 impl From<String> for Txt {
@@ -79,14 +73,16 @@ impl TryFrom<Txt> for String {
     }
 }
 
+/// the character-strings quoted, separated by spaces
 impl Display for Txt {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let s = match String::try_from(self.clone()) {
-            Ok(s) => s,
-            Err(_) => return self.text.fmt(f),
-        };
-
-        write!(f, "{}", s)
+        for (i, text) in self.text.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            fmt_character_string(f, text)?;
+        }
+        Ok(())
     }
 }
 
@@ -105,6 +101,26 @@ fn test_parse() {
     assert_eq!(end, 9);
 }
 
+#[test]
+fn test_empty_string() {
+    let rdata = Bytes::from(vec![0_u8, 1, 0]);
+    let (txt, end) = Txt::parse(rdata.clone(), 0).unwrap();
+    assert_eq!(txt.text, vec![Vec::<u8>::new()]);
+    assert_eq!(end, 3);
+    assert_eq!(txt.size(), 3);
+    assert_eq!(txt.try_into_bytes().unwrap().as_ref(), rdata.as_ref());
+
+    // empty strings between others are kept in place
+    let rdata = Bytes::from(vec![0_u8, 5, 1, b'a', 0, 1, b'b']);
+    let (txt, _) = Txt::parse(rdata.clone(), 0).unwrap();
+    assert_eq!(txt.text, vec![b"a".to_vec(), vec![], b"b".to_vec()]);
+    assert_eq!(txt.try_into_bytes().unwrap().as_ref(), rdata.as_ref());
+
+    // the last string runs past RDATA into the next record
+    let overrun = Bytes::from(vec![0_u8, 2, 0, 1, b'x']);
+    assert!(Txt::parse(overrun, 0).is_err());
+}
+
 #[test]
 fn test_to_bytes() {
     let s = String::from("114514");
@@ -113,3 +129,30 @@ fn test_to_bytes() {
     let rdata = [0_u8, 7, 6, b'1', b'1', b'4', b'5', b'1', b'4'];
     assert_eq!(&rdata, b.as_ref());
 }
+
+#[test]
+fn test_multiple_strings() {
+    let rdata = Bytes::from(b"\x00\x0d\x05hello\x05world\x00".to_vec());
+    let (txt, end) = Txt::parse(rdata.clone(), 0).unwrap();
+    assert_eq!(txt.text, vec![b"hello".to_vec(), b"world".to_vec(), vec![]]);
+    assert_eq!(end, rdata.len());
+    assert_eq!(txt.try_into_bytes().unwrap().as_ref(), rdata.as_ref());
+
+    // a string claiming more than left in RDATA, though the packet goes on
+    let overrun = Bytes::from(b"\x00\x07\x05hello\x09world\x00\x00\x00".to_vec());
+    assert!(Txt::parse(overrun, 0).is_err());
+    // a length byte at the end of RDATA without its string
+    let overrun = Bytes::from(b"\x00\x07\x05hello\x03abc".to_vec());
+    assert!(Txt::parse(overrun, 0).is_err());
+}
+
+#[test]
+fn test_display() {
+    let rdata = Bytes::from(b"\x00\x0d\x05hello\x05\"a\\b\"\x00".to_vec());
+    let (txt, _) = Txt::parse(rdata, 0).unwrap();
+    assert_eq!(txt.to_string(), r#""hello" "\"a\\b\"" """#);
+
+    let rdata = Bytes::from(b"\x00\x03\x02\x07\xff".to_vec());
+    let (txt, _) = Txt::parse(rdata, 0).unwrap();
+    assert_eq!(txt.to_string(), r#""\007\255""#);
+}
@@ -4,16 +4,18 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 
-use super::Rdata;
-use crate::protocol::{error::PacketError, rr::RRType};
+use super::{try_into_rdata_length, Rdata};
+use crate::protocol::{error::PacketError, rr::RRType, PacketReader};
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Unknown {
     rtype: RRType,
-    length: usize,
-    data: Bytes, // TODO: data maybe empty, fix it
+    /// RDATA as it is, empty if RDLENGTH is zero
+    #[cfg_attr(feature = "serde", serde(with = "super::encoding::base64_bytes"))]
+    data: Bytes,
 }
 
 impl Unknown {
@@ -22,44 +24,43 @@ impl Unknown {
     }
 
     pub fn set_type(&mut self, rtype: u16) {
-        self.rtype = RRType::UNKNOWN(rtype);
+        self.rtype = RRType::from(rtype);
     }
 
+    /// parse RDATA of an unknown type, which is left as 255
     pub fn parse_typeless(packet: Bytes, pos: usize) -> Result<(Self, usize), PacketError>
     where
         Self: Sized,
     {
-        let mut p = packet;
-        p.advance(pos);
-        let length = p.get_u16() as usize;
-        let data = Bytes::copy_from_slice(&p[..length]);
-        let unknown = Self {
-            length,
-            rtype: RRType::UNKNOWN(255), // always set as 255
-            data,
-        };
-        let end = pos + 2 + length;
-        Ok((unknown, end))
+        let mut reader = PacketReader::new(&packet, pos);
+        let unknown = Self::read(&mut reader)?;
+        Ok((unknown, reader.pos()))
     }
 }
 
 impl Rdata for Unknown {
+    /// read RDATA of an unknown type, which is left as 255 until set by `set_type`
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        let data = rdata.read_bytes(rdata.remaining())?;
+        Ok(Self {
+            rtype: RRType::UNKNOWN(255), // always set as 255
+            data,
+        })
+    }
+
     /// Warning: will look backward to other fields in RR.
     /// use only when parsing at least a whole RR.
     fn parse(packet: Bytes, pos: usize) -> Result<(Self, usize), PacketError>
     where
         Self: Sized,
     {
-        let packet_len = packet.len();
-        if pos < 8 || pos > packet_len {
+        if pos < 8 || pos > packet.len() {
             return Err(PacketError::FormatError);
         }
 
         // Get type of unknown
-        let type_pos = pos - 8;
-        let mut p = packet.clone();
-        p.advance(type_pos);
-        let tp = p.get_u16();
+        let tp = PacketReader::new(&packet, pos - 8).read_u16()?;
 
         // Parse remaining parts of the packet
         let (mut unknown, end) = Unknown::parse_typeless(packet, pos)?;
@@ -68,23 +69,22 @@ impl Rdata for Unknown {
     }
 
     fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
-        let mut buf = BytesMut::with_capacity(self.length + 2);
-        buf.put_u16(self.length as u16);
+        let mut buf = BytesMut::with_capacity(self.data.len() + 2);
+        buf.put_u16(try_into_rdata_length(self.data.len())?);
         buf.put_slice(&self.data);
         Ok(buf)
     }
+
+    fn size(&self) -> usize {
+        2 + self.data.len()
+    }
 }
 
 #[test]
 fn test_set_rtype() {
     let rtype = RRType::UNKNOWN(233);
-    let length = 0;
     let data = Bytes::new();
-    let mut u = Unknown {
-        rtype,
-        length,
-        data,
-    };
+    let mut u = Unknown { rtype, data };
     assert_eq!(u.get_type(), rtype);
 
     let rtype = 114;
@@ -122,3 +122,20 @@ fn test_parse_and_to_bytes() {
     assert_eq!(unknown.get_type(), RRType::from(233));
     assert_eq!(unknown.try_into_bytes().unwrap()[..], data[..]);
 }
+
+#[test]
+fn test_truncated() {
+    // RDLENGTH claims more than left in the buffer
+    let truncated = Bytes::from([0_u8, 8, 0, 0, 2, 0].to_vec());
+    assert!(Unknown::parse_typeless(truncated, 0).is_err());
+    // no room for RDLENGTH
+    assert!(Unknown::parse_typeless(Bytes::from([0_u8].to_vec()), 0).is_err());
+    assert!(Unknown::parse_typeless(Bytes::new(), 0).is_err());
+
+    // empty RDATA is fine
+    let empty = Bytes::from([0_u8, 0].to_vec());
+    let (unknown, end) = Unknown::parse_typeless(empty.clone(), 0).unwrap();
+    assert_eq!(end, 2);
+    assert_eq!(unknown.size(), 2);
+    assert_eq!(unknown.try_into_bytes().unwrap()[..], empty[..]);
+}
@@ -0,0 +1,107 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::fmt::Display;
+
+use bytes::{BufMut, BytesMut};
+
+use super::{fmt_character_string, try_into_rdata_length, Rdata};
+use crate::protocol::{error::PacketError, PacketReader};
+
+/// ## `Uri`
+/// Uniform Resource Identifier, see [RFC7553](https://datatracker.ietf.org/doc/html/rfc7553#section-4.5)
+///
+/// The target fills the rest of RDATA, without a length prefix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Uri {
+    priority: u16,
+    weight: u16,
+    #[cfg_attr(feature = "serde", serde(with = "super::encoding::character_string"))]
+    target: Vec<u8>,
+}
+
+impl Uri {
+    pub fn new(priority: u16, weight: u16, target: Vec<u8>) -> Self {
+        Self {
+            priority,
+            weight,
+            target,
+        }
+    }
+    pub fn get_priority(&self) -> u16 {
+        self.priority
+    }
+    pub fn get_weight(&self) -> u16 {
+        self.weight
+    }
+    pub fn get_target(&self) -> &[u8] {
+        &self.target
+    }
+}
+
+/// the target is quoted, though it is no character-string in packets
+impl Display for Uri {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} ", self.priority, self.weight)?;
+        fmt_character_string(f, &self.target)
+    }
+}
+
+impl Rdata for Uri {
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        let uri = Uri {
+            priority: rdata.read_u16()?,
+            weight: rdata.read_u16()?,
+            target: rdata.read_rest().to_vec(),
+        };
+        Ok(uri)
+    }
+
+    fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
+        let size = self.size();
+        let mut buf = BytesMut::with_capacity(size);
+        buf.put_u16(try_into_rdata_length(size - 2)?);
+        buf.put_u16(self.priority);
+        buf.put_u16(self.weight);
+        buf.put_slice(&self.target);
+        Ok(buf)
+    }
+
+    fn size(&self) -> usize {
+        2 + 2 + 2 + self.target.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bytes::{BufMut, BytesMut};
+
+    use super::{Rdata, Uri};
+
+    #[test]
+    fn test_round_trip() {
+        let target = b"https://example.com/api";
+        let mut rdata = BytesMut::new();
+        rdata.put_u16(4 + target.len() as u16);
+        rdata.put_u16(10);
+        rdata.put_u16(1);
+        rdata.put(&target[..]);
+        let rdata = rdata.freeze();
+
+        let (uri, end) = Uri::parse(rdata.clone(), 0).unwrap();
+        assert_eq!(end, rdata.len());
+        assert_eq!(uri, Uri::new(10, 1, target.to_vec()));
+        assert_eq!(uri.size(), rdata.len());
+        assert_eq!(uri.try_into_bytes().unwrap().freeze(), rdata);
+
+        // shorter than priority and weight
+        assert!(Uri::parse((&b"\x00\x03\x00\x0a\x00"[..]).into(), 0).is_err());
+        // overflowing the packet
+        assert!(Uri::parse(rdata.slice(..10), 0).is_err());
+    }
+}
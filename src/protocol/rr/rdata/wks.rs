@@ -1,44 +1,35 @@
-use bytes::{Buf, BufMut, Bytes, BytesMut};
+#[cfg(test)]
+use bytes::Bytes;
+use bytes::{BufMut, BytesMut};
 
-use crate::protocol::{rr::rdata::Rdata, PacketError};
+use crate::protocol::{
+    rr::rdata::{try_into_rdata_length, Rdata},
+    PacketError, PacketReader,
+};
 
-#[derive(Clone, Debug)]
+#[derive(PartialEq, Eq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Wks {
+    #[cfg_attr(feature = "serde", serde(with = "super::encoding::ipv4"))]
     addr: u32,
     proto: u8,
+    #[cfg_attr(feature = "serde", serde(with = "super::encoding::base64_bytes"))]
     bmp: Vec<u8>,
 }
 
 impl Rdata for Wks {
-    fn parse(packet: Bytes, pos: usize) -> Result<(Self, usize), PacketError>
-    where
-        Self: Sized,
-    {
-        if pos + 7 > packet.len() {
-            return Err(PacketError::FormatError);
-        }
-
-        let mut pos = pos;
-        let mut p = packet;
-        if pos + 2 >= p.len() {
-            return Err(PacketError::FormatError);
-        }
-        p.advance(pos);
-        pos += 2;
-        let mut rdata_length = p.get_u16() as usize;
-        let end = rdata_length + pos;
-
-        let addr = p.get_u32();
-        let proto = p.get_u8();
-        rdata_length -= 5;
-        let bmp = Vec::from(&p[..rdata_length]);
-
-        let wks = Wks { addr, proto, bmp };
-        Ok((wks, end))
+    fn read(reader: &mut PacketReader) -> Result<Self, PacketError> {
+        let mut rdata = reader.rdata()?;
+        let addr = rdata.read_u32()?;
+        let proto = rdata.read_u8()?;
+        let bmp = rdata.read_rest().to_vec();
+        Ok(Wks { addr, proto, bmp })
     }
 
     fn try_into_bytes(&self) -> Result<BytesMut, PacketError> {
-        let mut buf = BytesMut::new();
+        let rdlength = try_into_rdata_length(5 + self.bmp.len())?;
+        let mut buf = BytesMut::with_capacity(2 + rdlength as usize);
+        buf.put_u16(rdlength); // write RDLENGTH
         buf.put_u32(self.addr);
         buf.put_u8(self.proto);
         buf.put(&self.bmp.clone()[..]);
@@ -52,3 +43,45 @@ fn test_parse() {
     let parsed = Wks::parse(invalid, 0);
     assert!(parsed.is_err());
 }
+
+#[test]
+fn test_to_bytes() {
+    let wks = Wks {
+        addr: 0xc000_0201,
+        proto: 6,
+        bmp: vec![0b0100_0000, 0b0000_0100],
+    };
+    let rdata = b"\x00\x07\xc0\x00\x02\x01\x06\x40\x04";
+    let bytes = wks.try_into_bytes().unwrap();
+    assert_eq!(bytes[..], rdata[..]);
+    assert_eq!(wks.size(), rdata.len());
+}
+
+#[test]
+fn test_round_trip() {
+    use std::time::Duration;
+
+    use crate::protocol::{Name, PacketContent, RRClass, RRData, RR};
+
+    let rdata = Bytes::from(b"\x00\x07\xc0\x00\x02\x01\x06\x40\x04".to_vec());
+    let (wks, end) = Wks::parse(rdata.clone(), 0).unwrap();
+    assert_eq!(end, rdata.len());
+    assert_eq!(wks.try_into_bytes().unwrap().freeze(), rdata);
+
+    // a whole record, parsed again from its own bytes
+    let name = Name::try_from("example.com").unwrap();
+    let rr = RR::new(
+        name,
+        Duration::from_secs(60),
+        RRClass::Internet,
+        RRData::Wks(wks.clone()),
+    );
+    let bytes = rr.clone().into_bytes().unwrap().freeze();
+    let parsed = RR::parse(bytes.clone(), 0).unwrap();
+    assert_eq!(parsed.get_domain(), rr.get_domain());
+    match parsed.clone().into_rdata() {
+        RRData::Wks(parsed) => assert_eq!(parsed, wks),
+        _ => panic!("not a WKS record: {:?}", parsed),
+    }
+    assert_eq!(parsed.into_bytes().unwrap().freeze(), bytes);
+}
@@ -0,0 +1,233 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    io,
+    net::IpAddr,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::json;
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+};
+
+use crate::{
+    metrics::Transport,
+    protocol::{Question, Rcode, BADVERS},
+};
+
+/// records waiting for the writer at most, more are dropped rather than waited for
+const QUEUE: usize = 4096;
+
+/// ## `QueryRecord`
+/// A query answered, as it goes into the query log.
+#[derive(Debug, Clone)]
+pub struct QueryRecord {
+    /// when the response was sent
+    pub time: SystemTime,
+    pub client: IpAddr,
+    pub transport: Transport,
+    /// the question asked, if the query came with one
+    pub question: Option<Question>,
+    /// the 12-bit RCODE of the response
+    pub rcode: u16,
+    /// records in the answer section of the response
+    pub answers: u16,
+    /// time from the arrival of the query to its response
+    pub elapsed: Duration,
+}
+
+impl QueryRecord {
+    /// the record as a JSON object on a single line, without the line feed.
+    ///
+    /// The time is in seconds since the Unix epoch and the latency in milliseconds,
+    /// name, type and class are null for queries without a question.
+    pub fn to_json(&self) -> String {
+        let time = self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let rcode = match self.rcode {
+            BADVERS => String::from("BADVERS"),
+            rcode if rcode > 0xf => format!("RCODE{}", rcode),
+            rcode => Rcode::from(rcode as u8).to_string(),
+        };
+        let q = self.question.as_ref();
+        json!({
+            "time": time.as_secs_f64(),
+            "client": self.client.to_string(),
+            "transport": self.transport.name(),
+            "name": q.map(|q| q.get_name().to_string()),
+            "type": q.map(|q| q.get_type().to_string()),
+            "class": q.map(|q| q.get_class().to_string()),
+            "rcode": rcode,
+            "answers": self.answers,
+            "latency_ms": self.elapsed.as_secs_f64() * 1000.0,
+        })
+        .to_string()
+    }
+}
+
+/// ## `QueryLogger`
+/// Where the queries answered are logged, apart from tracing.
+///
+/// Logging is called as responses are sent, so it must not block or wait.
+pub trait QueryLogger: Send + Sync {
+    fn log(&self, record: QueryRecord);
+}
+
+/// ## `JsonLinesLogger`
+/// Writes the query log as JSON lines, one record per line, see `QueryRecord::to_json`.
+///
+/// Records are queued for a writer task of their own and buffered,
+/// the buffer is flushed whenever the queue runs dry.
+/// Records coming while the queue is full are dropped.
+#[derive(Debug, Clone)]
+pub struct JsonLinesLogger {
+    records: mpsc::Sender<QueryRecord>,
+}
+
+impl JsonLinesLogger {
+    /// append the log to the file at `path`, created if missing.
+    ///
+    /// Returns the writer task along, see `spawn`.
+    pub async fn open<P: AsRef<Path>>(path: P) -> io::Result<(Self, JoinHandle<io::Result<()>>)> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await?;
+        Ok(Self::spawn(file))
+    }
+
+    /// write the log to `writer` in a task, which returns once every clone of the logger
+    /// is dropped and the records queued are written, or once writing fails
+    pub fn spawn<W>(writer: W) -> (Self, JoinHandle<io::Result<()>>)
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (records, queued) = mpsc::channel(QUEUE);
+        let writing = tokio::spawn(write_lines(BufWriter::new(writer), queued));
+        (Self { records }, writing)
+    }
+}
+
+impl QueryLogger for JsonLinesLogger {
+    fn log(&self, record: QueryRecord) {
+        match self.records.try_send(record) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => tracing::warn!("query log is behind, a record dropped"),
+            Err(TrySendError::Closed(_)) => tracing::debug!("query log is closed"),
+        }
+    }
+}
+
+async fn write_lines<W>(
+    mut writer: BufWriter<W>,
+    mut queued: mpsc::Receiver<QueryRecord>,
+) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    while let Some(record) = queued.recv().await {
+        let mut record = Some(record);
+        while let Some(written) = record {
+            let line = written.to_json() + "\n";
+            if let Err(e) = writer.write_all(line.as_bytes()).await {
+                tracing::error!("cannot write the query log: {}", e);
+                return Err(e);
+            }
+            record = queued.try_recv().ok();
+        }
+        writer.flush().await?;
+    }
+    writer.flush().await
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::IpAddr,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
+
+    use serde_json::Value;
+
+    use super::{JsonLinesLogger, QueryLogger, QueryRecord};
+    use crate::{
+        metrics::Transport,
+        protocol::{Name, Question, RRClass, RRType},
+    };
+
+    fn record(question: Option<Question>, rcode: u16) -> QueryRecord {
+        QueryRecord {
+            time: UNIX_EPOCH + Duration::from_millis(1_700_000_000_250),
+            client: "2001:db8::1".parse().unwrap(),
+            transport: Transport::Tcp,
+            question,
+            rcode,
+            answers: 2,
+            elapsed: Duration::from_micros(1500),
+        }
+    }
+
+    #[test]
+    fn test_to_json() {
+        let name = Name::try_from("example.com").unwrap();
+        let q = Question::build(name, RRType::Aaaa, RRClass::Internet);
+        let line = record(Some(q), 3).to_json();
+        assert!(!line.contains('\n'));
+        let json: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(json["time"], 1_700_000_000.25);
+        assert_eq!(json["client"], "2001:db8::1");
+        assert_eq!(json["transport"], "tcp");
+        assert_eq!(json["name"], "example.com.");
+        assert_eq!(json["type"], "AAAA");
+        assert_eq!(json["class"], "IN");
+        assert_eq!(json["rcode"], "NXDOMAIN");
+        assert_eq!(json["answers"], 2);
+        assert_eq!(json["latency_ms"], 1.5);
+
+        // failures of queries without a question
+        let json: Value = serde_json::from_str(&record(None, 16).to_json()).unwrap();
+        assert_eq!(json["name"], Value::Null);
+        assert_eq!(json["rcode"], "BADVERS");
+    }
+
+    #[tokio::test]
+    async fn test_json_lines() {
+        let (writer, mut reader) = tokio::io::duplex(64 * 1024);
+        let (logger, writing) = JsonLinesLogger::spawn(writer);
+        let client: IpAddr = "192.0.2.1".parse().unwrap();
+        for answers in 0..3 {
+            logger.log(QueryRecord {
+                time: SystemTime::now(),
+                client,
+                answers,
+                ..record(None, 0)
+            });
+        }
+        // the records queued are written once the logger is dropped
+        drop(logger);
+        writing.await.unwrap().unwrap();
+
+        let mut log = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut reader, &mut log)
+            .await
+            .unwrap();
+        let lines: Vec<Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        for (answers, line) in lines.iter().enumerate() {
+            assert_eq!(line["answers"], answers);
+            assert_eq!(line["client"], "192.0.2.1");
+        }
+    }
+}
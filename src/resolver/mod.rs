@@ -0,0 +1,586 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_recursion::async_recursion;
+use rand::prelude::random;
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    sync::mpsc,
+    time::timeout,
+};
+
+use crate::{
+    comm::{stream::write_packet, Answer, Task},
+    protocol::{
+        Name, Packet, PacketError, Question, RRClass, RRData, RRType, Rcode, MAX_UDP_SIZE, RR,
+    },
+};
+
+/// addresses of the root servers `a` to `m`, see [root servers](https://www.iana.org/domains/root/servers)
+const ROOT_HINTS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+const DNS_PORT: u16 = 53;
+/// referrals followed at most to reach the zone of a name
+const MAX_REFERRALS: usize = 16;
+/// CNAME records followed at most for a query
+const MAX_CNAMES: usize = 8;
+/// nested lookups at most for addresses of name servers without glue
+const MAX_DEPTH: usize = 4;
+/// how long a name server is waited for, before asking the next one
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// ## `Resolver`
+/// Resolves queries iteratively instead of forwarding them, starting from the root servers
+/// and following the referrals down to the authoritative servers of the names.
+///
+/// Like the forwarders, it takes `Task`s from the cache and sends the `Answer`s back.
+pub struct Resolver {
+    rec: mpsc::UnboundedReceiver<Task>,
+    chase: Chase,
+}
+
+impl Resolver {
+    pub fn new(rec: mpsc::UnboundedReceiver<Task>) -> Self {
+        let roots = ROOT_HINTS
+            .iter()
+            .map(|ip| SocketAddr::new(IpAddr::V4(*ip), DNS_PORT))
+            .collect();
+        Self {
+            rec,
+            chase: Chase {
+                roots,
+                port: DNS_PORT,
+                servers: HashMap::new(),
+            },
+        }
+    }
+
+    /// start from `roots` instead of the built-in root hints
+    pub fn with_roots(mut self, roots: Vec<SocketAddr>) -> Self {
+        self.chase.roots = roots;
+        self
+    }
+
+    /// port of name servers found in referrals, which is 53 on the internet
+    pub fn with_port(mut self, port: u16) -> Self {
+        self.chase.port = port;
+        self
+    }
+
+    /// ask the name server found at `ip` in referrals on `server` instead,
+    /// like one behind a NAT
+    pub fn with_server(mut self, ip: IpAddr, server: SocketAddr) -> Self {
+        self.chase.servers.insert(ip, server);
+        self
+    }
+
+    pub async fn run(mut self) -> Result<()> {
+        tracing::info!("iterative resolver is running");
+        let chase = Arc::new(self.chase);
+        while let Some(Task::Query(query, ans_to, _)) = self.rec.recv().await {
+            let chase = chase.clone();
+            tokio::spawn(async move {
+                tokio::select! {
+                    answers = chase.resolve(query, 0) => {
+                        for ans in answers {
+                            let _ = ans_to.send(ans);
+                        }
+                    }
+                    // nobody is waiting for the answers
+                    _ = ans_to.closed() => {}
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+struct Chase {
+    roots: Vec<SocketAddr>,
+    port: u16,
+    servers: HashMap<IpAddr, SocketAddr>,
+}
+
+impl Chase {
+    /// where the name server at `ip` is asked
+    fn server(&self, ip: IpAddr) -> SocketAddr {
+        self.servers
+            .get(&ip)
+            .copied()
+            .unwrap_or_else(|| SocketAddr::new(ip, self.port))
+    }
+
+    /// answers to `query` in the order of a forwarded response,
+    /// the CNAME chain leads the answers.
+    #[async_recursion]
+    async fn resolve(&self, query: Question, depth: usize) -> Vec<Answer> {
+        let (ty, class) = (query.get_type(), query.get_class());
+        let mut name = query.get_name();
+        let mut chain: Vec<RR> = vec![];
+        loop {
+            let question = Question::build(name.clone(), ty, class);
+            let (zone, pkt) = match self.walk(&question, depth).await {
+                Ok(found) => found,
+                Err(error) => return vec![Answer::Error(error)],
+            };
+
+            // follow the chain within the response, as long as the zone is responsible for it
+            let mut followed = false;
+            loop {
+                let records: Vec<RR> = pkt
+                    .answers
+                    .iter()
+                    .filter(|rr| rr.get_type() == ty && rr.get_domain() == name)
+                    .cloned()
+                    .collect();
+                if !records.is_empty() {
+                    chain.extend(records);
+                    return chain.into_iter().map(Answer::Answer).collect();
+                }
+                let cname = pkt.answers.iter().find(|rr| {
+                    ty != RRType::Cname && rr.get_type() == RRType::Cname && rr.get_domain() == name
+                });
+                match cname {
+                    Some(_) if chain.len() >= MAX_CNAMES => {
+                        tracing::warn!("CNAME chain of {} is too long", query.get_name());
+                        return vec![Answer::Error(PacketError::ServFail)];
+                    }
+                    Some(rr) => {
+                        chain.push(rr.clone());
+                        if let RRData::Cname(target) = rr.clone().into_rdata() {
+                            name = Name::from(target);
+                        }
+                        followed = true;
+                        if !name.is_subdomain_of(&zone) {
+                            break;
+                        }
+                    }
+                    // the server did not follow the chain, ask again for the target
+                    None if followed && pkt.get_rcode() == Rcode::NoError => break,
+                    None => return negative(pkt, name, chain),
+                }
+            }
+        }
+    }
+
+    /// follow the referrals from the root to the zone of `query`,
+    /// returns the zone and the final response of its server.
+    async fn walk(&self, query: &Question, depth: usize) -> Result<(Name, Packet), PacketError> {
+        let name = query.get_name();
+        let mut zone = Name::try_from(".").unwrap();
+        let mut servers = self.roots.clone();
+        for _ in 0..MAX_REFERRALS {
+            let pkt = self.ask(&servers, query).await?;
+            if !pkt.answers.is_empty() || pkt.get_rcode() != Rcode::NoError {
+                return Ok((zone, pkt));
+            }
+            let (child, ns_names) = match referral(&pkt, &zone, &name) {
+                Some(referral) => referral,
+                // no data for the name
+                None => return Ok((zone, pkt)),
+            };
+            tracing::debug!("{} referred to {} by {}", name, child, zone);
+
+            servers = glue(&pkt, &zone, &ns_names)
+                .map(|ip| self.server(ip))
+                .collect();
+            if servers.is_empty() && depth < MAX_DEPTH {
+                for ns in ns_names.iter() {
+                    servers.extend(self.addresses(ns, depth + 1).await);
+                    if !servers.is_empty() {
+                        break;
+                    }
+                }
+            }
+            if servers.is_empty() {
+                tracing::warn!("no address of the name servers of {}", child);
+                return Err(PacketError::ServFail);
+            }
+            zone = child;
+        }
+        tracing::warn!("too many referrals resolving {}", name);
+        Err(PacketError::ServFail)
+    }
+
+    /// addresses of a name server referred to without glue
+    async fn addresses(&self, ns: &Name, depth: usize) -> Vec<SocketAddr> {
+        let query = Question::build(ns.clone(), RRType::A, RRClass::Internet);
+        self.resolve(query, depth)
+            .await
+            .into_iter()
+            .filter_map(|ans| match ans {
+                Answer::Answer(rr) => match rr.into_rdata() {
+                    RRData::A(a) => Some(self.server(IpAddr::V4(a.into()))),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// ask `servers` in turn, until one of them answers
+    async fn ask(&self, servers: &[SocketAddr], query: &Question) -> Result<Packet, PacketError> {
+        for server in servers {
+            match exchange(*server, query).await {
+                Ok(pkt) if matches!(pkt.get_rcode(), Rcode::NoError | Rcode::NameError) => {
+                    return Ok(pkt)
+                }
+                Ok(pkt) => tracing::debug!("{} failed with {:?}", server, pkt.get_rcode()),
+                Err(e) => tracing::debug!("cannot ask {}: {}", server, e),
+            }
+        }
+        Err(PacketError::ServFail)
+    }
+}
+
+/// the deeper zone of `name` delegated by the response of `zone` and its name servers
+fn referral(pkt: &Packet, zone: &Name, name: &Name) -> Option<(Name, Vec<Name>)> {
+    let child = pkt
+        .authorities
+        .iter()
+        .filter(|rr| rr.get_type() == RRType::Ns)
+        .map(RR::get_domain)
+        .find(|owner| {
+            owner.label_count() > zone.label_count()
+                && owner.is_subdomain_of(zone)
+                && name.is_subdomain_of(owner)
+        })?;
+    let ns_names = pkt
+        .authorities
+        .iter()
+        .filter(|rr| rr.get_domain() == child)
+        .filter_map(|rr| match rr.clone().into_rdata() {
+            RRData::Ns(ns) => Some(Name::from(ns)),
+            _ => None,
+        })
+        .collect();
+    Some((child, ns_names))
+}
+
+/// addresses of `ns_names` in the additional section, only trusted within `zone`
+fn glue<'a>(
+    pkt: &'a Packet,
+    zone: &'a Name,
+    ns_names: &'a [Name],
+) -> impl Iterator<Item = IpAddr> + 'a {
+    pkt.additions
+        .iter()
+        .filter(move |rr| {
+            let owner = rr.get_domain();
+            owner.is_subdomain_of(zone) && ns_names.contains(&owner)
+        })
+        .filter_map(|rr| match rr.clone().into_rdata() {
+            RRData::A(a) => Some(IpAddr::V4(a.into())),
+            RRData::Aaaa(aaaa) => Some(IpAddr::V6(aaaa.into())),
+            _ => None,
+        })
+}
+
+/// answers of a response without data for `name`, the SOA is kept for negative caching
+fn negative(pkt: Packet, name: Name, chain: Vec<RR>) -> Vec<Answer> {
+    let error = (pkt.get_rcode() == Rcode::NameError).then_some(PacketError::NameError(name));
+    error
+        .into_iter()
+        .map(Answer::Error)
+        .chain(chain.into_iter().map(Answer::Answer))
+        .chain(pkt.authorities.into_iter().map(Answer::NameServer))
+        .collect()
+}
+
+/// ask `server` for `query` over UDP, and again over TCP if the response is truncated
+async fn exchange(server: SocketAddr, query: &Question) -> io::Result<Packet> {
+    let id: u16 = random();
+    let mut packet = Packet::new_query(id, query.clone());
+    packet.header.set_rec_des(false);
+    let expected = |resp: &Packet| {
+        resp.get_id() == id
+            && !resp.is_query()
+            && resp.question().is_some_and(|q| {
+                q.get_name() == query.get_name() && q.get_type() == query.get_type()
+            })
+    };
+    let timed_out = |_| io::Error::new(io::ErrorKind::TimedOut, "name server timed out");
+
+    let local = match server {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+    let udp = UdpSocket::bind(local).await?;
+    udp.connect(server).await?;
+    udp.send(&packet.clone().into_bytes()).await?;
+    let resp = timeout(EXCHANGE_TIMEOUT, async {
+        let mut buf = [0_u8; MAX_UDP_SIZE];
+        loop {
+            let len = udp.recv(&mut buf).await?;
+            // spoofed or late responses are ignored
+            match Packet::parse_packet(buf[..len].to_vec().into(), 0) {
+                Ok(resp) if expected(&resp) => return Ok::<_, io::Error>(resp),
+                _ => continue,
+            }
+        }
+    })
+    .await
+    .map_err(timed_out)??;
+    if !resp.is_trunc() {
+        return Ok(resp);
+    }
+
+    tracing::debug!("truncated response from {}, retrying over tcp", server);
+    timeout(EXCHANGE_TIMEOUT, async {
+        let mut tcp = TcpStream::connect(server).await?;
+        write_packet(&mut tcp, packet).await?;
+        let resp = Packet::parse_stream(&mut tcp)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        if expected(&resp) {
+            Ok(resp)
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected response",
+            ))
+        }
+    })
+    .await
+    .map_err(timed_out)?
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{IpAddr, Ipv4Addr, SocketAddr},
+        time::Duration,
+    };
+
+    use tokio::{
+        net::{TcpListener, UdpSocket},
+        sync::mpsc,
+    };
+
+    use super::Resolver;
+    use crate::{
+        comm::{stream::write_packet, Answer, Task},
+        protocol::{Name, Packet, PacketError, Question, RRClass, RRData, RRType, RR},
+    };
+
+    fn name(name: &str) -> Name {
+        Name::try_from(name).unwrap()
+    }
+
+    fn record(owner: &str, data: RRData) -> RR {
+        RR::new(
+            name(owner),
+            Duration::from_secs(60),
+            RRClass::Internet,
+            data,
+        )
+    }
+
+    fn a(owner: &str, ip: [u8; 4]) -> RR {
+        record(owner, RRData::A(Ipv4Addr::from(ip).into()))
+    }
+
+    fn ns(owner: &str, server: &str) -> RR {
+        record(owner, RRData::Ns(name(server).into()))
+    }
+
+    fn cname(owner: &str, target: &str) -> RR {
+        record(owner, RRData::Cname(name(target).into()))
+    }
+
+    /// the root refers `com` to 192.0.2.53
+    fn root(id: u16, _: &Question) -> Packet {
+        let mut resp = Packet::new_plain_answer(id);
+        resp.add_authority(ns("com", "a.nic.com"));
+        resp.add_addition(a("a.nic.com", [192, 0, 2, 53])).unwrap();
+        resp
+    }
+
+    /// `com` refers `example.com` to 198.51.100.53, and `glueless.com` to `ns.example.com`
+    fn com(id: u16, q: &Question) -> Packet {
+        let mut resp = Packet::new_plain_answer(id);
+        if q.get_name().is_subdomain_of(&name("glueless.com")) {
+            resp.add_authority(ns("glueless.com", "ns.example.com"));
+        } else {
+            resp.add_authority(ns("example.com", "ns.example.com"));
+            resp.add_addition(a("ns.example.com", [198, 51, 100, 53]))
+                .unwrap();
+            // out of the zone of `com`, must not be trusted
+            resp.add_addition(a("ns.example.com.evil", [192, 0, 2, 66]))
+                .unwrap();
+        }
+        resp
+    }
+
+    /// authoritative server of `example.com` and `glueless.com`
+    fn example(id: u16, q: &Question) -> Packet {
+        let mut resp = Packet::new_plain_answer(id);
+        resp.header.set_authoritative(true);
+        match q.get_name().to_string().as_str() {
+            "www.example.com." => resp.add_answer(a("www.example.com", [192, 0, 2, 1])),
+            "big.example.com." => resp.add_answer(a("big.example.com", [192, 0, 2, 3])),
+            "ns.example.com." => resp.add_answer(a("ns.example.com", [198, 51, 100, 53])),
+            "alias.example.com." => {
+                resp.add_answer(cname("alias.example.com", "www.example.com"));
+                resp.add_answer(a("www.example.com", [192, 0, 2, 1]));
+            }
+            "cross.example.com." => resp.add_answer(cname("cross.example.com", "www.glueless.com")),
+            "www.glueless.com." => resp.add_answer(a("www.glueless.com", [192, 0, 2, 2])),
+            _ => return Packet::new_failure(id, PacketError::NameError(q.get_name())),
+        }
+        resp
+    }
+
+    type Zone = fn(u16, &Question) -> Packet;
+
+    fn respond(query: Packet, zone: Zone) -> Packet {
+        let q = query.question().unwrap().clone();
+        let mut resp = zone(query.get_id(), &q);
+        resp.set_question(q);
+        resp
+    }
+
+    /// a mock name server of `zone` on the loopback, `big.example.com` only fits into TCP
+    async fn name_server(zone: Zone) -> SocketAddr {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = udp.local_addr().unwrap();
+        let tcp = TcpListener::bind(addr).await.unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            while let Ok((len, peer)) = udp.recv_from(&mut buf).await {
+                let query = Packet::parse_packet(buf[..len].to_vec().into(), 0).unwrap();
+                assert!(!query.is_rec_des());
+                let mut resp = respond(query, zone);
+                if resp.question().unwrap().get_name() == name("big.example.com") {
+                    resp.set_answers(vec![]);
+                    resp.header.set_truncated(true);
+                }
+                udp.send_to(&resp.into_bytes(), peer).await.unwrap();
+            }
+        });
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = tcp.accept().await {
+                let query = Packet::parse_stream(&mut stream).await.unwrap();
+                write_packet(&mut stream, respond(query, zone))
+                    .await
+                    .unwrap();
+            }
+        });
+        addr
+    }
+
+    async fn lookup(tasks: &mpsc::UnboundedSender<Task>, query: &str) -> Vec<Answer> {
+        let query = Question::build(name(query), RRType::A, RRClass::Internet);
+        let (ans_to, mut ans_from) = mpsc::unbounded_channel();
+        tasks.send(Task::Query(query, ans_to, None)).unwrap();
+        let mut answers = vec![];
+        while let Some(ans) = ans_from.recv().await {
+            answers.push(ans);
+        }
+        answers
+    }
+
+    fn records(answers: &[Answer]) -> Vec<(String, RRType)> {
+        answers
+            .iter()
+            .map(|ans| match ans {
+                Answer::Answer(rr) => (rr.get_domain().to_string(), rr.get_type()),
+                ans => panic!("unexpected answer {:?}", ans),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_referrals() {
+        let root = name_server(root).await;
+        let com = name_server(com).await;
+        let example = name_server(example).await;
+
+        let (tasks, rec) = mpsc::unbounded_channel();
+        let resolver = Resolver::new(rec)
+            .with_roots(vec![root])
+            .with_server(IpAddr::from([192, 0, 2, 53]), com)
+            .with_server(IpAddr::from([198, 51, 100, 53]), example);
+        tokio::spawn(resolver.run());
+
+        let answers = lookup(&tasks, "www.example.com").await;
+        assert_eq!(records(&answers), [("www.example.com.".into(), RRType::A)]);
+        match &answers[0] {
+            Answer::Answer(rr) => assert!(matches!(
+                rr.clone().into_rdata(),
+                RRData::A(a) if Ipv4Addr::from(a) == Ipv4Addr::new(192, 0, 2, 1)
+            )),
+            _ => unreachable!(),
+        }
+
+        // CNAME chains within a zone and across zones, the latter delegated without glue
+        let answers = lookup(&tasks, "alias.example.com").await;
+        assert_eq!(
+            records(&answers),
+            [
+                ("alias.example.com.".into(), RRType::Cname),
+                ("www.example.com.".into(), RRType::A)
+            ]
+        );
+        let answers = lookup(&tasks, "cross.example.com").await;
+        assert_eq!(
+            records(&answers),
+            [
+                ("cross.example.com.".into(), RRType::Cname),
+                ("www.glueless.com.".into(), RRType::A)
+            ]
+        );
+
+        // retried over TCP
+        let answers = lookup(&tasks, "big.example.com").await;
+        assert_eq!(records(&answers), [("big.example.com.".into(), RRType::A)]);
+
+        let answers = lookup(&tasks, "nx.example.com").await;
+        assert!(matches!(
+            &answers[..],
+            [Answer::Error(PacketError::NameError(n))] if *n == name("nx.example.com")
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_unreachable() {
+        // nothing listens on the root
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let root = socket.local_addr().unwrap();
+        drop(socket);
+
+        let (tasks, rec) = mpsc::unbounded_channel();
+        tokio::spawn(Resolver::new(rec).with_roots(vec![root]).run());
+        let answers = lookup(&tasks, "www.example.com").await;
+        assert!(matches!(
+            &answers[..],
+            [Answer::Error(PacketError::ServFail)]
+        ));
+    }
+}
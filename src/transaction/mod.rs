@@ -0,0 +1,657 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use tokio::{sync::mpsc, time::timeout};
+
+use crate::{
+    blocklist::{CompiledList, DomainList, PatternList, RebindFilter, TunnelDetector},
+    cache::DnsCache,
+    comm::{Answer, ClientSubnet, Task},
+    protocol::{Name, PacketError, Question, RRData, RRType, RR},
+    zone::Zone,
+};
+
+/// at most this many search domains are tried for a query, as many as `resolv.conf` allows
+const MAX_SEARCH_DOMAINS: usize = 6;
+/// time a query may take in total by default, about when stub resolvers give up
+const QUERY_BUDGET: Duration = Duration::from_secs(10);
+
+/// `Transaction` answers tasks from the serving services,
+/// looking up the cache which forwards misses to upstream.
+#[derive(Clone)]
+pub struct Transaction {
+    cache: DnsCache,
+    zone: Option<Arc<Zone>>,
+    search: Arc<Vec<Name>>,
+    patterns: Option<Arc<PatternList>>,
+    list: Option<Arc<DomainList>>,
+    compiled: Option<Arc<CompiledList>>,
+    rebind_filter: Option<Arc<RebindFilter>>,
+    detector: Option<Arc<TunnelDetector>>,
+    svcb_hints: bool,
+    budget: Duration,
+}
+
+impl Transaction {
+    pub fn new(cache: DnsCache) -> Self {
+        Self {
+            cache,
+            zone: None,
+            search: Arc::new(vec![]),
+            patterns: None,
+            list: None,
+            compiled: None,
+            rebind_filter: None,
+            detector: None,
+            svcb_hints: false,
+            budget: QUERY_BUDGET,
+        }
+    }
+
+    /// answer queries covered by the local records of `zone` before anything else
+    pub fn with_zone(mut self, zone: Zone) -> Self {
+        self.zone = Some(Arc::new(zone));
+        self
+    }
+
+    /// retry single-label queries that got NXDOMAIN with each of `domains` appended,
+    /// like the search list of a stub resolver.
+    ///
+    /// Duplicated domains are dropped, and only the first 6 domains are kept.
+    pub fn with_search_list(mut self, domains: Vec<Name>) -> Self {
+        let mut search: Vec<Name> = vec![];
+        for domain in domains {
+            if domain.label_count() != 0 && !search.contains(&domain) {
+                search.push(domain);
+            }
+        }
+        search.truncate(MAX_SEARCH_DOMAINS);
+        self.search = Arc::new(search);
+        self
+    }
+
+    /// answer queries whose names match `patterns` by the configured action,
+    /// after the domain list and the compiled list are checked
+    pub fn with_patterns(mut self, patterns: PatternList) -> Self {
+        self.patterns = Some(Arc::new(patterns));
+        self
+    }
+
+    /// answer queries under names in `list` by its action,
+    /// without asking the cache or upstream
+    pub fn with_domain_list(mut self, list: DomainList) -> Self {
+        self.list = Some(Arc::new(list));
+        self
+    }
+
+    /// answer queries under names in the compiled `list` by its action,
+    /// after the domain list is checked
+    pub fn with_compiled_list(mut self, list: CompiledList) -> Self {
+        self.compiled = Some(Arc::new(list));
+        self
+    }
+
+    /// filter answers pointing public names to private addresses
+    pub fn with_rebind_filter(mut self, filter: RebindFilter) -> Self {
+        self.rebind_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// watch queries for DNS tunneling by `detector`, once the blocklists pass them
+    pub fn with_tunnel_detector(mut self, detector: TunnelDetector) -> Self {
+        self.detector = Some(Arc::new(detector));
+        self
+    }
+
+    /// add the `ipv4hint` and `ipv6hint` addresses of SVCB and HTTPS answers
+    /// as A and AAAA records to the additional section, unless upstream added them already
+    pub fn with_svcb_hints(mut self, svcb_hints: bool) -> Self {
+        self.svcb_hints = svcb_hints;
+        self
+    }
+
+    /// fail queries taking longer than `budget` in total, 10 seconds by default.
+    ///
+    /// Upstreams, retries and CNAME chains have timeouts of their own,
+    /// which may add up to more than clients wait for.
+    pub fn with_budget(mut self, budget: Duration) -> Self {
+        self.budget = budget;
+        self
+    }
+
+    pub async fn run(self, mut tasks: mpsc::UnboundedReceiver<Task>) {
+        tracing::info!("initiated transaction layer");
+        let lookups = futures::stream::FuturesUnordered::new();
+        while let Some(task) = tasks.recv().await {
+            tracing::debug!("received task");
+
+            match task {
+                Task::Query(query, ans_sender, client) => {
+                    tracing::debug!("looking up local cache for query: {}", query.get_name());
+                    let transaction = self.clone();
+                    let lookup = tokio::spawn(async move {
+                        let name = query.get_name();
+                        // stop looking up once the client is gone
+                        let answers = tokio::select! {
+                            answers = transaction.lookup_for(query, client) => answers,
+                            _ = ans_sender.closed() => {
+                                tracing::debug!("transaction on query {} cancelled", name);
+                                return;
+                            }
+                        };
+                        for ans in answers.into_iter() {
+                            let _ = ans_sender.send(ans);
+                        }
+                        tracing::debug!("transaction on query {} successful!", name);
+                    });
+                    lookups.push(lookup);
+                }
+            };
+        }
+        for lookup in lookups {
+            let _ = tokio::join!(lookup);
+        }
+    }
+
+    /// answer `query`, or fail with `PacketError::TimedOut` once it runs out of the budget
+    pub async fn lookup(&self, query: Question) -> Vec<Answer> {
+        self.lookup_for(query, None).await
+    }
+
+    /// answer `query` asked from `client`, which upstreams may answer differently, see `lookup`
+    pub async fn lookup_for(&self, query: Question, client: Option<ClientSubnet>) -> Vec<Answer> {
+        let name = query.get_name();
+        match timeout(self.budget, self.answer(query, client)).await {
+            Ok(answers) => answers,
+            Err(_) => {
+                tracing::warn!("query {} ran out of its {:?} budget", name, self.budget);
+                vec![Answer::Error(PacketError::TimedOut)]
+            }
+        }
+    }
+
+    async fn answer(&self, query: Question, client: Option<ClientSubnet>) -> Vec<Answer> {
+        if let Some(answers) = self
+            .zone
+            .as_ref()
+            .and_then(|zone| zone.lookup_for(&query, client))
+        {
+            tracing::debug!("query {} answered by local records", query.get_name());
+            return answers;
+        }
+
+        let name = query.get_name();
+        if let Some(action) = self
+            .list
+            .as_ref()
+            .and_then(|list| list.check(&name))
+            .or_else(|| self.compiled.as_ref().and_then(|list| list.check(&name)))
+            .or_else(|| self.patterns.as_ref().and_then(|p| p.check(&name)))
+        {
+            tracing::debug!("query {} blocked: {:?}", query.get_name(), action);
+            return action.answer(&query);
+        }
+        if let Some(action) = self.detector.as_ref().and_then(|d| d.check(&query)) {
+            return action.answer(&query);
+        }
+
+        let mut answers = self.resolve(query.clone(), client).await;
+        if self.svcb_hints {
+            let hints = svcb_hints(&query, &answers);
+            answers.extend(hints);
+        }
+        match &self.rebind_filter {
+            Some(filter) => filter.filter(&query, answers),
+            None => answers,
+        }
+    }
+
+    /// look up the cache, expanding single-label names by the search list
+    async fn resolve(&self, query: Question, client: Option<ClientSubnet>) -> Vec<Answer> {
+        let mut cache = self.cache.clone();
+        let answers = cache.get_for(query.clone(), client).await;
+
+        // names with more labels are never expanded, so expansions cannot loop
+        let name = query.get_name();
+        if name.label_count() != 1 || !is_name_error(&answers) {
+            return answers;
+        }
+
+        for domain in self.search.iter() {
+            let expanded = match name.join(domain) {
+                Ok(expanded) => expanded,
+                Err(_) => continue,
+            };
+            tracing::debug!("search {} for query {}", expanded, name);
+            let mut q = query.clone();
+            q.set_name(expanded.clone());
+            let found = cache.get_for(q, client).await;
+            if found.iter().any(|ans| matches!(ans, Answer::Error(_))) {
+                continue;
+            }
+
+            // alias the queried name to the expanded one, so the answers match the question
+            let alias = RR::new(
+                name,
+                Duration::ZERO,
+                query.get_class(),
+                RRData::Cname(expanded.into()),
+            );
+            return [vec![Answer::Answer(alias)], found].concat();
+        }
+        answers
+    }
+}
+
+/// A and AAAA records of the address hints in SVCB and HTTPS answers to `query`,
+/// owned by the target names, or by the owner names for the target `.`
+fn svcb_hints(query: &Question, answers: &[Answer]) -> Vec<Answer> {
+    let mut hints: Vec<(Name, Duration, IpAddr)> = vec![];
+    for rr in answers.iter().filter_map(|ans| match ans {
+        Answer::Answer(rr) => Some(rr),
+        _ => None,
+    }) {
+        let svcb = match rr.clone().into_rdata() {
+            RRData::Svcb(svcb) | RRData::Https(svcb) if svcb.get_priority() != 0 => svcb,
+            _ => continue,
+        };
+        let target = match svcb.get_target() {
+            target if target.label_count() == 0 => rr.get_domain(),
+            target => target,
+        };
+        let v4 = svcb
+            .ipv4hint()
+            .unwrap_or_default()
+            .into_iter()
+            .map(IpAddr::from);
+        let v6 = svcb
+            .ipv6hint()
+            .unwrap_or_default()
+            .into_iter()
+            .map(IpAddr::from);
+        for address in v4.chain(v6) {
+            let ty = match address {
+                IpAddr::V4(_) => RRType::A,
+                IpAddr::V6(_) => RRType::Aaaa,
+            };
+            // addresses given by upstream are trusted over the hints
+            let given = answers.iter().any(|ans| match ans {
+                Answer::Additional(rr) => rr.get_domain() == target && rr.get_type() == ty,
+                _ => false,
+            });
+            let hinted = hints.iter().any(|(n, _, a)| *n == target && *a == address);
+            if !given && !hinted {
+                hints.push((target.clone(), rr.get_ttl(), address));
+            }
+        }
+    }
+    hints
+        .into_iter()
+        .map(|(target, ttl, address)| {
+            let rdata = match address {
+                IpAddr::V4(a) => RRData::A(a.into()),
+                IpAddr::V6(aaaa) => RRData::Aaaa(aaaa.into()),
+            };
+            Answer::Additional(RR::new(target, ttl, query.get_class(), rdata))
+        })
+        .collect()
+}
+
+fn is_name_error(answers: &[Answer]) -> bool {
+    answers
+        .iter()
+        .any(|ans| matches!(ans, Answer::Error(PacketError::NameError(_))))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        net::{Ipv4Addr, Ipv6Addr},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::sync::mpsc;
+
+    use super::Transaction;
+    use crate::{
+        blocklist::{Action, DomainList, PatternList, RebindFilter},
+        cache::DnsCache,
+        comm::{Answer, Task},
+        protocol::{Https, Name, PacketError, Question, RRClass, RRData, RRType, RR},
+    };
+
+    /// a mock upstream knowing only `web.corp.internal.`, returns the count of forwarded queries,
+    /// SOA lookups made by the cache for bare NXDOMAIN answers are not counted
+    fn upstream(mut rec: mpsc::UnboundedReceiver<Task>) -> Arc<AtomicUsize> {
+        let count = Arc::new(AtomicUsize::new(0));
+        let counter = count.clone();
+        let known = Name::try_from("web.corp.internal").unwrap();
+        tokio::spawn(async move {
+            while let Some(Task::Query(q, ans_to, _)) = rec.recv().await {
+                if q.get_type() != RRType::Soa {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                }
+                let ans = if q.get_name() == known {
+                    let a = RRData::A(Ipv4Addr::new(192, 0, 2, 1).into());
+                    let rr = RR::new(q.get_name(), Duration::from_secs(60), q.get_class(), a);
+                    Answer::Answer(rr)
+                } else {
+                    Answer::Error(PacketError::NameError(q.get_name()))
+                };
+                let _ = ans_to.send(ans);
+            }
+        });
+        count
+    }
+
+    fn transaction(search: &[&str]) -> (Transaction, Arc<AtomicUsize>) {
+        let (rec_sender, rec) = mpsc::unbounded_channel();
+        let forwarded = upstream(rec);
+        let search = search.iter().map(|d| Name::try_from(*d).unwrap()).collect();
+        let cache = DnsCache::new(16, rec_sender);
+        (Transaction::new(cache).with_search_list(search), forwarded)
+    }
+
+    fn question(name: &str) -> Question {
+        Question::build(Name::try_from(name).unwrap(), RRType::A, RRClass::Internet)
+    }
+
+    #[tokio::test]
+    async fn test_search_list() {
+        let (transaction, forwarded) = transaction(&["example.com", "corp.internal"]);
+        let answers = transaction.lookup(question("web")).await;
+        let expanded = Name::try_from("web.corp.internal").unwrap();
+        match &answers[..] {
+            [Answer::Answer(alias), Answer::Answer(a)] => {
+                assert_eq!(alias.get_domain(), Name::try_from("web").unwrap());
+                match alias.clone().into_rdata() {
+                    RRData::Cname(cname) => assert_eq!(Name::from(cname), expanded),
+                    rdata => panic!("unexpected alias: {:?}", rdata),
+                }
+                assert_eq!(a.get_domain(), expanded);
+            }
+            _ => panic!("unexpected answers: {:?}", answers),
+        }
+        // web., web.example.com. and web.corp.internal.
+        assert_eq!(forwarded.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_search_list_bounds() {
+        let (transaction, forwarded) = transaction(&["a", "b", "c", "d", "e", "f", "g", "a"]);
+        let answers = transaction.lookup(question("web")).await;
+        assert!(matches!(
+            answers[..],
+            [Answer::Error(PacketError::NameError(_))]
+        ));
+        // the query itself, and the first 6 distinct domains
+        assert_eq!(forwarded.load(Ordering::SeqCst), 7);
+
+        // names with more than one label are not expanded
+        let answers = transaction.lookup(question("web.corp")).await;
+        assert!(matches!(
+            answers[..],
+            [Answer::Error(PacketError::NameError(_))]
+        ));
+        assert_eq!(forwarded.load(Ordering::SeqCst), 8);
+    }
+
+    #[tokio::test]
+    async fn test_patterns() {
+        let (transaction, forwarded) = transaction(&[]);
+        let patterns = PatternList::builder()
+            .glob("*.doubleclick.*", Action::NxDomain)
+            .regex(r"^ads?\d*\.", Action::Sinkhole)
+            .glob("telemetry.*", Action::NoData)
+            .glob("*.kids.example", Action::Refused)
+            .glob("portal.example", "redirect 192.0.2.1".parse().unwrap())
+            .build()
+            .unwrap();
+        let transaction = transaction.with_patterns(patterns);
+
+        let answers = transaction.lookup(question("ad.doubleclick.net")).await;
+        assert!(matches!(
+            answers[..],
+            [Answer::Error(PacketError::NameError(_))]
+        ));
+
+        let answers = transaction.lookup(question("ads1.example.com")).await;
+        match &answers[..] {
+            [Answer::Answer(rr)] => match rr.clone().into_rdata() {
+                RRData::A(a) => assert_eq!(Ipv4Addr::from(a), Ipv4Addr::UNSPECIFIED),
+                rdata => panic!("unexpected sinkhole: {:?}", rdata),
+            },
+            _ => panic!("unexpected answers: {:?}", answers),
+        }
+
+        let answers = transaction.lookup(question("telemetry.example.com")).await;
+        assert!(answers.is_empty());
+
+        let answers = transaction.lookup(question("games.kids.example")).await;
+        assert!(matches!(
+            answers[..],
+            [Answer::Error(PacketError::Prohibited(_))]
+        ));
+
+        let answers = transaction.lookup(question("portal.example")).await;
+        match &answers[..] {
+            [Answer::Answer(rr)] => match rr.clone().into_rdata() {
+                RRData::A(a) => assert_eq!(Ipv4Addr::from(a), Ipv4Addr::new(192, 0, 2, 1)),
+                rdata => panic!("unexpected redirection: {:?}", rdata),
+            },
+            _ => panic!("unexpected answers: {:?}", answers),
+        }
+        assert_eq!(forwarded.load(Ordering::SeqCst), 0);
+
+        // names not matching are forwarded
+        transaction.lookup(question("web.corp.internal")).await;
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_domain_list() {
+        let (transaction, forwarded) = transaction(&[]);
+        let blocked = ["corp.internal", "tracker.example"];
+        let list = DomainList::new(
+            blocked.iter().map(|n| Name::try_from(*n).unwrap()),
+            Action::Sinkhole,
+        );
+        let transaction = transaction.with_domain_list(list);
+
+        for name in ["corp.internal", "web.corp.internal", "a.b.Tracker.Example"] {
+            let answers = transaction.lookup(question(name)).await;
+            match &answers[..] {
+                [Answer::Answer(rr)] => match rr.clone().into_rdata() {
+                    RRData::A(a) => assert_eq!(Ipv4Addr::from(a), Ipv4Addr::UNSPECIFIED),
+                    rdata => panic!("unexpected sinkhole: {:?}", rdata),
+                },
+                _ => panic!("unexpected answers of {}: {:?}", name, answers),
+            }
+        }
+        assert_eq!(forwarded.load(Ordering::SeqCst), 0);
+
+        // siblings and parents of blocked names are forwarded
+        for name in ["other.internal", "internal", "tracker2.example"] {
+            transaction.lookup(question(name)).await;
+        }
+        assert_eq!(forwarded.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_domain_list_before_patterns() {
+        let (transaction, _) = transaction(&[]);
+        let list = DomainList::new(
+            [Name::try_from("tracker.example").unwrap()],
+            Action::Sinkhole,
+        );
+        let patterns = PatternList::builder()
+            .glob("*.example", Action::NxDomain)
+            .build()
+            .unwrap();
+        let transaction = transaction.with_patterns(patterns).with_domain_list(list);
+
+        let answers = transaction.lookup(question("a.tracker.example")).await;
+        assert!(matches!(answers[..], [Answer::Answer(_)]));
+        let answers = transaction.lookup(question("other.example")).await;
+        assert!(matches!(
+            answers[..],
+            [Answer::Error(PacketError::NameError(_))]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_svcb_hints() {
+        let (rec_sender, mut rec) = mpsc::unbounded_channel();
+        // `1 . ipv4hint=192.0.2.1,192.0.2.2 ipv6hint=2001:db8::1`, with the AAAA record given
+        tokio::spawn(async move {
+            while let Some(Task::Query(q, ans_to, _)) = rec.recv().await {
+                let ttl = Duration::from_secs(60);
+                let https = Https::new(1, Name::try_from(".").unwrap())
+                    .with_param(4, vec![192, 0, 2, 1, 192, 0, 2, 2])
+                    .with_param(6, Ipv6Addr::LOCALHOST.octets().to_vec());
+                let rr = RR::new(q.get_name(), ttl, q.get_class(), RRData::Https(https));
+                let _ = ans_to.send(Answer::Answer(rr));
+                let aaaa = RRData::Aaaa(Ipv6Addr::LOCALHOST.into());
+                let rr = RR::new(q.get_name(), ttl, q.get_class(), aaaa);
+                let _ = ans_to.send(Answer::Additional(rr));
+            }
+        });
+        let query = Question::build(
+            Name::try_from("example.com").unwrap(),
+            RRType::Https,
+            RRClass::Internet,
+        );
+
+        let transaction = Transaction::new(DnsCache::new(16, rec_sender));
+        let answers = transaction.lookup(query.clone()).await;
+        assert_eq!(answers.len(), 2, "unexpected answers: {:?}", answers);
+
+        let transaction = transaction.with_svcb_hints(true);
+        let answers = transaction.lookup(query).await;
+        let hinted: Vec<_> = answers[2..]
+            .iter()
+            .map(|ans| match ans {
+                Answer::Additional(rr) => {
+                    assert_eq!(rr.get_domain(), Name::try_from("example.com").unwrap());
+                    match rr.clone().into_rdata() {
+                        RRData::A(a) => Ipv4Addr::from(a),
+                        rdata => panic!("unexpected hint: {:?}", rdata),
+                    }
+                }
+                ans => panic!("unexpected answer: {:?}", ans),
+            })
+            .collect();
+        assert_eq!(
+            hinted,
+            vec![Ipv4Addr::new(192, 0, 2, 1), Ipv4Addr::new(192, 0, 2, 2)]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_zone() {
+        let (transaction, forwarded) = transaction(&[]);
+        let zone = "nas.home.arpa A 300 192.168.1.10".parse().unwrap();
+        let transaction = transaction.with_zone(zone);
+
+        let answers = transaction.lookup(question("NAS.home.arpa")).await;
+        match &answers[..] {
+            [Answer::Authoritative, Answer::Answer(rr)] => match rr.clone().into_rdata() {
+                RRData::A(a) => assert_eq!(Ipv4Addr::from(a), Ipv4Addr::new(192, 168, 1, 10)),
+                rdata => panic!("unexpected local record: {:?}", rdata),
+            },
+            _ => panic!("unexpected answers: {:?}", answers),
+        }
+        assert_eq!(forwarded.load(Ordering::SeqCst), 0);
+
+        // misses are forwarded
+        let answers = transaction.lookup(question("web.corp.internal")).await;
+        assert!(matches!(answers[..], [Answer::Answer(_)]));
+        assert_eq!(forwarded.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rebind_filter() {
+        let (rec_sender, mut rec) = mpsc::unbounded_channel();
+        // every name resolves to loopback
+        tokio::spawn(async move {
+            while let Some(Task::Query(q, ans_to, _)) = rec.recv().await {
+                let a = RRData::A(Ipv4Addr::LOCALHOST.into());
+                let rr = RR::new(q.get_name(), Duration::from_secs(60), q.get_class(), a);
+                let _ = ans_to.send(Answer::Answer(rr));
+            }
+        });
+        let local = Name::try_from("corp.internal").unwrap();
+        let transaction = Transaction::new(DnsCache::new(16, rec_sender))
+            .with_rebind_filter(RebindFilter::default().with_local_zones(vec![local]));
+
+        let answers = transaction.lookup(question("evil.example.com")).await;
+        assert!(answers.is_empty(), "unexpected answers: {:?}", answers);
+
+        let answers = transaction.lookup(question("web.corp.internal")).await;
+        assert!(matches!(answers[..], [Answer::Answer(_)]));
+    }
+
+    /// a mock resolver chasing a chain of 8 CNAME records under `example.com.`,
+    /// each found by a retry after a name server timed out in 2 seconds
+    fn slow_upstream(mut rec: mpsc::UnboundedReceiver<Task>) {
+        tokio::spawn(async move {
+            while let Some(Task::Query(q, ans_to, _)) = rec.recv().await {
+                tokio::spawn(async move {
+                    let mut owner = q.get_name();
+                    let mut chain = vec![];
+                    for i in 1..=8 {
+                        tokio::time::sleep(Duration::from_secs(2)).await;
+                        let target = Name::try_from(format!("link{}.example.com", i).as_str());
+                        let target = target.unwrap();
+                        let cname = RRData::Cname(target.clone().into());
+                        let ttl = Duration::from_secs(60);
+                        chain.push(RR::new(owner, ttl, q.get_class(), cname));
+                        owner = target;
+                    }
+                    for rr in chain {
+                        let _ = ans_to.send(Answer::Answer(rr));
+                    }
+                });
+            }
+        });
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_budget() {
+        let (rec_sender, rec) = mpsc::unbounded_channel();
+        slow_upstream(rec);
+        let transaction = Transaction::new(DnsCache::new(16, rec_sender));
+
+        // failed right at the deadline, though the chain takes 16 seconds
+        let start = tokio::time::Instant::now();
+        let answers = transaction.lookup(question("alias.example.com")).await;
+        assert!(matches!(
+            answers[..],
+            [Answer::Error(PacketError::TimedOut)]
+        ));
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+
+        let transaction = transaction.with_budget(Duration::from_secs(5));
+        let start = tokio::time::Instant::now();
+        let answers = transaction.lookup(question("other.example.com")).await;
+        assert!(matches!(
+            answers[..],
+            [Answer::Error(PacketError::TimedOut)]
+        ));
+        assert_eq!(start.elapsed(), Duration::from_secs(5));
+
+        // chased to the end within a larger budget
+        let transaction = transaction.with_budget(Duration::from_secs(20));
+        let answers = transaction.lookup(question("www.example.com")).await;
+        assert_eq!(answers.len(), 8);
+    }
+}
@@ -0,0 +1,532 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
+
+use thiserror::Error;
+
+use crate::{
+    blocklist::Network,
+    comm::{Answer, ClientSubnet},
+    protocol::{Name, Question, RRClass, RRData, RRType, RR},
+};
+
+/// CNAME records followed at most within the zone
+const MAX_CNAMES: usize = 8;
+
+/// Error occurred in loading local records
+#[derive(Error, Debug)]
+pub enum ZoneError {
+    #[error("cannot read zone file: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("invalid record at line {0}: {1}")]
+    InvalidRecord(usize, String),
+}
+
+/// ## `Zone`
+/// Local records answered authoritatively, before the cache or upstream is asked.
+///
+/// Records are read from lines of name, type, TTL in seconds and value, like
+/// `www.example.com A 300 192.0.2.1`, comments start with `#`.
+/// Only `A`, `AAAA` and `CNAME` records are supported, and `*.example.com` matches
+/// every name under `example.com` without records of its own,
+/// names owning records of other types have no data of the wildcard types.
+/// `PTR` queries of the addresses are answered by the names owning them.
+///
+/// Records following a `$VIEW 192.0.2.0/24` line are only answered to clients within the network,
+/// by the ECS option of their queries or their addresses, see `with_view`.
+///
+/// Queries for types a name has no records of are still forwarded.
+#[derive(Debug, Clone, Default)]
+pub struct Zone {
+    records: HashMap<(Name, RRType), Vec<RR>>,
+    // names owning records of any type
+    owners: HashSet<Name>,
+    // `PTR` records synthesized from the `A` and `AAAA` records, by their reverse names
+    reverse: HashMap<Name, Vec<RR>>,
+    views: Vec<(Network, Zone)>,
+}
+
+impl Zone {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ZoneError> {
+        std::fs::read_to_string(path)?.parse()
+    }
+
+    /// answer clients within `network` by the records of `view` first,
+    /// views of longer networks covering a client are looked up before the shorter ones
+    pub fn with_view(mut self, network: Network, view: Zone) -> Self {
+        self.views.push((network, view));
+        self
+    }
+
+    /// count of the records, including those of the views
+    pub fn len(&self) -> usize {
+        let views: usize = self.views.iter().map(|(_, view)| view.len()).sum();
+        self.records.values().map(Vec::len).sum::<usize>() + views
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// the views of networks covering the whole subnet of `client`, the longest first
+    fn views(&self, client: ClientSubnet) -> Vec<&Zone> {
+        let mut views: Vec<_> = self
+            .views
+            .iter()
+            .filter(|(network, _)| {
+                network.prefix() <= client.prefix() && network.contains(client.address())
+            })
+            .collect();
+        views.sort_by_key(|(network, _)| std::cmp::Reverse(network.prefix()));
+        views.into_iter().map(|(_, view)| view).collect()
+    }
+
+    fn insert(&mut self, rr: RR) {
+        let (name, ttl) = (rr.get_domain(), rr.get_ttl());
+        let address = match rr.clone().into_rdata() {
+            RRData::A(a) => Some(IpAddr::from(Ipv4Addr::from(a))),
+            RRData::Aaaa(aaaa) => Some(IpAddr::from(Ipv6Addr::from(aaaa))),
+            _ => None,
+        };
+        let key = (rr.get_domain(), rr.get_type());
+        self.records.entry(key).or_default().push(rr);
+        self.owners.insert(name.clone());
+
+        // wildcards own no address of their own
+        let wildcard = name.labels().next() == Some(&b"*"[..]);
+        if let (Some(address), false) = (address, wildcard) {
+            let owner = reverse_name(address);
+            let names = self.reverse.entry(owner.clone()).or_default();
+            let known = names.iter().any(|rr| match rr.clone().into_rdata() {
+                RRData::Ptr(ptr) => Name::from(ptr) == name,
+                _ => false,
+            });
+            if !known {
+                let ptr = RRData::Ptr(name.into());
+                names.push(RR::new(owner, ttl, RRClass::Internet, ptr));
+            }
+        }
+    }
+
+    /// records of `name`, or of the closest wildcard covering it with the owner rewritten.
+    ///
+    /// Wildcards never cover names owning records of other types,
+    /// see [RFC4592](https://datatracker.ietf.org/doc/html/rfc4592#section-2.2.1).
+    fn find(&self, name: &Name, ty: RRType) -> Option<Vec<RR>> {
+        if ty == RRType::Ptr {
+            return self.reverse.get(name).cloned();
+        }
+        if let Some(records) = self.records.get(&(name.clone(), ty)) {
+            return Some(records.clone());
+        }
+        if self.owners.contains(name) {
+            return None;
+        }
+        self.wildcard(name, ty)
+    }
+
+    /// records of the closest wildcard covering `name`, with the owner rewritten
+    fn wildcard(&self, name: &Name, ty: RRType) -> Option<Vec<RR>> {
+        let wildcard = Name::try_from("*").unwrap();
+        let mut parent = name.clone();
+        while parent.label_count() != 0 {
+            parent = parent.get_parent_domain();
+            let key = match wildcard.join(&parent) {
+                Ok(key) => (key, ty),
+                Err(_) => continue,
+            };
+            if let Some(records) = self.records.get(&key) {
+                let synthesized = records
+                    .iter()
+                    .map(|rr| {
+                        let rdata = rr.clone().into_rdata();
+                        RR::new(name.clone(), rr.get_ttl(), RRClass::Internet, rdata)
+                    })
+                    .collect();
+                return Some(synthesized);
+            }
+        }
+        None
+    }
+
+    /// answers to `query` of `client`, from the first of its views covering the query,
+    /// or from the records of all, see `lookup`
+    pub fn lookup_for(
+        &self,
+        query: &Question,
+        client: Option<ClientSubnet>,
+    ) -> Option<Vec<Answer>> {
+        let views = client.map(|client| self.views(client)).unwrap_or_default();
+        views
+            .into_iter()
+            .find_map(|view| view.lookup(query))
+            .or_else(|| self.lookup(query))
+    }
+
+    /// answers to `query` from the local records, none if they do not cover it.
+    ///
+    /// CNAME chains within the zone are followed, the chain leads the answers.
+    pub fn lookup(&self, query: &Question) -> Option<Vec<Answer>> {
+        if query.get_class() != RRClass::Internet {
+            return None;
+        }
+        let ty = query.get_type();
+        let mut name = query.get_name();
+        let mut chain = vec![];
+        for _ in 0..=MAX_CNAMES {
+            if let Some(records) = self.find(&name, ty) {
+                chain.extend(records);
+                break;
+            }
+            let cname = match ty {
+                RRType::Cname => None,
+                _ => self.find(&name, RRType::Cname),
+            };
+            match cname.and_then(|records| records.into_iter().next()) {
+                Some(rr) => {
+                    if let RRData::Cname(target) = rr.clone().into_rdata() {
+                        name = Name::from(target);
+                    }
+                    chain.push(rr);
+                }
+                // a chain leaving the zone is completed by upstream
+                None => break,
+            }
+        }
+        if chain.is_empty() {
+            // names a wildcard would cover, were they not owning records, have no such data
+            if self.owners.contains(&name) && self.wildcard(&name, ty).is_some() {
+                return Some(vec![Answer::Authoritative]);
+            }
+            return None;
+        }
+        let answers = chain.into_iter().map(Answer::Answer);
+        Some(
+            std::iter::once(Answer::Authoritative)
+                .chain(answers)
+                .collect(),
+        )
+    }
+}
+
+/// the name under `in-addr.arpa` or `ip6.arpa` `PTR` records of `address` are owned by
+fn reverse_name(address: IpAddr) -> Name {
+    let name = match address {
+        IpAddr::V4(v4) => {
+            let [a, b, c, d] = v4.octets();
+            format!("{}.{}.{}.{}.in-addr.arpa", d, c, b, a)
+        }
+        IpAddr::V6(v6) => {
+            let nibbles: Vec<String> = v6
+                .octets()
+                .iter()
+                .rev()
+                .flat_map(|octet| [octet & 0xf, octet >> 4])
+                .map(|nibble| format!("{:x}", nibble))
+                .collect();
+            format!("{}.ip6.arpa", nibbles.join("."))
+        }
+    };
+    Name::try_from(name.as_str()).unwrap()
+}
+
+impl FromStr for Zone {
+    type Err = ZoneError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut zone = Zone::default();
+        // the network of the view records go to, none for the records of all
+        let mut view: Option<Network> = None;
+        for (i, line) in s.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+            let invalid = |reason: &str| ZoneError::InvalidRecord(i + 1, reason.to_string());
+            if let ["$VIEW", network] = words[..] {
+                view = Some(network.parse().map_err(|_| invalid("invalid network"))?);
+                continue;
+            }
+            let (name, ty, ttl, value) = match words[..] {
+                [name, ty, ttl, value] => (name, ty, ttl, value),
+                _ => return Err(invalid("expecting name, type, TTL and value")),
+            };
+            let name = Name::try_from(name).map_err(|_| invalid("invalid name"))?;
+            let ttl: u32 = ttl.parse().map_err(|_| invalid("invalid TTL"))?;
+            let rdata = match ty.to_ascii_uppercase().as_str() {
+                "A" => value
+                    .parse::<Ipv4Addr>()
+                    .map(|ip| RRData::A(ip.into()))
+                    .map_err(|_| invalid("invalid IPv4 address"))?,
+                "AAAA" => value
+                    .parse::<Ipv6Addr>()
+                    .map(|ip| RRData::Aaaa(ip.into()))
+                    .map_err(|_| invalid("invalid IPv6 address"))?,
+                "CNAME" => Name::try_from(value)
+                    .map(|target| RRData::Cname(target.into()))
+                    .map_err(|_| invalid("invalid name"))?,
+                _ => return Err(invalid("unsupported type")),
+            };
+            let ttl = Duration::from_secs(ttl as u64);
+            let rr = RR::new(name, ttl, RRClass::Internet, rdata);
+            match view {
+                Some(network) => match zone.views.iter_mut().find(|(n, _)| *n == network) {
+                    Some((_, view)) => view.insert(rr),
+                    None => {
+                        let mut records = Zone::default();
+                        records.insert(rr);
+                        zone.views.push((network, records));
+                    }
+                },
+                None => zone.insert(rr),
+            }
+        }
+        Ok(zone)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use super::{Zone, ZoneError};
+    use crate::{
+        comm::{Answer, ClientSubnet},
+        protocol::{Name, Question, RRClass, RRData, RRType},
+    };
+
+    const ZONE: &str = "
+        # local services
+        nas.home.arpa      A     300  192.168.1.10
+        NAS.home.arpa      AAAA  300  fd00::10
+        *.dev.home.arpa    A     60   192.168.1.20
+        media.home.arpa    CNAME 300  nas.home.arpa
+        docs.home.arpa     CNAME 300  docs.example.com  # served by upstream
+    ";
+
+    /// owners and data of the answers, the data is compared by its debug form
+    fn lookup(zone: &Zone, name: &str, ty: RRType) -> Option<Vec<(String, String)>> {
+        let query = Question::build(Name::try_from(name).unwrap(), ty, RRClass::Internet);
+        let answers = zone.lookup(&query)?;
+        assert!(matches!(answers[0], Answer::Authoritative));
+        let records = answers[1..]
+            .iter()
+            .map(|ans| match ans {
+                Answer::Answer(rr) => {
+                    let rdata = format!("{:?}", rr.clone().into_rdata());
+                    (rr.get_domain().to_string(), rdata)
+                }
+                ans => panic!("unexpected answer {:?}", ans),
+            })
+            .collect();
+        Some(records)
+    }
+
+    fn a(ip: [u8; 4]) -> String {
+        format!("{:?}", RRData::A(Ipv4Addr::from(ip).into()))
+    }
+
+    #[test]
+    fn test_exact() {
+        let zone: Zone = ZONE.parse().unwrap();
+        assert_eq!(zone.len(), 5);
+        assert_eq!(
+            lookup(&zone, "Nas.Home.Arpa", RRType::A),
+            Some(vec![("nas.home.arpa.".into(), a([192, 168, 1, 10]))])
+        );
+        assert_eq!(
+            lookup(&zone, "nas.home.arpa", RRType::Aaaa),
+            Some(vec![(
+                "NAS.home.arpa.".into(),
+                format!(
+                    "{:?}",
+                    RRData::Aaaa("fd00::10".parse::<Ipv6Addr>().unwrap().into())
+                )
+            )])
+        );
+        assert_eq!(
+            lookup(&zone, "media.home.arpa", RRType::A),
+            Some(vec![
+                (
+                    "media.home.arpa.".into(),
+                    format!(
+                        "{:?}",
+                        RRData::Cname(Name::try_from("nas.home.arpa").unwrap().into())
+                    )
+                ),
+                ("nas.home.arpa.".into(), a([192, 168, 1, 10])),
+            ])
+        );
+        // the rest of the chain is left to upstream
+        assert_eq!(lookup(&zone, "docs.home.arpa", RRType::A).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_wildcard() {
+        let zone: Zone = ZONE.parse().unwrap();
+        for name in ["api.dev.home.arpa", "a.b.DEV.home.arpa"] {
+            let expected = format!("{}.", name);
+            assert_eq!(
+                lookup(&zone, name, RRType::A),
+                Some(vec![(expected, a([192, 168, 1, 20]))])
+            );
+        }
+        assert_eq!(lookup(&zone, "dev.home.arpa", RRType::A), None);
+    }
+
+    #[test]
+    fn test_wildcard_existing() {
+        let zone: Zone = "
+            *.dev.home.arpa    A     60   192.168.1.20
+            api.dev.home.arpa  AAAA  60   fd00::20
+            ci.dev.home.arpa   CNAME 60   nas.home.arpa
+            nas.home.arpa      A     300  192.168.1.10
+        "
+        .parse()
+        .unwrap();
+        // names with records of their own are not covered, but have no data of the type
+        assert_eq!(lookup(&zone, "api.dev.home.arpa", RRType::A), Some(vec![]));
+        assert_eq!(
+            lookup(&zone, "api.dev.home.arpa", RRType::Aaaa)
+                .unwrap()
+                .len(),
+            1
+        );
+        // aliases are followed instead
+        assert_eq!(
+            lookup(&zone, "ci.dev.home.arpa", RRType::A).unwrap()[1],
+            ("nas.home.arpa.".into(), a([192, 168, 1, 10]))
+        );
+        // types no wildcard has are still forwarded
+        assert_eq!(lookup(&zone, "api.dev.home.arpa", RRType::Mx), None);
+    }
+
+    #[test]
+    fn test_miss() {
+        let zone: Zone = ZONE.parse().unwrap();
+        assert_eq!(lookup(&zone, "printer.home.arpa", RRType::A), None);
+        assert_eq!(lookup(&zone, "api.dev.home.arpa", RRType::Aaaa), None);
+        assert_eq!(lookup(&zone, "nas.home.arpa", RRType::Mx), None);
+        assert_eq!(lookup(&zone, "home.arpa", RRType::A), None);
+    }
+
+    #[test]
+    fn test_reverse() {
+        let zone: Zone = "
+            host.example.com  A     300  192.0.2.5
+            www.example.com   A     60   192.0.2.5
+            WWW.example.com   A     60   192.0.2.5
+            host.example.com  AAAA  300  2001:db8::5
+            *.example.com     A     60   192.0.2.9
+        "
+        .parse()
+        .unwrap();
+        let ptr = |name: &str| format!("{:?}", RRData::Ptr(Name::try_from(name).unwrap().into()));
+
+        // names owning one address share it, each once
+        let owner = "5.2.0.192.in-addr.arpa.";
+        assert_eq!(
+            lookup(&zone, "5.2.0.192.in-addr.arpa", RRType::Ptr),
+            Some(vec![
+                (owner.into(), ptr("host.example.com")),
+                (owner.into(), ptr("www.example.com"))
+            ])
+        );
+        let owner = "5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa";
+        assert_eq!(
+            lookup(&zone, owner, RRType::Ptr),
+            Some(vec![(format!("{}.", owner), ptr("host.example.com"))])
+        );
+
+        // addresses of wildcards and unknown ones are left to upstream
+        assert_eq!(lookup(&zone, "9.2.0.192.in-addr.arpa", RRType::Ptr), None);
+        assert_eq!(lookup(&zone, "6.2.0.192.in-addr.arpa", RRType::Ptr), None);
+    }
+
+    #[test]
+    fn test_views() {
+        let zone: Zone = "
+            www.example.com  A  300  192.0.2.1
+            nas.example.com  A  300  192.0.2.2
+            $VIEW 198.51.100.0/24
+            www.example.com  A  300  198.51.100.10
+            $VIEW 198.51.0.0/16
+            www.example.com  A  300  198.51.0.10
+            api.example.com  A  300  198.51.0.20
+        "
+        .parse()
+        .unwrap();
+        assert_eq!(zone.len(), 5);
+        let lookup = |client: &str, name: &str| {
+            let client = ClientSubnet::from(client.parse::<IpAddr>().unwrap()).narrowed(24, 56);
+            let query =
+                Question::build(Name::try_from(name).unwrap(), RRType::A, RRClass::Internet);
+            match &zone.lookup_for(&query, Some(client)).unwrap()[..] {
+                [Answer::Authoritative, Answer::Answer(rr)] => {
+                    format!("{:?}", rr.clone().into_rdata())
+                }
+                answers => panic!("unexpected answers: {:?}", answers),
+            }
+        };
+
+        // the longest network covering the client answers
+        assert_eq!(
+            lookup("198.51.100.7", "www.example.com"),
+            a([198, 51, 100, 10])
+        );
+        assert_eq!(lookup("198.51.7.7", "www.example.com"), a([198, 51, 0, 10]));
+        assert_eq!(lookup("192.0.2.7", "www.example.com"), a([192, 0, 2, 1]));
+        assert_eq!(
+            lookup("::ffff:198.51.7.7", "www.example.com"),
+            a([198, 51, 0, 10])
+        );
+        // names out of a view are answered by the shorter views, then by the records of all
+        assert_eq!(
+            lookup("198.51.100.7", "api.example.com"),
+            a([198, 51, 0, 20])
+        );
+        assert_eq!(lookup("198.51.100.7", "nas.example.com"), a([192, 0, 2, 2]));
+
+        // subnets wider than the network are not covered
+        let wide = ClientSubnet::from("198.51.100.7".parse::<IpAddr>().unwrap()).narrowed(8, 56);
+        let query = Question::build(
+            Name::try_from("www.example.com").unwrap(),
+            RRType::A,
+            RRClass::Internet,
+        );
+        match &zone.lookup_for(&query, Some(wide)).unwrap()[..] {
+            [_, Answer::Answer(rr)] => {
+                assert_eq!(format!("{:?}", rr.clone().into_rdata()), a([192, 0, 2, 1]))
+            }
+            answers => panic!("unexpected answers: {:?}", answers),
+        }
+    }
+
+    #[test]
+    fn test_invalid() {
+        let cases = [
+            ("nas.home.arpa A 300", 1),
+            ("\nnas.home.arpa A 300 192.168.1.300", 2),
+            ("nas.home.arpa A forever 192.168.1.10", 1),
+            ("nas.home.arpa MX 300 mail.home.arpa", 1),
+            ("$VIEW 198.51.100.0/33", 1),
+        ];
+        for (text, line) in cases {
+            match text.parse::<Zone>() {
+                Err(ZoneError::InvalidRecord(l, _)) => assert_eq!(l, line, "{}", text),
+                result => panic!("unexpected result of {}: {:?}", text, result),
+            }
+        }
+    }
+}
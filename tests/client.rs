@@ -0,0 +1,76 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+mod common;
+
+use std::{net::Ipv4Addr, sync::Arc};
+
+use common::{Server, ADDRESS};
+use tsein_dns::{
+    client::{Client, Upstream},
+    protocol::{RRData, RRType, RR},
+};
+
+fn addresses(records: Vec<RR>) -> Vec<Ipv4Addr> {
+    records
+        .into_iter()
+        .filter_map(|rr| match rr.into_rdata() {
+            RRData::A(a) => Some(a.addr()),
+            _ => None,
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_client_tcp() {
+    let server = Server::spawn().await;
+    let client = Client::new(Upstream::Tcp(server.tcp)).await.unwrap();
+    let records = client.query("example.com", RRType::A).await.unwrap();
+    assert_eq!(addresses(records), vec![ADDRESS]);
+
+    // the mock upstream has no other records
+    let records = client.query("example.com", RRType::Aaaa).await.unwrap();
+    assert!(records.is_empty());
+    let too_long = format!("{}.example.com", "a".repeat(64));
+    assert!(client.query(&too_long, RRType::A).await.is_err());
+}
+
+#[tokio::test]
+async fn test_client_tls() {
+    let server = Server::spawn().await;
+    let upstream = Upstream::Tls {
+        addr: server.tls,
+        domain: "localhost".to_string(),
+        config: Arc::new(server.client_config()),
+    };
+    let client = Client::new(upstream).await.unwrap();
+    let records = client.query("example.com", RRType::A).await.unwrap();
+    assert_eq!(addresses(records), vec![ADDRESS]);
+}
+
+#[tokio::test]
+async fn test_client_quic() {
+    let server = Server::spawn().await;
+    let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+    let config = quinn::ClientConfig::new(Arc::new(server.client_config()));
+    endpoint.set_default_client_config(config);
+    let upstream = Upstream::Quic {
+        addr: server.quic,
+        domain: "localhost".to_string(),
+        endpoint,
+    };
+    let client = Client::new(upstream).await.unwrap();
+    let records = client.query("example.com", RRType::A).await.unwrap();
+    assert_eq!(addresses(records), vec![ADDRESS]);
+}
+
+#[tokio::test]
+async fn test_client_unreachable() {
+    let server = Server::spawn().await;
+    // nothing but UDP listens there
+    let upstream = Upstream::Tcp(server.udp);
+    assert!(Client::new(upstream).await.is_err());
+}
@@ -0,0 +1,266 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! a full server on ephemeral ports, forwarding to a mock upstream
+
+// each test crate uses a part of the helpers
+#![allow(dead_code)]
+
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::mpsc,
+};
+use tokio_rustls::{
+    rustls::{self, Certificate, PrivateKey, ServerName},
+    TlsConnector,
+};
+use tsein_dns::{
+    cache::DnsCache,
+    comm::{Answer, QuicService, Task, TcpService, TlsListener, TlsService, UdpService},
+    protocol::{Name, Packet, PacketError, Question, RRClass, RRData, RRType, RR},
+    transaction::Transaction,
+};
+
+/// the address every `A` query is answered with
+pub const ADDRESS: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+const CACHE_SIZE: u64 = 1024;
+
+/// `MockForwarder` answers `A` queries with `ADDRESS` in place of an upstream,
+/// other types are answered without records.
+/// Answers are marked authoritative, like those of the servers of a zone.
+///
+/// Names led by `nx` do not exist, the SOA of their parent comes with the NXDOMAIN,
+/// and names led by `servfail` fail.
+pub struct MockForwarder {
+    rec: mpsc::UnboundedReceiver<Task>,
+    forwarded: Arc<AtomicUsize>,
+}
+
+impl MockForwarder {
+    pub fn new(rec: mpsc::UnboundedReceiver<Task>) -> Self {
+        Self {
+            rec,
+            forwarded: Arc::default(),
+        }
+    }
+
+    /// the number of queries forwarded so far
+    pub fn counter(&self) -> Arc<AtomicUsize> {
+        self.forwarded.clone()
+    }
+
+    pub async fn run(mut self) {
+        while let Some(Task::Query(q, ans_to, _)) = self.rec.recv().await {
+            self.forwarded.fetch_add(1, Ordering::SeqCst);
+            let name = q.get_name();
+            match name.to_string().split('.').next() {
+                Some("nx") => {
+                    let _ = ans_to.send(Answer::Error(PacketError::NameError(name.clone())));
+                    let _ = ans_to.send(Answer::NameServer(soa(name.get_parent_domain())));
+                    continue;
+                }
+                Some("servfail") => {
+                    let _ = ans_to.send(Answer::Error(PacketError::ServFail));
+                    continue;
+                }
+                _ => {}
+            }
+            let _ = ans_to.send(Answer::Authoritative);
+            if q.get_type() == RRType::A {
+                let a = RRData::A(ADDRESS.into());
+                let rr = RR::new(q.get_name(), Duration::from_secs(300), q.get_class(), a);
+                let _ = ans_to.send(Answer::Answer(rr));
+            }
+        }
+    }
+}
+
+/// the SOA record of `zone`, with a negative TTL of 60 seconds
+pub fn soa(zone: Name) -> RR {
+    let mname = Name::try_from("ns.example")
+        .unwrap()
+        .as_bytes_uncompressed();
+    let rname = Name::try_from("admin.example")
+        .unwrap()
+        .as_bytes_uncompressed();
+    let mut pkt = BytesMut::new();
+    // a response with the record alone in its authority section
+    pkt.put_slice(&[0, 0, 0x81, 0x80, 0, 0, 0, 0, 0, 1, 0, 0]);
+    pkt.put(zone.as_bytes_uncompressed());
+    pkt.put_u16(RRType::Soa.into());
+    pkt.put_u16(RRClass::Internet.into());
+    pkt.put_u32(300);
+    pkt.put_u16((mname.len() + rname.len() + 4 * 5) as u16);
+    pkt.put(mname);
+    pkt.put(rname);
+    for field in [1, 3600, 600, 86400, 60] {
+        pkt.put_u32(field);
+    }
+    Packet::parse_packet(pkt.freeze(), 0)
+        .unwrap()
+        .authorities
+        .remove(0)
+}
+
+/// addresses of the running listeners, and the certificate TLS and QUIC are served by
+pub struct Server {
+    pub udp: SocketAddr,
+    pub tcp: SocketAddr,
+    pub tls: SocketAddr,
+    pub quic: SocketAddr,
+    pub cert: Certificate,
+    pub forwarded: Arc<AtomicUsize>,
+}
+
+impl Server {
+    /// bind all listeners on localhost, wired to the transaction layer,
+    /// the cache and a `MockForwarder` like `run` does
+    pub async fn spawn() -> Self {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_der = Certificate(cert.serialize_der().unwrap());
+        let key = PrivateKey(cert.serialize_private_key_der());
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert_der.clone()], key)
+            .unwrap();
+        let config = Arc::new(config);
+
+        let (tasks, task_recv) = mpsc::unbounded_channel();
+        let (rec_sender, rec_recv) = mpsc::unbounded_channel();
+        let forwarder = MockForwarder::new(rec_recv);
+        let forwarded = forwarder.counter();
+        tokio::spawn(forwarder.run());
+        let cache = DnsCache::new(CACHE_SIZE, rec_sender);
+        tokio::spawn(Transaction::new(cache).run(task_recv));
+
+        let local: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+        let udp_serve = UdpSocket::bind(local).await.unwrap();
+        let udp = udp_serve.local_addr().unwrap();
+        let forward = UdpSocket::bind(local).await.unwrap();
+        let udp_server = Arc::new(UdpService::new(udp_serve, forward));
+        tokio::spawn(udp_server.run_udp(tasks.clone()));
+
+        let tcp_serve = TcpListener::bind(local).await.unwrap();
+        let tcp = tcp_serve.local_addr().unwrap();
+        tokio::spawn(TcpService::new(tcp_serve, tasks.clone(), CACHE_SIZE).run());
+
+        let tls_underlay = TcpListener::bind(local).await.unwrap();
+        let tls = tls_underlay.local_addr().unwrap();
+        let tls_serve = TlsListener::new(tls_underlay, config.clone());
+        tokio::spawn(TlsService::new(tls_serve, tasks.clone(), CACHE_SIZE).run());
+
+        let quic_config = quinn::ServerConfig::with_crypto(config);
+        let (endpoint, incoming) = quinn::Endpoint::server(quic_config, local).unwrap();
+        let quic = endpoint.local_addr().unwrap();
+        tokio::spawn(async move {
+            // the endpoint stops serving once dropped
+            let _endpoint = endpoint;
+            QuicService::new(incoming, tasks).run().await
+        });
+
+        Self {
+            udp,
+            tcp,
+            tls,
+            quic,
+            cert: cert_der,
+            forwarded,
+        }
+    }
+
+    pub fn client_config(&self) -> rustls::ClientConfig {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(&self.cert).unwrap();
+        rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth()
+    }
+
+    pub async fn query_udp(&self, query: Packet) -> Packet {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client.connect(self.udp).await.unwrap();
+        client.send(&query.into_bytes()).await.unwrap();
+        let mut buf = [0; 1232];
+        let n = client.recv(&mut buf).await.unwrap();
+        Packet::parse_packet(Bytes::copy_from_slice(&buf[..n]), 0).unwrap()
+    }
+
+    pub async fn query_tcp(&self, query: Packet) -> Packet {
+        let mut stream = TcpStream::connect(self.tcp).await.unwrap();
+        exchange(&mut stream, query).await
+    }
+
+    pub async fn query_tls(&self, query: Packet) -> Packet {
+        let connector = TlsConnector::from(Arc::new(self.client_config()));
+        let tcp = TcpStream::connect(self.tls).await.unwrap();
+        let domain = ServerName::try_from("localhost").unwrap();
+        let mut stream = connector.connect(domain, tcp).await.unwrap();
+        exchange(&mut stream, query).await
+    }
+
+    pub async fn query_quic(&self, query: Packet) -> Packet {
+        let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint
+            .set_default_client_config(quinn::ClientConfig::new(Arc::new(self.client_config())));
+        let conn = endpoint
+            .connect(self.quic, "localhost")
+            .unwrap()
+            .await
+            .unwrap()
+            .connection;
+        let (mut send, recv) = conn.open_bi().await.unwrap();
+        send.write_all(&query.into_bytes()).await.unwrap();
+        send.finish().await.unwrap();
+        let buf = recv.read_to_end(u16::MAX as usize).await.unwrap();
+        Packet::parse_packet(buf.into(), 0).unwrap()
+    }
+}
+
+/// send a query over a stream prefixed by its length, and read the response back
+async fn exchange<S>(stream: &mut S, query: Packet) -> Packet
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let query = query.into_bytes();
+    stream
+        .write_all(&(query.len() as u16).to_be_bytes())
+        .await
+        .unwrap();
+    stream.write_all(&query).await.unwrap();
+    Packet::parse_stream(stream).await.unwrap()
+}
+
+pub fn query(id: u16, name: &str, ty: RRType) -> Packet {
+    let name = Name::try_from(name).unwrap();
+    Packet::new_query(id, Question::build(name, ty, RRClass::Internet))
+}
+
+/// check `resp` answers query `id` for `name` by `ADDRESS`
+pub fn assert_answered(resp: &Packet, id: u16, name: &str) {
+    assert_eq!(resp.get_id(), id);
+    assert!(!resp.is_query());
+    assert_eq!(resp.answers.len(), 1);
+    let answer = resp.answers[0].clone();
+    assert_eq!(answer.get_domain(), Name::try_from(name).unwrap());
+    match answer.into_rdata() {
+        RRData::A(a) => assert_eq!(Ipv4Addr::from(a), ADDRESS),
+        rdata => panic!("unexpected answer {:?}", rdata),
+    }
+}
@@ -0,0 +1,46 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+//! the forward timeout is set once per process, so it is tested apart from the other tests
+
+use std::{sync::Arc, time::Duration};
+
+use tokio::{net::UdpSocket, sync::mpsc};
+use tsein_dns::{
+    comm::{self, Answer, Task, UdpService},
+    protocol::{Name, PacketError, Question, RRClass, RRType},
+};
+
+#[tokio::test]
+async fn test_forward_timeout() {
+    assert!(comm::set_forward_timeout(Duration::from_millis(1)));
+    // too late for another one
+    assert!(!comm::set_forward_timeout(Duration::from_secs(5)));
+
+    // an upstream never answering
+    let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let forward = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    forward
+        .connect(upstream.local_addr().unwrap())
+        .await
+        .unwrap();
+    let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let service = Arc::new(UdpService::new(udp, forward));
+    let (task_sender, task_receiver) = mpsc::unbounded_channel();
+    tokio::spawn(service.run_forward(task_receiver));
+
+    let name = Name::try_from("example.com").unwrap();
+    let q = Question::build(name, RRType::A, RRClass::Internet);
+    let (answer_sender, mut answers) = mpsc::unbounded_channel();
+    task_sender
+        .send(Task::Query(q, answer_sender, None))
+        .unwrap();
+    let answer = tokio::time::timeout(Duration::from_secs(1), answers.recv())
+        .await
+        .expect("the timeout is not applied");
+    assert!(matches!(answer, Some(Answer::Error(PacketError::ServFail))));
+    assert!(answers.recv().await.is_none());
+}
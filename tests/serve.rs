@@ -0,0 +1,122 @@
+// Copyright (c) 2022 ClSlaid <cailue@bupt.edu.cn>
+//
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+mod common;
+
+use std::sync::atomic::Ordering;
+
+use common::{assert_answered, query, Server};
+use tsein_dns::protocol::{Name, RRType, Rcode};
+
+#[tokio::test]
+async fn test_udp() {
+    let server = Server::spawn().await;
+    let resp = server.query_udp(query(1, "example.com", RRType::A)).await;
+    assert_answered(&resp, 1, "example.com");
+}
+
+#[tokio::test]
+async fn test_tcp() {
+    let server = Server::spawn().await;
+    let resp = server.query_tcp(query(2, "example.com", RRType::A)).await;
+    assert_answered(&resp, 2, "example.com");
+}
+
+#[tokio::test]
+async fn test_tls() {
+    let server = Server::spawn().await;
+    let resp = server.query_tls(query(3, "example.com", RRType::A)).await;
+    assert_answered(&resp, 3, "example.com");
+}
+
+#[tokio::test]
+async fn test_quic() {
+    let server = Server::spawn().await;
+    let resp = server.query_quic(query(4, "example.com", RRType::A)).await;
+    assert_answered(&resp, 4, "example.com");
+}
+
+#[tokio::test]
+async fn test_cached_across_transports() {
+    let server = Server::spawn().await;
+    let resp = server.query_udp(query(5, "example.org", RRType::A)).await;
+    assert_answered(&resp, 5, "example.org");
+    let resp = server.query_tcp(query(6, "example.org", RRType::A)).await;
+    assert_answered(&resp, 6, "example.org");
+    assert_eq!(server.forwarded.load(Ordering::SeqCst), 1);
+
+    // answered without records, not failed
+    let resp = server
+        .query_tcp(query(7, "example.org", RRType::Aaaa))
+        .await;
+    assert_eq!(resp.get_rcode(), Rcode::NoError);
+    assert!(resp.answers.is_empty());
+}
+
+#[tokio::test]
+async fn test_cached_flags() {
+    let server = Server::spawn().await;
+    for id in 8..10 {
+        // we are not authoritative for answers from upstream, fresh or cached
+        let resp = server.query_udp(query(id, "example.net", RRType::A)).await;
+        assert_answered(&resp, id, "example.net");
+        assert!(!resp.is_auth());
+        assert!(resp.is_rec_avl());
+    }
+    assert_eq!(server.forwarded.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_udp_failures() {
+    let server = Server::spawn().await;
+    // failures are full responses over UDP too, like over TCP
+    let resp = server
+        .query_udp(query(10, "nx.example.com", RRType::A))
+        .await;
+    assert_eq!(resp.get_id(), 10);
+    assert_eq!(resp.get_rcode(), Rcode::NameError);
+    assert!(resp.is_rec_avl());
+    assert_eq!(resp.question_count(), 1);
+    let tcp = server
+        .query_tcp(query(10, "nx.example.com", RRType::A))
+        .await;
+    assert_eq!(resp.size(), tcp.size());
+
+    let resp = server
+        .query_udp(query(11, "servfail.example.com", RRType::A))
+        .await;
+    assert_eq!(resp.get_id(), 11);
+    assert_eq!(resp.get_rcode(), Rcode::ServFail);
+    assert!(resp.is_rec_avl());
+    assert_eq!(resp.question_count(), 1);
+}
+
+#[tokio::test]
+async fn test_negative_soa() {
+    let server = Server::spawn().await;
+    // the SOA of the zone lets downstream resolvers cache the NXDOMAIN, on every transport
+    let responses = [
+        server
+            .query_udp(query(12, "nx.example.com", RRType::A))
+            .await,
+        server
+            .query_tcp(query(13, "nx.example.com", RRType::A))
+            .await,
+        server
+            .query_tls(query(14, "nx.example.com", RRType::A))
+            .await,
+        server
+            .query_quic(query(15, "nx.example.com", RRType::A))
+            .await,
+    ];
+    for resp in responses {
+        assert_eq!(resp.get_rcode(), Rcode::NameError);
+        assert_eq!(resp.authorities.len(), 1);
+        let soa = resp.authorities[0].clone();
+        assert_eq!(soa.get_type(), RRType::Soa);
+        assert_eq!(soa.get_domain(), Name::try_from("example.com").unwrap());
+    }
+}